@@ -1,514 +1,4395 @@
-use std::fs::{copy, create_dir_all, File};
-use std::path::{Path, PathBuf};
+use std::fs::{read_dir, read_to_string, remove_file, rename, File, Metadata};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
-use crate::container::{handle_container_output, run_dockyard_command};
+use crate::catalog::record_backup;
+use crate::container::{
+    download_mount_archive, exec_in_container, export_image, get_bind_mount, get_volume_mount,
+    handle_container_output, remove_abandoned_helpers, run_dockyard_command, upload_to_mount,
+    OUT_OF_SPACE_MESSAGE,
+};
+use crate::exclude::ExcludePatterns;
+use crate::file::{ensure_absent, path_str, FileModes};
+use crate::gzip::ArchiveEncoder;
+use crate::hash::{HashAlgorithm, HashingReader};
+use crate::list::backup_file_exists;
+use crate::mount;
+use crate::network::{find_network_endpoints, NetworkEndpoint};
+use crate::progress::MountProgress;
+use crate::prune::{remove_old_backups, trim_backups};
+use crate::resume::{
+    partial_backup_path, read_partial_backup, record_partial_backup, verify_partial_backup,
+    PartialBackup,
+};
+use crate::snapshot::{take_snapshot, Snapshot};
+use crate::swarm::{find_swarm_references, SwarmReferences};
 use anyhow::{Context, Result};
-use bollard::container::InspectContainerOptions;
+use bollard::container::{InspectContainerOptions, LogOutput};
 use bollard::models::{
     ContainerConfig, ContainerInspectResponse, HostConfig, Mount, MountPoint, MountTypeEnum,
 };
+use bollard::volume::ListVolumesOptions;
 use bollard::Docker;
-use chrono::Utc;
+use chrono::{DateTime, FixedOffset, Local, Offset, Utc};
+use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use futures::future::*;
-use std::collections::HashSet;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use uuid::Uuid;
 
 /// Backup of volume/directory contents and mount info
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MountBackup {
     pub(crate) path: PathBuf,
     pub(crate) mount: MountPoint,
+    /// Checksum of the archive at `path` with `hash_algorithm`, not recorded for pointers to
+    /// earlier archives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) checksum: Option<String>,
+    /// Labels of the backed up volume, recreated with it on restore
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) volume_labels: HashMap<String, String>,
+    /// Directory a bind mount source that is a symlink resolved to when it was backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resolved_source: Option<String>,
+}
+
+/// Archive written by a volume or directory backup
+#[derive(Debug, PartialEq)]
+pub struct ArchiveBackup {
+    /// Path of archive relative to the backup destination
+    pub path: PathBuf,
+    /// Checksum of the archive with `hash_algorithm`, `None` if the backup only wrote a pointer to an
+    /// earlier archive
+    pub checksum: Option<String>,
+}
+
+/// Latest version of the `ContainerBackup` format, which this version of dockyard reads
+///
+/// Version 2 added `layout` and `hash_algorithm`, which change how archive paths and checksums
+/// are read. Backups that use the defaults for both are still written as version 1, see
+/// `schema_version_for`.
+pub const CONTAINER_BACKUP_SCHEMA_VERSION: u32 = 2;
+
+/// Return the oldest schema version that describes a backup with `layout` and `hash_algorithm`
+///
+/// Readers that predate version 2 would resolve flat layout archives against the backup root
+/// and check every checksum as SHA-256, so such backups are written as version 2 for them to
+/// refuse.
+///
+/// # Arguments
+///
+/// * `layout` - Layout of the backup
+/// * `hash_algorithm` - Algorithm of its archive checksums
+///
+pub(crate) fn schema_version_for(layout: Layout, hash_algorithm: HashAlgorithm) -> u32 {
+    if layout.is_nested() && hash_algorithm.is_default() {
+        1
+    } else {
+        2
+    }
 }
 
 /// Backup of container configs with links to volume/directory backups
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ContainerBackup {
+    /// Format version, manifests written before versioning was added are version 1
+    #[serde(default = "legacy_schema_version")]
+    pub(crate) schema_version: u32,
     pub(crate) name: String,
     pub(crate) container_config: ContainerConfig,
+    /// Host config as inspected, restored as is so bind propagation and consistency are kept
     pub(crate) host_config: HostConfig,
     pub(crate) mounts: Vec<MountBackup>,
+    /// Tarball of the container's image, relative to the backup mount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) image_archive: Option<PathBuf>,
+    /// ID of the image the container was running, to detect tags that moved since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) image_id: Option<String>,
+    /// Environment variables left out of `container_config`, to be set again on restore
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) redacted_env: Vec<String>,
+    /// Layout the backup was written in, which decides what archive paths are relative to
+    #[serde(default, skip_serializing_if = "Layout::is_nested")]
+    pub(crate) layout: Layout,
+    /// Algorithm of the archive checksums in `mounts`
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+    pub(crate) hash_algorithm: HashAlgorithm,
+    /// Secrets and configs of the swarm service the container was a task of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) swarm: Option<SwarmReferences>,
+    /// User-defined networks the container was connected to, with its aliases on them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) networks: BTreeMap<String, NetworkEndpoint>,
 }
 
-/// Back up directory as tarball
+impl ContainerBackup {
+    /// Make archive paths relative to the backup location, for a backup read from `backup_file`
+    ///
+    /// Flat layout backups record archives relative to the directory of the container backup
+    /// file, so that the directory can be moved as a whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup_file` - Path of container backup file relative to the backup location
+    ///
+    pub(crate) fn resolve_archives(&mut self, backup_file: &Path) {
+        if self.layout.is_nested() {
+            return;
+        }
+        let directory = backup_file.parent().unwrap_or_else(|| Path::new(""));
+        for mount in &mut self.mounts {
+            mount.path = directory.join(&mount.path);
+        }
+        if let Some(image_archive) = self.image_archive.as_mut() {
+            *image_archive = directory.join(&image_archive);
+        }
+    }
+
+    /// Make absolute archive paths, as older or hand-edited container backup files may record,
+    /// relative to the backup location
+    ///
+    /// Fails if an archive path leads out of the backup location.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup_mount` - Mount representing backup location
+    ///
+    pub(crate) fn relativize_archives(&mut self, backup_mount: &Mount) -> Result<()> {
+        for mount in &mut self.mounts {
+            mount.path = archive_in_backup_mount(&mount.path, backup_mount)?;
+        }
+        if let Some(image_archive) = self.image_archive.as_mut() {
+            *image_archive = archive_in_backup_mount(image_archive, backup_mount)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the path of an archive recorded in a container backup relative to the backup location
+///
+/// Absolute paths under the source of the backup mount on the host, or under its target in
+/// helper containers, are made relative to it. Any other absolute path is taken to start at the
+/// root of the backup location.
 ///
 /// # Arguments
 ///
-/// * `name` - Name of output archive
-/// * `input` - Directory to back up
-/// * `output` - Output directory of archive
+/// * `path` - Archive path recorded in the container backup
+/// * `backup_mount` - Mount representing backup location
 ///
-pub fn backup_directory(input: &str, output: &str) -> Result<PathBuf> {
-    let input_path = Path::new(input);
-    let output_path = Path::new(output);
-    let name = Utc::now().to_rfc3339();
-
-    let path = if input_path.is_dir() {
-        let backup_path = output_path.join(format!("{}.tgz", &name));
-        create_directory(backup_path.as_path())?;
-        log::info!(
-            "Backing up directory {} to {}",
-            input_path.display(),
-            backup_path.display()
-        );
-        let archive = File::create(&backup_path)
-            .with_context(|| format!("Unable to create file {}", &backup_path.display()))?;
-        let enc = GzEncoder::new(archive, Compression::default());
-        let mut tar = tar::Builder::new(enc);
-        tar.append_dir_all("", input_path).with_context(|| {
-            format!(
-                "Failed to create tarball {} from {}",
-                &backup_path.display(),
-                input
-            )
-        })?;
-        backup_path
-    } else {
-        let backup_path = output_path.join(&name);
-        create_directory(backup_path.as_path())?;
-        log::info!(
-            "Backing up file {} to {}",
-            input_path.display(),
-            &backup_path.display()
+fn archive_in_backup_mount(path: &Path, backup_mount: &Mount) -> Result<PathBuf> {
+    let relative = if path.has_root() {
+        let relative = [
+            backup_mount.source.as_deref(),
+            backup_mount.target.as_deref(),
+        ]
+        .iter()
+        .flatten()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .unwrap_or_else(|| path.strip_prefix("/").unwrap_or(path));
+        log::debug!(
+            "Archive {} is absolute, reading it from {} in the backup location",
+            path.display(),
+            relative.display()
         );
-        copy(input_path, &backup_path)?;
-        backup_path
+        relative
+    } else {
+        path
     };
-    Ok(path.strip_prefix(output_path)?.to_path_buf())
+    let mut components = vec![];
+    for component in relative.components() {
+        match component {
+            Component::Normal(c) => components.push(c),
+            Component::CurDir => {}
+            Component::ParentDir if !components.is_empty() => {
+                components.pop();
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Archive {} is outside the backup location",
+                    path.display()
+                ))
+            }
+        }
+    }
+    Ok(components.into_iter().collect())
 }
 
-fn create_directory(path: &Path) -> Result<()> {
-    let directory = if path.is_dir() {
-        path
-    } else {
-        path.parent().unwrap()
-    };
-    log::info!("Creating directory {}", directory.display());
-    create_dir_all(directory)?;
-    Ok(())
+fn legacy_schema_version() -> u32 {
+    1
 }
 
-pub async fn backup_directory_to_mount(
-    docker: &Docker,
-    input: String,
-    output: String,
-    mount: Mount,
-) -> Result<PathBuf> {
-    log::info!(
-        "Backing up directory {} to {}/ on {}",
-        &input,
-        output,
-        mount.source.as_ref().unwrap()
-    );
-    let mounted_input = Path::new("/input");
-    let mounted_output = Path::new(mount.target.as_ref().unwrap()).join(&output);
-    let log_prefix = format!("backup directory {}", &input);
-    let input_mount = Mount {
-        source: Some(input),
-        target: Some("/input".to_string()),
-        typ: Some(MountTypeEnum::BIND),
-        ..Default::default()
-    };
-    let args = vec![
-        "backup",
-        "directory",
-        mounted_input.to_str().unwrap(),
-        mounted_output.to_str().unwrap(),
-    ];
-    let (exit_code, logs) =
-        run_dockyard_command(docker, Some(vec![input_mount, mount]), args).await?;
-    let output_path = logs
-        .last()
-        .unwrap()
-        .to_string()
-        .trim()
-        .split_ascii_whitespace()
-        .last()
-        .unwrap()
-        .to_string();
-    handle_container_output(exit_code, &log_prefix, &logs)
-        .map(|_| Path::new(&output).join(output_path))
+/// File name of the container backup file in a flat layout backup directory
+pub const FLAT_CONTAINER_BACKUP: &str = "container.json";
+
+/// Arrangement of the files of a container backup in the backup destination
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Container backup files in `dockyard/containers/<container>`, with archives in the
+    /// `dockyard/volumes`, `dockyard/binds` and `dockyard/images` trees shared by all backups
+    Nested,
+    /// Container backup file and archives together in `<container>/<timestamp>`
+    Flat,
 }
 
-/// Back up volume
-///
-/// # Arguments
-///
-/// * `docker` - Docker client
-/// * `volume` - Name of volume to back up
-/// * `backup_mount` - Mount of backup destination
-///
-pub async fn backup_volume(
-    docker: &Docker,
-    volume: String,
-    backup_mount: Mount,
-) -> Result<PathBuf> {
-    let mounts = vec![
-        Mount {
-            source: Some(volume.to_string()),
-            target: Some("/volume".to_string()),
-            typ: Some(MountTypeEnum::VOLUME),
-            ..Default::default()
-        },
-        backup_mount,
-    ];
-    let output = Path::new("dockyard/volumes").join(&volume);
-    log::info!(
-        "Backing up volume {} to {} on {}",
-        &volume,
-        output.display(),
-        mounts[0].source.as_ref().unwrap()
-    );
-    let mounted_output = Path::new("/backup").join(&output);
-    let args = vec![
-        "backup",
-        "directory",
-        "/volume",
-        mounted_output.to_str().unwrap(),
-    ];
-    let log_prefix = format!("backup volume {}", &volume);
-    match run_dockyard_command(docker, Some(mounts), args).await {
-        Ok((exit_code, logs)) => handle_container_output(exit_code, &log_prefix, &logs).map(|_| {
-            let archive_name = logs
-                .last()
-                .unwrap()
-                .to_string()
-                .trim()
-                .split_ascii_whitespace()
-                .last()
-                .unwrap()
-                .to_string();
-            output.join(archive_name)
-        }),
-        Err(e) => Err(e),
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Nested
     }
 }
 
-/// Back up container
-///
-/// # Arguments
-///
-/// * `docker` - Docker client
-/// * `container_info` - Container inspection result
-/// * `mounts` - List of mounts to back up
-/// * `output` - Output directory relative to `backup_mount`
-/// * `backup_mount` - Mount representing backup destination
-///
-pub async fn backup_container(
-    docker: &Docker,
-    container_name: &str,
-    backup_mount: Mount,
-    exclude_volumes: &HashSet<String>,
-) -> Result<PathBuf> {
-    let output = Path::new("dockyard/containers").join(container_name);
-    log::info!(
-        "Backing up container {} to {}",
-        container_name,
-        output.display()
-    );
-    let (info, mounts) = get_container_info(docker, container_name, exclude_volumes).await?;
-    let mut mount_backup_processes = vec![];
-    for mp in mounts {
-        if mp.typ.as_ref().unwrap() == "bind" {
-            if mp.source.as_ref().unwrap() == "/var/run/docker.sock" {
-                log::info!("Ignoring bind /var/run/docker.sock")
-            } else {
-                let output = format!(
-                    "dockyard/binds/{}",
-                    mp.source.as_ref().unwrap().replace("/", ":")
-                );
-                let directory = mp.source.as_ref().unwrap().clone();
-                mount_backup_processes.push((
-                    mp,
-                    Either::Left(backup_directory_to_mount(
-                        docker,
-                        directory,
-                        output,
-                        backup_mount.clone(),
-                    )),
-                ));
-            }
-        } else {
-            let volume_name = mp.name.as_ref().unwrap().clone();
-            mount_backup_processes.push((
-                mp,
-                Either::Right(backup_volume(docker, volume_name, backup_mount.clone())),
-            ));
+impl Layout {
+    fn is_nested(&self) -> bool {
+        *self == Layout::Nested
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nested" => Ok(Layout::Nested),
+            "flat" => Ok(Layout::Flat),
+            _ => Err(anyhow!("Invalid layout {}, expected nested or flat", s)),
         }
     }
-    let mount_backups = validate_process_results(mount_backup_processes).await?;
-    let container_backup = ContainerBackup {
-        name: container_name.to_string(),
-        container_config: info.config.unwrap(),
-        host_config: info.host_config.unwrap(),
-        mounts: mount_backups,
-    };
-    write_container_backup(docker, container_backup, output, backup_mount).await
 }
 
-/// Include only bind mounts and non-network volumes
-///
-/// # Arguments
-///
-/// * `docker` - Docker client
-/// * `mount` - Mount to inspect and filter
+/// Time zone of the timestamps backups are named with
 ///
-async fn filter_mount(
-    docker: &Docker,
-    mount: &MountPoint,
-    exclude_volumes: &HashSet<String>,
-) -> Result<bool> {
-    match mount.typ.as_deref() {
-        Some("volume") => {
-            let volume_name = mount.name.as_ref().unwrap();
-            let volume = docker.inspect_volume(volume_name).await?;
-            match volume.options.get("type").map(String::as_str) {
-                Some("nfs") | Some("nfs4") => {
-                    log::info!("Ignoring network volume {}", volume_name);
-                    Ok(false)
-                }
-                _ => {
-                    if exclude_volumes.contains(volume_name) {
-                        log::info!("Ignoring excluded volume {}", volume_name);
-                        Ok(false)
-                    } else {
-                        log::info!("Including volume {}", volume_name);
-                        Ok(true)
-                    }
-                }
-            }
-        }
-        Some("bind") => {
-            let source = mount.source.as_ref().unwrap();
-            if exclude_volumes.contains(source) {
-                log::info!("Ignoring excluded directory {}", source);
-                Ok(false)
-            } else {
-                log::info!("Including directory {}", source);
-                Ok(true)
-            }
+/// Names always end with their UTC offset, so backups sort chronologically by the instant they
+/// name whatever the time zone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timezone {
+    Utc,
+    /// Local time of the dockyard process
+    Local,
+    /// Fixed offset, which passes local time on to helper containers as they run in UTC
+    Offset(FixedOffset),
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Utc
+    }
+}
+
+impl Timezone {
+    /// Return the current time in this time zone as an RFC3339 timestamp
+    pub fn timestamp(self) -> String {
+        match self {
+            Timezone::Utc => Utc::now().to_rfc3339(),
+            Timezone::Local => Local::now().to_rfc3339(),
+            Timezone::Offset(offset) => Utc::now().with_timezone(&offset).to_rfc3339(),
         }
-        Some(t) => {
-            log::info!("Ignoring mount with type {}", t);
-            Ok(false)
+    }
+
+    /// Return the argument passing this time zone to a helper container, `None` for UTC
+    fn arg(self) -> Option<String> {
+        match self {
+            Timezone::Utc => None,
+            Timezone::Local => Some(format!("--timezone={}", Local::now().offset().fix())),
+            Timezone::Offset(offset) => Some(format!("--timezone={}", offset)),
         }
-        _ => {
-            log::info!("Ignoring mount {:?}", &mount);
-            Ok(false)
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(Timezone::Utc),
+            "local" => Ok(Timezone::Local),
+            _ => DateTime::parse_from_rfc3339(&format!("2000-01-01T00:00:00{}", s))
+                .map(|t| Timezone::Offset(*t.offset()))
+                .map_err(|_| {
+                    anyhow!(
+                        "Invalid timezone {}, expected utc, local or an offset like +02:00",
+                        s
+                    )
+                }),
         }
     }
 }
 
-/// Return Container Inspection and mounts for container
+/// Container backup file holding a gzipped, base64-encoded `ContainerBackup`
+#[derive(Serialize, Deserialize, Debug)]
+struct CompressedManifest {
+    compression: String,
+    manifest: String,
+}
+
+/// Serialize container backup to the contents of a container backup file
 ///
 /// # Arguments
 ///
-/// * `docker` - Docker client
-/// * `container_name` - Name of container to inspect
-/// * `volumes` - Optional list of volumes to retrieve
+/// * `container_backup` - Container backup
+/// * `compress` - Gzip the JSON, wrapping it in a `CompressedManifest`
 ///
-async fn get_container_info(
-    docker: &Docker,
-    container_name: &str,
-    exclude_volumes: &HashSet<String>,
-) -> Result<(ContainerInspectResponse, Vec<MountPoint>)> {
-    let container_info = docker
-        .inspect_container(&container_name, None::<InspectContainerOptions>)
-        .await?;
-    let mut filtered_mounts = vec![];
-    for mp in container_info.mounts.as_ref().unwrap() {
-        if filter_mount(docker, mp, exclude_volumes).await? {
-            filtered_mounts.push(mp.clone())
-        }
+pub(crate) fn encode_container_backup(
+    container_backup: &ContainerBackup,
+    compress: bool,
+) -> Result<String> {
+    let json = serde_json::to_string_pretty(container_backup)?;
+    if !compress {
+        return Ok(json);
     }
-    Ok((container_info, filtered_mounts))
+    let mut encoder = GzEncoder::new(vec![], Compression::best());
+    encoder.write_all(json.as_bytes())?;
+    Ok(serde_json::to_string(&CompressedManifest {
+        compression: Codec::Gzip.name().to_string(),
+        manifest: base64::encode(encoder.finish()?),
+    })?)
 }
 
-/// Await volume backups and return a MountBackup for each
+/// Parse container backup, upgrading manifests written by older versions of dockyard
 ///
 /// # Arguments
 ///
-/// * `backup_results` - List of volume backup results
+/// * `json` - Contents of container backup file
 ///
-async fn validate_process_results(
-    backup_results: Vec<(
-        MountPoint,
-        Either<impl Future<Output = Result<PathBuf>>, impl Future<Output = Result<PathBuf>>>,
-    )>,
-) -> Result<Vec<MountBackup>> {
-    let mut backups = vec![];
-    for (mount, result) in backup_results {
-        match result.await {
-            Ok(path) => {
-                log::info!("Successfully backed up to {}", path.display());
-                backups.push(MountBackup { path, mount })
-            }
-            Err(e) => return Err(e),
+pub fn parse_container_backup(json: &str) -> Result<ContainerBackup> {
+    let mut backup: serde_json::Value = serde_json::from_str(json)?;
+    if backup.get("compression").is_some() {
+        let compressed: CompressedManifest = serde_json::from_value(backup)?;
+        if compressed.compression != Codec::Gzip.name() {
+            return Err(anyhow!(
+                "Unsupported container backup compression {}",
+                compressed.compression
+            ));
         }
+        let mut json = String::new();
+        GzDecoder::new(&base64::decode(&compressed.manifest)?[..])
+            .read_to_string(&mut json)
+            .context("Unable to decompress container backup")?;
+        return parse_container_backup(&json);
     }
-    Ok(backups)
+    let version = match backup.get("schema_version") {
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| anyhow!("Invalid container backup schema version {}", v))?
+            as u32,
+        None => legacy_schema_version(),
+    };
+    if version > CONTAINER_BACKUP_SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Container backup has schema version {}, this version of dockyard supports up to {}",
+            version,
+            CONTAINER_BACKUP_SCHEMA_VERSION
+        ));
+    }
+    for from in version..CONTAINER_BACKUP_SCHEMA_VERSION {
+        upgrade_container_backup(from, &mut backup)?;
+    }
+    backup
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Container backup is not a JSON object"))?
+        .insert(
+            "schema_version".to_string(),
+            CONTAINER_BACKUP_SCHEMA_VERSION.into(),
+        );
+    Ok(serde_json::from_value(backup)?)
 }
 
-/// Write container backup json to file
+/// Upgrade container backup from schema version `from` to `from + 1`
 ///
 /// # Arguments
 ///
-/// * `docker` - Docker client
-/// * `container_backup` - Container backup info
-/// * `output` - Directory relative to `backup_mount` to write file
-/// * `backup_mount` - Mount representing backup location
+/// * `from` - Schema version of `backup`
+/// * `backup` - Container backup to upgrade in place
 ///
-async fn write_container_backup(
-    docker: &Docker,
-    container_backup: ContainerBackup,
-    output: PathBuf,
-    backup_mount: Mount,
-) -> Result<PathBuf> {
-    let backup_path = output
-        .as_path()
-        .join(format!("{}.json", Utc::now().to_rfc3339()));
-    let backup_json = base64::encode(serde_json::to_string_pretty(&container_backup)?);
-    log::info!("Writing container backup file {}", backup_path.display());
+fn upgrade_container_backup(from: u32, _backup: &mut serde_json::Value) -> Result<()> {
+    // Add a match arm here for each schema version when the format changes
+    match from {
+        // Version 1 backups are nested and checksummed with SHA-256, the defaults of the
+        // fields version 2 added
+        1 => Ok(()),
+        _ => Err(anyhow!(
+            "No upgrade from container backup schema version {}",
+            from
+        )),
+    }
+}
 
-    let log_prefix = format!("backup container {}", container_backup.name);
-    let mounted_backup_path = format!("/backup/{}", backup_path.as_path().to_str().unwrap());
-    let args = vec![
-        "write",
-        "--file",
-        &mounted_backup_path,
-        "--contents",
-        &backup_json,
-        "--encoded",
-    ];
+/// Options controlling which mounts are backed up and how
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    /// Names of volumes and bind directories to exclude
+    pub exclude_volumes: HashSet<String>,
+    /// Write a pointer to the previous archive instead of a new archive if
+    /// nothing changed since the last backup
+    pub skip_unchanged: bool,
+    /// Compression level of archives, the codec default if not set
+    pub compression_level: Option<u32>,
+    /// Save the container's image alongside its config
+    pub include_image: bool,
+    /// Store hardlinked files once, with the other paths archived as links to it
+    pub preserve_hardlinks: bool,
+    /// Archive sockets, FIFOs and device nodes instead of skipping them
+    pub include_special: bool,
+    /// Archive only files that changed since the last full backup
+    pub incremental: bool,
+    /// Fail instead of overwriting archives and container backup files that already exist
+    pub no_clobber: bool,
+    /// Gzip container backup files instead of writing readable JSON
+    pub compress_manifest: bool,
+    /// Shell command run in the container before its mounts are backed up
+    pub pre_backup_hook: Option<String>,
+    /// Shell command run in the container after its mounts are backed up, even if that failed
+    pub post_backup_hook: Option<String>,
+    /// Gitignore-style patterns of paths to leave out of directory archives
+    pub exclude: Vec<String>,
+    /// Paths relative to the volume or directory to archive instead of all of it, archived
+    /// with their paths from its root so they are restored to the same place
+    pub subpaths: Vec<PathBuf>,
+    /// Give up on a volume or directory backup that takes longer than this
+    pub timeout: Option<Duration>,
+    /// Never overwrite or delete anything in the backup destination, implies `no_clobber`
+    pub append_only: bool,
+    /// Compress volumes smaller than this many bytes in process instead of in a helper container
+    pub inline_threshold: Option<u64>,
+    /// Names of environment variables to leave out of container backup files
+    pub redact_env: Vec<String>,
+    /// Remove all but this many of the most recent backups of the volume or container afterwards
+    pub keep_last: Option<usize>,
+    /// Extension of archives, the codec's extension if not set
+    pub extension: Option<String>,
+    /// Reset the compressor at content-defined boundaries so unchanged data compresses to the
+    /// same bytes, at the cost of slightly larger archives
+    pub rsyncable: bool,
+    /// Write to the backup directory from this process instead of a helper container, for
+    /// destinations only mounted on the host such as FUSE or rclone mounts
+    pub destination_on_host: bool,
+    /// Arrangement of container backup files and their archives
+    pub layout: Layout,
+    /// Name of archives without extension, the backup timestamp if not set
+    pub archive_stem: Option<String>,
+    /// Algorithm of archive checksums and of the file hashes `incremental` compares
+    pub hash_algorithm: HashAlgorithm,
+    /// Back up volumes from a snapshot of their filesystem where it supports snapshots
+    pub snapshot: bool,
+    /// Time zone of the timestamps backups are named with
+    pub timezone: Timezone,
+    /// Permissions of the directories and files backups create, owner only by default
+    pub modes: FileModes,
+    /// Record the progress of a container backup, and finish an interrupted one, reusing the
+    /// archives it already wrote
+    pub resume: bool,
+    /// Record the hash of every archived file in the archive, so restores can verify them
+    pub file_manifest: bool,
+    /// Names of regenerable build cache volumes to skip, see `cache_volume_patterns`
+    pub cache_volumes: Vec<Regex>,
+}
 
-    match run_dockyard_command(docker, Some(vec![backup_mount]), args).await {
-        Ok((exit_code, logs)) => {
-            handle_container_output(exit_code, &log_prefix, &logs).map(|_| backup_path)
+impl BackupOptions {
+    /// Return arguments passing these options to `dockyard backup directory`
+    fn directory_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.skip_unchanged {
+            args.push("--skip-unchanged".to_string());
         }
-        Err(e) => Err(e),
+        if self.preserve_hardlinks {
+            args.push("--preserve-hardlinks".to_string());
+        }
+        if self.include_special {
+            args.push("--include-special".to_string());
+        }
+        if self.incremental {
+            args.push("--incremental".to_string());
+        }
+        if self.file_manifest {
+            args.push("--file-manifest".to_string());
+        }
+        if self.no_clobber {
+            args.push("--no-clobber".to_string());
+        }
+        if self.append_only {
+            args.push("--append-only".to_string());
+        }
+        if let Some(level) = self.compression_level {
+            args.push("--compression-level".to_string());
+            args.push(level.to_string());
+        }
+        if let Some(extension) = &self.extension {
+            args.push(format!("--extension={}", extension));
+        }
+        if self.rsyncable {
+            args.push("--rsyncable".to_string());
+        }
+        if let Some(stem) = &self.archive_stem {
+            args.push(format!("--archive-stem={}", stem));
+        }
+        args.extend(self.timezone.arg());
+        if !self.hash_algorithm.is_default() {
+            args.push(format!("--hash-algo={}", self.hash_algorithm));
+        }
+        args.extend(self.modes.args());
+        for pattern in &self.exclude {
+            // Attached with = so patterns starting with - aren't read as flags
+            args.push(format!("--exclude={}", pattern));
+        }
+        for subpath in &self.subpaths {
+            args.push(format!("--subpath={}", subpath.display()));
+        }
+        args
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::fs;
-    use std::fs::{create_dir, read_to_string};
-    use std::io::Write;
+    /// Return true if existing files in the backup destination must not be overwritten
+    fn forbids_overwrite(&self) -> bool {
+        self.no_clobber || self.append_only
+    }
 
-    use flate2::read::GzDecoder;
-    use log::LevelFilter;
-    use simple_logger::SimpleLogger;
-    use tar::Archive;
-    use tempfile::TempDir;
+    /// Fail if options that rewrite files in the backup destination are used with `append_only`
+    ///
+    /// `skip_unchanged` and `incremental` update state files next to the archives on every
+    /// backup, so they can't be used with an append-only destination.
+    pub fn check_append_only(&self) -> Result<()> {
+        if self.append_only && (self.skip_unchanged || self.incremental) {
+            return Err(anyhow!(
+                "--skip-unchanged and --incremental rewrite {} and {} in the backup destination, they can't be used with --append-only",
+                DIRECTORY_STATE_FILE,
+                CATALOG_FILE
+            ));
+        }
+        if self.append_only && self.keep_last.is_some() {
+            return Err(anyhow!(
+                "--keep-last deletes old backups, it can't be used with --append-only"
+            ));
+        }
+        if self.append_only && self.resume {
+            return Err(anyhow!(
+                "--resume needs the backup progress append-only backups don't record, it can't be used with --append-only"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fail if options that rely on the nested layout are used with the flat layout
+    ///
+    /// Flat layout backups each get a directory of their own, so there are no earlier archives
+    /// next to them to skip, extend or remove.
+    pub fn check_layout(&self) -> Result<()> {
+        if self.layout == Layout::Flat
+            && (self.skip_unchanged || self.incremental || self.keep_last.is_some())
+        {
+            return Err(anyhow!(
+                "--skip-unchanged, --incremental and --keep-last work on the nested layout, they can't be used with --layout flat"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return true if volumes can be archived without running `dockyard backup directory`
+    fn allows_inline(&self) -> bool {
+        self.exclude.is_empty()
+            && self.subpaths.is_empty()
+            && !self.skip_unchanged
+            && !self.incremental
+            && !self.file_manifest
+    }
+
+    /// Return name of an archive of a backup taken at `timestamp`, named after `archive_stem`
+    /// instead if set
+    fn archive_name(&self, timestamp: &str) -> String {
+        let extension = self
+            .extension
+            .as_deref()
+            .unwrap_or_else(|| Codec::Gzip.extension());
+        let stem = self.archive_stem.as_deref().unwrap_or(timestamp);
+        format!("{}.{}", stem, extension)
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression_level
+            .map(Compression::new)
+            .unwrap_or_default()
+    }
+
+    /// Return encoder compressing archives written to `inner`
+    fn encoder<W: Write>(&self, inner: W) -> ArchiveEncoder<W> {
+        ArchiveEncoder::new(inner, self.compression(), self.rsyncable)
+    }
+}
+
+/// Codec used to compress archives
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+}
+
+impl Codec {
+    /// Every codec archives can be compressed with
+    pub const ALL: &'static [Codec] = &[Codec::Gzip];
+
+    /// Name of the codec
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of archives compressed with the codec, without the leading `.`
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "tgz",
+        }
+    }
+
+    /// Return the codec that compressed data starting with `header`, found from its magic bytes
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Start of the compressed data
+    ///
+    pub fn detect(header: &[u8]) -> Option<Codec> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// Compression levels accepted by the codec
+    pub fn level_range(self) -> RangeInclusive<u32> {
+        match self {
+            Codec::Gzip => 0..=9,
+        }
+    }
+}
+
+/// Return the codec that compressed the archive at `path`, or `None` if it isn't compressed
+///
+/// # Arguments
+///
+/// * `path` - Path of archive
+///
+pub(crate) fn archive_codec(path: &Path) -> Result<Option<Codec>> {
+    let mut header = vec![];
+    File::open(path)
+        .with_context(|| format!("Unable to open {}", path.display()))?
+        .take(2)
+        .read_to_end(&mut header)?;
+    Ok(Codec::detect(&header))
+}
+
+/// Parse an archive extension, with or without a leading `.`
+///
+/// Extensions ending in `.ref` or `.json` are rejected, since they mark pointers and container
+/// backup files, as are extensions containing `/` or whitespace.
+///
+/// # Arguments
+///
+/// * `extension` - Extension such as `tar.gz`
+///
+pub fn parse_extension(extension: &str) -> Result<String> {
+    let extension = extension.trim_start_matches('.');
+    let last = extension.rsplit('.').next().unwrap();
+    let invalid_char = |c: char| c == '/' || c.is_whitespace();
+    if last.is_empty()
+        || extension.contains(invalid_char)
+        || last == POINTER_EXTENSION
+        || last == "json"
+    {
+        return Err(anyhow!("Invalid archive extension {}", extension));
+    }
+    Ok(extension.to_string())
+}
+
+/// Parse a compression level, checking it is within the range supported by the codec
+///
+/// # Arguments
+///
+/// * `codec` - Codec the level applies to
+/// * `level` - Level to parse
+///
+pub fn parse_compression_level(codec: Codec, level: &str) -> Result<u32> {
+    let parsed = level.parse::<u32>().map_err(|_| {
+        anyhow!(
+            "Invalid {} level {}, expected a number",
+            codec.name(),
+            level
+        )
+    })?;
+    let range = codec.level_range();
+    if range.contains(&parsed) {
+        Ok(parsed)
+    } else {
+        Err(anyhow!(
+            "{} level {} out of range {}-{}",
+            codec.name(),
+            parsed,
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+/// Parse a duration given in seconds, or with an `s`, `m`, `h` or `d` suffix
+///
+/// # Arguments
+///
+/// * `duration` - Duration to parse, e.g. `90`, `30m` or `2h`
+///
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let (value, unit_secs) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some('d') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    match value.parse::<u64>() {
+        Ok(value) if value > 0 => Ok(Duration::from_secs(value * unit_secs)),
+        _ => Err(anyhow!(
+            "Invalid duration {}, expected a positive number of seconds or e.g. 30m, 2h",
+            duration
+        )),
+    }
+}
+
+/// Parse a size given in bytes, or with a `K`, `M` or `G` suffix
+///
+/// # Arguments
+///
+/// * `size` - Size to parse, e.g. `4096`, `512K` or `10M`
+///
+pub fn parse_size(size: &str) -> Result<u64> {
+    let (value, unit) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    match value.parse::<u64>().ok().and_then(|v| v.checked_mul(unit)) {
+        Some(bytes) if bytes > 0 => Ok(bytes),
+        _ => Err(anyhow!(
+            "Invalid size {}, expected a positive number of bytes or e.g. 512K, 10M",
+            size
+        )),
+    }
+}
+
+/// Error returned when a volume or directory backup exceeds `BackupOptions::timeout`
+#[derive(Debug)]
+pub struct BackupTimeout {
+    /// Volume or directory that was being backed up
+    pub target: String,
+    /// Timeout that expired
+    pub timeout: Duration,
+}
+
+impl fmt::Display for BackupTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backup of {} timed out after {}s",
+            self.target,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for BackupTimeout {}
+
+/// Run `backup` of `target`, failing it if it takes longer than `options.timeout`
+///
+/// Helper containers abandoned by cancelled commands, including this backup's when it times out,
+/// are removed however the backup ends, so a hung mount doesn't leave them behind.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `target` - Volume or directory being backed up, for the error message
+/// * `options` - Backup options
+/// * `backup` - Backup to run
+///
+async fn with_backup_timeout<T>(
+    docker: &Docker,
+    target: &str,
+    options: &BackupOptions,
+    backup: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let result = match options.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, backup).await {
+            Ok(result) => result,
+            Err(_) => Err(BackupTimeout {
+                target: target.to_string(),
+                timeout,
+            }
+            .into()),
+        },
+        None => backup.await,
+    };
+    let removal = remove_abandoned_helpers(docker);
+    // Removing a helper stuck on a hung mount can hang too, so bound it as well
+    let removed = match options.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, removal)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timed out removing helper containers"))),
+        None => removal.await,
+    };
+    if let Err(e) = removed {
+        log::warn!("Unable to remove helper containers: {}", e);
+    }
+    result
+}
+
+/// File name of the state recorded alongside archives for `skip_unchanged`
+const DIRECTORY_STATE_FILE: &str = ".dockyard-state.json";
+
+/// Extension of files pointing to an earlier, identical archive
+pub const POINTER_EXTENSION: &str = "ref";
+
+/// Prefix of archives still being written, which aren't named like backups until they're complete
+const PARTIAL_ARCHIVE_PREFIX: &str = ".partial-";
+
+/// Age after which a partial archive is assumed to be left by an interrupted backup, rather than
+/// still being written by a concurrent one
+const STALE_PARTIAL_ARCHIVE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// File name of the catalog written alongside full archives for `incremental`
+const CATALOG_FILE: &str = ".dockyard-catalog.json";
+
+/// Name of the member describing an incremental archive, always its first member
+pub(crate) const INCREMENTAL_MEMBER: &str = ".dockyard-incremental.json";
+
+/// Name of the member recording the hash of every archived file, after `INCREMENTAL_MEMBER` in
+/// incremental archives and first otherwise
+pub(crate) const FILE_MANIFEST_MEMBER: &str = ".dockyard-files.json";
+
+/// Content hashes of the files in the last full backup of a directory
+#[derive(Serialize, Deserialize, Debug)]
+struct Catalog {
+    base: String,
+    /// Algorithm of the hashes in `files`
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+    algorithm: HashAlgorithm,
+    files: BTreeMap<String, String>,
+}
+
+/// Full archive an incremental archive applies to and the files deleted since
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct IncrementalInfo {
+    pub(crate) base: String,
+    pub(crate) deleted: Vec<String>,
+}
+
+/// Hash of every file a directory archive restores, for verifying restored files
+///
+/// The files of incremental archives include those restored from their full archive.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct FileManifest {
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+    pub(crate) algorithm: HashAlgorithm,
+    pub(crate) files: BTreeMap<String, String>,
+}
+
+/// Size and modification time of a file within a backed up directory
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct FileState {
+    path: String,
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+/// Archive produced by the last backup of a directory and the state it captured
+#[derive(Serialize, Deserialize, Debug)]
+struct DirectoryState {
+    archive: String,
+    files: Vec<FileState>,
+}
+
+/// Back up directory as tarball
+///
+/// # Arguments
+///
+/// * `input` - Directory to back up
+/// * `output` - Output directory of archive
+/// * `options` - Backup options
+///
+pub fn backup_directory(input: &str, output: &str, options: &BackupOptions) -> Result<PathBuf> {
+    options.check_append_only()?;
+    let input_path = Path::new(input);
+    let output_path = Path::new(output);
+    let name = options.timezone.timestamp();
+
+    let path = if input_path.is_dir() {
+        check_disjoint(input_path, output_path)?;
+        let excludes = ExcludePatterns::new(&options.exclude)?.with_subpaths(&options.subpaths);
+        for subpath in &options.subpaths {
+            if input_path.join(subpath).symlink_metadata().is_err() {
+                return Err(anyhow!(
+                    "Subpath {} not found in {}",
+                    subpath.display(),
+                    input_path.display()
+                ));
+            }
+        }
+        let files = if options.skip_unchanged {
+            let files = get_file_states(input_path, &excludes)?;
+            if let Some(archive) = find_unchanged_archive(output_path, &files) {
+                let pointer_path = output_path.join(format!("{}.{}", &name, POINTER_EXTENSION));
+                check_clobber(&pointer_path, options)?;
+                log::info!(
+                    "Directory {} is unchanged since {}, writing {}",
+                    input_path.display(),
+                    archive,
+                    pointer_path.display()
+                );
+                options.modes.write(&pointer_path, &archive)?;
+                return Ok(pointer_path.strip_prefix(output_path)?.to_path_buf());
+            }
+            Some(files)
+        } else {
+            None
+        };
+        let archive_name = options.archive_name(&name);
+        let backup_path = output_path.join(&archive_name);
+        check_clobber(&backup_path, options)?;
+        create_directory(backup_path.as_path(), &options.modes)?;
+        let partial_path = partial_archive_path(output_path, &archive_name, options)?;
+        let catalog = if options.incremental {
+            read_catalog(output_path, options.hash_algorithm)
+        } else {
+            None
+        };
+        if let Some(catalog) = catalog {
+            let hashes = hash_files(input_path, &excludes, options.hash_algorithm)?;
+            log::info!(
+                "Backing up changes to directory {} since {} to {}",
+                input_path.display(),
+                catalog.base,
+                backup_path.display()
+            );
+            remove_partial_backup(
+                &partial_path,
+                options,
+                write_incremental_archive(input_path, &partial_path, options, &catalog, &hashes),
+            )?;
+            complete_archive(&partial_path, &backup_path)?;
+        } else {
+            log::info!(
+                "Backing up directory {} to {}",
+                input_path.display(),
+                backup_path.display()
+            );
+            let hashes = remove_partial_backup(
+                &partial_path,
+                options,
+                write_archive(input_path, &partial_path, options, &excludes),
+            )?;
+            complete_archive(&partial_path, &backup_path)?;
+            if options.incremental {
+                write_catalog(
+                    output_path,
+                    &Catalog {
+                        base: archive_name.clone(),
+                        algorithm: options.hash_algorithm,
+                        files: hashes,
+                    },
+                    &options.modes,
+                )?;
+            }
+        }
+        if let Some(files) = files {
+            write_directory_state(output_path, archive_name, files, &options.modes)?;
+        }
+        backup_path
+    } else {
+        let backup_path = output_path.join(&name);
+        check_clobber(&backup_path, options)?;
+        create_directory(backup_path.as_path(), &options.modes)?;
+        let partial_path = partial_archive_path(output_path, &name, options)?;
+        log::info!(
+            "Backing up file {} to {}",
+            input_path.display(),
+            &backup_path.display()
+        );
+        remove_partial_backup(
+            &partial_path,
+            options,
+            copy_file(input_path, &partial_path, &options.modes),
+        )?;
+        complete_archive(&partial_path, &backup_path)?;
+        backup_path
+    };
+    Ok(path.strip_prefix(output_path)?.to_path_buf())
+}
+
+/// Fail if the backup location is inside the directory being backed up or the other way round,
+/// which would archive earlier backups, or the archive being written, into every backup
+///
+/// # Arguments
+///
+/// * `input` - Directory to back up
+/// * `output` - Output directory of archive
+///
+fn check_disjoint(input: &Path, output: &Path) -> Result<()> {
+    let input = resolve_path(input);
+    let output = resolve_path(output);
+    if output.starts_with(&input) {
+        Err(anyhow!(
+            "Refusing to back up {} to {}, the backup location is inside the backed up directory",
+            input.display(),
+            output.display()
+        ))
+    } else if input.starts_with(&output) {
+        Err(anyhow!(
+            "Refusing to back up {} to {}, the backed up directory is inside the backup location",
+            input.display(),
+            output.display()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Return `path` with symlinks resolved, as far as it exists
+fn resolve_path(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut missing = vec![];
+    loop {
+        let directory = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        if let Ok(resolved) = directory.canonicalize() {
+            return missing.iter().rev().fold(resolved, |p, name| p.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// Fail if `path` exists and `options` forbid overwriting it
+///
+/// # Arguments
+///
+/// * `path` - Path about to be written
+/// * `options` - Backup options
+///
+fn check_clobber(path: &Path, options: &BackupOptions) -> Result<()> {
+    if options.forbids_overwrite() {
+        ensure_absent(path)?;
+    }
+    Ok(())
+}
+
+/// Write `input` to a gzipped tarball at `backup_path`, returning the hashes of the archived
+/// files if `options` record a file manifest or catalog
+///
+/// The file manifest is written as the last member, from the data appended to the archive.
+///
+/// # Arguments
+///
+/// * `input` - Directory to archive
+/// * `backup_path` - Path of archive
+/// * `options` - Backup options
+/// * `excludes` - Paths to leave out of the archive
+///
+fn write_archive(
+    input: &Path,
+    backup_path: &Path,
+    options: &BackupOptions,
+    excludes: &ExcludePatterns,
+) -> Result<BTreeMap<String, String>> {
+    let archive = options.modes.create_file(backup_path)?;
+    let mut tar = tar::Builder::new(options.encoder(archive));
+    tar.follow_symlinks(false);
+    let algorithm =
+        Some(options.hash_algorithm).filter(|_| options.incremental || options.file_manifest);
+    let hashes = append_dir(&mut tar, input, options, excludes, algorithm).with_context(|| {
+        format!(
+            "Failed to create tarball {} from {}",
+            backup_path.display(),
+            input.display()
+        )
+    })?;
+    let hashes = if options.file_manifest {
+        let manifest = FileManifest {
+            algorithm: options.hash_algorithm,
+            files: hashes,
+        };
+        append_json_member(&mut tar, FILE_MANIFEST_MEMBER, &manifest)?;
+        manifest.files
+    } else {
+        hashes
+    };
+    // Finish explicitly, errors are ignored if the encoder is dropped
+    tar.into_inner()?.finish()?.sync_all()?;
+    Ok(hashes)
+}
+
+/// Write files in `input` that changed since the full backup in `catalog` to an archive
+///
+/// The archive starts with an `IncrementalInfo` member naming the full archive it applies to
+/// and the files deleted since. The file manifest is written as the last member, with the
+/// hashes of the changed files taken from the data appended to the archive.
+///
+/// # Arguments
+///
+/// * `input` - Directory to archive
+/// * `backup_path` - Path of archive
+/// * `options` - Backup options
+/// * `catalog` - Catalog of the last full backup
+/// * `hashes` - Current hashes of the files in `input`
+///
+fn write_incremental_archive(
+    input: &Path,
+    backup_path: &Path,
+    options: &BackupOptions,
+    catalog: &Catalog,
+    hashes: &BTreeMap<String, String>,
+) -> Result<()> {
+    let info = IncrementalInfo {
+        base: catalog.base.clone(),
+        deleted: catalog
+            .files
+            .keys()
+            .filter(|path| !hashes.contains_key(*path))
+            .cloned()
+            .collect(),
+    };
+    let archive = options.modes.create_file(backup_path)?;
+    let mut tar = tar::Builder::new(options.encoder(archive));
+    tar.follow_symlinks(false);
+    append_json_member(&mut tar, INCREMENTAL_MEMBER, &info)?;
+    let mut files = BTreeMap::new();
+    let mut changed = 0;
+    for (path, hash) in hashes {
+        if catalog.files.get(path) == Some(hash) {
+            files.insert(path.clone(), hash.clone());
+        } else {
+            let appended = append_hashed(
+                &mut tar,
+                &input.join(path),
+                Path::new(path),
+                Some(options.hash_algorithm),
+            )?;
+            files.extend(appended.map(|hash| (path.clone(), hash)));
+            changed += 1;
+        }
+    }
+    if options.file_manifest {
+        let manifest = FileManifest {
+            algorithm: options.hash_algorithm,
+            files,
+        };
+        append_json_member(&mut tar, FILE_MANIFEST_MEMBER, &manifest)?;
+    }
+    log::info!(
+        "Archived {} changed files, {} files deleted",
+        changed,
+        info.deleted.len()
+    );
+    tar.into_inner()?.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Append `value` as a JSON member named `name` to `tar`
+fn append_json_member<W: io::Write>(
+    tar: &mut tar::Builder<W>,
+    name: &str,
+    value: &impl serde::Serialize,
+) -> Result<()> {
+    let contents = serde_json::to_vec(value)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    tar.append_data(&mut header, name, contents.as_slice())?;
+    Ok(())
+}
+
+/// Return catalog of the last full backup in `output`, if its archive still exists and its
+/// hashes were computed with `algorithm`
+///
+/// # Arguments
+///
+/// * `output` - Output directory of archives
+/// * `algorithm` - Algorithm of the hashes the catalog will be compared with
+///
+fn read_catalog(output: &Path, algorithm: HashAlgorithm) -> Option<Catalog> {
+    let contents = read_to_string(output.join(CATALOG_FILE)).ok()?;
+    match serde_json::from_str::<Catalog>(&contents) {
+        Ok(catalog) if catalog.algorithm != algorithm => {
+            log::warn!(
+                "Full backup {} was hashed with {} instead of {}, creating a new full backup",
+                catalog.base,
+                catalog.algorithm,
+                algorithm
+            );
+            None
+        }
+        Ok(catalog) if output.join(&catalog.base).exists() => Some(catalog),
+        Ok(catalog) => {
+            log::warn!(
+                "Full backup {} is missing, creating a new full backup",
+                catalog.base
+            );
+            None
+        }
+        Err(e) => {
+            log::warn!("Ignoring unreadable {}: {}", CATALOG_FILE, e);
+            None
+        }
+    }
+}
+
+/// Return names of archives in `output` that `skip_unchanged` and `incremental` state refers to
+///
+/// # Arguments
+///
+/// * `output` - Output directory of archives
+///
+pub(crate) fn referenced_by_state(output: &Path) -> Vec<String> {
+    let mut archives = vec![];
+    if let Ok(contents) = read_to_string(output.join(DIRECTORY_STATE_FILE)) {
+        if let Ok(state) = serde_json::from_str::<DirectoryState>(&contents) {
+            archives.push(state.archive);
+        }
+    }
+    if let Ok(contents) = read_to_string(output.join(CATALOG_FILE)) {
+        if let Ok(catalog) = serde_json::from_str::<Catalog>(&contents) {
+            archives.push(catalog.base);
+        }
+    }
+    archives
+}
+
+fn write_catalog(output: &Path, catalog: &Catalog, modes: &FileModes) -> Result<()> {
+    modes.write(&output.join(CATALOG_FILE), serde_json::to_string(catalog)?)
+}
+
+/// Return hash of every file under `input`, keyed by path relative to `input`
+///
+/// # Arguments
+///
+/// * `input` - Directory to hash
+/// * `excludes` - Paths to skip
+/// * `algorithm` - Hash algorithm
+///
+fn hash_files(
+    input: &Path,
+    excludes: &ExcludePatterns,
+    algorithm: HashAlgorithm,
+) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    collect_file_hashes(input, input, excludes, algorithm, &mut hashes)?;
+    Ok(hashes)
+}
+
+fn collect_file_hashes(
+    root: &Path,
+    directory: &Path,
+    excludes: &ExcludePatterns,
+    algorithm: HashAlgorithm,
+    hashes: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        let metadata = path.symlink_metadata()?;
+        let relative = path.strip_prefix(root)?;
+        if excludes.is_excluded(relative, metadata.is_dir()) {
+            continue;
+        }
+        let name = manifest_name(relative)?;
+        if metadata.is_dir() {
+            collect_file_hashes(root, &path, excludes, algorithm, hashes)?;
+        } else if let Some(hash) = hash_path(&path, &metadata, algorithm)? {
+            hashes.insert(name, hash);
+        }
+    }
+    Ok(())
+}
+
+/// Return hash of a file's contents or a symlink's target, `None` for other kinds of files
+///
+/// # Arguments
+///
+/// * `path` - Path of file
+/// * `metadata` - Metadata of `path`, without following symlinks
+/// * `algorithm` - Hash algorithm
+///
+pub(crate) fn hash_path(
+    path: &Path,
+    metadata: &Metadata,
+    algorithm: HashAlgorithm,
+) -> Result<Option<String>> {
+    if metadata.file_type().is_symlink() {
+        let target = path.read_link()?;
+        Ok(Some(algorithm.digest(target.to_string_lossy().as_bytes())))
+    } else if metadata.is_file() {
+        algorithm.digest_file(path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Append contents of `input` to `tar`, skipping excluded paths, and return the hashes of the
+/// appended files and symlinks if an `algorithm` is given
+///
+/// Symlinks are archived as symlinks rather than the files or directories they point to.
+/// FIFOs and device nodes are skipped unless `options.include_special` is set, sockets can't be
+/// archived and are always skipped.
+///
+/// # Arguments
+///
+/// * `tar` - Archive to append to
+/// * `input` - Directory to archive
+/// * `options` - Backup options
+/// * `excludes` - Paths to leave out of the archive
+/// * `algorithm` - Algorithm to hash the appended files with
+///
+fn append_dir<W: io::Write>(
+    tar: &mut tar::Builder<W>,
+    input: &Path,
+    options: &BackupOptions,
+    excludes: &ExcludePatterns,
+    algorithm: Option<HashAlgorithm>,
+) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    let mut archived: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut directories = vec![input.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in read_dir(&directory)? {
+            let path = entry?.path();
+            let name = path.strip_prefix(input)?;
+            let metadata = path.symlink_metadata()?;
+            if excludes.is_excluded(name, metadata.is_dir()) {
+                log::debug!("Excluding {}", path.display());
+                continue;
+            }
+            if metadata.is_dir() {
+                tar.append_dir(name, &path)?;
+                directories.push(path);
+                continue;
+            }
+            let special = !metadata.is_file() && !metadata.file_type().is_symlink();
+            if special && !options.include_special {
+                log::info!("Skipping special file {}", path.display());
+                continue;
+            }
+            if special {
+                append_special(tar, name, &path, &metadata)?;
+                continue;
+            }
+            if options.preserve_hardlinks && metadata.is_file() && metadata.nlink() > 1 {
+                let inode = (metadata.dev(), metadata.ino());
+                if let Some(target) = archived.get(&inode) {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_metadata(&metadata);
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    // Link names over 100 bytes can't be stored, so archive a copy instead
+                    if header.set_link_name(target).is_ok() {
+                        tar.append_data(&mut header, name, io::empty())?;
+                        if algorithm.is_some() {
+                            if let Some(hash) = hashes.get(&manifest_name(target)?).cloned() {
+                                hashes.insert(manifest_name(name)?, hash);
+                            }
+                        }
+                        continue;
+                    }
+                } else {
+                    archived.insert(inode, name.to_path_buf());
+                }
+            }
+            if let Some(hash) = append_hashed(tar, &path, name, algorithm)? {
+                hashes.insert(manifest_name(name)?, hash);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Append a file to `tar`, returning the hash of the contents or symlink target it was archived
+/// with if an `algorithm` is given and it is a file or symlink
+///
+/// # Arguments
+///
+/// * `tar` - Archive to append to
+/// * `path` - Path of the file
+/// * `name` - Name of the member
+/// * `algorithm` - Algorithm to hash the appended file with
+///
+fn append_hashed<W: io::Write>(
+    tar: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    algorithm: Option<HashAlgorithm>,
+) -> Result<Option<String>> {
+    let metadata = path.symlink_metadata()?;
+    let algorithm = match algorithm {
+        Some(algorithm) if metadata.is_file() => algorithm,
+        Some(algorithm) if metadata.file_type().is_symlink() => {
+            let target = path.read_link()?;
+            tar.append_path_with_name(path, name)?;
+            return Ok(Some(algorithm.digest(target.to_string_lossy().as_bytes())));
+        }
+        _ => {
+            tar.append_path_with_name(path, name)?;
+            return Ok(None);
+        }
+    };
+    // Hash the data as it is appended, so the hash matches the archived file even if the
+    // file changes during the backup
+    let file = File::open(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&file.metadata()?);
+    let mut reader = HashingReader::new(file, algorithm);
+    tar.append_data(&mut header, name, &mut reader)?;
+    Ok(Some(reader.finish()))
+}
+
+/// Return the name `path` is recorded under in file manifests and catalogs
+fn manifest_name(path: &Path) -> Result<String> {
+    // Catalogs are JSON, so names that aren't UTF-8 can't be recorded
+    Ok(path_str(path)
+        .context("File hashes can't be recorded for names that aren't UTF-8")?
+        .to_string())
+}
+
+/// Append a FIFO or device node to `tar`, which would block or fail reading it as a file
+///
+/// # Arguments
+///
+/// * `tar` - Archive to append to
+/// * `name` - Name of the member
+/// * `path` - Path of the special file
+/// * `metadata` - Metadata of `path`
+///
+fn append_special<W: io::Write>(
+    tar: &mut tar::Builder<W>,
+    name: &Path,
+    path: &Path,
+    metadata: &Metadata,
+) -> Result<()> {
+    let file_type = metadata.file_type();
+    if file_type.is_socket() {
+        log::info!("Skipping socket {}", path.display());
+        return Ok(());
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(metadata);
+    header.set_size(0);
+    if file_type.is_block_device() || file_type.is_char_device() {
+        // Split the device number the way glibc's major() and minor() do
+        let device = metadata.rdev();
+        header
+            .set_device_major((((device >> 32) & 0xffff_f000) | ((device >> 8) & 0xfff)) as u32)?;
+        header.set_device_minor((((device >> 12) & 0xffff_ff00) | (device & 0xff)) as u32)?;
+    }
+    tar.append_data(&mut header, name, io::empty())?;
+    Ok(())
+}
+
+/// Return the path to write archive `name` to until it's complete
+///
+/// Archives are written under a name that isn't mistaken for a backup and renamed once they're
+/// complete, so a helper killed part way through, e.g. by `--backup-timeout`, doesn't leave what
+/// looks like a finished archive. The name is unique to this backup, as other backups to
+/// `output_path` may be running at the same time, and partial archives left by interrupted
+/// backups are removed once they're older than `STALE_PARTIAL_ARCHIVE_AGE`. Append-only
+/// destinations don't allow renaming or removing files, so archives are written in place there.
+///
+/// # Arguments
+///
+/// * `output_path` - Directory the archive is written to
+/// * `name` - File name of the archive
+/// * `options` - Backup options
+///
+fn partial_archive_path(
+    output_path: &Path,
+    name: &str,
+    options: &BackupOptions,
+) -> Result<PathBuf> {
+    if options.append_only {
+        return Ok(output_path.join(name));
+    }
+    remove_stale_partial_archives(output_path, STALE_PARTIAL_ARCHIVE_AGE)?;
+    Ok(output_path.join(format!(
+        "{}{}-{}",
+        PARTIAL_ARCHIVE_PREFIX,
+        Uuid::new_v4(),
+        name
+    )))
+}
+
+/// Remove partial archives in `output_path` last modified more than `max_age` ago
+///
+/// # Arguments
+///
+/// * `output_path` - Directory archives are written to
+/// * `max_age` - Age after which a partial archive is removed
+///
+fn remove_stale_partial_archives(output_path: &Path, max_age: Duration) -> Result<()> {
+    for entry in read_dir(output_path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_partial = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.starts_with(PARTIAL_ARCHIVE_PREFIX));
+        if !is_partial {
+            continue;
+        }
+        // A partial archive completed or removed since it was listed is no longer stale
+        let age = match entry.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => modified.elapsed().unwrap_or_default(),
+            Err(_) => continue,
+        };
+        if age >= max_age {
+            log::info!(
+                "Removing partial archive {} left by an interrupted backup",
+                path.display()
+            );
+            if let Err(e) = remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e).with_context(|| format!("Unable to remove {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Give a complete archive its final name
+///
+/// # Arguments
+///
+/// * `partial_path` - Path the archive was written to
+/// * `backup_path` - Final path of the archive
+///
+fn complete_archive(partial_path: &Path, backup_path: &Path) -> Result<()> {
+    if partial_path != backup_path {
+        rename(partial_path, backup_path)
+            .with_context(|| format!("Unable to rename archive to {}", backup_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Remove `backup_path` if writing it failed, reporting a full destination clearly
+///
+/// Partial backups are left in place for append-only destinations.
+///
+/// # Arguments
+///
+/// * `backup_path` - Path of archive that was being written
+/// * `options` - Backup options
+/// * `result` - Result of writing archive
+///
+fn remove_partial_backup<T>(
+    backup_path: &Path,
+    options: &BackupOptions,
+    result: Result<T>,
+) -> Result<T> {
+    result.map_err(|e| {
+        if options.append_only && backup_path.exists() {
+            log::warn!(
+                "Leaving partial backup {} in append-only destination",
+                backup_path.display()
+            );
+        } else if backup_path.exists() {
+            log::info!("Removing partial backup {}", backup_path.display());
+            if let Err(remove_error) = remove_file(backup_path) {
+                log::warn!(
+                    "Unable to remove partial backup {}: {}",
+                    backup_path.display(),
+                    remove_error
+                );
+            }
+        }
+        if is_out_of_space(&e) {
+            e.context(OUT_OF_SPACE_MESSAGE)
+        } else {
+            e
+        }
+    })
+}
+
+/// Return true if error was caused by the filesystem running out of space
+fn is_out_of_space(error: &anyhow::Error) -> bool {
+    // ENOSPC on Linux, where the helper containers run
+    const ENOSPC: i32 = 28;
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.raw_os_error() == Some(ENOSPC))
+}
+
+/// Return state of every file under `input`, sorted by path
+///
+/// # Arguments
+///
+/// * `input` - Directory to inspect
+/// * `excludes` - Paths to skip
+///
+fn get_file_states(input: &Path, excludes: &ExcludePatterns) -> Result<Vec<FileState>> {
+    let mut files = vec![];
+    collect_file_states(input, input, excludes, &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn collect_file_states(
+    root: &Path,
+    directory: &Path,
+    excludes: &ExcludePatterns,
+    files: &mut Vec<FileState>,
+) -> Result<()> {
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        let metadata = path.symlink_metadata()?;
+        let relative = path.strip_prefix(root)?;
+        if excludes.is_excluded(relative, metadata.is_dir()) {
+            continue;
+        }
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        files.push(FileState {
+            path: relative.to_string_lossy().to_string(),
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        });
+        if metadata.is_dir() {
+            collect_file_states(root, &path, excludes, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Return previous archive in `output` if it was created from identical files
+///
+/// # Arguments
+///
+/// * `output` - Output directory of archives
+/// * `files` - Current state of the directory being backed up
+///
+fn find_unchanged_archive(output: &Path, files: &[FileState]) -> Option<String> {
+    let contents = read_to_string(output.join(DIRECTORY_STATE_FILE)).ok()?;
+    let state: DirectoryState = match serde_json::from_str(&contents) {
+        Ok(state) => state,
+        Err(e) => {
+            log::warn!("Ignoring unreadable {}: {}", DIRECTORY_STATE_FILE, e);
+            return None;
+        }
+    };
+    if state.files == files && output.join(&state.archive).exists() {
+        Some(state.archive)
+    } else {
+        None
+    }
+}
+
+fn write_directory_state(
+    output: &Path,
+    archive: String,
+    files: Vec<FileState>,
+    modes: &FileModes,
+) -> Result<()> {
+    let state_path = output.join(DIRECTORY_STATE_FILE);
+    log::debug!("Writing {}", state_path.display());
+    modes.write(
+        &state_path,
+        serde_json::to_string(&DirectoryState { archive, files })?,
+    )
+}
+
+fn create_directory(path: &Path, modes: &FileModes) -> Result<()> {
+    let directory = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap()
+    };
+    log::info!("Creating directory {}", directory.display());
+    modes.create_dir_all(directory)
+}
+
+/// Copy a single backed up file to `backup_path`, which gets the file mode rather than the
+/// permissions of `input`
+///
+/// # Arguments
+///
+/// * `input` - File to back up
+/// * `backup_path` - Path of the copy
+/// * `modes` - Backup permissions
+///
+fn copy_file(input: &Path, backup_path: &Path, modes: &FileModes) -> Result<()> {
+    let mut source = File::open(input)?;
+    let mut copy = modes.create_file(backup_path)?;
+    io::copy(&mut source, &mut copy)?;
+    copy.sync_all()?;
+    Ok(())
+}
+
+pub async fn backup_directory_to_mount(
+    docker: &Docker,
+    input: String,
+    output: String,
+    mount: Mount,
+    options: &BackupOptions,
+) -> Result<ArchiveBackup> {
+    log::info!(
+        "Backing up directory {} to {}/ on {}",
+        &input,
+        output,
+        mount.source.as_ref().unwrap()
+    );
+    if options.destination_on_host {
+        let backup = backup_directory_on_host(&input, Path::new(&output), &mount, options);
+        return with_backup_timeout(docker, &input, options, backup).await;
+    }
+    let mounted_input = Path::new("/input");
+    let mounted_output = Path::new(mount.target.as_ref().unwrap()).join(&output);
+    let log_prefix = format!("backup directory {}", &input);
+    let input_mount = Mount {
+        source: Some(input.clone()),
+        target: Some("/input".to_string()),
+        typ: Some(MountTypeEnum::BIND),
+        ..Default::default()
+    };
+    let directory_args = options.directory_args();
+    let mut args = vec![
+        "backup",
+        "directory",
+        path_str(mounted_input)?,
+        path_str(&mounted_output)?,
+    ];
+    args.extend(directory_args.iter().map(String::as_str));
+    let (exit_code, logs) = with_backup_timeout(
+        docker,
+        &input,
+        options,
+        run_dockyard_command(docker, Some(vec![input_mount, mount]), args),
+    )
+    .await?;
+    handle_container_output(exit_code, &log_prefix, &logs)
+        .map(|_| parse_backup_output(Path::new(&output), &logs))
+}
+
+/// Back up a host directory with `backup_directory` in this process, for `destination_on_host`
+///
+/// Archiving and checksumming block, so they run on a thread where blocking is allowed. That
+/// thread can't be cancelled, so when `--backup-timeout` fails the backup of a hung mount the
+/// thread is left blocked until the mount responds, and its partial archive until it's stale.
+///
+/// # Arguments
+///
+/// * `input` - Directory to back up
+/// * `output` - Output directory of archive, relative to the backup destination
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options
+///
+async fn backup_directory_on_host(
+    input: &str,
+    output: &Path,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<ArchiveBackup> {
+    let directory = host_destination(backup_mount)?.join(output);
+    let input = input.to_string();
+    let output = output.to_path_buf();
+    let options = options.clone();
+    tokio::task::spawn_blocking(move || {
+        let name = backup_directory(&input, path_str(&directory)?, &options)?;
+        Ok(ArchiveBackup {
+            path: output.join(&name),
+            checksum: archive_checksum(&directory.join(&name), options.hash_algorithm)?,
+        })
+    })
+    .await?
+}
+
+/// Write `contents` to `path` in the backup destination from this process, for
+/// `destination_on_host`
+///
+/// # Arguments
+///
+/// * `backup_mount` - Mount representing backup destination
+/// * `path` - Path of file relative to the backup destination
+/// * `contents` - Contents to write
+/// * `options` - Backup options
+///
+fn write_to_host_destination(
+    backup_mount: &Mount,
+    path: &Path,
+    contents: &[u8],
+    options: &BackupOptions,
+) -> Result<()> {
+    let path = host_destination(backup_mount)?.join(path);
+    if options.forbids_overwrite() {
+        ensure_absent(&path)?;
+    }
+    create_directory(&path, &options.modes)?;
+    options.modes.write(&path, contents)
+}
+
+/// Return the host directory of a backup destination, which must be a directory for
+/// `destination_on_host`
+///
+/// # Arguments
+///
+/// * `backup_mount` - Mount representing backup destination
+///
+pub(crate) fn host_destination(backup_mount: &Mount) -> Result<PathBuf> {
+    match (backup_mount.typ, backup_mount.source.as_ref()) {
+        (Some(MountTypeEnum::BIND), Some(source)) => Ok(PathBuf::from(source)),
+        _ => Err(anyhow!(
+            "--destination-on-host needs a directory destination, use --output-type directory"
+        )),
+    }
+}
+
+/// Return the archive reported by `dockyard backup directory` on its last line of output
+///
+/// # Arguments
+///
+/// * `output` - Output directory of archive, relative to the backup destination
+/// * `logs` - Output of the helper container
+///
+fn parse_backup_output(output: &Path, logs: &[LogOutput]) -> ArchiveBackup {
+    lazy_static! {
+        static ref CHECKSUM: Regex =
+            Regex::new(r"\((?:sha256|sha512|blake3) ([0-9a-f]{64,128})\)").unwrap();
+    }
+    let line = logs.last().unwrap().to_string();
+    let name = line.trim().split_ascii_whitespace().last().unwrap();
+    ArchiveBackup {
+        path: output.join(name),
+        checksum: CHECKSUM
+            .captures(&line)
+            .map(|captures| captures[1].to_string()),
+    }
+}
+
+/// Return checksum of an archive, or `None` if it only points to an earlier archive
+///
+/// # Arguments
+///
+/// * `path` - Path of archive
+/// * `algorithm` - Hash algorithm
+///
+pub fn archive_checksum(path: &Path, algorithm: HashAlgorithm) -> Result<Option<String>> {
+    if path.extension().and_then(|e| e.to_str()) == Some(POINTER_EXTENSION) {
+        return Ok(None);
+    }
+    algorithm.digest_file(path).map(Some)
+}
+
+/// Back up volume
+///
+/// With `keep_last`, older backups of the volume are removed afterwards.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `volume` - Name of volume to back up
+/// * `backup_mount` - Mount of backup destination
+/// * `options` - Backup options
+///
+pub async fn backup_volume(
+    docker: &Docker,
+    volume: String,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<ArchiveBackup> {
+    let output = Path::new("dockyard/volumes").join(&volume);
+    let archive = archive_volume(
+        docker,
+        volume.clone(),
+        output.clone(),
+        backup_mount.clone(),
+        options,
+    )
+    .await?;
+    if let Some(keep) = options.keep_last {
+        remove_old_backups_of(docker, &output, keep, backup_mount, options)
+            .await
+            .with_context(|| format!("Failed to remove old backups of volume {}", volume))?;
+    }
+    Ok(archive)
+}
+
+/// Remove all but the `keep` most recent backups in `output`, for `keep_last`
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `output` - Directory of backups, relative to the backup destination
+/// * `keep` - Number of backups to keep
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options
+///
+async fn remove_old_backups_of(
+    docker: &Docker,
+    output: &Path,
+    keep: usize,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<Vec<PathBuf>> {
+    if options.destination_on_host {
+        remove_old_backups(&host_destination(&backup_mount)?, output, keep)
+    } else {
+        trim_backups(docker, output, keep, backup_mount).await
+    }
+}
+
+/// Archive volume to a directory on the backup destination
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `volume` - Name of volume to back up
+/// * `output` - Output directory of archive, relative to the backup destination
+/// * `backup_mount` - Mount of backup destination
+/// * `options` - Backup options
+///
+pub(crate) async fn archive_volume(
+    docker: &Docker,
+    volume: String,
+    output: PathBuf,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<ArchiveBackup> {
+    let mut volume_mount = get_volume_mount(volume.clone());
+    log::info!(
+        "Backing up volume {} to {} on {}",
+        &volume,
+        output.display(),
+        volume_mount.source.as_ref().unwrap()
+    );
+    // Released when dropped, once the archive is written
+    let snapshot = if options.snapshot {
+        snapshot_volume(docker, &volume).await?
+    } else {
+        None
+    };
+    if options.destination_on_host {
+        if let Some(snapshot) = &snapshot {
+            let backup = backup_directory_on_host(
+                path_str(snapshot.path())?,
+                &output,
+                &backup_mount,
+                options,
+            );
+            return with_backup_timeout(docker, &volume, options, backup).await;
+        }
+        // Read the volume where Docker stores it, which needs dockyard to run on the Docker host
+        let mountpoint = docker.inspect_volume(&volume).await?.mountpoint;
+        if !Path::new(&mountpoint).is_dir() {
+            return Err(anyhow!(
+                "Data of volume {} at {} isn't readable here, --destination-on-host needs dockyard to run on the Docker host with access to its volumes",
+                volume,
+                mountpoint
+            ));
+        }
+        let backup = backup_directory_on_host(&mountpoint, &output, &backup_mount, options);
+        return with_backup_timeout(docker, &volume, options, backup).await;
+    }
+    if let Some(snapshot) = &snapshot {
+        volume_mount = get_bind_mount(path_str(snapshot.path())?.to_string());
+    }
+    let inline_threshold = options
+        .inline_threshold
+        .filter(|_| options.allows_inline() && snapshot.is_none());
+    if let Some(threshold) = inline_threshold {
+        let inline = backup_volume_inline(
+            docker,
+            volume_mount.clone(),
+            &backup_mount,
+            &output,
+            threshold,
+            options,
+        );
+        if let Some(archive) = with_backup_timeout(docker, &volume, options, inline).await? {
+            return Ok(archive);
+        }
+        log::debug!(
+            "Volume {} is larger than {} bytes, backing it up in a helper container",
+            volume,
+            threshold
+        );
+    }
+    let mounts = vec![volume_mount, backup_mount];
+    let mounted_output = Path::new("/backup").join(&output);
+    let directory_args = options.directory_args();
+    let mut args = vec!["backup", "directory", "/volume", path_str(&mounted_output)?];
+    args.extend(directory_args.iter().map(String::as_str));
+    let log_prefix = format!("backup volume {}", &volume);
+    let command = run_dockyard_command(docker, Some(mounts), args);
+    match with_backup_timeout(docker, &volume, options, command).await {
+        Ok((exit_code, logs)) => handle_container_output(exit_code, &log_prefix, &logs)
+            .map(|_| parse_backup_output(&output, &logs)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Snapshot the data of a volume where Docker stores it, for `snapshot`
+///
+/// Returns `None` after warning if the volume can't be snapshotted, including when dockyard
+/// isn't running on the Docker host and can't see the volume's data.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `volume` - Name of volume
+///
+async fn snapshot_volume(docker: &Docker, volume: &str) -> Result<Option<Snapshot>> {
+    let mountpoint = docker.inspect_volume(volume).await?.mountpoint;
+    if !Path::new(&mountpoint).is_dir() {
+        log::warn!(
+            "Data of volume {} at {} isn't visible here, backing up the live volume. Snapshots need dockyard to run on the Docker host",
+            volume,
+            mountpoint
+        );
+        return Ok(None);
+    }
+    Ok(take_snapshot(Path::new(&mountpoint)))
+}
+
+/// Volumes backed up by `backup_volumes`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeSelection {
+    /// Every volume
+    All,
+    /// Volumes not used by any container
+    Dangling,
+}
+
+/// Back up several volumes, including volumes not attached to any container
+///
+/// The backup destination and volumes in `options.exclude_volumes` are skipped. A volume that
+/// fails to back up doesn't stop the others, the error is returned once all were attempted.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `selection` - Volumes to back up
+/// * `name_filter` - Only back up volumes whose names match this
+/// * `backup_mount` - Mount of backup destination
+/// * `options` - Backup options
+///
+pub async fn backup_volumes(
+    docker: &Docker,
+    selection: VolumeSelection,
+    name_filter: Option<&Regex>,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<Vec<ArchiveBackup>> {
+    let volumes = list_volumes(docker, selection).await?;
+    let destination = match backup_mount.typ {
+        Some(MountTypeEnum::VOLUME) => backup_mount.source.clone(),
+        _ => None,
+    };
+    let mut archives = vec![];
+    let mut failed = vec![];
+    for volume in volumes {
+        if destination.as_ref() == Some(&volume)
+            || options.exclude_volumes.contains(&volume)
+            || name_filter.map_or(false, |filter| !filter.is_match(&volume))
+        {
+            log::debug!("Skipping volume {}", volume);
+            continue;
+        }
+        match backup_volume(docker, volume.clone(), backup_mount.clone(), options).await {
+            Ok(archive) => {
+                log::info!(
+                    "Successfully backed up volume {} to {}",
+                    volume,
+                    archive.path.display()
+                );
+                archives.push(archive);
+            }
+            Err(e) => {
+                log::error!("Failed to back up volume {}: {:#}", volume, e);
+                failed.push(volume);
+            }
+        }
+    }
+    if failed.is_empty() {
+        Ok(archives)
+    } else {
+        Err(anyhow!("Failed to back up volumes {}", failed.join(", ")))
+    }
+}
+
+/// Return names of volumes, sorted
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `selection` - Volumes to list
+///
+pub(crate) async fn list_volumes(
+    docker: &Docker,
+    selection: VolumeSelection,
+) -> Result<Vec<String>> {
+    let mut filters = HashMap::new();
+    if selection == VolumeSelection::Dangling {
+        filters.insert("dangling", vec!["true"]);
+    }
+    let mut volumes = docker
+        .list_volumes(Some(ListVolumesOptions { filters }))
+        .await
+        .context("Unable to list volumes")?
+        .volumes
+        .into_iter()
+        .map(|volume| volume.name)
+        .collect::<Vec<_>>();
+    volumes.sort();
+    Ok(volumes)
+}
+
+/// Back up a volume without running `dockyard backup directory` in a helper container
+///
+/// The volume is read into memory through the Docker archive API and compressed in process,
+/// which avoids starting a container for volumes that only hold a few files. Returns `None`
+/// if the volume is larger than `threshold` bytes.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `volume_mount` - Mount of volume to back up
+/// * `backup_mount` - Mount of backup destination
+/// * `output` - Directory of archive, relative to `backup_mount`
+/// * `threshold` - Largest volume to back up in memory, in bytes
+/// * `options` - Backup options
+///
+async fn backup_volume_inline(
+    docker: &Docker,
+    volume_mount: Mount,
+    backup_mount: &Mount,
+    output: &Path,
+    threshold: u64,
+    options: &BackupOptions,
+) -> Result<Option<ArchiveBackup>> {
+    let archive = match download_mount_archive(docker, volume_mount, threshold).await? {
+        Some(archive) => archive,
+        None => return Ok(None),
+    };
+    let path = output.join(options.archive_name(&options.timezone.timestamp()));
+    if options.forbids_overwrite()
+        && backup_file_exists(docker, &path, backup_mount.clone()).await?
+    {
+        return Err(anyhow!(
+            "{} already exists, refusing to overwrite it with --no-clobber",
+            path.display()
+        ));
+    }
+    log::info!(
+        "Compressing {} bytes in memory to {}",
+        archive.len(),
+        path.display()
+    );
+    let compressed = compress_mount_archive(&archive, Path::new("volume"), options)?;
+    upload_to_mount(
+        docker,
+        backup_mount.clone(),
+        &path,
+        &compressed,
+        &options.modes,
+    )
+    .await?;
+    Ok(Some(ArchiveBackup {
+        path,
+        checksum: Some(options.hash_algorithm.digest(&compressed)),
+    }))
+}
+
+/// Gzip a tarball from the Docker archive API into the archive `backup_directory` would write
+///
+/// Entries are renamed relative to `root` and, unless `options.preserve_hardlinks` is set,
+/// hardlinks are replaced with copies of the file they link to. FIFOs and device nodes are
+/// skipped unless `options.include_special` is set.
+///
+/// # Arguments
+///
+/// * `archive` - Tarball of `root`
+/// * `root` - Directory the tarball was taken of
+/// * `options` - Backup options
+///
+fn compress_mount_archive(archive: &[u8], root: &Path, options: &BackupOptions) -> Result<Vec<u8>> {
+    let mut tar = tar::Builder::new(options.encoder(vec![]));
+    let mut files: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    let mut source = tar::Archive::new(archive);
+    for entry in source.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.strip_prefix(root)?.to_path_buf();
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let special = matches!(
+            header.entry_type(),
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo
+        );
+        if special && !options.include_special {
+            log::info!("Skipping special file {}", name.display());
+            continue;
+        }
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+        if header.entry_type().is_hard_link() {
+            let target = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("Hardlink {} has no target", name.display()))?
+                .strip_prefix(root)?
+                .to_path_buf();
+            // Link names over 100 bytes can't be stored, so archive a copy instead
+            if !options.preserve_hardlinks || header.set_link_name(&target).is_err() {
+                contents = files
+                    .get(&target)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Hardlink target {} not found", target.display()))?;
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(contents.len() as u64);
+                header.as_old_mut().linkname = [0; 100];
+            }
+        }
+        if header.entry_type().is_file() {
+            files.insert(name.clone(), contents.clone());
+        }
+        tar.append_data(&mut header, &name, contents.as_slice())?;
+    }
+    Ok(tar.into_inner()?.finish()?)
+}
+
+/// Container backup file written by `backup_container` and the mounts it considered
+#[derive(Serialize, Debug)]
+pub struct ContainerBackupResult {
+    /// Container backup file relative to the backup destination
+    pub path: PathBuf,
+    /// Every mount of the container and whether it was backed up
+    pub mounts: Vec<MountDecision>,
+}
+
+/// Whether a container mount is backed up
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MountDecision {
+    #[serde(rename = "type")]
+    pub typ: Option<String>,
+    /// Volume name or host directory
+    pub source: Option<String>,
+    pub destination: Option<String>,
+    pub included: bool,
+    /// Why the mount isn't backed up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<ExclusionReason>,
+}
+
+/// Why a container mount isn't backed up
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExclusionReason {
+    /// The mount is the backup destination
+    BackupDestination,
+    /// NFS volume
+    NetworkVolume,
+    /// Named with `--exclude-volumes`
+    Excluded,
+    /// Bind of the Docker socket
+    DockerSocket,
+    /// Mount type other than volume or bind, e.g. tmpfs
+    UnsupportedType,
+    /// Build cache volume, skipped with `--skip-cache-volumes`
+    CacheVolume,
+}
+
+/// Label marking a volume as a regenerable cache, skipped like volumes matching
+/// `DEFAULT_CACHE_VOLUME_PATTERNS`
+pub const CACHE_VOLUME_LABEL: &str = "com.github.aig787.dockyard.cache";
+
+/// Names of BuildKit cache volumes: the state of `docker buildx` container builders and volumes
+/// of standalone buildkitd deployments
+pub const DEFAULT_CACHE_VOLUME_PATTERNS: &[&str] =
+    &["buildx_buildkit_.+_state", "buildkitd?([-_].+)?"];
+
+/// Compile the default cache volume patterns and `extra`, each of which must match the whole
+/// volume name
+///
+/// # Arguments
+///
+/// * `extra` - Regular expressions for names of other cache volumes
+///
+pub fn cache_volume_patterns(extra: &[&str]) -> Result<Vec<Regex>> {
+    DEFAULT_CACHE_VOLUME_PATTERNS
+        .iter()
+        .chain(extra)
+        .map(|pattern| {
+            Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| anyhow!("Invalid --cache-volume-pattern {}: {}", pattern, e))
+        })
+        .collect()
+}
+
+/// Return true if a volume is a cache, by its name or `CACHE_VOLUME_LABEL`
+///
+/// # Arguments
+///
+/// * `name` - Name of volume
+/// * `labels` - Labels of volume
+/// * `patterns` - Names of cache volumes
+///
+fn is_cache_volume(name: &str, labels: &HashMap<String, String>, patterns: &[Regex]) -> bool {
+    !patterns.is_empty()
+        && (labels.contains_key(CACHE_VOLUME_LABEL)
+            || patterns.iter().any(|pattern| pattern.is_match(name)))
+}
+
+/// Back up container
+///
+/// With the flat layout, the container backup file and all archives are written to
+/// `<container>/<timestamp>` on the backup destination, as `container.json`,
+/// `volume-<name>.<extension>`, `bind-<source>.<extension>` and `image.tar`.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name or ID of container to back up
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options
+///
+pub async fn backup_container(
+    docker: &Docker,
+    container_name: &str,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<ContainerBackupResult> {
+    options.check_layout()?;
+    let (info, mounts, decisions) =
+        get_container_info(docker, container_name, &backup_mount, options).await?;
+    check_bind_overlap(&mounts, &backup_mount)?;
+    // Store backups under the container's name even if it was given by ID
+    let container_name = canonical_container_name(&info, container_name);
+    let container_name = container_name.as_str();
+    let resumed = if options.resume {
+        let path = partial_backup_path(container_name, options.layout);
+        read_partial_backup(docker, &path, &backup_mount, options).await?
+    } else {
+        None
+    };
+    let mut partial = match resumed {
+        Some(mut partial) => {
+            verify_partial_backup(docker, &mut partial, &backup_mount, options).await?;
+            log::info!(
+                "Resuming backup {} of container {}, {} of {} mounts already backed up",
+                partial.timestamp,
+                container_name,
+                partial.completed.len(),
+                partial.mounts.len()
+            );
+            partial
+        }
+        None => {
+            if options.resume {
+                log::info!(
+                    "No interrupted backup of container {} to resume",
+                    container_name
+                );
+            }
+            PartialBackup {
+                timestamp: options.timezone.timestamp(),
+                layout: options.layout,
+                hash_algorithm: options.hash_algorithm,
+                mounts: vec![],
+                completed: vec![],
+                finished: false,
+            }
+        }
+    };
+    partial.mounts = mounts
+        .iter()
+        .map(|mount| mount.destination.clone().unwrap_or_default())
+        .collect();
+    let timestamp = partial.timestamp.clone();
+    let (output, flat_directory) = match options.layout {
+        Layout::Nested => (Path::new("dockyard/containers").join(container_name), None),
+        Layout::Flat => {
+            let directory = Path::new(container_name).join(&timestamp);
+            (directory.clone(), Some(directory))
+        }
+    };
+    log::info!(
+        "Backing up container {} to {}",
+        container_name,
+        output.display()
+    );
+    let pre_hook = match &options.pre_backup_hook {
+        Some(hook) => run_backup_hook(docker, container_name, "pre-backup", hook).await,
+        None => Ok(()),
+    };
+    let mount_backups = match pre_hook {
+        Ok(_) => {
+            backup_mounts(
+                docker,
+                container_name,
+                mounts,
+                &backup_mount,
+                flat_directory.as_deref(),
+                options,
+                &mut partial,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Some(hook) = &options.post_backup_hook {
+        let hook_result = run_backup_hook(docker, container_name, "post-backup", hook).await;
+        match (&mount_backups, hook_result) {
+            (Ok(_), Err(e)) => return Err(e),
+            (Err(_), Err(e)) => log::error!("{:#}", e),
+            _ => {}
+        }
+    }
+    let mount_backups = mount_backups?;
+    let networks = find_network_endpoints(&info);
+    let mut container_config = info.config.unwrap();
+    let redacted_env = redact_env(&mut container_config.env, &options.redact_env);
+    let swarm = find_swarm_references(docker, container_config.labels.as_ref()).await;
+    let image_archive = if options.include_image {
+        let image = container_config.image.as_ref().unwrap();
+        let path = match &flat_directory {
+            Some(directory) => directory.join("image.tar"),
+            None => Path::new("dockyard/images").join(format!("{}.tar", image.replace("/", ":"))),
+        };
+        Some(backup_image(docker, image, path, backup_mount.clone(), options).await?)
+    } else {
+        None
+    };
+    let mut container_backup = ContainerBackup {
+        schema_version: schema_version_for(options.layout, options.hash_algorithm),
+        name: container_name.to_string(),
+        container_config,
+        host_config: info.host_config.unwrap(),
+        mounts: mount_backups,
+        image_archive,
+        image_id: info.image,
+        redacted_env,
+        layout: options.layout,
+        hash_algorithm: options.hash_algorithm,
+        swarm,
+        networks,
+    };
+    let backup_path = match &flat_directory {
+        Some(directory) => {
+            // Record archives relative to the directory so it can be moved as a whole
+            for mount_backup in &mut container_backup.mounts {
+                mount_backup.path = relative_to(&mount_backup.path, directory);
+            }
+            if let Some(image_archive) = container_backup.image_archive.as_mut() {
+                *image_archive = relative_to(image_archive, directory);
+            }
+            directory.join(FLAT_CONTAINER_BACKUP)
+        }
+        None => output.join(format!("{}.json", timestamp)),
+    };
+    let path = write_container_backup(
+        docker,
+        container_backup,
+        backup_path,
+        backup_mount.clone(),
+        options,
+    )
+    .await?;
+    partial.finished = true;
+    record_progress(docker, container_name, &partial, &backup_mount, options).await;
+    if let Some(keep) = options.keep_last {
+        remove_old_backups_of(docker, &output, keep, backup_mount, options)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to remove old backups of container {}",
+                    container_name
+                )
+            })?;
+    }
+    Ok(ContainerBackupResult {
+        path,
+        mounts: decisions,
+    })
+}
+
+/// Return `path` relative to `directory`, or `path` itself if it isn't inside `directory`
+fn relative_to(path: &Path, directory: &Path) -> PathBuf {
+    path.strip_prefix(directory)
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Remove the variables named in `names` from `env`, returning the names removed
+///
+/// # Arguments
+///
+/// * `env` - Container environment of `KEY=VALUE` strings
+/// * `names` - Names of variables to remove
+///
+fn redact_env(env: &mut Option<Vec<String>>, names: &[String]) -> Vec<String> {
+    let mut redacted = vec![];
+    if let Some(env) = env {
+        env.retain(|variable| {
+            let name = variable.splitn(2, '=').next().unwrap();
+            if names.iter().any(|n| n == name) {
+                redacted.push(name.to_string());
+                false
+            } else {
+                true
+            }
+        });
+    }
+    redacted
+}
+
+/// Return name of an inspected container without Docker's leading `/`
+///
+/// # Arguments
+///
+/// * `info` - Inspected container
+/// * `requested` - Name or ID the container was looked up by, used if it has no name
+///
+fn canonical_container_name(info: &ContainerInspectResponse, requested: &str) -> String {
+    info.name
+        .as_ref()
+        .map(|name| name.trim_start_matches('/'))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(requested)
+        .to_string()
+}
+
+/// Back up volumes and bind directories of a container
+///
+/// Progress is recorded in `partial` after each mount, mounts it lists as completed are reused
+/// instead of backed up again.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of container, for progress
+/// * `mounts` - Container mounts to back up
+/// * `backup_mount` - Mount representing backup destination
+/// * `flat_directory` - Directory of a flat layout backup, relative to the backup destination
+/// * `options` - Backup options
+/// * `partial` - Progress of the backup
+///
+async fn backup_mounts(
+    docker: &Docker,
+    container_name: &str,
+    mounts: Vec<MountPoint>,
+    backup_mount: &Mount,
+    flat_directory: Option<&Path>,
+    options: &BackupOptions,
+    partial: &mut PartialBackup,
+) -> Result<Vec<MountBackup>> {
+    let progress = MountProgress::new("Backing up", container_name, mounts.len());
+    let mut backups = vec![];
+    record_progress(docker, container_name, partial, backup_mount, options).await;
+    for mp in mounts {
+        if let Some(completed) = partial.completed_mount(&mp) {
+            log::info!(
+                "Mount {} was already backed up to {}",
+                mp.destination.as_deref().unwrap_or_default(),
+                completed.path.display()
+            );
+            backups.push(completed.clone());
+            progress.finish_mount();
+            continue;
+        }
+        progress.start(
+            mp.name
+                .as_deref()
+                .or_else(|| mp.source.as_deref())
+                .unwrap_or_default(),
+        );
+        let backup = mount::backup_mount(docker, mp, backup_mount, flat_directory, options).await?;
+        partial.completed.push(backup.clone());
+        record_progress(docker, container_name, partial, backup_mount, options).await;
+        backups.push(backup);
+        progress.finish_mount();
+    }
+    Ok(backups)
+}
+
+/// Record backup progress of a container with more than one mount when backing up with
+/// `resume`, only warning if it can't be as the backup itself can still succeed
+async fn record_progress(
+    docker: &Docker,
+    container_name: &str,
+    partial: &PartialBackup,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) {
+    if !options.resume || partial.mounts.len() < 2 {
+        return;
+    }
+    let path = partial_backup_path(container_name, partial.layout);
+    if let Err(e) = record_partial_backup(docker, partial, &path, backup_mount, options).await {
+        log::warn!("{:#}", e);
+    }
+}
+
+/// Run a backup hook in the container, failing if it exits with a non-zero code
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of container to run hook in
+/// * `stage` - Name of hook for logging
+/// * `hook` - Shell command to run
+///
+async fn run_backup_hook(
+    docker: &Docker,
+    container_name: &str,
+    stage: &str,
+    hook: &str,
+) -> Result<()> {
+    log::info!("Running {} hook in {}: {}", stage, container_name, hook);
+    let log_prefix = format!("{} hook {}", stage, container_name);
+    let (exit_code, logs) = exec_in_container(docker, container_name, vec!["sh", "-c", hook])
+        .await
+        .with_context(|| format!("Unable to run {} hook in {}", stage, container_name))?;
+    handle_container_output(exit_code, &log_prefix, &logs)
+        .with_context(|| format!("{} hook failed in {}", stage, container_name))
+}
+
+/// Save image to `path` in the backup mount
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `image` - Image to back up
+/// * `path` - Path of image archive relative to the backup destination
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options
+///
+async fn backup_image(
+    docker: &Docker,
+    image: &str,
+    path: PathBuf,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<PathBuf> {
+    if options.forbids_overwrite()
+        && backup_file_exists(docker, &path, backup_mount.clone()).await?
+    {
+        return Err(anyhow!(
+            "{} already exists, refusing to overwrite it with --no-clobber",
+            path.display()
+        ));
+    }
+    let contents = export_image(docker, image).await?;
+    log::info!("Writing image {} to {}", image, path.display());
+    store_backup_file(docker, backup_mount, &path, &contents, options).await?;
+    Ok(path)
+}
+
+/// Write `contents` to `path` in the backup destination
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup destination
+/// * `path` - Path of file relative to the backup destination
+/// * `contents` - Contents to write
+/// * `options` - Backup options
+///
+pub(crate) async fn store_backup_file(
+    docker: &Docker,
+    backup_mount: Mount,
+    path: &Path,
+    contents: &[u8],
+    options: &BackupOptions,
+) -> Result<()> {
+    if options.destination_on_host {
+        write_to_host_destination(&backup_mount, path, contents, options)
+    } else {
+        upload_to_mount(docker, backup_mount, path, contents, &options.modes).await
+    }
+}
+
+/// Return true if mount refers to the same resource as the backup destination
+///
+/// # Arguments
+///
+/// * `mount` - Container mount to check
+/// * `backup_mount` - Mount representing backup destination
+///
+fn is_backup_destination(mount: &MountPoint, backup_mount: &Mount) -> bool {
+    let backup_source = backup_mount
+        .source
+        .as_deref()
+        .map(|s| s.trim_end_matches('/'));
+    match (mount.typ.as_deref(), backup_mount.typ) {
+        (Some("volume"), Some(MountTypeEnum::VOLUME)) => mount.name.as_deref() == backup_source,
+        (Some("bind"), Some(MountTypeEnum::BIND)) => {
+            mount.source.as_deref().map(|s| s.trim_end_matches('/')) == backup_source
+        }
+        _ => false,
+    }
+}
+
+/// Fail if a bind mount to back up contains a directory backup location or is inside it
+///
+/// Binds of the backup location itself are excluded by `filter_mount` instead.
+///
+/// # Arguments
+///
+/// * `mounts` - Container mounts to back up
+/// * `backup_mount` - Mount representing backup destination
+///
+fn check_bind_overlap(mounts: &[MountPoint], backup_mount: &Mount) -> Result<()> {
+    let backup_source = match (backup_mount.typ, backup_mount.source.as_deref()) {
+        (Some(MountTypeEnum::BIND), Some(source)) => Path::new(source),
+        _ => return Ok(()),
+    };
+    for mount in mounts.iter().filter(|m| m.typ.as_deref() == Some("bind")) {
+        let source = Path::new(mount.source.as_deref().unwrap_or_default());
+        if backup_source.starts_with(source) {
+            return Err(anyhow!(
+                "Bind mount {} contains the backup location {}, backing it up would archive earlier backups",
+                source.display(),
+                backup_source.display()
+            ));
+        }
+        if source.starts_with(backup_source) {
+            return Err(anyhow!(
+                "Bind mount {} is inside the backup location {}, choose a backup location outside of it",
+                source.display(),
+                backup_source.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Include only bind mounts and non-network volumes
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to inspect and filter
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options, for the volumes and directories to exclude
+///
+async fn filter_mount(
+    docker: &Docker,
+    mount: &MountPoint,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<MountDecision> {
+    let exclude_volumes = &options.exclude_volumes;
+    let source = match mount.typ.as_deref() {
+        Some("volume") => mount.name.clone(),
+        _ => mount.source.clone(),
+    };
+    let reason = if is_backup_destination(mount, backup_mount) {
+        log::warn!(
+            "Ignoring mount {} because it is the backup destination",
+            backup_mount.source.as_ref().unwrap()
+        );
+        Some(ExclusionReason::BackupDestination)
+    } else {
+        match mount.typ.as_deref() {
+            Some("volume") => {
+                let volume_name = mount.name.as_ref().unwrap();
+                let volume = docker.inspect_volume(volume_name).await?;
+                match volume.options.get("type").map(String::as_str) {
+                    Some("nfs") | Some("nfs4") => {
+                        log::info!("Ignoring network volume {}", volume_name);
+                        Some(ExclusionReason::NetworkVolume)
+                    }
+                    _ if exclude_volumes.contains(volume_name) => {
+                        log::info!("Ignoring excluded volume {}", volume_name);
+                        Some(ExclusionReason::Excluded)
+                    }
+                    _ if is_cache_volume(volume_name, &volume.labels, &options.cache_volumes) => {
+                        log::info!("Ignoring cache volume {}", volume_name);
+                        Some(ExclusionReason::CacheVolume)
+                    }
+                    _ => {
+                        log::info!("Including volume {}", volume_name);
+                        None
+                    }
+                }
+            }
+            Some("bind") => {
+                let source = mount.source.as_ref().unwrap();
+                if source == "/var/run/docker.sock" {
+                    log::info!("Ignoring bind /var/run/docker.sock");
+                    Some(ExclusionReason::DockerSocket)
+                } else if exclude_volumes.contains(source) {
+                    log::info!("Ignoring excluded directory {}", source);
+                    Some(ExclusionReason::Excluded)
+                } else {
+                    log::info!("Including directory {}", source);
+                    None
+                }
+            }
+            Some(t) => {
+                log::info!("Ignoring mount with type {}", t);
+                Some(ExclusionReason::UnsupportedType)
+            }
+            _ => {
+                log::info!("Ignoring mount {:?}", &mount);
+                Some(ExclusionReason::UnsupportedType)
+            }
+        }
+    };
+    Ok(MountDecision {
+        typ: mount.typ.clone(),
+        source,
+        destination: mount.destination.clone(),
+        included: reason.is_none(),
+        reason,
+    })
+}
+
+/// Return Container Inspection, mounts to back up and the decision made for every mount
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of container to inspect
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options, for the mounts to exclude
+///
+async fn get_container_info(
+    docker: &Docker,
+    container_name: &str,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<(
+    ContainerInspectResponse,
+    Vec<MountPoint>,
+    Vec<MountDecision>,
+)> {
+    let container_info = docker
+        .inspect_container(&container_name, None::<InspectContainerOptions>)
+        .await?;
+    let mut filtered_mounts = vec![];
+    let mut decisions = vec![];
+    for mp in container_info.mounts.as_ref().unwrap() {
+        let decision = filter_mount(docker, mp, backup_mount, options).await?;
+        if decision.included {
+            filtered_mounts.push(mp.clone())
+        }
+        decisions.push(decision);
+    }
+    Ok((container_info, filtered_mounts, decisions))
+}
+
+/// Write container backup json to file
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_backup` - Container backup info
+/// * `backup_path` - Path relative to `backup_mount` to write file to
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Backup options
+///
+async fn write_container_backup(
+    docker: &Docker,
+    container_backup: ContainerBackup,
+    backup_path: PathBuf,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<PathBuf> {
+    let encoded = encode_container_backup(&container_backup, options.compress_manifest)?;
+    log::info!("Writing container backup file {}", backup_path.display());
+    if options.destination_on_host {
+        write_to_host_destination(&backup_mount, &backup_path, encoded.as_bytes(), options)?;
+        if !options.append_only {
+            record_backup(&host_destination(&backup_mount)?, &backup_path);
+        }
+        return Ok(backup_path);
+    }
+    let backup_json = base64::encode(encoded);
+
+    let log_prefix = format!("backup container {}", container_backup.name);
+    let mounted_backup_path = format!("/backup/{}", path_str(&backup_path)?);
+    let mut args = vec![
+        "write",
+        "--file",
+        &mounted_backup_path,
+        "--contents",
+        &backup_json,
+        "--encoded",
+    ];
+    // The catalog database is rewritten on every backup, append-only destinations go without
+    if !options.append_only {
+        args.extend(&["--catalog", "/backup"]);
+    }
+    if options.forbids_overwrite() {
+        args.push("--no-clobber");
+    }
+    let mode_args = options.modes.args();
+    args.extend(mode_args.iter().map(String::as_str));
+
+    match run_dockyard_command(docker, Some(vec![backup_mount]), args).await {
+        Ok((exit_code, logs)) => {
+            handle_container_output(exit_code, &log_prefix, &logs).map(|_| backup_path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::fs::{create_dir, read_to_string};
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    use flate2::read::GzDecoder;
+    use log::LevelFilter;
+    use simple_logger::SimpleLogger;
+    use tar::Archive;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::container::{
+        check_image, get_backup_directory_mount, get_backup_volume_mount, run_docker_command,
+    };
+    use crate::exclude::read_exclude_file;
+    use crate::list::parse_backup_timestamp;
+    use crate::restore::{restore_directory, RestoreOptions};
+    use crate::watch::parse_name_filter;
+    use bollard::container::{
+        Config, CreateContainerOptions, KillContainerOptions, RemoveContainerOptions,
+        StartContainerOptions,
+    };
+    use bollard::models::MountTypeEnum;
+    use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions};
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    #[test]
+    fn backup_file_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        let contents = "I am some contents";
+        File::create(&input)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        create_dir(&output).unwrap();
+
+        let created = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            read_to_string(output.join(created)).unwrap(),
+            contents.to_string()
+        );
+    }
+
+    #[test]
+    fn backup_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(&output).unwrap();
+
+        for i in 0..100 {
+            let mut f = File::create(Path::join(&input, i.to_string())).unwrap();
+            f.write_all(format!("Backup test data {}", i).as_bytes())
+                .unwrap();
+        }
+
+        let created = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let tar_file = File::open(output.join(created)).unwrap();
+
+        let tar = GzDecoder::new(tar_file);
+        let mut archive = Archive::new(tar);
+        let scratch = working_dir.path().join("scratch");
+        create_dir(&scratch).unwrap();
+
+        archive.unpack(&scratch).unwrap();
+
+        let mut count = 0;
+        for maybe_entry in fs::read_dir(&scratch).unwrap() {
+            let entry = maybe_entry.unwrap();
+            let num = entry.file_name();
+            count += 1;
+            assert_eq!(
+                fs::read_to_string(entry.path()).unwrap(),
+                format!("Backup test data {}", num.to_str().unwrap())
+            );
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn backup_directory_non_utf8_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        // Latin-1 "café", which Linux allows in file names
+        let name = OsStr::from_bytes(b"caf\xe9");
+        fs::write(input.join(name), "not utf-8").unwrap();
+
+        let created = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            output.join(&created).to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(restored.join(name)).unwrap(), "not utf-8");
+
+        let incremental = BackupOptions {
+            incremental: true,
+            ..Default::default()
+        };
+        let error = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &incremental,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("caf\u{fffd} is not valid UTF-8"));
+    }
+
+    #[test]
+    fn backup_directory_modes_test() {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        fs::write(input.join("file"), "contents").unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        let created = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions {
+                skip_unchanged: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(mode(&output.join(&created)), 0o600);
+        assert_eq!(mode(&output.join(DIRECTORY_STATE_FILE)), 0o600);
+        assert_eq!(mode(&output), 0o700);
+
+        let options = BackupOptions {
+            modes: FileModes {
+                directory: 0o750,
+                file: 0o640,
+            },
+            ..Default::default()
+        };
+        let nested = output.join("nested");
+        let created =
+            backup_directory(input.to_str().unwrap(), nested.to_str().unwrap(), &options).unwrap();
+        assert_eq!(mode(&nested.join(&created)), 0o640);
+        assert_eq!(mode(&nested), 0o750);
+        assert!(options
+            .directory_args()
+            .contains(&"--file-mode=0640".to_string()));
+    }
+
+    #[test]
+    fn backup_directory_preserve_hardlinks_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(input.join("nested")).unwrap();
+        fs::write(input.join("original"), "linked contents").unwrap();
+        fs::hard_link(input.join("original"), input.join("nested/link")).unwrap();
+        let options = BackupOptions {
+            preserve_hardlinks: true,
+            ..Default::default()
+        };
+        let archive =
+            backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            output.join(archive).to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        let original = fs::metadata(restored.join("original")).unwrap();
+        let link = fs::metadata(restored.join("nested/link")).unwrap();
+        assert_eq!(original.ino(), link.ino());
+        assert_eq!(
+            read_to_string(restored.join("nested/link")).unwrap(),
+            "linked contents"
+        );
+    }
+
+    #[test]
+    fn backup_directory_exclude_from_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(input.join("cache")).unwrap();
+        fs::write(input.join("data"), "data").unwrap();
+        fs::write(input.join("debug.log"), "debug").unwrap();
+        fs::write(input.join("keep.log"), "keep").unwrap();
+        fs::write(input.join("cache/entry"), "cached").unwrap();
+        let exclude_file = working_dir.path().join("excludes");
+        fs::write(
+            &exclude_file,
+            "# logs and caches\n*.log\n!keep.log\ncache/\n",
+        )
+        .unwrap();
+        let options = BackupOptions {
+            exclude: read_exclude_file(&exclude_file).unwrap(),
+            ..Default::default()
+        };
+        let archive =
+            backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            output.join(archive).to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(restored.join("data")).unwrap(), "data");
+        assert_eq!(read_to_string(restored.join("keep.log")).unwrap(), "keep");
+        assert!(!restored.join("debug.log").exists());
+        assert!(!restored.join("cache").exists());
+    }
+
+    #[test]
+    fn backup_directory_special_files_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        fs::write(input.join("data"), "data").unwrap();
+        let status = std::process::Command::new("mkfifo")
+            .arg(input.join("pipe"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let members = |options: &BackupOptions| {
+            let archive =
+                backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), options)
+                    .unwrap();
+            let tar_file = File::open(output.join(archive)).unwrap();
+            Archive::new(GzDecoder::new(tar_file))
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(members(&BackupOptions::default()), vec!["data"]);
+        let mut with_special = members(&BackupOptions {
+            include_special: true,
+            ..Default::default()
+        });
+        with_special.sort();
+        assert_eq!(with_special, vec!["data", "pipe"]);
+    }
+
+    #[test]
+    fn backup_directory_symlinks_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(input.join("data")).unwrap();
+        fs::write(input.join("data/table"), "rows").unwrap();
+        std::os::unix::fs::symlink("data/table", input.join("current")).unwrap();
+        std::os::unix::fs::symlink("data", input.join("latest")).unwrap();
+        for options in &[
+            BackupOptions::default(),
+            BackupOptions {
+                include_special: true,
+                ..Default::default()
+            },
+        ] {
+            let archive =
+                backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), options)
+                    .unwrap();
+            let tar_file = File::open(output.join(&archive)).unwrap();
+            let mut members = Archive::new(GzDecoder::new(tar_file))
+                .entries()
+                .unwrap()
+                .map(|e| {
+                    let e = e.unwrap();
+                    let link = e
+                        .link_name()
+                        .unwrap()
+                        .map(|l| l.to_str().unwrap().to_string());
+                    (e.path().unwrap().to_str().unwrap().to_string(), link)
+                })
+                .collect::<Vec<_>>();
+            members.sort();
+            assert_eq!(
+                members,
+                vec![
+                    ("current".to_string(), Some("data/table".to_string())),
+                    ("data".to_string(), None),
+                    ("data/table".to_string(), None),
+                    ("latest".to_string(), Some("data".to_string())),
+                ]
+            );
+            fs::remove_file(output.join(&archive)).unwrap();
+        }
+    }
+
+    #[test]
+    fn backup_incremental_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        fs::write(input.join("changed"), "before").unwrap();
+        fs::write(input.join("deleted"), "deleted").unwrap();
+        fs::write(input.join("unchanged"), "unchanged").unwrap();
+        let options = BackupOptions {
+            incremental: true,
+            ..Default::default()
+        };
+        let full =
+            backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+        assert!(output.join(CATALOG_FILE).exists());
+
+        fs::write(input.join("changed"), "after").unwrap();
+        fs::remove_file(input.join("deleted")).unwrap();
+        fs::write(input.join("added"), "added").unwrap();
+        let incremental =
+            backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+
+        let tar_file = File::open(output.join(&incremental)).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(tar_file));
+        let members = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(members, vec![INCREMENTAL_MEMBER, "added", "changed"]);
+
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            output.join(&incremental).to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(restored.join("changed")).unwrap(), "after");
+        assert_eq!(read_to_string(restored.join("added")).unwrap(), "added");
+        assert_eq!(
+            read_to_string(restored.join("unchanged")).unwrap(),
+            "unchanged"
+        );
+        assert!(!restored.join("deleted").exists());
+        assert!(!restored.join(INCREMENTAL_MEMBER).exists());
+        assert_ne!(full, incremental);
+    }
+
+    #[test]
+    fn backup_incremental_hash_algorithm_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        fs::write(input.join("file"), "before").unwrap();
+        let options = BackupOptions {
+            incremental: true,
+            hash_algorithm: HashAlgorithm::Blake3,
+            ..Default::default()
+        };
+        assert!(options
+            .directory_args()
+            .contains(&"--hash-algo=blake3".to_string()));
+        backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+        let catalog = read_catalog(&output, HashAlgorithm::Blake3).unwrap();
+        assert_eq!(
+            catalog.files["file"],
+            HashAlgorithm::Blake3.digest(b"before")
+        );
+
+        // Hashes of another algorithm can't be compared, so the next backup is full
+        fs::write(input.join("file"), "after").unwrap();
+        assert!(read_catalog(&output, HashAlgorithm::Sha256).is_none());
+        let full = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions {
+                incremental: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let tar_file = File::open(output.join(&full)).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(tar_file));
+        let members = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(members, vec!["file"]);
+        assert!(read_catalog(&output, HashAlgorithm::Sha256).is_some());
+    }
+
+    #[test]
+    fn backup_unchanged_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(&output).unwrap();
+        File::create(input.join("file"))
+            .unwrap()
+            .write_all(b"unchanged")
+            .unwrap();
+        let options = BackupOptions {
+            skip_unchanged: true,
+            ..Default::default()
+        };
+        let input = input.to_str().unwrap();
+        let first = backup_directory(input, output.to_str().unwrap(), &options).unwrap();
+        let second = backup_directory(input, output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(first.extension().unwrap(), "tgz");
+        assert_eq!(second.extension().unwrap(), POINTER_EXTENSION);
+        assert_eq!(
+            read_to_string(output.join(&second)).unwrap(),
+            first.to_str().unwrap()
+        );
+
+        File::create(Path::new(input).join("new"))
+            .unwrap()
+            .write_all(b"changed")
+            .unwrap();
+        let third = backup_directory(input, output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(third.extension().unwrap(), "tgz");
+    }
+
+    #[test]
+    fn backup_directory_bad_paths_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let error = backup_directory("/tmp/one/bad", "/tmp/two/bad", &BackupOptions::default())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No such file or directory (os error 2)")
+    }
+
+    #[test]
+    fn remove_partial_backup_test() {
+        let working_dir = TempDir::new().unwrap();
+        let backup_path = working_dir.path().join("partial.tgz");
+        File::create(&backup_path).unwrap();
+        let error = anyhow::Error::new(io::Error::from_raw_os_error(28)).context("Failed");
+        let options = BackupOptions::default();
+        let error = remove_partial_backup::<()>(&backup_path, &options, Err(error)).unwrap_err();
+        assert!(!backup_path.exists());
+        assert_eq!(error.to_string(), OUT_OF_SPACE_MESSAGE);
+
+        let error = remove_partial_backup::<()>(&backup_path, &options, Err(anyhow!("Failed")))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Failed");
+    }
+
+    #[test]
+    fn backup_directory_overlapping_paths_test() {
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        create_dir(&input).unwrap();
+        fs::write(input.join("file"), "contents").unwrap();
+        let options = BackupOptions::default();
+
+        let inside_input = input.join("backups");
+        let error = backup_directory(
+            input.to_str().unwrap(),
+            inside_input.to_str().unwrap(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("the backup location is inside the backed up directory"));
+        assert!(!inside_input.exists());
+
+        let error = backup_directory(
+            input.to_str().unwrap(),
+            working_dir.path().to_str().unwrap(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("the backed up directory is inside the backup location"));
+
+        // Paths are compared after resolving symlinks
+        let link = working_dir.path().join("link");
+        std::os::unix::fs::symlink(&input, &link).unwrap();
+        assert!(backup_directory(
+            link.to_str().unwrap(),
+            input.join("nested").join("backups").to_str().unwrap(),
+            &options,
+        )
+        .is_err());
+
+        assert!(backup_directory(
+            input.to_str().unwrap(),
+            working_dir.path().join("output").to_str().unwrap(),
+            &options,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn check_bind_overlap_test() {
+        let bind = |source: &str| MountPoint {
+            typ: Some("bind".to_string()),
+            source: Some(source.to_string()),
+            ..Default::default()
+        };
+        let backup_mount = get_backup_directory_mount("/srv/backups".to_string());
+        assert!(check_bind_overlap(&[bind("/srv/data")], &backup_mount).is_ok());
+        assert!(check_bind_overlap(&[bind("/srv/backups-old")], &backup_mount).is_ok());
+        assert!(check_bind_overlap(&[bind("/srv")], &backup_mount)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Bind mount /srv contains the backup location /srv/backups"));
+        assert!(
+            check_bind_overlap(&[bind("/srv/backups/cache")], &backup_mount)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Bind mount /srv/backups/cache is inside the backup location")
+        );
+        let volume_mount = get_backup_volume_mount("backups".to_string());
+        assert!(check_bind_overlap(&[bind("/srv")], &volume_mount).is_ok());
+    }
+
+    #[test]
+    fn is_cache_volume_test() {
+        let patterns = cache_volume_patterns(&["ccache"]).unwrap();
+        let unlabeled = HashMap::new();
+        let mut labeled = HashMap::new();
+        labeled.insert(CACHE_VOLUME_LABEL.to_string(), "".to_string());
+        for name in &[
+            "buildx_buildkit_builder0_state",
+            "buildkit",
+            "buildkitd-state",
+            "buildkit_cache",
+            "ccache",
+        ] {
+            assert!(is_cache_volume(name, &unlabeled, &patterns), "{}", name);
+        }
+        for name in &[
+            "postgres",
+            "mybuildkit",
+            "ccache-data",
+            "buildx_buildkit_state",
+        ] {
+            assert!(!is_cache_volume(name, &unlabeled, &patterns), "{}", name);
+        }
+        assert!(is_cache_volume("npm", &labeled, &patterns));
+        // Without --skip-cache-volumes nothing is a cache
+        assert!(!is_cache_volume("buildkit", &labeled, &[]));
+        assert!(cache_volume_patterns(&["build("]).is_err());
+    }
+
+    #[test]
+    fn partial_archive_test() {
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        create_dir(&input).unwrap();
+        create_dir(&output).unwrap();
+        fs::write(input.join("file"), "data").unwrap();
+        // Being written by a concurrent backup, or left by one that was killed while writing it
+        let partial = output.join(format!("{}other.tgz", PARTIAL_ARCHIVE_PREFIX));
+        fs::write(&partial, "partial").unwrap();
+
+        let created = backup_directory(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let mut files = fs::read_dir(&output)
+            .unwrap()
+            .map(|e| PathBuf::from(e.unwrap().file_name()))
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec![partial.file_name().unwrap().into(), created]);
+
+        let first = partial_archive_path(&output, "a.tgz", &Default::default()).unwrap();
+        let second = partial_archive_path(&output, "a.tgz", &Default::default()).unwrap();
+        assert_ne!(first, second);
+        assert!(partial.exists());
+        remove_stale_partial_archives(&output, Duration::from_secs(0)).unwrap();
+        assert!(!partial.exists());
+
+        let options = BackupOptions {
+            append_only: true,
+            ..Default::default()
+        };
+        fs::write(&partial, "partial").unwrap();
+        assert_eq!(
+            partial_archive_path(&output, "a.tgz", &options).unwrap(),
+            output.join("a.tgz")
+        );
+        assert!(partial.exists());
+    }
+
+    #[test]
+    fn append_only_backup_test() {
+        let working_dir = TempDir::new().unwrap();
+        let backup_path = working_dir.path().join("partial.tgz");
+        File::create(&backup_path).unwrap();
+        let options = BackupOptions {
+            append_only: true,
+            ..Default::default()
+        };
+        remove_partial_backup::<()>(&backup_path, &options, Err(anyhow!("Failed"))).unwrap_err();
+        assert!(backup_path.exists());
+        assert!(check_clobber(&backup_path, &options).is_err());
+        assert!(options
+            .directory_args()
+            .contains(&"--append-only".to_string()));
+        assert!(BackupOptions {
+            resume: true,
+            ..options.clone()
+        }
+        .check_append_only()
+        .is_err());
+
+        let incremental = BackupOptions {
+            incremental: true,
+            ..options
+        };
+        assert!(incremental.check_append_only().is_err());
+        let input = working_dir.path().join("input");
+        create_dir(&input).unwrap();
+        assert!(backup_directory(
+            input.to_str().unwrap(),
+            working_dir.path().join("output").to_str().unwrap(),
+            &incremental,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_legacy_container_backup_test() {
+        let legacy = r#"{
+            "name": "nginx",
+            "container_config": {"Image": "nginx:latest"},
+            "host_config": {},
+            "mounts": []
+        }"#;
+        let backup = parse_container_backup(legacy).unwrap();
+        assert_eq!(backup.schema_version, CONTAINER_BACKUP_SCHEMA_VERSION);
+        assert_eq!(backup.name, "nginx");
+        assert_eq!(
+            backup.container_config.image,
+            Some("nginx:latest".to_string())
+        );
+        assert!(backup.image_archive.is_none());
+    }
+
+    #[test]
+    fn relativize_archives_test() {
+        let manifest = r#"{
+            "name": "nginx",
+            "container_config": {"Image": "nginx:latest"},
+            "host_config": {},
+            "mounts": [
+                {"path": "/srv/backups/dockyard/volumes/html/1.tgz", "mount": {}},
+                {"path": "/backup/dockyard/volumes/conf/1.tgz", "mount": {}},
+                {"path": "/dockyard/volumes/logs/1.tgz", "mount": {}},
+                {"path": "dockyard/containers/nginx/../../volumes/cache/1.tgz", "mount": {}}
+            ],
+            "image_archive": "/srv/backups/dockyard/images/nginx:latest.tar"
+        }"#;
+        let backup_mount = get_backup_directory_mount("/srv/backups".to_string());
+        let mut backup = parse_container_backup(manifest).unwrap();
+        backup.relativize_archives(&backup_mount).unwrap();
+        let paths = backup
+            .mounts
+            .iter()
+            .map(|mount| mount.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "dockyard/volumes/html/1.tgz",
+                "dockyard/volumes/conf/1.tgz",
+                "dockyard/volumes/logs/1.tgz",
+                "dockyard/volumes/cache/1.tgz",
+            ]
+        );
+        assert_eq!(
+            backup.image_archive,
+            Some(PathBuf::from("dockyard/images/nginx:latest.tar"))
+        );
+
+        let escaping =
+            manifest.replace("/dockyard/volumes/logs/1.tgz", "/srv/backups/../etc/shadow");
+        let mut backup = parse_container_backup(&escaping).unwrap();
+        assert_eq!(
+            backup
+                .relativize_archives(&backup_mount)
+                .unwrap_err()
+                .to_string(),
+            "Archive /srv/backups/../etc/shadow is outside the backup location"
+        );
+    }
 
-    use super::*;
-    use crate::container::{check_image, get_backup_directory_mount};
-    use bollard::container::{
-        Config, CreateContainerOptions, KillContainerOptions, RemoveContainerOptions,
-        StartContainerOptions,
-    };
-    use bollard::models::MountTypeEnum;
-    use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions};
-    use tokio::runtime::Runtime;
-    use uuid::Uuid;
+    #[test]
+    fn compressed_container_backup_test() {
+        let backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "large".to_string(),
+            container_config: ContainerConfig {
+                image: Some("alpine:latest".to_string()),
+                env: Some(
+                    (0..5000)
+                        .map(|i| format!("VAR_{}=value-{}", i, i))
+                        .collect(),
+                ),
+                labels: Some(
+                    (0..1000)
+                        .map(|i| (format!("com.example.label-{}", i), i.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            host_config: Default::default(),
+            mounts: vec![],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        let plain = encode_container_backup(&backup, false).unwrap();
+        let compressed = encode_container_backup(&backup, true).unwrap();
+        assert!(compressed.len() < plain.len() / 4);
+
+        let parsed = parse_container_backup(&compressed).unwrap();
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(&backup).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_value(&parse_container_backup(&plain).unwrap()).unwrap(),
+            serde_json::to_value(&backup).unwrap()
+        );
+    }
 
     #[test]
-    fn backup_file_test() {
+    fn schema_version_for_test() {
+        assert_eq!(schema_version_for(Layout::Nested, HashAlgorithm::Sha256), 1);
+        assert_eq!(schema_version_for(Layout::Flat, HashAlgorithm::Sha256), 2);
+        assert_eq!(schema_version_for(Layout::Nested, HashAlgorithm::Blake3), 2);
+        assert_eq!(
+            schema_version_for(Layout::Flat, HashAlgorithm::Sha512),
+            CONTAINER_BACKUP_SCHEMA_VERSION
+        );
+
+        let flat = r#"{"schema_version": 2, "name": "nginx", "container_config": {}, "host_config": {}, "mounts": [], "layout": "flat", "hash_algorithm": "blake3"}"#;
+        let backup = parse_container_backup(flat).unwrap();
+        assert_eq!(backup.layout, Layout::Flat);
+        assert_eq!(backup.hash_algorithm, HashAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn parse_newer_container_backup_test() {
+        let newer = format!(
+            r#"{{"schema_version": {}, "name": "nginx", "container_config": {{}}, "host_config": {{}}, "mounts": []}}"#,
+            CONTAINER_BACKUP_SCHEMA_VERSION + 1
+        );
+        let error = parse_container_backup(&newer).unwrap_err();
+        assert!(error.to_string().contains("supports up to"));
+    }
+
+    #[test]
+    fn parse_compression_level_test() {
+        assert_eq!(parse_compression_level(Codec::Gzip, "0").unwrap(), 0);
+        assert_eq!(parse_compression_level(Codec::Gzip, "9").unwrap(), 9);
+        assert_eq!(
+            parse_compression_level(Codec::Gzip, "10")
+                .unwrap_err()
+                .to_string(),
+            "gzip level 10 out of range 0-9"
+        );
+        assert_eq!(
+            parse_compression_level(Codec::Gzip, "-1")
+                .unwrap_err()
+                .to_string(),
+            "Invalid gzip level -1, expected a number"
+        );
+    }
+
+    #[test]
+    fn parse_extension_test() {
+        assert_eq!(parse_extension("tar.gz").unwrap(), "tar.gz");
+        assert_eq!(parse_extension(".tgz").unwrap(), "tgz");
+        assert!(parse_extension("").is_err());
+        assert!(parse_extension("tar.").is_err());
+        assert!(parse_extension("tgz.ref").is_err());
+        assert!(parse_extension("json").is_err());
+        assert!(parse_extension("a/tgz").is_err());
+    }
+
+    #[test]
+    fn codec_detect_test() {
+        assert_eq!(Codec::detect(&[0x1f, 0x8b, 0x08]), Some(Codec::Gzip));
+        assert_eq!(Codec::detect(b"ustar"), None);
+        assert_eq!(Codec::detect(&[]), None);
+    }
+
+    #[test]
+    fn backup_directory_to_mount_on_host_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
         let working_dir = TempDir::new().unwrap();
         let input = working_dir.path().join("input");
-        let output = working_dir.path().join("output");
-        let contents = "I am some contents";
-        File::create(&input)
-            .unwrap()
-            .write_all(contents.as_bytes())
-            .unwrap();
-        create_dir(&output).unwrap();
+        let destination = working_dir.path().join("destination");
+        create_dir(&input).unwrap();
+        fs::write(input.join("data"), "data").unwrap();
+        let mut rt = Runtime::new().unwrap();
+        // Nothing is sent to Docker, the directory is archived in process
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let options = BackupOptions {
+            destination_on_host: true,
+            ..Default::default()
+        };
 
-        let created = backup_directory(input.to_str().unwrap(), output.to_str().unwrap()).unwrap();
+        let archive = rt
+            .block_on(backup_directory_to_mount(
+                &docker,
+                input.to_str().unwrap().to_string(),
+                "dockyard/binds/input".to_string(),
+                get_backup_directory_mount(destination.to_str().unwrap().to_string()),
+                &options,
+            ))
+            .unwrap();
+        assert!(archive.path.starts_with("dockyard/binds/input"));
+        let archive_path = destination.join(&archive.path);
         assert_eq!(
-            read_to_string(output.join(created)).unwrap(),
-            contents.to_string()
+            archive.checksum,
+            archive_checksum(&archive_path, HashAlgorithm::Sha256).unwrap()
         );
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            archive_path.to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(restored.join("data")).unwrap(), "data");
+
+        let error = rt
+            .block_on(backup_directory_to_mount(
+                &docker,
+                input.to_str().unwrap().to_string(),
+                "dockyard/binds/input".to_string(),
+                get_backup_volume_mount("backups".to_string()),
+                &options,
+            ))
+            .unwrap_err();
+        assert!(error.to_string().contains("--output-type directory"));
     }
 
     #[test]
-    fn backup_directory_test() {
+    fn write_to_host_destination_test() {
+        let working_dir = TempDir::new().unwrap();
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let path = Path::new("dockyard/containers/web/backup.json");
+        let options = BackupOptions {
+            no_clobber: true,
+            ..Default::default()
+        };
+        write_to_host_destination(&backup_mount, path, b"{}", &options).unwrap();
+        assert_eq!(read_to_string(working_dir.path().join(path)).unwrap(), "{}");
+        assert!(write_to_host_destination(&backup_mount, path, b"{}", &options).is_err());
+    }
+
+    #[test]
+    fn backup_directory_extension_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
         let working_dir = TempDir::new().unwrap();
         let input = working_dir.path().join("input");
         let output = working_dir.path().join("output");
         create_dir(&input).unwrap();
+        fs::write(input.join("data"), "data").unwrap();
+        let options = BackupOptions {
+            extension: Some("tar.gz".to_string()),
+            ..Default::default()
+        };
+        let archive =
+            backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options).unwrap();
+        assert!(archive.to_str().unwrap().ends_with(".tar.gz"));
+        assert!(parse_backup_timestamp(&output.join(&archive)).is_some());
+        assert_eq!(
+            archive_codec(&output.join(&archive)).unwrap(),
+            Some(Codec::Gzip)
+        );
+
+        let restored = working_dir.path().join("restored");
+        restore_directory(
+            output.join(&archive).to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(restored.join("data")).unwrap(), "data");
+
+        let plain = working_dir.path().join("plain.tar.gz");
+        fs::write(&plain, "not compressed").unwrap();
+        assert!(restore_directory(
+            plain.to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn canonical_container_name_test() {
+        let info = ContainerInspectResponse {
+            name: Some("/nginx".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(canonical_container_name(&info, "4f66ad9a0b2e"), "nginx");
+        assert_eq!(
+            canonical_container_name(&ContainerInspectResponse::default(), "4f66ad9a0b2e"),
+            "4f66ad9a0b2e"
+        );
+    }
+
+    #[test]
+    fn timezone_test() {
+        assert_eq!("utc".parse::<Timezone>().unwrap(), Timezone::Utc);
+        assert_eq!("local".parse::<Timezone>().unwrap(), Timezone::Local);
+        let offset = FixedOffset::east(2 * 3600);
+        assert_eq!(
+            "+02:00".parse::<Timezone>().unwrap(),
+            Timezone::Offset(offset)
+        );
+        assert!("Europe/Paris".parse::<Timezone>().is_err());
+        assert!(Timezone::Utc.timestamp().ends_with("+00:00"));
+        assert!(Timezone::Offset(offset).timestamp().ends_with("+02:00"));
+        assert_eq!(Timezone::Utc.arg(), None);
+        assert_eq!(
+            Timezone::Offset(offset).arg(),
+            Some("--timezone=+02:00".to_string())
+        );
+        let local = Timezone::Local.arg().unwrap();
+        let passed = local.trim_start_matches("--timezone=").parse::<Timezone>();
+        assert!(matches!(passed, Ok(Timezone::Offset(_))));
+    }
+
+    #[test]
+    fn parse_duration_test() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(
+            parse_duration("2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("ten minutes").is_err());
+    }
+
+    #[test]
+    fn parse_size_test() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("1g").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("ten megabytes").is_err());
+    }
+
+    #[test]
+    fn backup_timeout_removes_helper_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let container_name = format!("backup_test_slow_helper_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        rt.block_on(check_image(&docker, "alpine:latest")).unwrap();
+        let options = BackupOptions {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        let slow_helper = run_docker_command(
+            &docker,
+            &container_name,
+            "alpine:latest",
+            None,
+            vec!["sleep", "60"],
+            None,
+        );
+        let error = rt
+            .block_on(with_backup_timeout(&docker, "slow", &options, slow_helper))
+            .unwrap_err();
+        assert!(error.downcast_ref::<BackupTimeout>().is_some());
+        assert_eq!(error.to_string(), "Backup of slow timed out after 1s");
+        assert!(rt
+            .block_on(docker.inspect_container(&container_name, None::<InspectContainerOptions>))
+            .is_err());
+    }
+
+    #[test]
+    fn backup_volume_to_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let test_id = Uuid::new_v4().to_string();
+        let volume_name = format!("backup_test_volume_{}", test_id);
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        // Setup
+        rt.block_on(docker.create_volume(CreateVolumeOptions {
+            name: &volume_name,
+            driver: &"local".to_string(),
+            driver_opts: Default::default(),
+            labels: Default::default(),
+        }))
+        .unwrap();
+
+        let working_dir = TempDir::new().unwrap();
+        let output = Path::join(working_dir.path(), "output");
         create_dir(&output).unwrap();
+        let relative = rt
+            .block_on(backup_volume(
+                &docker,
+                volume_name,
+                get_backup_directory_mount(output.to_str().unwrap().to_string()),
+                &BackupOptions::default(),
+            ))
+            .unwrap();
+        assert!(&output.join(&relative.path).exists());
+        assert_eq!(
+            relative.checksum,
+            archive_checksum(&output.join(&relative.path), HashAlgorithm::Sha256).unwrap()
+        );
+    }
 
-        for i in 0..100 {
-            let mut f = File::create(Path::join(&input, i.to_string())).unwrap();
-            f.write_all(format!("Backup test data {}", i).as_bytes())
+    #[test]
+    fn backup_volume_keep_last_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let volume_name = format!("backup_test_volume_{}", Uuid::new_v4());
+        let working_dir = TempDir::new().unwrap();
+        let backup_location =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let options = BackupOptions {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let archives = rt.block_on(async {
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume_name.as_str(),
+                    driver: "local",
+                    driver_opts: Default::default(),
+                    labels: Default::default(),
+                })
+                .await
+                .unwrap();
+            let mut archives = vec![];
+            for _ in 0..4 {
+                let archive = backup_volume(
+                    &docker,
+                    volume_name.clone(),
+                    backup_location.clone(),
+                    &options,
+                )
+                .await
+                .unwrap();
+                archives.push(archive.path);
+            }
+            docker
+                .remove_volume(&volume_name, None::<RemoveVolumeOptions>)
+                .await
+                .unwrap();
+            archives
+        });
+
+        let output = working_dir
+            .path()
+            .join("dockyard/volumes")
+            .join(&volume_name);
+        let remaining = read_dir(&output)
+            .unwrap()
+            .map(|e| {
+                Path::new("dockyard/volumes")
+                    .join(&volume_name)
+                    .join(e.unwrap().file_name())
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(remaining, archives[2..].iter().cloned().collect());
+    }
+
+    #[test]
+    fn backup_volumes_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let prefix = format!("backup_volumes_test_{}", Uuid::new_v4());
+        let dangling = vec![format!("{}_1", prefix), format!("{}_2", prefix)];
+        let attached = format!("{}_3", prefix);
+        let container_name = format!("{}_container", prefix);
+        let name_filter = parse_name_filter(&format!("{}_.*", prefix)).unwrap();
+        let working_dir = TempDir::new().unwrap();
+        let backup_location =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (dangling_archives, all_archives) = rt.block_on(async {
+            for volume in dangling.iter().chain(Some(&attached)) {
+                docker
+                    .create_volume(CreateVolumeOptions {
+                        name: volume.as_str(),
+                        driver: "local",
+                        driver_opts: Default::default(),
+                        labels: Default::default(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            check_image(&docker, "alpine:latest").await.unwrap();
+            docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: container_name.as_str(),
+                    }),
+                    Config {
+                        image: Some("alpine:latest"),
+                        host_config: Some(HostConfig {
+                            mounts: Some(vec![Mount {
+                                source: Some(attached.clone()),
+                                target: Some("/data".to_string()),
+                                typ: Some(MountTypeEnum::VOLUME),
+                                ..Default::default()
+                            }]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            let dangling_archives = backup_volumes(
+                &docker,
+                VolumeSelection::Dangling,
+                Some(&name_filter),
+                backup_location.clone(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            let all_archives = backup_volumes(
+                &docker,
+                VolumeSelection::All,
+                Some(&name_filter),
+                backup_location.clone(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            docker
+                .remove_container(&container_name, None::<RemoveContainerOptions>)
+                .await
                 .unwrap();
+            for volume in dangling.iter().chain(Some(&attached)) {
+                docker
+                    .remove_volume(volume, None::<RemoveVolumeOptions>)
+                    .await
+                    .unwrap();
+            }
+            (dangling_archives, all_archives)
+        });
+        assert_eq!(dangling_archives.len(), 2);
+        for (archive, volume) in dangling_archives.iter().zip(&dangling) {
+            assert!(archive
+                .path
+                .starts_with(Path::new("dockyard/volumes").join(volume)));
+            assert!(working_dir.path().join(&archive.path).exists());
         }
+        assert_eq!(all_archives.len(), 3);
+        assert!(all_archives[2]
+            .path
+            .starts_with(Path::new("dockyard/volumes").join(&attached)));
+    }
 
-        let created = backup_directory(input.to_str().unwrap(), output.to_str().unwrap()).unwrap();
-        let tar_file = File::open(output.join(created)).unwrap();
+    #[test]
+    fn backup_volume_inline_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let volume_name = format!("backup_test_volume_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().join("output");
+        create_dir(&output).unwrap();
+        let backup_location = get_backup_directory_mount(output.to_str().unwrap().to_string());
 
-        let tar = GzDecoder::new(tar_file);
-        let mut archive = Archive::new(tar);
-        let scratch = working_dir.path().join("scratch");
-        create_dir(&scratch).unwrap();
+        let (helper, inline) = rt.block_on(async {
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume_name.as_str(),
+                    driver: "local",
+                    driver_opts: Default::default(),
+                    labels: Default::default(),
+                })
+                .await
+                .unwrap();
+            let volume_mount = get_volume_mount(volume_name.clone());
+            for (path, contents) in &[("file", "top level"), ("nested/file", "nested")] {
+                upload_to_mount(
+                    &docker,
+                    volume_mount.clone(),
+                    Path::new(path),
+                    contents.as_bytes(),
+                    &FileModes::default(),
+                )
+                .await
+                .unwrap();
+            }
+            let helper = backup_volume(
+                &docker,
+                volume_name.clone(),
+                backup_location.clone(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            let inline = backup_volume(
+                &docker,
+                volume_name.clone(),
+                backup_location.clone(),
+                &BackupOptions {
+                    inline_threshold: Some(1 << 20),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            docker
+                .remove_volume(&volume_name, None::<RemoveVolumeOptions>)
+                .await
+                .unwrap();
+            (helper, inline)
+        });
+        let helper_entries = read_archive_entries(&output.join(&helper.path));
+        assert!(helper_entries
+            .iter()
+            .any(|(path, _, contents)| path == Path::new("nested/file") && contents == b"nested"));
+        assert_eq!(
+            helper_entries,
+            read_archive_entries(&output.join(&inline.path))
+        );
+        assert_eq!(
+            inline.checksum,
+            archive_checksum(&output.join(&inline.path), HashAlgorithm::Sha256).unwrap()
+        );
+    }
 
-        archive.unpack(&scratch).unwrap();
+    #[test]
+    fn backup_volume_subpath_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let volume_name = format!("backup_test_volume_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().join("output");
+        create_dir(&output).unwrap();
+        let backup_location = get_backup_directory_mount(output.to_str().unwrap().to_string());
 
-        let mut count = 0;
-        for maybe_entry in fs::read_dir(&scratch).unwrap() {
-            let entry = maybe_entry.unwrap();
-            let num = entry.file_name();
-            count += 1;
-            assert_eq!(
-                fs::read_to_string(entry.path()).unwrap(),
-                format!("Backup test data {}", num.to_str().unwrap())
-            );
-        }
-        assert_eq!(count, 100);
+        let archive = rt.block_on(async {
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume_name.as_str(),
+                    driver: "local",
+                    driver_opts: Default::default(),
+                    labels: Default::default(),
+                })
+                .await
+                .unwrap();
+            let volume_mount = get_volume_mount(volume_name.clone());
+            for path in &["data/uploads/a.png", "data/cache", "scratch/tmp"] {
+                upload_to_mount(
+                    &docker,
+                    volume_mount.clone(),
+                    Path::new(path),
+                    path.as_bytes(),
+                    &FileModes::default(),
+                )
+                .await
+                .unwrap();
+            }
+            let archive = backup_volume(
+                &docker,
+                volume_name.clone(),
+                backup_location.clone(),
+                &BackupOptions {
+                    subpaths: vec![PathBuf::from("data/uploads")],
+                    inline_threshold: Some(1 << 20),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            docker
+                .remove_volume(&volume_name, None::<RemoveVolumeOptions>)
+                .await
+                .unwrap();
+            archive
+        });
+        let paths = read_archive_entries(&output.join(&archive.path))
+            .into_iter()
+            .map(|(path, _, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("data"),
+                PathBuf::from("data/uploads"),
+                PathBuf::from("data/uploads/a.png")
+            ]
+        );
     }
 
-    #[test]
-    fn backup_directory_bad_paths_test() {
-        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
-        let error = backup_directory("/tmp/one/bad", "/tmp/two/bad").unwrap_err();
-        assert_eq!(error.to_string(), "No such file or directory (os error 2)")
+    /// Return the path, mode and contents of each member of a gzipped tarball, sorted by path
+    fn read_archive_entries(archive: &Path) -> Vec<(PathBuf, u32, Vec<u8>)> {
+        let mut archive = Archive::new(GzDecoder::new(File::open(archive).unwrap()));
+        let mut entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_path_buf();
+                // Only compare permissions, file type bits in the mode differ between tar writers
+                let mode = entry.header().mode().unwrap() & 0o7777;
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+                (path, mode, contents)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
     }
 
     #[test]
-    fn backup_volume_to_directory_test() {
+    fn backup_container_to_directory_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
         let test_id = Uuid::new_v4().to_string();
         let volume_name = format!("backup_test_volume_{}", test_id);
+        let container_name = format!("backup_test_container_{}", test_id);
         let mut rt = Runtime::new().unwrap();
         let docker = Docker::connect_with_unix_defaults().unwrap();
 
         // Setup
-        rt.block_on(docker.create_volume(CreateVolumeOptions {
-            name: &volume_name,
-            driver: &"local".to_string(),
-            driver_opts: Default::default(),
-            labels: Default::default(),
-        }))
-        .unwrap();
+        rt.block_on(async {
+            &docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume_name.as_str(),
+                    driver: &"local".to_string(),
+                    driver_opts: Default::default(),
+                    labels: Default::default(),
+                })
+                .await
+                .unwrap();
+            let mounts = vec![Mount {
+                target: Some("/volume".to_string()),
+                source: Some(volume_name.clone()),
+                typ: Some(MountTypeEnum::VOLUME),
+                ..Default::default()
+            }];
+            create_and_start_container(&docker, &container_name, mounts)
+                .await
+                .unwrap();
+        });
 
         let working_dir = TempDir::new().unwrap();
         let output = Path::join(working_dir.path(), "output");
         create_dir(&output).unwrap();
-        let relative = rt
-            .block_on(backup_volume(
+
+        let relative_path = rt
+            .block_on(backup_container(
                 &docker,
-                volume_name,
+                &container_name,
                 get_backup_directory_mount(output.to_str().unwrap().to_string()),
+                &BackupOptions::default(),
             ))
+            .unwrap()
+            .path;
+        let absolute = &output.join(relative_path);
+        assert!(&absolute.exists());
+        let backup_string = fs::read_to_string(&absolute).unwrap();
+        let backup: ContainerBackup = serde_json::from_str(&backup_string).unwrap();
+        assert_eq!(backup.name, container_name);
+        assert_eq!(backup.mounts.len(), 1);
+        let volume_archive = Path::new(&backup.mounts.first().unwrap().path);
+        assert!(&output.join(volume_archive).exists());
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
             .unwrap();
-        assert!(&output.join(relative).exists());
     }
 
     #[test]
-    fn backup_container_to_directory_test() {
+    fn backup_container_flat_layout_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
         let test_id = Uuid::new_v4().to_string();
         let volume_name = format!("backup_test_volume_{}", test_id);
@@ -518,10 +4399,10 @@ mod test {
 
         // Setup
         rt.block_on(async {
-            &docker
+            docker
                 .create_volume(CreateVolumeOptions {
                     name: volume_name.as_str(),
-                    driver: &"local".to_string(),
+                    driver: "local",
                     driver_opts: Default::default(),
                     labels: Default::default(),
                 })
@@ -539,25 +4420,403 @@ mod test {
         });
 
         let working_dir = TempDir::new().unwrap();
-        let output = Path::join(working_dir.path(), "output");
+        let output = working_dir.path().join("output");
         create_dir(&output).unwrap();
+        let options = BackupOptions {
+            layout: Layout::Flat,
+            ..Default::default()
+        };
 
         let relative_path = rt
             .block_on(backup_container(
                 &docker,
                 &container_name,
                 get_backup_directory_mount(output.to_str().unwrap().to_string()),
-                &HashSet::new(),
+                &options,
             ))
+            .unwrap()
+            .path;
+        assert_eq!(relative_path.file_name().unwrap(), FLAT_CONTAINER_BACKUP);
+        assert!(relative_path.starts_with(&container_name));
+        assert!(parse_backup_timestamp(&relative_path).is_some());
+        let backup =
+            parse_container_backup(&read_to_string(output.join(&relative_path)).unwrap()).unwrap();
+        assert_eq!(backup.layout, Layout::Flat);
+        let archive = &backup.mounts.first().unwrap().path;
+        assert_eq!(
+            archive,
+            &PathBuf::from(format!("volume-{}.tgz", volume_name))
+        );
+
+        // Moving the directory keeps it restorable, archives are found next to the backup file
+        let moved = working_dir.path().join("moved");
+        fs::rename(output.join(relative_path.parent().unwrap()), &moved).unwrap();
+        let mut backup = backup;
+        backup.resolve_archives(Path::new("moved").join(FLAT_CONTAINER_BACKUP).as_path());
+        assert!(working_dir
+            .path()
+            .join(&backup.mounts.first().unwrap().path)
+            .exists());
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
             .unwrap();
-        let absolute = &output.join(relative_path);
-        assert!(&absolute.exists());
-        let backup_string = fs::read_to_string(&absolute).unwrap();
-        let backup: ContainerBackup = serde_json::from_str(&backup_string).unwrap();
+    }
+
+    #[test]
+    fn check_layout_test() {
+        let flat = BackupOptions {
+            layout: Layout::Flat,
+            ..Default::default()
+        };
+        flat.check_layout().unwrap();
+        assert!(BackupOptions {
+            incremental: true,
+            ..flat.clone()
+        }
+        .check_layout()
+        .is_err());
+        assert!(BackupOptions {
+            keep_last: Some(3),
+            ..flat
+        }
+        .check_layout()
+        .is_err());
+        assert_eq!("flat".parse::<Layout>().unwrap(), Layout::Flat);
+        assert!("deep".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn archive_stem_test() {
+        let options = BackupOptions {
+            archive_stem: Some("volume-data".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.archive_name("2020-10-22T23:09:02+00:00"),
+            "volume-data.tgz"
+        );
+        assert!(options
+            .directory_args()
+            .contains(&"--archive-stem=volume-data".to_string()));
+    }
+
+    #[test]
+    fn backup_container_by_id_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let container_name = format!("backup_test_container_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let container_id = rt.block_on(async {
+            create_and_start_container(&docker, &container_name, vec![])
+                .await
+                .unwrap();
+            docker
+                .inspect_container(&container_name, None::<InspectContainerOptions>)
+                .await
+                .unwrap()
+                .id
+                .unwrap()
+        });
+
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().join("output");
+        create_dir(&output).unwrap();
+        let relative_path = rt
+            .block_on(backup_container(
+                &docker,
+                &container_id,
+                get_backup_directory_mount(output.to_str().unwrap().to_string()),
+                &BackupOptions::default(),
+            ))
+            .unwrap()
+            .path;
+        assert!(relative_path.starts_with(Path::new("dockyard/containers").join(&container_name)));
+        let backup: ContainerBackup =
+            serde_json::from_str(&fs::read_to_string(output.join(relative_path)).unwrap()).unwrap();
         assert_eq!(backup.name, container_name);
-        assert_eq!(backup.mounts.len(), 1);
-        let volume_archive = Path::new(&backup.mounts.first().unwrap().path);
-        assert!(&output.join(volume_archive).exists());
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
+            .unwrap();
+    }
+
+    #[test]
+    fn backup_container_include_image_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let container_name = format!("backup_test_container_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        rt.block_on(create_and_start_container(&docker, &container_name, vec![]))
+            .unwrap();
+
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().to_path_buf();
+        let options = BackupOptions {
+            include_image: true,
+            ..Default::default()
+        };
+        let relative_path = rt
+            .block_on(backup_container(
+                &docker,
+                &container_name,
+                get_backup_directory_mount(output.to_str().unwrap().to_string()),
+                &options,
+            ))
+            .unwrap()
+            .path;
+        let backup_string = fs::read_to_string(output.join(relative_path)).unwrap();
+        let backup: ContainerBackup = serde_json::from_str(&backup_string).unwrap();
+        let image_archive = backup.image_archive.unwrap();
+        assert_eq!(
+            image_archive,
+            Path::new("dockyard/images/alpine:latest.tar")
+        );
+        let mut archive = Archive::new(File::open(output.join(image_archive)).unwrap());
+        assert!(archive
+            .entries()
+            .unwrap()
+            .any(|e| e.unwrap().path().unwrap() == Path::new("manifest.json")));
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
+            .unwrap();
+    }
+
+    #[test]
+    fn backup_container_hooks_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let container_name = format!("backup_test_container_{}", Uuid::new_v4());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        rt.block_on(create_and_start_container(&docker, &container_name, vec![]))
+            .unwrap();
+
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().to_path_buf();
+        let backup_mount = get_backup_directory_mount(output.to_str().unwrap().to_string());
+
+        // Failing pre-backup hook aborts the backup but still runs the post-backup hook
+        let options = BackupOptions {
+            pre_backup_hook: Some("exit 3".to_string()),
+            post_backup_hook: Some("touch /tmp/post-backup".to_string()),
+            ..Default::default()
+        };
+        let error = rt
+            .block_on(backup_container(
+                &docker,
+                &container_name,
+                backup_mount.clone(),
+                &options,
+            ))
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("pre-backup hook failed"));
+        assert!(!output.join("dockyard/containers").exists());
+        let (exit_code, _) = rt
+            .block_on(exec_in_container(
+                &docker,
+                &container_name,
+                vec!["test", "-f", "/tmp/post-backup"],
+            ))
+            .unwrap();
+        assert_eq!(exit_code, 0);
+
+        let options = BackupOptions {
+            pre_backup_hook: Some("touch /tmp/pre-backup".to_string()),
+            ..Default::default()
+        };
+        let relative_path = rt
+            .block_on(backup_container(
+                &docker,
+                &container_name,
+                backup_mount,
+                &options,
+            ))
+            .unwrap()
+            .path;
+        assert!(output.join(relative_path).exists());
+        let (exit_code, _) = rt
+            .block_on(exec_in_container(
+                &docker,
+                &container_name,
+                vec!["test", "-f", "/tmp/pre-backup"],
+            ))
+            .unwrap();
+        assert_eq!(exit_code, 0);
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
+            .unwrap();
+    }
+
+    #[test]
+    fn backup_container_resume_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("backup_test_container_{}", test_id);
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        // Setup
+        rt.block_on(async {
+            let mut mounts = vec![];
+            for target in &["/first", "/second"] {
+                let volume_name =
+                    format!("backup_test_volume{}_{}", target.replace("/", "_"), test_id);
+                docker
+                    .create_volume(CreateVolumeOptions {
+                        name: volume_name.as_str(),
+                        driver: "local",
+                        driver_opts: Default::default(),
+                        labels: Default::default(),
+                    })
+                    .await
+                    .unwrap();
+                mounts.push(Mount {
+                    target: Some(target.to_string()),
+                    source: Some(volume_name),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                });
+            }
+            create_and_start_container(&docker, &container_name, mounts)
+                .await
+                .unwrap();
+        });
+
+        let working_dir = TempDir::new().unwrap();
+        let output = Path::join(working_dir.path(), "output");
+        create_dir(&output).unwrap();
+        let backup_mount = get_backup_directory_mount(output.to_str().unwrap().to_string());
+
+        // Turn a finished backup into one interrupted after its first mount, with the archive
+        // of the second mount corrupt
+        let path = rt
+            .block_on(backup_container(
+                &docker,
+                &container_name,
+                backup_mount.clone(),
+                &BackupOptions::default(),
+            ))
+            .unwrap()
+            .path;
+        let backup: ContainerBackup =
+            serde_json::from_str(&fs::read_to_string(output.join(&path)).unwrap()).unwrap();
+        assert_eq!(backup.mounts.len(), 2);
+        fs::remove_file(output.join(&path)).unwrap();
+        let mut completed = backup.mounts.clone();
+        completed[1].checksum = Some("4f66ad9a".to_string());
+        let partial = PartialBackup {
+            timestamp: path.file_stem().unwrap().to_string_lossy().to_string(),
+            layout: Layout::Nested,
+            hash_algorithm: HashAlgorithm::default(),
+            mounts: vec!["/first".to_string(), "/second".to_string()],
+            completed,
+            finished: false,
+        };
+        fs::write(
+            output.join(partial_backup_path(&container_name, Layout::Nested)),
+            serde_json::to_vec(&partial).unwrap(),
+        )
+        .unwrap();
+
+        let options = BackupOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let resumed_path = rt
+            .block_on(backup_container(
+                &docker,
+                &container_name,
+                backup_mount,
+                &options,
+            ))
+            .unwrap()
+            .path;
+        assert_eq!(resumed_path, path);
+        let resumed: ContainerBackup =
+            serde_json::from_str(&fs::read_to_string(output.join(&path)).unwrap()).unwrap();
+        assert_eq!(resumed.mounts[0].path, backup.mounts[0].path);
+        assert_ne!(resumed.mounts[1].path, backup.mounts[1].path);
+        assert!(output.join(&resumed.mounts[1].path).exists());
+
+        // Cleanup
+        rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
+            .unwrap();
+    }
+
+    #[test]
+    fn backup_container_excludes_backup_volume_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let test_id = Uuid::new_v4().to_string();
+        let volume_name = format!("backup_test_volume_{}", test_id);
+        let backup_volume_name = format!("backup_test_destination_{}", test_id);
+        let container_name = format!("backup_test_container_{}", test_id);
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let mounts = rt.block_on(async {
+            for name in &[&volume_name, &backup_volume_name] {
+                docker
+                    .create_volume(CreateVolumeOptions {
+                        name: name.as_str(),
+                        driver: "local",
+                        driver_opts: Default::default(),
+                        labels: Default::default(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            let mounts = vec![
+                Mount {
+                    target: Some("/volume".to_string()),
+                    source: Some(volume_name.clone()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                },
+                Mount {
+                    target: Some("/backups".to_string()),
+                    source: Some(backup_volume_name.clone()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                },
+            ];
+            create_and_start_container(&docker, &container_name, mounts)
+                .await
+                .unwrap();
+            let (_, mounts, decisions) = get_container_info(
+                &docker,
+                &container_name,
+                &get_backup_volume_mount(backup_volume_name.clone()),
+                &BackupOptions::default(),
+            )
+            .await
+            .unwrap();
+            (mounts, decisions)
+        });
+        let (mounts, mut decisions) = mounts;
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts.first().unwrap().name.as_ref().unwrap(), &volume_name);
+        decisions.sort_by(|a, b| a.destination.cmp(&b.destination));
+        assert_eq!(
+            decisions,
+            vec![
+                MountDecision {
+                    typ: Some("volume".to_string()),
+                    source: Some(backup_volume_name),
+                    destination: Some("/backups".to_string()),
+                    included: false,
+                    reason: Some(ExclusionReason::BackupDestination),
+                },
+                MountDecision {
+                    typ: Some("volume".to_string()),
+                    source: Some(volume_name),
+                    destination: Some("/volume".to_string()),
+                    included: true,
+                    reason: None,
+                },
+            ]
+        );
 
         // Cleanup
         rt.block_on(cleanup_container_and_volumes(&docker, &container_name))
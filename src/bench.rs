@@ -0,0 +1,171 @@
+use crate::backup::{backup_directory, BackupOptions, Codec};
+use crate::check::ByteSize;
+use crate::file::path_str;
+use anyhow::Result;
+use std::fs::{read_dir, remove_file};
+use std::path::Path;
+use std::time::Instant;
+use tempfile::TempDir;
+
+/// Size and time of archiving a directory with one codec and compression level
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CompressionResult {
+    pub codec: &'static str,
+    pub level: u32,
+    /// Size of the archive in bytes
+    pub size: u64,
+    /// Size of the archive as a fraction of the size of the files archived
+    pub ratio: f64,
+    /// Time taken to write the archive in milliseconds
+    pub millis: u64,
+}
+
+/// Return the compression levels each codec is compared at: fastest, default and smallest
+fn bench_levels(codec: Codec) -> Vec<u32> {
+    match codec {
+        Codec::Gzip => vec![1, 6, 9],
+    }
+}
+
+/// Archive `input` with every codec at a few compression levels, returning the size and time
+/// taken by each
+///
+/// Archives are written the way `backup directory` writes them, to a temporary directory that
+/// is removed afterwards.
+///
+/// # Arguments
+///
+/// * `input` - Directory to archive
+///
+pub fn bench_compression(input: &str) -> Result<Vec<CompressionResult>> {
+    let input_path = Path::new(input);
+    if !input_path.is_dir() {
+        return Err(anyhow!("{} is not a directory", input));
+    }
+    let input_size = directory_size(input_path)?;
+    let output = TempDir::new()?;
+    let mut results = vec![];
+    for codec in Codec::ALL.iter().copied() {
+        for level in bench_levels(codec) {
+            log::info!("Archiving {} with {} level {}", input, codec.name(), level);
+            let options = BackupOptions {
+                compression_level: Some(level),
+                archive_stem: Some(format!("{}-{}", codec.name(), level)),
+                ..Default::default()
+            };
+            let started = Instant::now();
+            let archive =
+                output
+                    .path()
+                    .join(backup_directory(input, path_str(output.path())?, &options)?);
+            let millis = started.elapsed().as_millis() as u64;
+            let size = archive.metadata()?.len();
+            remove_file(&archive)?;
+            results.push(CompressionResult {
+                codec: codec.name(),
+                level,
+                size,
+                ratio: size as f64 / input_size.max(1) as f64,
+                millis,
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Return total size of the files in `directory` and its subdirectories
+fn directory_size(directory: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        let metadata = path.symlink_metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&path)?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Render results as a table with one row per codec and level
+pub fn format_compression_results(results: &[CompressionResult]) -> String {
+    let mut lines = vec![format!(
+        "{:<6} {:>5} {:>11} {:>7} {:>9}",
+        "CODEC", "LEVEL", "SIZE", "RATIO", "TIME"
+    )];
+    for result in results {
+        lines.push(format!(
+            "{:<6} {:>5} {:>11} {:>6.1}% {:>7}ms",
+            result.codec,
+            result.level,
+            ByteSize(result.size).to_string(),
+            result.ratio * 100.0,
+            result.millis
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir, write};
+
+    #[test]
+    fn bench_compression_test() {
+        let input_dir = TempDir::new().unwrap();
+        write(
+            input_dir.path().join("log"),
+            "GET /index.html 200\n".repeat(1000),
+        )
+        .unwrap();
+        create_dir(input_dir.path().join("data")).unwrap();
+        write(input_dir.path().join("data").join("empty"), "").unwrap();
+        let input = input_dir.path().to_str().unwrap();
+
+        let results = bench_compression(input).unwrap();
+        let levels = results
+            .iter()
+            .map(|result| (result.codec, result.level))
+            .collect::<Vec<_>>();
+        assert_eq!(levels, vec![("gzip", 1), ("gzip", 6), ("gzip", 9)]);
+        for result in &results {
+            assert!(result.size > 0);
+            assert!(result.ratio < 0.5);
+        }
+        assert_eq!(read_dir(input).unwrap().count(), 2);
+
+        let file = input_dir.path().join("log");
+        assert!(bench_compression(file.to_str().unwrap())
+            .unwrap_err()
+            .to_string()
+            .ends_with("is not a directory"));
+    }
+
+    #[test]
+    fn format_compression_results_test() {
+        let results = vec![
+            CompressionResult {
+                codec: "gzip",
+                level: 1,
+                size: 1536 * 1024,
+                ratio: 0.31,
+                millis: 120,
+            },
+            CompressionResult {
+                codec: "gzip",
+                level: 9,
+                size: 900,
+                ratio: 0.0002,
+                millis: 1450,
+            },
+        ];
+        assert_eq!(
+            format_compression_results(&results),
+            "CODEC  LEVEL        SIZE   RATIO      TIME
+gzip       1     1.5 MiB   31.0%     120ms
+gzip       9       900 B    0.0%    1450ms"
+        );
+    }
+}
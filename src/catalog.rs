@@ -0,0 +1,589 @@
+use crate::backup::{parse_container_backup, FLAT_CONTAINER_BACKUP};
+use crate::container::{
+    handle_container_output, last_stdout_line, read_only_mount, run_dockyard_command,
+};
+use crate::file::{list_directory, path_str};
+use crate::hash::HashAlgorithm;
+use crate::list::{parse_backup_timestamp, sort_by_timestamp};
+use anyhow::{Context, Result};
+use bollard::models::Mount;
+use bollard::Docker;
+use chrono::DateTime;
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{read_to_string, remove_file, rename};
+use std::path::{Path, PathBuf};
+
+/// File name of the catalog database in the root of a backup location
+pub const CATALOG_DB: &str = "dockyard.db";
+
+/// Tables of the catalog, archives are removed along with the backup that references them
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS backups (
+        path TEXT PRIMARY KEY,
+        container TEXT NOT NULL,
+        algorithm TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS archives (
+        backup TEXT NOT NULL,
+        path TEXT NOT NULL,
+        checksum TEXT,
+        size INTEGER,
+        image INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS backups_container ON backups (container);
+    CREATE INDEX IF NOT EXISTS archives_backup ON archives (backup);
+";
+
+/// Container backup recorded in the catalog
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub container: String,
+    /// Container backup file relative to the backup location
+    pub backup_file: PathBuf,
+    pub algorithm: HashAlgorithm,
+    pub archives: Vec<CatalogArchive>,
+}
+
+/// Archive referenced by a container backup recorded in the catalog
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogArchive {
+    /// Path relative to the backup location
+    pub path: PathBuf,
+    /// Checksum recorded in the container backup, `None` for images and pointers
+    pub checksum: Option<String>,
+    /// Size in bytes when recorded, `None` if the archive was missing
+    pub size: Option<u64>,
+    /// Whether the archive is the container's image rather than a mount
+    pub image: bool,
+}
+
+/// SQLite database of the container backups in a backup location
+///
+/// The catalog is optional. It is created by `dockyard reindex` and kept up to date by every
+/// backup and prune after that, so that `list`, `prune` and `--latest` can skip reading every
+/// container backup file.
+pub struct BackupCatalog {
+    connection: Connection,
+}
+
+impl BackupCatalog {
+    /// Open the catalog of the backup location at `root`, `None` if it has none
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Root of the backup location
+    /// * `writable` - Whether the catalog will be updated
+    ///
+    fn open(root: &Path, writable: bool) -> Result<Option<Self>> {
+        let path = root.join(CATALOG_DB);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let flags = if writable {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        };
+        let connection = Connection::open_with_flags(&path, flags)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        Ok(Some(BackupCatalog { connection }))
+    }
+
+    /// Create an empty catalog at `path`
+    fn create(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(BackupCatalog { connection })
+    }
+
+    /// Record container backups, replacing earlier records of the same files
+    fn record(&mut self, entries: &[CatalogEntry]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        for entry in entries {
+            let backup_file = entry.backup_file.to_string_lossy().to_string();
+            transaction.execute(
+                "DELETE FROM archives WHERE backup = ?1",
+                params![backup_file],
+            )?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO backups (path, container, algorithm) VALUES (?1, ?2, ?3)",
+                params![backup_file, entry.container, entry.algorithm.name()],
+            )?;
+            for archive in &entry.archives {
+                transaction.execute(
+                    "INSERT INTO archives (backup, path, checksum, size, image) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        backup_file,
+                        archive.path.to_string_lossy().to_string(),
+                        archive.checksum,
+                        archive.size.map(|size| size as i64),
+                        archive.image
+                    ],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Remove records of container backup files, paths that aren't recorded are ignored
+    fn forget(&mut self, backup_files: &[PathBuf]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        for backup_file in backup_files {
+            let backup_file = backup_file.to_string_lossy().to_string();
+            transaction.execute(
+                "DELETE FROM archives WHERE backup = ?1",
+                params![backup_file],
+            )?;
+            transaction.execute("DELETE FROM backups WHERE path = ?1", params![backup_file])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Return recorded container backups, by container and oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `container` - Only return backups of this container
+    ///
+    fn entries(&self, container: Option<&str>) -> Result<Vec<CatalogEntry>> {
+        let mut archives = HashMap::<String, Vec<CatalogArchive>>::new();
+        let mut statement = self.connection.prepare(
+            "SELECT archives.backup, archives.path, archives.checksum, archives.size, archives.image
+             FROM archives JOIN backups ON archives.backup = backups.path
+             WHERE ?1 IS NULL OR backups.container = ?1
+             ORDER BY archives.rowid",
+        )?;
+        let rows = statement.query_map(params![container], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                CatalogArchive {
+                    path: PathBuf::from(row.get::<_, String>(1)?),
+                    checksum: row.get(2)?,
+                    size: row.get::<_, Option<i64>>(3)?.map(|size| size as u64),
+                    image: row.get(4)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (backup_file, archive) = row?;
+            archives.entry(backup_file).or_default().push(archive);
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT path, container, algorithm FROM backups WHERE ?1 IS NULL OR container = ?1",
+        )?;
+        let rows = statement.query_map(params![container], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+        let mut entries = vec![];
+        for row in rows {
+            let (backup_file, container, algorithm) = row?;
+            entries.push(CatalogEntry {
+                container,
+                archives: archives.remove(&backup_file).unwrap_or_default(),
+                backup_file: PathBuf::from(backup_file),
+                algorithm: algorithm.parse()?,
+            });
+        }
+        entries.sort_by(|a, b| {
+            a.container.cmp(&b.container).then_with(|| {
+                parse_backup_timestamp(&a.backup_file).cmp(&parse_backup_timestamp(&b.backup_file))
+            })
+        });
+        Ok(entries)
+    }
+}
+
+/// Return the catalog entry of a container backup file
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `backup_file` - Container backup file relative to `root`
+///
+fn read_entry(root: &Path, backup_file: &Path) -> Result<CatalogEntry> {
+    let contents = read_to_string(root.join(backup_file))
+        .with_context(|| format!("Unable to read {}", backup_file.display()))?;
+    let mut backup = parse_container_backup(&contents)
+        .with_context(|| format!("Unable to parse {}", backup_file.display()))?;
+    backup.resolve_archives(backup_file);
+    let container = backup_container_name(backup_file)
+        .ok_or_else(|| anyhow!("{} isn't a container backup file", backup_file.display()))?;
+    let size = |path: &Path| root.join(path).metadata().ok().map(|m| m.len());
+    let mut archives = backup
+        .mounts
+        .into_iter()
+        .map(|m| CatalogArchive {
+            size: size(&m.path),
+            path: m.path,
+            checksum: m.checksum,
+            image: false,
+        })
+        .collect::<Vec<_>>();
+    if let Some(path) = backup.image_archive {
+        archives.push(CatalogArchive {
+            size: size(&path),
+            path,
+            checksum: None,
+            image: true,
+        });
+    }
+    Ok(CatalogEntry {
+        container,
+        backup_file: backup_file.to_path_buf(),
+        algorithm: backup.hash_algorithm,
+        archives,
+    })
+}
+
+/// Return the name of the container a backup file is under, in either layout
+fn backup_container_name(backup_file: &Path) -> Option<String> {
+    let directory = if backup_file.file_name()? == FLAT_CONTAINER_BACKUP {
+        backup_file.parent()?.parent()?
+    } else {
+        backup_file.parent()?
+    };
+    Some(directory.file_name()?.to_string_lossy().to_string())
+}
+
+/// Return container backup files of `container` in either layout, relative to `root`
+fn scan_container(root: &Path, container: &str) -> Result<Vec<PathBuf>> {
+    let directory = Path::new("dockyard/containers").join(container);
+    let mut backup_files = list_directory(path_str(&root.join(&directory))?)?
+        .into_iter()
+        .filter(|e| e.ends_with(".json"))
+        .map(|e| directory.join(e))
+        .collect::<Vec<_>>();
+    let flat_directory = Path::new(container);
+    backup_files.extend(
+        list_directory(path_str(&root.join(flat_directory))?)?
+            .into_iter()
+            .filter(|e| DateTime::parse_from_rfc3339(e).is_ok())
+            .map(|e| flat_directory.join(e).join(FLAT_CONTAINER_BACKUP)),
+    );
+    Ok(sort_by_timestamp(backup_files))
+}
+
+/// Return every container backup file in the backup location, relative to `root`
+///
+/// Containers are found in `dockyard/containers` and, for the flat layout, in the root.
+fn scan_backup_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut containers = BTreeSet::new();
+    for (directory, name) in list_directory(path_str(&root.join("dockyard/containers"))?)?
+        .into_iter()
+        .map(|name| (root.join("dockyard/containers").join(&name), name))
+        .chain(
+            list_directory(path_str(root)?)?
+                .into_iter()
+                .filter(|name| name != "dockyard")
+                .map(|name| (root.join(&name), name)),
+        )
+    {
+        if directory.is_dir() {
+            containers.insert(name);
+        }
+    }
+    let mut backup_files = BTreeSet::new();
+    for container in containers {
+        backup_files.extend(scan_container(root, &container)?);
+    }
+    Ok(backup_files)
+}
+
+/// Return recorded container backups if the backup location has an up to date catalog
+///
+/// The catalog is out of date if the container backup files it records aren't exactly those in
+/// the backup location, e.g. after backing up with an older dockyard. Listing directories is cheap
+/// next to reading every container backup file, so this is checked on every query.
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `container` - Only return backups of this container
+///
+pub fn query_catalog(root: &Path, container: Option<&str>) -> Result<Option<Vec<CatalogEntry>>> {
+    let catalog = match BackupCatalog::open(root, false)? {
+        Some(catalog) => catalog,
+        None => return Ok(None),
+    };
+    let entries = catalog.entries(container)?;
+    let recorded = entries
+        .iter()
+        .map(|e| e.backup_file.clone())
+        .collect::<BTreeSet<_>>();
+    let present = match container {
+        Some(container) => scan_container(root, container)?.into_iter().collect(),
+        None => scan_backup_files(root)?,
+    };
+    if recorded != present {
+        log::warn!(
+            "Catalog {} is out of date, reading container backup files instead. Rebuild it with dockyard reindex",
+            CATALOG_DB
+        );
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+/// Rebuild the catalog of the backup location from its container backup files, returning the
+/// number of backups recorded
+///
+/// The catalog is written next to the old one and renamed over it, so readers never see a
+/// partial catalog.
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+///
+pub fn rebuild_catalog(root: &Path) -> Result<usize> {
+    let entries = scan_backup_files(root)?
+        .iter()
+        .map(|backup_file| read_entry(root, backup_file))
+        .collect::<Result<Vec<_>>>()?;
+    let path = root.join(CATALOG_DB);
+    let temporary = root.join(format!("{}.tmp", CATALOG_DB));
+    if temporary.exists() {
+        remove_file(&temporary)?;
+    }
+    BackupCatalog::create(&temporary)?.record(&entries)?;
+    rename(&temporary, &path).with_context(|| format!("Unable to replace {}", path.display()))?;
+    Ok(entries.len())
+}
+
+/// Record a container backup in the catalog of the backup location, if it has one
+///
+/// Failing to update the catalog doesn't fail the backup, the catalog is out of date until it is
+/// rebuilt and ignored until then.
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `backup_file` - Container backup file relative to `root`
+///
+pub fn record_backup(root: &Path, backup_file: &Path) {
+    let result = BackupCatalog::open(root, true).and_then(|catalog| match catalog {
+        Some(mut catalog) => catalog.record(&[read_entry(root, backup_file)?]),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        log::warn!(
+            "Unable to record {} in {}, rebuild it with dockyard reindex: {:#}",
+            backup_file.display(),
+            CATALOG_DB,
+            e
+        );
+    }
+}
+
+/// Remove removed container backup files from the catalog of the backup location, if it has one
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `backup_files` - Removed files relative to `root`, those not in the catalog are ignored
+///
+pub fn forget_backups(root: &Path, backup_files: &[PathBuf]) {
+    let result = BackupCatalog::open(root, true).and_then(|catalog| match catalog {
+        Some(mut catalog) => catalog.forget(backup_files),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        log::warn!(
+            "Unable to remove backups from {}, rebuild it with dockyard reindex: {:#}",
+            CATALOG_DB,
+            e
+        );
+    }
+}
+
+/// Return container backups recorded in the catalog of the backup destination, `None` if it has
+/// no catalog or the catalog is out of date
+///
+/// Runs `query_catalog` in a helper container with the backup location mounted.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Only return backups of this container
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn read_catalog_entries(
+    docker: &Docker,
+    container: Option<&str>,
+    backup_mount: Mount,
+) -> Result<Option<Vec<CatalogEntry>>> {
+    let mut args = vec!["catalog", "/backup"];
+    if let Some(container) = container {
+        args.extend(vec!["--container", container]);
+    }
+    let (exit_code, logs) =
+        run_dockyard_command(docker, Some(vec![read_only_mount(backup_mount)]), args).await?;
+    handle_container_output(exit_code, "read catalog", &logs)?;
+    let output = last_stdout_line(&logs).unwrap_or_else(|| "null".to_string());
+    serde_json::from_str(&output).context("Unable to parse catalog")
+}
+
+/// Rebuild the catalog of the backup destination, returning the number of backups recorded
+///
+/// Runs `rebuild_catalog` in a helper container with the backup location mounted.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn reindex(docker: &Docker, backup_mount: Mount) -> Result<usize> {
+    let (exit_code, logs) = run_dockyard_command(
+        docker,
+        Some(vec![backup_mount]),
+        vec!["catalog", "--rebuild", "/backup"],
+    )
+    .await?;
+    handle_container_output(exit_code, "reindex", &logs)?;
+    let output = last_stdout_line(&logs).unwrap_or_default();
+    output
+        .trim()
+        .parse()
+        .with_context(|| format!("Unable to parse number of backups indexed {}", output))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::{
+        encode_container_backup, ContainerBackup, MountBackup, CONTAINER_BACKUP_SCHEMA_VERSION,
+    };
+    use bollard::models::MountPoint;
+    use std::fs::{create_dir_all, write};
+    use tempfile::TempDir;
+
+    fn write_manifest(root: &Path, path: &str, archives: &[&str]) {
+        let backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "web".to_string(),
+            container_config: Default::default(),
+            host_config: Default::default(),
+            mounts: archives
+                .iter()
+                .map(|a| MountBackup {
+                    path: PathBuf::from(a),
+                    mount: MountPoint::default(),
+                    checksum: Some("abc".to_string()),
+                    volume_labels: Default::default(),
+                    resolved_source: None,
+                })
+                .collect(),
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: HashAlgorithm::Blake3,
+            swarm: None,
+            networks: Default::default(),
+        };
+        let path = root.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, encode_container_backup(&backup, false).unwrap()).unwrap();
+    }
+
+    fn backup_files(entries: &[CatalogEntry]) -> Vec<PathBuf> {
+        entries.iter().map(|e| e.backup_file.clone()).collect()
+    }
+
+    #[test]
+    fn rebuild_catalog_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        assert!(query_catalog(root, None).unwrap().is_none());
+        let archive = "dockyard/volumes/data/2020-10-22T01:00:00+00:00.tgz";
+        write(root.join("notes.txt"), "").unwrap();
+        create_dir_all(root.join("dockyard/volumes/data")).unwrap();
+        write(root.join(archive), "archive").unwrap();
+        write_manifest(
+            root,
+            "dockyard/containers/web/2020-10-22T02:00:00+00:00.json",
+            &[archive],
+        );
+        write_manifest(
+            root,
+            "dockyard/containers/web/2020-10-22T01:00:00+00:00.json",
+            &[archive, "dockyard/volumes/data/missing.tgz"],
+        );
+        write_manifest(root, "db/2020-10-22T03:00:00+00:00/container.json", &[]);
+        assert_eq!(rebuild_catalog(root).unwrap(), 3);
+
+        let entries = query_catalog(root, None).unwrap().unwrap();
+        assert_eq!(
+            backup_files(&entries),
+            vec![
+                PathBuf::from("db/2020-10-22T03:00:00+00:00/container.json"),
+                PathBuf::from("dockyard/containers/web/2020-10-22T01:00:00+00:00.json"),
+                PathBuf::from("dockyard/containers/web/2020-10-22T02:00:00+00:00.json"),
+            ]
+        );
+        assert_eq!(entries[0].container, "db");
+        assert_eq!(
+            entries[1],
+            CatalogEntry {
+                container: "web".to_string(),
+                backup_file: PathBuf::from(
+                    "dockyard/containers/web/2020-10-22T01:00:00+00:00.json"
+                ),
+                algorithm: HashAlgorithm::Blake3,
+                archives: vec![
+                    CatalogArchive {
+                        path: PathBuf::from(archive),
+                        checksum: Some("abc".to_string()),
+                        size: Some(7),
+                        image: false,
+                    },
+                    CatalogArchive {
+                        path: PathBuf::from("dockyard/volumes/data/missing.tgz"),
+                        checksum: Some("abc".to_string()),
+                        size: None,
+                        image: false,
+                    },
+                ],
+            }
+        );
+        let web = query_catalog(root, Some("web")).unwrap().unwrap();
+        assert_eq!(backup_files(&web), backup_files(&entries[1..]));
+    }
+
+    #[test]
+    fn record_and_forget_backup_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        let first = "dockyard/containers/web/2020-10-22T01:00:00+00:00.json";
+        let second = "dockyard/containers/web/2020-10-22T02:00:00+00:00.json";
+        write_manifest(root, first, &[]);
+        // Without a catalog there is nothing to record in
+        record_backup(root, Path::new(first));
+        assert!(!root.join(CATALOG_DB).exists());
+
+        rebuild_catalog(root).unwrap();
+        write_manifest(root, second, &[]);
+        assert!(query_catalog(root, Some("web")).unwrap().is_none());
+        record_backup(root, Path::new(second));
+        assert_eq!(
+            backup_files(&query_catalog(root, Some("web")).unwrap().unwrap()),
+            vec![PathBuf::from(first), PathBuf::from(second)]
+        );
+
+        remove_file(root.join(first)).unwrap();
+        assert!(query_catalog(root, None).unwrap().is_none());
+        forget_backups(root, &[PathBuf::from(first)]);
+        assert_eq!(
+            backup_files(&query_catalog(root, None).unwrap().unwrap()),
+            vec![PathBuf::from(second)]
+        );
+    }
+}
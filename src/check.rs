@@ -0,0 +1,451 @@
+use crate::backup::ContainerBackup;
+use crate::container::{
+    handle_container_output, last_stdout_line, read_only_mount, run_dockyard_command,
+};
+use crate::file::available_space;
+use crate::network::BUILTIN_NETWORK_MODES;
+use crate::restore::{
+    check_backup_mount, check_container_name, read_container_backup, restored_container_name,
+    RestoreOptions,
+};
+use crate::swarm::check_swarm_references;
+use anyhow::{Context, Result};
+use bollard::container::InspectContainerOptions;
+use bollard::models::Mount;
+use bollard::network::InspectNetworkOptions;
+use bollard::Docker;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Outcome of one precondition of a container restore
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RestoreCheck {
+    /// What was checked, e.g. image
+    pub name: String,
+    /// False if the restore would fail on this check
+    pub passed: bool,
+    /// What was found
+    pub detail: String,
+}
+
+impl RestoreCheck {
+    fn new(name: &str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => RestoreCheck {
+                name: name.to_string(),
+                passed: true,
+                detail,
+            },
+            Err(e) => RestoreCheck {
+                name: name.to_string(),
+                passed: false,
+                detail: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+/// Sizes of files and the space available, as printed by `dockyard space`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SpaceReport {
+    /// Bytes available on the filesystem holding `/`
+    pub available: u64,
+    /// Size of each file in bytes keyed by path, null for files that don't exist
+    pub sizes: BTreeMap<String, Option<u64>>,
+}
+
+/// Return the sizes of files and the space available on the filesystem holding `/`
+///
+/// Run in a helper container, whose root filesystem is in the Docker data root alongside the
+/// volumes of the local driver.
+///
+/// # Arguments
+///
+/// * `paths` - Paths of files
+///
+pub fn space_report(paths: &[&str]) -> Result<SpaceReport> {
+    let sizes = paths
+        .iter()
+        .map(|path| {
+            let size = Path::new(path).metadata().ok().map(|m| m.len());
+            (path.to_string(), size)
+        })
+        .collect();
+    Ok(SpaceReport {
+        available: available_space(Path::new("/"))?,
+        sizes,
+    })
+}
+
+/// Check whether a container backup can be restored, without changing anything
+///
+/// Checks that the backup file and its archives can be read, the restored name is free, the
+/// image and networks are available, swarm secrets and configs exist and volumes fit on the
+/// Docker host. Failures are returned as failed checks rather than errors.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Container backup file relative to `backup_mount`
+/// * `container` - Name of restored container, generated from `options.name_template` if not set
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn check_restore(
+    docker: &Docker,
+    backup_file: &str,
+    container: Option<&str>,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Vec<RestoreCheck> {
+    let mut checks = vec![RestoreCheck::new(
+        "backup location",
+        check_backup_mount(docker, &backup_mount)
+            .await
+            .map(|_| backup_mount.source.clone().unwrap_or_default()),
+    )];
+    let container_backup = match read_container_backup(docker, backup_file, &backup_mount).await {
+        Ok(container_backup) => container_backup,
+        Err(e) => {
+            checks.push(RestoreCheck::new("backup file", Err(e)));
+            return checks;
+        }
+    };
+    checks.push(RestoreCheck::new(
+        "backup file",
+        Ok(format!("{} of {}", backup_file, container_backup.name)),
+    ));
+    let name = restored_container_name(&container_backup, backup_file, container, options);
+    checks.push(RestoreCheck::new(
+        "container name",
+        check_name_free(docker, name).await,
+    ));
+    checks.push(RestoreCheck::new(
+        "image",
+        check_image_available(docker, &container_backup, options).await,
+    ));
+    checks.push(RestoreCheck::new(
+        "network",
+        check_network(docker, &container_backup).await,
+    ));
+    if let Some(references) = &container_backup.swarm {
+        let result = if options.ignore_swarm_references {
+            Ok("Ignored with --ignore-swarm-references".to_string())
+        } else {
+            check_swarm_references(references)
+                .map(|_| format!("Secrets and configs of {} exist", references.service))
+        };
+        checks.push(RestoreCheck::new("swarm references", result));
+    }
+    let archives = restored_archives(&container_backup, options);
+    match read_space_report(docker, &archives, backup_mount).await {
+        Ok(report) => {
+            checks.push(RestoreCheck::new(
+                "archives",
+                check_archives(&archives, &report),
+            ));
+            checks.push(RestoreCheck::new(
+                "disk space",
+                check_disk_space(&archives, &report),
+            ));
+        }
+        Err(e) => checks.push(RestoreCheck::new("archives", Err(e))),
+    }
+    checks
+}
+
+/// Fail if the restored container's name is invalid or taken
+async fn check_name_free(docker: &Docker, name: Result<String>) -> Result<String> {
+    let name = name?;
+    check_container_name(&name)?;
+    match docker
+        .inspect_container(&name, None::<InspectContainerOptions>)
+        .await
+    {
+        Ok(_) => Err(anyhow!("Container {} already exists", name)),
+        Err(_) => Ok(format!("{} is free", name)),
+    }
+}
+
+/// Fail if the image is missing and can't be pulled or loaded from the backup
+async fn check_image_available(
+    docker: &Docker,
+    container_backup: &ContainerBackup,
+    options: &RestoreOptions,
+) -> Result<String> {
+    let image = container_backup
+        .container_config
+        .image
+        .as_deref()
+        .ok_or_else(|| anyhow!("Backup doesn't name an image"))?;
+    let present = docker.inspect_image(image).await.is_ok();
+    if options.pulls_image(present) {
+        return Ok(format!("{} will be pulled", image));
+    }
+    if present {
+        return Ok(format!("{} is present", image));
+    }
+    match &container_backup.image_archive {
+        Some(archive) => Ok(format!(
+            "{} will be loaded from {}",
+            image,
+            archive.display()
+        )),
+        None => Err(anyhow!(
+            "Image {} not found and not included in backup, run without --no-pull to pull it",
+            image
+        )),
+    }
+}
+
+/// Fail if the container was on a user-defined network that doesn't exist
+async fn check_network(docker: &Docker, container_backup: &ContainerBackup) -> Result<String> {
+    let mode = container_backup
+        .host_config
+        .network_mode
+        .as_deref()
+        .unwrap_or("default");
+    if let Some(other) = mode.strip_prefix("container:") {
+        return docker
+            .inspect_container(other, None::<InspectContainerOptions>)
+            .await
+            .map(|_| format!("Container {} exists", other))
+            .map_err(|_| anyhow!("Container {} whose network is shared doesn't exist", other));
+    }
+    let mut found = if BUILTIN_NETWORK_MODES.contains(&mode) {
+        format!("{} is built in", mode)
+    } else {
+        check_network_exists(docker, mode).await?;
+        format!("{} exists", mode)
+    };
+    for network in container_backup.networks.keys() {
+        if network != mode {
+            check_network_exists(docker, network).await?;
+            found.push_str(&format!(", {} exists", network));
+        }
+    }
+    Ok(found)
+}
+
+/// Fail if a user-defined network doesn't exist
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `network` - Name of network
+///
+async fn check_network_exists(docker: &Docker, network: &str) -> Result<()> {
+    docker
+        .inspect_network(network, None::<InspectNetworkOptions<String>>)
+        .await
+        .map(|_| ())
+        .map_err(|_| {
+            anyhow!(
+                "Network {} doesn't exist, create it with docker network create",
+                network
+            )
+        })
+}
+
+/// Return archives restored into volumes and bind mounts, and whether each is a volume
+fn restored_archives(
+    container_backup: &ContainerBackup,
+    options: &RestoreOptions,
+) -> Vec<(PathBuf, bool)> {
+    container_backup
+        .mounts
+        .iter()
+        .filter(|m| !(options.rehearse && m.mount.typ.as_deref() == Some("bind")))
+        .map(|m| (m.path.clone(), m.mount.typ.as_deref() != Some("bind")))
+        .collect()
+}
+
+/// Return sizes of `archives` and the space available to volumes, from a helper container
+async fn read_space_report(
+    docker: &Docker,
+    archives: &[(PathBuf, bool)],
+    backup_mount: Mount,
+) -> Result<SpaceReport> {
+    let paths = archives
+        .iter()
+        .map(|(path, _)| {
+            Path::new("/backup")
+                .join(path)
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    let mut cmd = vec!["space"];
+    cmd.extend(paths.iter().map(String::as_str));
+    let (exit_code, logs) =
+        run_dockyard_command(docker, Some(vec![read_only_mount(backup_mount)]), cmd).await?;
+    handle_container_output(exit_code, "check archives", &logs)?;
+    let output = last_stdout_line(&logs).unwrap_or_default();
+    serde_json::from_str(&output).context("Unable to parse archive sizes")
+}
+
+/// Return the size recorded for an archive in `report`, if it exists
+fn archive_size(report: &SpaceReport, archive: &Path) -> Option<u64> {
+    let path = Path::new("/backup").join(archive);
+    report
+        .sizes
+        .get(path.to_string_lossy().as_ref())
+        .copied()
+        .flatten()
+}
+
+/// Fail if any archive is missing from the backup location
+fn check_archives(archives: &[(PathBuf, bool)], report: &SpaceReport) -> Result<String> {
+    let missing = archives
+        .iter()
+        .filter(|(path, _)| archive_size(report, path).is_none())
+        .map(|(path, _)| path.display().to_string())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Ok(format!("{} archives readable", archives.len()))
+    } else {
+        Err(anyhow!("Missing {}", missing.join(", ")))
+    }
+}
+
+/// Fail if the volume archives are larger than the space available to volumes
+///
+/// Archives are compressed, so restored volumes take more space than this estimates.
+fn check_disk_space(archives: &[(PathBuf, bool)], report: &SpaceReport) -> Result<String> {
+    let needed = archives
+        .iter()
+        .filter(|(_, is_volume)| *is_volume)
+        .filter_map(|(path, _)| archive_size(report, path))
+        .sum::<u64>();
+    let detail = format!(
+        "{} of volume archives, {} available",
+        ByteSize(needed),
+        ByteSize(report.available)
+    );
+    if needed > report.available {
+        Err(anyhow!("Not enough space: {}", detail))
+    } else {
+        Ok(detail)
+    }
+}
+
+/// Byte count displayed with a binary unit, e.g. 1.5 GiB
+pub(crate) struct ByteSize(pub(crate) u64);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{:.1} {}", size, units[unit])
+        }
+    }
+}
+
+/// Format checks as one `PASS` or `FAIL` line each
+pub fn format_checks(checks: &[RestoreCheck]) -> String {
+    checks
+        .iter()
+        .map(|check| {
+            format!(
+                "{} {:<16} {}",
+                if check.passed { "PASS" } else { "FAIL" },
+                check.name,
+                check.detail
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    fn report(available: u64, sizes: &[(&str, Option<u64>)]) -> SpaceReport {
+        SpaceReport {
+            available,
+            sizes: sizes
+                .iter()
+                .map(|(path, size)| (path.to_string(), *size))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn space_report_test() {
+        let working_dir = TempDir::new().unwrap();
+        let archive = working_dir.path().join("archive.tgz");
+        write(&archive, "12345").unwrap();
+        let missing = working_dir.path().join("missing.tgz");
+        let report = space_report(&[archive.to_str().unwrap(), missing.to_str().unwrap()]).unwrap();
+        assert_eq!(report.sizes[archive.to_str().unwrap()], Some(5));
+        assert_eq!(report.sizes[missing.to_str().unwrap()], None);
+        assert!(report.available > 0);
+    }
+
+    #[test]
+    fn check_archives_test() {
+        let archives = vec![
+            (PathBuf::from("dockyard/volumes/data/a.tgz"), true),
+            (PathBuf::from("dockyard/binds/srv/b.tgz"), false),
+        ];
+        let found = report(
+            100,
+            &[
+                ("/backup/dockyard/volumes/data/a.tgz", Some(60)),
+                ("/backup/dockyard/binds/srv/b.tgz", Some(80)),
+            ],
+        );
+        assert_eq!(
+            check_archives(&archives, &found).unwrap(),
+            "2 archives readable"
+        );
+        // Bind mounts aren't restored into the Docker data root
+        assert_eq!(
+            check_disk_space(&archives, &found).unwrap(),
+            "60 B of volume archives, 100 B available"
+        );
+
+        let missing = report(
+            10,
+            &[
+                ("/backup/dockyard/volumes/data/a.tgz", Some(60)),
+                ("/backup/dockyard/binds/srv/b.tgz", None),
+            ],
+        );
+        assert_eq!(
+            check_archives(&archives, &missing).unwrap_err().to_string(),
+            "Missing dockyard/binds/srv/b.tgz"
+        );
+        assert!(check_disk_space(&archives, &missing)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Not enough space"));
+    }
+
+    #[test]
+    fn format_checks_test() {
+        let checks = vec![
+            RestoreCheck::new("image", Ok("nginx:latest is present".to_string())),
+            RestoreCheck::new("network", Err(anyhow!("Network web doesn't exist"))),
+        ];
+        assert_eq!(
+            format_checks(&checks),
+            "PASS image            nginx:latest is present\n\
+             FAIL network          Network web doesn't exist"
+        );
+        assert_eq!(ByteSize(1536 * 1024).to_string(), "1.5 MiB");
+        assert_eq!(ByteSize(1023).to_string(), "1023 B");
+    }
+}
@@ -1,18 +1,44 @@
 use crate::container::{DOCKYARD_COMMAND_LABEL, PID_LABEL};
-use anyhow::Result;
+use crate::restore::REHEARSAL_LABEL;
+use anyhow::{Context, Result};
 use bollard::container::{KillContainerOptions, ListContainersOptions, RemoveContainerOptions};
 use bollard::models::{ContainerStateStatusEnum, ContainerSummaryInner};
+use bollard::volume::{ListVolumesOptions, RemoveVolumeOptions};
 use bollard::Docker;
 use std::collections::HashMap;
+use std::path::Path;
 use std::process;
 
+/// Containers removed by a cleanup and any that could not be removed
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CleanupReport {
+    pub removed: Vec<CleanedContainer>,
+    pub failed: Vec<CleanedContainer>,
+    /// Volumes removed along with the containers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_volumes: Vec<String>,
+    /// Volumes that could not be removed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_volumes: Vec<String>,
+}
+
+/// Container stopped and removed by a cleanup
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CleanedContainer {
+    pub id: String,
+    pub name: String,
+    /// Why the container could not be removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 /// Stop and remove all dockyard containers
 ///
 /// # Arguments
 ///
 /// * `docker` - Docker client
 ///
-pub async fn cleanup_dockyard_containers(docker: &Docker) -> Result<()> {
+pub async fn cleanup_dockyard_containers(docker: &Docker) -> Result<CleanupReport> {
     let containers = get_dockyard_containers(docker).await?;
     log::info!("Removing {} dockyard containers", containers.len());
     stop_and_remove_containers(docker, containers).await
@@ -24,7 +50,7 @@ pub async fn cleanup_dockyard_containers(docker: &Docker) -> Result<()> {
 ///
 /// * `docker` - Docker client
 ///
-pub async fn cleanup_child_containers(docker: &Docker) -> Result<()> {
+pub async fn cleanup_child_containers(docker: &Docker) -> Result<CleanupReport> {
     let pid = process::id();
     let containers = get_containers_by_pid(docker, pid).await?;
     log::info!(
@@ -35,7 +61,105 @@ pub async fn cleanup_child_containers(docker: &Docker) -> Result<()> {
     stop_and_remove_containers(docker, containers).await
 }
 
-/// Stop and remove specified containers
+/// Stop and remove helper containers whose dockyard process is no longer running
+///
+/// Processes are looked up in `/proc`, so only helpers started by dockyard processes in the
+/// same PID namespace are recognized as live.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+///
+pub async fn reap_orphaned_containers(docker: &Docker) -> Result<CleanupReport> {
+    let containers = get_containers_by_label(docker, vec![PID_LABEL.to_string()]).await?;
+    let orphans = containers
+        .into_iter()
+        .filter(|c| is_orphaned(c, process_running))
+        .collect::<Vec<_>>();
+    log::info!("Removing {} orphaned helper containers", orphans.len());
+    stop_and_remove_containers(docker, orphans).await
+}
+
+/// Stop and remove rehearsal containers created by `restore container --rehearse`, and the
+/// volumes restored for them
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+///
+pub async fn cleanup_rehearsals(docker: &Docker) -> Result<CleanupReport> {
+    let containers = get_containers_by_label(docker, vec![REHEARSAL_LABEL.to_string()]).await?;
+    let prefixes = containers
+        .iter()
+        .filter_map(rehearsal_volume_prefix)
+        .collect::<Vec<_>>();
+    log::info!("Removing {} rehearsal containers", containers.len());
+    let mut report = stop_and_remove_containers(docker, containers).await?;
+
+    let volumes = docker
+        .list_volumes(None::<ListVolumesOptions<String>>)
+        .await
+        .context("Unable to list volumes")?
+        .volumes
+        .into_iter()
+        .map(|volume| volume.name)
+        .filter(|name| prefixes.iter().any(|prefix| name.starts_with(prefix)))
+        .collect::<Vec<_>>();
+    for volume in volumes {
+        log::info!("Removing volume {}", volume);
+        match docker
+            .remove_volume(&volume, None::<RemoveVolumeOptions>)
+            .await
+        {
+            Ok(_) => report.removed_volumes.push(volume),
+            Err(e) => {
+                log::error!("Failed to remove volume {}: {}", volume, e);
+                report.failed_volumes.push(volume);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Return the prefix of a rehearsal container's volumes
+///
+/// Only prefixes of the form `restore container --rehearse` generates are returned, so a
+/// hand-set label can't select unrelated volumes.
+///
+/// # Arguments
+///
+/// * `container` - Rehearsal container
+///
+fn rehearsal_volume_prefix(container: &ContainerSummaryInner) -> Option<String> {
+    container
+        .labels
+        .as_ref()?
+        .get(REHEARSAL_LABEL)
+        .filter(|prefix| prefix.contains("-rehearsal-") && prefix.ends_with('_'))
+        .cloned()
+}
+
+/// Return true if the process that started a helper container is no longer running
+///
+/// # Arguments
+///
+/// * `container` - Helper container
+/// * `running` - Returns whether a PID is running
+///
+fn is_orphaned(container: &ContainerSummaryInner, running: impl Fn(u32) -> bool) -> bool {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(PID_LABEL))
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map_or(false, |pid| !running(pid))
+}
+
+fn process_running(pid: u32) -> bool {
+    pid == process::id() || Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Stop and remove specified containers, carrying on past containers that fail to be removed
 ///
 /// # Arguments
 ///
@@ -45,7 +169,8 @@ pub async fn cleanup_child_containers(docker: &Docker) -> Result<()> {
 async fn stop_and_remove_containers(
     docker: &Docker,
     containers: Vec<ContainerSummaryInner>,
-) -> Result<()> {
+) -> Result<CleanupReport> {
+    let mut report = CleanupReport::default();
     for container in containers {
         let id = container.id.unwrap();
         let names = container.names.unwrap();
@@ -64,11 +189,24 @@ async fn stop_and_remove_containers(
             }
         }
         log::info!("Removing container {}", &name);
-        docker
+        let removed = docker
             .remove_container(&id, None::<RemoveContainerOptions>)
-            .await?;
+            .await;
+        let mut cleaned = CleanedContainer {
+            id,
+            name: name.trim_start_matches('/').to_string(),
+            error: None,
+        };
+        match removed {
+            Ok(_) => report.removed.push(cleaned),
+            Err(e) => {
+                log::error!("Failed to remove container {}: {}", &name, e);
+                cleaned.error = Some(e.to_string());
+                report.failed.push(cleaned);
+            }
+        }
     }
-    Ok(())
+    Ok(report)
 }
 
 /// Return all containers started by dockyard process with pid
@@ -96,9 +234,22 @@ async fn get_dockyard_containers(docker: &Docker) -> Result<Vec<ContainerSummary
     .await
 }
 
-pub(crate) async fn get_all_containers(docker: &Docker) -> Result<Vec<ContainerSummaryInner>> {
+/// Return all containers, optionally including stopped containers
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `include_stopped` - Whether to include containers that are not running
+///
+pub(crate) async fn get_all_containers(
+    docker: &Docker,
+    include_stopped: bool,
+) -> Result<Vec<ContainerSummaryInner>> {
     match docker
-        .list_containers(None::<ListContainersOptions<String>>)
+        .list_containers(Some(ListContainersOptions::<String> {
+            all: include_stopped,
+            ..Default::default()
+        }))
         .await
     {
         Ok(r) => Ok(r),
@@ -144,6 +295,45 @@ mod test {
     use tokio::runtime::Runtime;
     use uuid::Uuid;
 
+    #[test]
+    fn is_orphaned_test() {
+        let container = |pid: &str| ContainerSummaryInner {
+            labels: Some(
+                vec![(PID_LABEL.to_string(), pid.to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let running = |pid: u32| pid == 100;
+        assert!(!is_orphaned(&container("100"), running));
+        assert!(is_orphaned(&container("200"), running));
+        assert!(!is_orphaned(&container("not-a-pid"), running));
+        assert!(!is_orphaned(&ContainerSummaryInner::default(), running));
+    }
+
+    #[test]
+    fn rehearsal_volume_prefix_test() {
+        let container = |prefix: &str| ContainerSummaryInner {
+            labels: Some(
+                vec![(REHEARSAL_LABEL.to_string(), prefix.to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            rehearsal_volume_prefix(&container("web-rehearsal-20201022T230902Z_")),
+            Some("web-rehearsal-20201022T230902Z_".to_string())
+        );
+        assert_eq!(rehearsal_volume_prefix(&container("")), None);
+        assert_eq!(rehearsal_volume_prefix(&container("web")), None);
+        assert_eq!(
+            rehearsal_volume_prefix(&ContainerSummaryInner::default()),
+            None
+        );
+    }
+
     #[test]
     fn get_containers_by_pid_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
@@ -197,8 +387,58 @@ mod test {
                 .collect::<Vec<_>>()
         });
         assert_eq!(dockyard_containers.len(), 2);
-        rt.block_on(stop_and_remove_containers(&docker, dockyard_containers))
+        let missing = ContainerSummaryInner {
+            id: Some("dockyard-cleanup-missing".to_string()),
+            names: Some(vec!["/dockyard-cleanup-missing".to_string()]),
+            state: Some("exited".to_string()),
+            ..Default::default()
+        };
+        let containers = dockyard_containers
+            .into_iter()
+            .chain(vec![missing])
+            .collect();
+        let report = rt
+            .block_on(stop_and_remove_containers(&docker, containers))
             .unwrap();
+        assert_eq!(
+            report
+                .removed
+                .iter()
+                .map(|c| c.id.clone())
+                .collect::<HashSet<_>>(),
+            ids
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].name, "dockyard-cleanup-missing");
+        assert!(report.failed[0].error.is_some());
+    }
+
+    #[test]
+    fn get_all_containers_include_stopped_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let id = rt.block_on(async {
+            check_image(&docker, "hello-world:linux").await.unwrap();
+            create_hello_container(&docker, rand::random())
+                .await
+                .unwrap()
+        });
+
+        let contains_id = |containers: Vec<ContainerSummaryInner>| {
+            containers.iter().any(|c| c.id.as_ref().unwrap() == &id)
+        };
+        let running = rt.block_on(get_all_containers(&docker, false)).unwrap();
+        assert!(!contains_id(running));
+        let all = rt.block_on(get_all_containers(&docker, true)).unwrap();
+        assert!(contains_id(all));
+        rt.block_on(async {
+            docker
+                .remove_container(id.as_str(), None::<RemoveContainerOptions>)
+                .await
+                .unwrap();
+        });
     }
 
     async fn create_hello_container(
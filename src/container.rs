@@ -1,37 +1,347 @@
+use crate::file::{path_str, FileModes};
 use crate::watch::DISABLED_LABEL;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bollard::container::{
-    Config, CreateContainerOptions, InspectContainerOptions, LogOutput, LogsOptions,
-    RemoveContainerOptions, StartContainerOptions, WaitContainerOptions,
+    Config, CreateContainerOptions, DownloadFromContainerOptions, InspectContainerOptions,
+    LogOutput, LogsOptions, RemoveContainerOptions, StartContainerOptions,
+    UploadToContainerOptions, WaitContainerOptions,
 };
-use bollard::image::{BuildImageOptions, CreateImageOptions};
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::image::{BuildImageOptions, CreateImageOptions, ImportImageOptions};
 use bollard::models::{
     BuildInfo, ContainerStateStatusEnum, CreateImageInfo, HostConfig, Mount, MountTypeEnum,
 };
-use bollard::Docker;
+use bollard::{Docker, API_DEFAULT_VERSION};
 use flate2::read::GzEncoder;
 use flate2::Compression;
 use futures::TryStreamExt;
 use futures_core::Stream;
+use hyper::Body;
 use log::LevelFilter;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::future::Future;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::Command;
-use std::sync::atomic::AtomicU8;
+use std::str::FromStr;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU16, AtomicU8};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
+use tokio::sync::Semaphore;
 use uuid::Uuid;
 
 pub static PID_LABEL: &str = "com.github.aig787.dockyard.pid";
 pub static DOCKYARD_COMMAND_LABEL: &str = "com.github.aig787.dockyard.command";
+/// Label of helper containers with the trace ID of the dockyard run that started them
+pub static TRACE_ID_LABEL: &str = "com.github.aig787.dockyard.trace-id";
+
+/// Error reported by dockyard helpers when the backup destination fills up
+pub(crate) static OUT_OF_SPACE_MESSAGE: &str = "Backup destination out of space";
 
 static COMMAND_VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static CONTAINER_RUNTIME: AtomicU8 = AtomicU8::new(ContainerRuntime::Docker as u8);
+/// Block IO weight of helper containers, 0 leaves it at the daemon default
+static HELPER_BLKIO_WEIGHT: AtomicU16 = AtomicU16::new(0);
+/// Number of times a failed image pull is retried
+static PULL_RETRIES: AtomicU8 = AtomicU8::new(DEFAULT_PULL_RETRIES);
+
+/// Default number of times a failed image pull is retried
+const DEFAULT_PULL_RETRIES: u8 = 3;
+
+/// Delay before the first retry of an image pull, doubled for each later retry
+const PULL_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Number of times the pull retry delay is doubled at most
+const MAX_PULL_BACKOFF_DOUBLINGS: u8 = 5;
+
+const DOCKER_TIMEOUT_SECS: u64 = 120;
+
+/// Oldest Docker API version dockyard supports, as (major, minor)
+///
+/// Helpers and restored containers are created with `HostConfig.Mounts`, and backups find volumes
+/// in the `Mounts` of container inspect responses. Daemons older than API 1.25 (Docker 1.13)
+/// ignore the first and leave out the second, so mounts would silently not be backed up or
+/// restored.
+pub const MIN_DOCKER_API_VERSION: (u32, u32) = (1, 25);
+
+/// Exit code used when the Docker daemon can not be reached
+pub const DOCKER_CONNECTION_EXIT_CODE: i32 = 3;
+
+/// Socket used by `Docker::connect_with_unix_defaults`
+const DEFAULT_DOCKER_SOCKET: &str = "unix:///var/run/docker.sock";
+
+/// Sockets Docker Desktop creates relative to the user's home directory, newest versions first
+const DESKTOP_DOCKER_SOCKETS: [&str; 2] =
+    [".docker/run/docker.sock", ".docker/desktop/docker.sock"];
+
+/// Default maximum number of helper containers running at once
+const DEFAULT_JOBS: usize = 4;
+/// Length of generated trace IDs, short enough to prefix every log line with
+const TRACE_ID_LENGTH: usize = 12;
+
+lazy_static! {
+    static ref HELPER_LABELS: RwLock<Vec<(String, String)>> = RwLock::new(vec![]);
+    /// Network mode of helper containers, `None` leaves them on the default bridge
+    static ref HELPER_NETWORK: RwLock<Option<String>> = RwLock::new(None);
+    /// ID correlating the log lines and helper containers of this dockyard run
+    static ref TRACE_ID: RwLock<Option<String>> = RwLock::new(None);
+    static ref HELPER_SLOTS: RwLock<Arc<Semaphore>> =
+        RwLock::new(Arc::new(Semaphore::new(DEFAULT_JOBS)));
+    /// Helper containers whose commands were dropped before they finished
+    static ref ABANDONED_HELPERS: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+/// Records a helper container as abandoned if dropped before its command finished
+struct HelperGuard {
+    container_name: String,
+    finished: bool,
+}
+
+impl Drop for HelperGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            log::debug!("Abandoning helper container {}", self.container_name);
+            ABANDONED_HELPERS
+                .lock()
+                .unwrap()
+                .push(self.container_name.clone());
+        }
+    }
+}
+
+/// Remove helper containers left behind by commands that were cancelled, e.g. by a timeout
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+///
+pub async fn remove_abandoned_helpers(docker: &Docker) -> Result<()> {
+    let abandoned = ABANDONED_HELPERS
+        .lock()
+        .unwrap()
+        .drain(..)
+        .collect::<Vec<_>>();
+    for container_name in abandoned {
+        log::info!("Removing abandoned helper container {}", container_name);
+        remove_helper_container(docker, &container_name).await?;
+    }
+    Ok(())
+}
 
 pub fn set_command_verbosity(verbosity: u8) {
     COMMAND_VERBOSITY.store(verbosity, Relaxed);
 }
 
+/// Set additional labels applied to every helper container
+pub fn set_helper_labels(labels: Vec<(String, String)>) {
+    *HELPER_LABELS.write().unwrap() = labels;
+}
+
+fn get_helper_labels() -> Vec<(String, String)> {
+    HELPER_LABELS.read().unwrap().clone()
+}
+
+/// Set the network helper containers join, e.g. `none` to isolate them, or leave them on the
+/// default bridge with `None`
+///
+/// Helpers only read and write their mounts, so they don't need a network.
+pub fn set_helper_network(network: Option<String>) {
+    *HELPER_NETWORK.write().unwrap() = network;
+}
+
+fn get_helper_network() -> Option<String> {
+    HELPER_NETWORK.read().unwrap().clone()
+}
+
+/// Set the trace ID helper containers are labelled with and helper dockyard commands log with
+pub fn set_trace_id(trace_id: Option<String>) {
+    *TRACE_ID.write().unwrap() = trace_id;
+}
+
+/// Return the trace ID of this dockyard run, if one was set
+pub fn get_trace_id() -> Option<String> {
+    TRACE_ID.read().unwrap().clone()
+}
+
+/// Return a new random trace ID
+pub fn new_trace_id() -> String {
+    Uuid::new_v4().to_simple().to_string()[..TRACE_ID_LENGTH].to_string()
+}
+
+/// Set the maximum number of helper containers running at once
+pub fn set_jobs(jobs: usize) {
+    *HELPER_SLOTS.write().unwrap() = Arc::new(Semaphore::new(jobs));
+}
+
+/// Set the block IO weight of helper containers, or leave it unset with `None`
+///
+/// Requires a host with the cgroup blkio (v1) or io (v2) controller and a weight-aware IO
+/// scheduler such as BFQ, otherwise the daemon ignores it or refuses to create helpers.
+pub fn set_helper_blkio_weight(weight: Option<u16>) {
+    HELPER_BLKIO_WEIGHT.store(weight.unwrap_or(0), Relaxed);
+}
+
+/// Set the number of times a failed image pull is retried
+pub fn set_pull_retries(retries: u8) {
+    PULL_RETRIES.store(retries, Relaxed);
+}
+
+fn get_helper_blkio_weight() -> Option<u16> {
+    match HELPER_BLKIO_WEIGHT.load(Relaxed) {
+        0 => None,
+        weight => Some(weight),
+    }
+}
+
+/// Parse block IO weight, which Docker accepts between 10 and 1000
+///
+/// # Arguments
+///
+/// * `weight` - Weight to parse
+///
+pub fn parse_blkio_weight(weight: &str) -> Result<u16> {
+    match weight.parse::<u16>() {
+        Ok(w) if (10..=1000).contains(&w) => Ok(w),
+        _ => Err(anyhow!(
+            "Invalid block IO weight {}, expected a number from 10 to 1000",
+            weight
+        )),
+    }
+}
+
+/// Container engine serving the Docker API
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+}
+
+impl FromStr for ContainerRuntime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "docker" => Ok(ContainerRuntime::Docker),
+            "podman" => Ok(ContainerRuntime::Podman),
+            _ => Err(anyhow!(
+                "Unsupported runtime {}, expected docker or podman",
+                s
+            )),
+        }
+    }
+}
+
+/// Set the container engine dockyard is talking to
+pub fn set_container_runtime(runtime: ContainerRuntime) {
+    CONTAINER_RUNTIME.store(runtime as u8, Relaxed);
+}
+
+pub fn get_container_runtime() -> ContainerRuntime {
+    if CONTAINER_RUNTIME.load(Relaxed) == ContainerRuntime::Podman as u8 {
+        ContainerRuntime::Podman
+    } else {
+        ContainerRuntime::Docker
+    }
+}
+
+/// Docker socket connected to when no host is given
+#[derive(Debug, PartialEq)]
+enum DockerSocket {
+    Native,
+    /// Socket of Docker Desktop in the user's home directory
+    Desktop(PathBuf),
+}
+
+impl DockerSocket {
+    fn uri(&self) -> String {
+        match self {
+            DockerSocket::Native => DEFAULT_DOCKER_SOCKET.to_string(),
+            DockerSocket::Desktop(path) => format!("unix://{}", path.display()),
+        }
+    }
+}
+
+/// Return the Docker socket to connect to when no host is given
+///
+/// The native socket is used if it exists. Docker Desktop on macOS only creates it when allowed
+/// to in its settings and Docker Desktop on Linux never does, so otherwise the sockets Desktop
+/// creates in the home directory are used if they exist.
+///
+/// # Arguments
+///
+/// * `home` - Home directory of the user
+/// * `exists` - Whether a socket exists
+///
+fn find_docker_socket<F: Fn(&Path) -> bool>(home: Option<&Path>, exists: F) -> DockerSocket {
+    if exists(Path::new(
+        DEFAULT_DOCKER_SOCKET.trim_start_matches("unix://"),
+    )) {
+        return DockerSocket::Native;
+    }
+    home.into_iter()
+        .flat_map(|home| DESKTOP_DOCKER_SOCKETS.iter().map(move |s| home.join(s)))
+        .find(|path| exists(path))
+        .map(DockerSocket::Desktop)
+        .unwrap_or(DockerSocket::Native)
+}
+
+fn default_docker_socket() -> DockerSocket {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    find_docker_socket(home.as_deref(), Path::exists)
+}
+
+/// Default Podman API socket, rootless if a user runtime directory is available
+fn podman_socket_path() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => format!("unix://{}/podman/podman.sock", dir),
+        _ => "unix:///run/podman/podman.sock".to_string(),
+    }
+}
+
+/// Fully qualify an image name before pulling
+///
+/// Podman does not assume Docker Hub for short names unless configured to, so `alpine:latest`
+/// becomes `docker.io/library/alpine:latest`. Names are left unchanged for Docker.
+///
+/// # Arguments
+///
+/// * `image` - Image to pull
+/// * `runtime` - Container engine that will pull the image
+///
+fn qualify_image(image: &str, runtime: ContainerRuntime) -> String {
+    if runtime == ContainerRuntime::Docker {
+        return image.to_string();
+    }
+    match image.find('/') {
+        Some(i) => {
+            let registry = &image[..i];
+            if registry.contains('.') || registry.contains(':') || registry == "localhost" {
+                image.to_string()
+            } else {
+                format!("docker.io/{}", image)
+            }
+        }
+        None => format!("docker.io/library/{}", image),
+    }
+}
+
+/// Parse a `key=value` label
+///
+/// # Arguments
+///
+/// * `label` - Label to parse
+///
+pub fn parse_label(label: &str) -> Result<(String, String)> {
+    match label.find('=') {
+        Some(i) if i > 0 => Ok((label[..i].to_string(), label[i + 1..].to_string())),
+        _ => Err(anyhow!("Invalid label {}, expected key=value", label)),
+    }
+}
+
 fn get_verbosity_arg() -> String {
     let level = COMMAND_VERBOSITY.load(Relaxed);
     if level > 0 {
@@ -41,53 +351,278 @@ fn get_verbosity_arg() -> String {
     }
 }
 
+/// Connect to Docker daemon, using the runtime's local unix socket if no host is given
+///
+/// `host` bypasses detecting the connection.
+///
+/// Without a host, Docker is reached through `DOCKER_HOST` if it's set, otherwise through the
+/// native socket or, if that doesn't exist, the socket of Docker Desktop. The connection chosen
+/// is logged. Windows named pipes aren't supported, as dockyard only builds on Unix.
+///
+/// # Arguments
+///
+/// * `host` - Optional Docker host, e.g. `tcp://host:2375` or `unix:///var/run/docker.sock`
+///
+pub fn connect_docker(host: Option<&str>) -> Result<Docker> {
+    match host {
+        Some(host) => {
+            log::info!("Connecting to Docker host {}", host);
+            connect_docker_host(host)
+        }
+        None => match get_container_runtime() {
+            ContainerRuntime::Docker => {
+                connect_default_docker(std::env::var("DOCKER_HOST").ok().as_deref())
+            }
+            ContainerRuntime::Podman => {
+                let socket = podman_socket_path();
+                log::info!("Connecting to Podman socket {}", socket);
+                connect_docker_host(&socket)
+            }
+        },
+    }
+}
+
+/// Connect to Docker through `docker_host`, the value of `DOCKER_HOST`, if it's set, otherwise
+/// through the native socket or the socket of Docker Desktop
+fn connect_default_docker(docker_host: Option<&str>) -> Result<Docker> {
+    if let Some(docker_host) = docker_host.filter(|h| !h.is_empty()) {
+        log::info!("Connecting to Docker host {} from DOCKER_HOST", docker_host);
+        return connect_docker_host(docker_host);
+    }
+    let socket = default_docker_socket();
+    match socket {
+        DockerSocket::Native => log::info!("Connecting to Docker socket {}", socket.uri()),
+        DockerSocket::Desktop(_) => {
+            log::info!("Connecting to Docker Desktop socket {}", socket.uri())
+        }
+    }
+    connect_docker_host(&socket.uri())
+}
+
+/// Connect to Docker at `host`, e.g. `tcp://host:2375` or `unix:///var/run/docker.sock`
+fn connect_docker_host(host: &str) -> Result<Docker> {
+    let docker = if host.starts_with("unix://") {
+        Docker::connect_with_unix(host, DOCKER_TIMEOUT_SECS, API_DEFAULT_VERSION)
+    } else if host.starts_with("tcp://") || host.starts_with("http://") {
+        Docker::connect_with_http(host, DOCKER_TIMEOUT_SECS, API_DEFAULT_VERSION)
+    } else {
+        return Err(anyhow!("Unsupported Docker host {}", host));
+    };
+    docker.map_err(|e| anyhow!("Failed to connect to Docker host {}: {}", host, e))
+}
+
+/// Fail if the Docker daemon's API is older than `MIN_DOCKER_API_VERSION`
+///
+/// Versions that can't be parsed are warned about rather than refused.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+///
+pub async fn check_api_version(docker: &Docker) -> Result<()> {
+    let version = docker.version().await?;
+    let api_version = version.api_version.unwrap_or_default();
+    let (major, minor) = MIN_DOCKER_API_VERSION;
+    match parse_api_version(&api_version) {
+        Some(parsed) if parsed >= MIN_DOCKER_API_VERSION => {
+            log::debug!("Docker API version {}", api_version);
+            Ok(())
+        }
+        Some(_) => Err(anyhow!(
+            "Docker API version {} is older than {}.{}, the oldest dockyard supports. Upgrade to Docker 1.13 or later",
+            api_version,
+            major,
+            minor
+        )),
+        None => {
+            log::warn!(
+                "Unable to parse Docker API version {}, dockyard needs {}.{} or later",
+                api_version,
+                major,
+                minor
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Parse a Docker API version, e.g. `1.41`, into (major, minor)
+fn parse_api_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().splitn(2, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Return a friendly explanation if `error` was caused by being unable to reach the daemon socket
+///
+/// Only I/O errors underneath a Docker API error are considered, so failures reading local
+/// files are not mistaken for connection problems.
+///
+/// # Arguments
+///
+/// * `error` - Error returned by a command
+///
+pub fn explain_connection_error(error: &anyhow::Error) -> Option<String> {
+    let io_error = error
+        .chain()
+        .skip_while(|e| !e.is::<bollard::errors::Error>())
+        .find_map(|e| e.downcast_ref::<std::io::Error>())?;
+    connection_error_message(io_error.kind(), get_container_runtime())
+}
+
+fn connection_error_message(kind: ErrorKind, runtime: ContainerRuntime) -> Option<String> {
+    let (socket, name) = match runtime {
+        ContainerRuntime::Docker => (default_docker_socket().uri(), "Docker"),
+        ContainerRuntime::Podman => (podman_socket_path(), "Podman"),
+    };
+    match (kind, runtime) {
+        (ErrorKind::PermissionDenied, ContainerRuntime::Docker) => Some(format!(
+            "Permission denied connecting to {}. Add your user to the docker group or run dockyard with sufficient privileges",
+            socket
+        )),
+        (ErrorKind::PermissionDenied, ContainerRuntime::Podman) => Some(format!(
+            "Permission denied connecting to {}. Run dockyard as the user that owns the socket or with sufficient privileges",
+            socket
+        )),
+        (ErrorKind::NotFound, _) | (ErrorKind::ConnectionRefused, _) => Some(format!(
+            "Unable to connect to {}, is {} running?",
+            socket, name
+        )),
+        _ => None,
+    }
+}
+
 pub async fn check_image(
     docker: &Docker,
     image: &str,
 ) -> Result<Option<Vec<CreateImageInfo>>, bollard::errors::Error> {
     match docker.inspect_image(image).await {
         Ok(_) => Ok(None),
-        Err(_) => download_image(docker, image).await.map(Some),
+        Err(_) => download_image(docker, &qualify_image(image, get_container_runtime()))
+            .await
+            .map(Some),
     }
 }
 
+/// Pull the latest image for `image`'s tag, even if it is present locally
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `image` - Image to pull
+///
+pub async fn pull_image(
+    docker: &Docker,
+    image: &str,
+) -> Result<Vec<CreateImageInfo>, bollard::errors::Error> {
+    download_image(docker, &qualify_image(image, get_container_runtime())).await
+}
+
 async fn download_image(
     docker: &Docker,
     image: &str,
 ) -> Result<Vec<CreateImageInfo>, bollard::errors::Error> {
-    log::info!("Pulling {}", image);
-    docker
-        .create_image(
-            Some(CreateImageOptions {
-                from_image: image,
-                ..Default::default()
-            }),
-            None,
-            None,
-        )
-        .try_collect::<Vec<_>>()
-        .await
+    let retries = PULL_RETRIES.load(Relaxed);
+    retry_pull(image, retries, PULL_RETRY_DELAY, || async move {
+        log::info!("Pulling {}", image);
+        docker
+            .create_image(
+                Some(CreateImageOptions {
+                    from_image: image,
+                    ..Default::default()
+                }),
+                None,
+                None,
+            )
+            .try_collect::<Vec<_>>()
+            .await
+    })
+    .await
 }
 
-pub(crate) async fn run_docker_command(
+/// Run `pull` until it succeeds, fails permanently or has been retried `retries` times
+///
+/// # Arguments
+///
+/// * `image` - Image being pulled
+/// * `retries` - Maximum number of retries
+/// * `delay` - Delay before the first retry, doubled for each later retry
+/// * `pull` - Function starting a pull
+///
+async fn retry_pull<T, E, F, Fut>(
+    image: &str,
+    retries: u8,
+    delay: Duration,
+    mut pull: F,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match pull().await {
+            Err(e) if attempt < retries && is_retryable_pull_error(&e.to_string()) => {
+                let wait = delay * 2u32.pow(attempt.min(MAX_PULL_BACKOFF_DOUBLINGS) as u32);
+                log::warn!("Failed to pull {}, retrying in {:?}: {}", image, wait, e);
+                tokio::time::delay_for(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a pull failed for a reason that might go away, rather than e.g. a missing image
+/// or denied access
+///
+/// # Arguments
+///
+/// * `message` - Error message of the failed pull
+///
+fn is_retryable_pull_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ![
+        "status code 401",
+        "status code 403",
+        "status code 404",
+        "not found",
+        "manifest unknown",
+        "does not exist",
+        "denied",
+        "unauthorized",
+        "invalid reference format",
+    ]
+    .iter()
+    .any(|permanent| message.contains(permanent))
+}
+
+async fn create_helper_container(
     docker: &Docker,
     container_name: &str,
     image: &str,
     mounts: Option<Vec<Mount>>,
     cmd: Vec<&str>,
     labels: Option<Vec<(&str, &str)>>,
-) -> Result<(i64, Vec<LogOutput>)> {
+) -> Result<()> {
     check_image(docker, image).await?;
-    log::debug!(
-        "Running '{}' in container {}",
-        cmd.join(" "),
-        container_name
-    );
     log::trace!(
         "Creating container {} with mounts: {:?}",
         container_name,
         mounts
     );
+    let helper_labels = get_helper_labels();
+    let mut all_labels = helper_labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<HashMap<_, _>>();
+    all_labels.extend(labels.unwrap_or_default());
+    let trace_id = get_trace_id();
+    if let Some(trace_id) = &trace_id {
+        all_labels.insert(TRACE_ID_LABEL, trace_id);
+    }
     docker
         .create_container(
             Some(CreateContainerOptions {
@@ -96,15 +631,58 @@ pub(crate) async fn run_docker_command(
             Config {
                 cmd: Some(cmd),
                 image: Some(&image),
-                labels: labels.map(|l| l.into_iter().collect()),
+                labels: if all_labels.is_empty() {
+                    None
+                } else {
+                    Some(all_labels)
+                },
                 host_config: Some(HostConfig {
                     mounts,
+                    blkio_weight: get_helper_blkio_weight(),
+                    network_mode: get_helper_network(),
                     ..Default::default()
                 }),
                 ..Default::default()
             },
         )
         .await?;
+    Ok(())
+}
+
+async fn remove_helper_container(docker: &Docker, container_name: &str) -> Result<()> {
+    log::trace!("Removing container {}", container_name);
+    docker
+        .remove_container(
+            container_name,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn run_docker_command(
+    docker: &Docker,
+    container_name: &str,
+    image: &str,
+    mounts: Option<Vec<Mount>>,
+    cmd: Vec<&str>,
+    labels: Option<Vec<(&str, &str)>>,
+) -> Result<(i64, Vec<LogOutput>)> {
+    let slots = HELPER_SLOTS.read().unwrap().clone();
+    let _permit = slots.acquire().await;
+    log::debug!(
+        "Running '{}' in container {}",
+        cmd.join(" "),
+        container_name
+    );
+    create_helper_container(docker, container_name, image, mounts, cmd, labels).await?;
+    let mut guard = HelperGuard {
+        container_name: container_name.to_string(),
+        finished: false,
+    };
 
     // Run command and wait for it to finish
     docker
@@ -151,22 +729,52 @@ pub(crate) async fn run_docker_command(
         }
     };
 
-    log::trace!("Removing container {}", &container_name);
-    docker
-        .remove_container(
-            &container_name,
-            Some(RemoveContainerOptions {
-                force: true,
-                ..Default::default()
-            }),
-        )
-        .await?;
+    remove_helper_container(docker, container_name).await?;
+    guard.finished = true;
     Ok((
         inspection.state.and_then(|s| s.exit_code).unwrap_or(0),
         logs,
     ))
 }
 
+/// Run command in a running container, returning its exit code and output
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Name of container
+/// * `cmd` - Command to run
+///
+pub(crate) async fn exec_in_container(
+    docker: &Docker,
+    container: &str,
+    cmd: Vec<&str>,
+) -> Result<(i64, Vec<LogOutput>)> {
+    let exec = docker
+        .create_exec(
+            container,
+            CreateExecOptions {
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                cmd: Some(cmd),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let logs = docker
+        .start_exec(&exec.id, None::<StartExecOptions>)
+        .try_collect::<Vec<_>>()
+        .await?
+        .into_iter()
+        .filter_map(|result| match result {
+            StartExecResults::Attached { log } => Some(log),
+            _ => None,
+        })
+        .collect();
+    let exit_code = docker.inspect_exec(&exec.id).await?.exit_code.unwrap_or(-1);
+    Ok((exit_code, logs))
+}
+
 /// Run command in dockyard Docker container
 ///
 /// # Arguments
@@ -186,6 +794,11 @@ pub async fn run_dockyard_command(
     if !verbosity.is_empty() {
         cmd.push(&verbosity);
     }
+    let trace_id = get_trace_id();
+    if let Some(trace_id) = &trace_id {
+        cmd.push("--trace-id");
+        cmd.push(trace_id);
+    }
 
     let image = get_or_build_image(&docker).await?;
     let container_name = format!("dockyard_{}", Uuid::new_v4());
@@ -194,7 +807,351 @@ pub async fn run_dockyard_command(
     run_docker_command(docker, &container_name, &image, mounts, cmd, Some(labels)).await
 }
 
-async fn get_or_build_image(docker: &Docker) -> Result<String> {
+/// Check Docker can mount `mount` by creating a stopped dockyard container with it
+///
+/// Docker refuses to create a container bind mounting a path that doesn't exist on its host.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to check
+///
+pub(crate) async fn check_mount(docker: &Docker, mount: Mount) -> Result<()> {
+    let container_name = create_stopped_dockyard_container(docker, vec![mount]).await?;
+    remove_helper_container(docker, &container_name).await
+}
+
+/// Create a dockyard container with `mounts` without starting it, returning its name
+async fn create_stopped_dockyard_container(docker: &Docker, mounts: Vec<Mount>) -> Result<String> {
+    let image = get_or_build_image(&docker).await?;
+    let container_name = format!("dockyard_{}", Uuid::new_v4());
+    let pid = process::id().to_string();
+    let labels = vec![(PID_LABEL, pid.as_str()), (DISABLED_LABEL, "true")];
+    create_helper_container(
+        docker,
+        &container_name,
+        &image,
+        Some(mounts),
+        vec!["dockyard"],
+        Some(labels),
+    )
+    .await?;
+    Ok(container_name)
+}
+
+/// Write file to mount by uploading it to a stopped dockyard container
+///
+/// The file is held in memory while it is uploaded. Missing parent directories are created by
+/// a helper first, as the daemon would create them world-readable and would reset the
+/// permissions of existing ones given entries for them.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to write to
+/// * `path` - Path of file relative to the mount
+/// * `contents` - Contents of file
+/// * `modes` - Permissions of the file and the directories leading to it
+///
+pub(crate) async fn upload_to_mount(
+    docker: &Docker,
+    mount: Mount,
+    path: &Path,
+    contents: &[u8],
+    modes: &FileModes,
+) -> Result<()> {
+    let mut archive = tar::Builder::new(vec![]);
+    append_upload(&mut archive, path, contents.len() as u64, contents, modes)?;
+    let archive = archive.into_inner()?;
+    upload_archive_to_mount(docker, mount, path, archive.into(), modes).await
+}
+
+/// Write file to mount by streaming it to a stopped dockyard container
+///
+/// Like `upload_to_mount`, but the file is copied into a temporary tar and streamed from
+/// disk rather than held in memory.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to write to
+/// * `path` - Path of file relative to the mount
+/// * `file` - File to upload, read from its current position
+/// * `size` - Number of bytes to upload from `file`
+/// * `modes` - Permissions of the file and the directories leading to it
+///
+pub(crate) async fn upload_file_to_mount(
+    docker: &Docker,
+    mount: Mount,
+    path: &Path,
+    file: File,
+    size: u64,
+    modes: &FileModes,
+) -> Result<()> {
+    let mut archive = tar::Builder::new(tempfile::tempfile()?);
+    append_upload(&mut archive, path, size, file, modes)?;
+    let mut archive = archive.into_inner()?;
+    archive.seek(SeekFrom::Start(0))?;
+    upload_archive_to_mount(docker, mount, path, file_body(archive), modes).await
+}
+
+/// Append file to an archive uploaded to a mount
+fn append_upload<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &Path,
+    size: u64,
+    contents: impl Read,
+    modes: &FileModes,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(modes.file);
+    header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    archive.append_data(&mut header, path, contents.take(size))?;
+    Ok(())
+}
+
+/// Return a body streaming the contents of `file` in chunks
+fn file_body(mut file: File) -> Body {
+    let chunks = std::iter::from_fn(move || {
+        let mut chunk = vec![0; 64 * 1024];
+        match file.read(&mut chunk) {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some(Ok(chunk))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    });
+    Body::wrap_stream(futures::stream::iter(chunks))
+}
+
+/// Upload tar with a single file at `path` to the root of `mount`
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to write to
+/// * `path` - Path of file in the archive relative to the mount
+/// * `archive` - Tar to upload
+/// * `modes` - Permissions of the directories leading to the file
+///
+async fn upload_archive_to_mount(
+    docker: &Docker,
+    mount: Mount,
+    path: &Path,
+    archive: Body,
+    modes: &FileModes,
+) -> Result<()> {
+    let target = mount.target.clone().unwrap();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let directory = Path::new(&target).join(parent);
+        let dir_mode = format!("--dir-mode={:04o}", modes.directory);
+        let (exit_code, logs) = run_dockyard_command(
+            docker,
+            Some(vec![mount.clone()]),
+            vec!["mkdir", &dir_mode, path_str(&directory)?],
+        )
+        .await?;
+        handle_container_output(exit_code, &format!("mkdir {}", parent.display()), &logs)?;
+    }
+    let container_name = create_stopped_dockyard_container(docker, vec![mount]).await?;
+    let result = docker
+        .upload_to_container(
+            &container_name,
+            Some(UploadToContainerOptions {
+                path: target,
+                ..Default::default()
+            }),
+            archive,
+        )
+        .await;
+    remove_helper_container(docker, &container_name).await?;
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Read file from mount by downloading it from a stopped dockyard container
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to read from
+/// * `path` - Path of file relative to the mount
+///
+pub(crate) async fn download_from_mount(
+    docker: &Docker,
+    mount: Mount,
+    path: &Path,
+) -> Result<Vec<u8>> {
+    let source = Path::new(mount.target.as_ref().unwrap()).join(path);
+    let source = path_str(&source)?.to_string();
+    let container_name = create_stopped_dockyard_container(docker, vec![mount]).await?;
+    let archive = docker
+        .download_from_container(
+            &container_name,
+            Some(DownloadFromContainerOptions {
+                path: source.as_str(),
+            }),
+        )
+        .try_fold(vec![], |mut archive, chunk| {
+            archive.extend_from_slice(&chunk);
+            futures::future::ok(archive)
+        })
+        .await;
+    remove_helper_container(docker, &container_name).await?;
+    let archive = archive.with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| anyhow!("{} not found in archive", path.display()))??;
+    let mut contents = vec![];
+    entry.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Read file from mount into an unnamed temporary file, rewound to its start
+///
+/// Unlike `download_from_mount`, the file is never held in memory, so large archives can be
+/// read.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to read from
+/// * `path` - Path of file relative to the mount
+///
+pub(crate) async fn download_from_mount_to_file(
+    docker: &Docker,
+    mount: Mount,
+    path: &Path,
+) -> Result<File> {
+    let source = Path::new(mount.target.as_ref().unwrap()).join(path);
+    let source = path_str(&source)?.to_string();
+    let container_name = create_stopped_dockyard_container(docker, vec![mount]).await?;
+    let mut archive = tempfile::tempfile()?;
+    let mut stream = Box::pin(docker.download_from_container(
+        &container_name,
+        Some(DownloadFromContainerOptions {
+            path: source.as_str(),
+        }),
+    ));
+    let read = loop {
+        match stream.try_next().await {
+            Ok(Some(chunk)) => {
+                if let Err(e) = archive.write_all(&chunk) {
+                    break Err(e.into());
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(anyhow::Error::from(e)),
+        }
+    };
+    drop(stream);
+    remove_helper_container(docker, &container_name).await?;
+    read.with_context(|| format!("Failed to read {}", path.display()))?;
+
+    archive.seek(SeekFrom::Start(0))?;
+    let mut archive = tar::Archive::new(archive);
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| anyhow!("{} not found in archive", path.display()))??;
+    let mut file = tempfile::tempfile()?;
+    io::copy(&mut entry, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Read the contents of a mount as a tarball through the Docker archive API
+///
+/// Entries are named relative to the parent of the mount target. Returns `None` without
+/// reading the rest of the mount once the tarball grows past `limit` bytes.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Mount to read
+/// * `limit` - Largest tarball to read, in bytes
+///
+pub(crate) async fn download_mount_archive(
+    docker: &Docker,
+    mount: Mount,
+    limit: u64,
+) -> Result<Option<Vec<u8>>> {
+    let source = mount.target.clone().unwrap();
+    let slots = HELPER_SLOTS.read().unwrap().clone();
+    let _permit = slots.acquire().await;
+    let container_name = create_stopped_dockyard_container(docker, vec![mount]).await?;
+    let mut guard = HelperGuard {
+        container_name: container_name.clone(),
+        finished: false,
+    };
+    let mut stream = Box::pin(docker.download_from_container(
+        &container_name,
+        Some(DownloadFromContainerOptions {
+            path: source.as_str(),
+        }),
+    ));
+    let mut archive = vec![];
+    let read = loop {
+        match stream.try_next().await {
+            Ok(Some(chunk)) => {
+                archive.extend_from_slice(&chunk);
+                if archive.len() as u64 > limit {
+                    break Ok(false);
+                }
+            }
+            Ok(None) => break Ok(true),
+            Err(e) => break Err(e),
+        }
+    };
+    drop(stream);
+    remove_helper_container(docker, &container_name).await?;
+    guard.finished = true;
+    let fits = read.with_context(|| format!("Failed to read {}", source))?;
+    Ok(if fits { Some(archive) } else { None })
+}
+
+/// Export image as a tarball, equivalent to `docker save`
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `image` - Image to export
+///
+pub(crate) async fn export_image(docker: &Docker, image: &str) -> Result<Vec<u8>> {
+    log::info!("Exporting image {}", image);
+    let contents = docker
+        .export_image(image)
+        .try_fold(vec![], |mut contents, chunk| {
+            contents.extend_from_slice(&chunk);
+            futures::future::ok(contents)
+        })
+        .await
+        .with_context(|| format!("Failed to export image {}", image))?;
+    Ok(contents)
+}
+
+/// Load image from a tarball created by `export_image`, equivalent to `docker load`
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `image` - Name of image being loaded, used for logging
+/// * `archive` - Image tarball
+///
+pub(crate) async fn load_image(docker: &Docker, image: &str, archive: Vec<u8>) -> Result<()> {
+    log::info!("Loading image {}", image);
+    let output = docker.import_image(ImportImageOptions::default(), archive.into(), None);
+    stream_output(image, output)
+        .await
+        .with_context(|| format!("Failed to load image {}", image))
+}
+
+pub(crate) async fn get_or_build_image(docker: &Docker) -> Result<String> {
     match Command::new("git")
         .arg("rev-parse")
         .arg("HEAD")
@@ -285,6 +1242,9 @@ fn build_context(root: &str) -> Result<Vec<u8>> {
 
 /// Print output logs, returning failure on non-zero exit code
 ///
+/// On failure stdout is printed at info level and stderr is printed last at
+/// error level so the cause of the failure is the most visible output.
+///
 /// # Arguments
 ///
 /// * `exit_code` - Exit code from container
@@ -302,15 +1262,35 @@ pub(crate) fn handle_container_output(
             Ok(())
         }
         _ => {
-            print_logs(prefix, logs, LevelFilter::Error);
-            Err(anyhow!("Docker returned non-zero exit code: {}", exit_code))
+            let (stderr, other): (Vec<&LogOutput>, Vec<&LogOutput>) = logs
+                .iter()
+                .partition(|line| matches!(line, LogOutput::StdErr { .. }));
+            print_logs(prefix, other, LevelFilter::Info);
+            print_logs(prefix, stderr, LevelFilter::Error);
+            if logs
+                .iter()
+                .any(|line| line.to_string().contains(OUT_OF_SPACE_MESSAGE))
+            {
+                Err(anyhow!("{}, partial archive removed", OUT_OF_SPACE_MESSAGE))
+            } else {
+                Err(anyhow!("Docker returned non-zero exit code: {}", exit_code))
+            }
         }
     }
 }
 
-pub(crate) fn print_logs(prefix: &str, logs: &[LogOutput], level: LevelFilter) {
+pub(crate) fn print_logs<'a>(
+    prefix: &str,
+    logs: impl IntoIterator<Item = &'a LogOutput>,
+    level: LevelFilter,
+) {
     for line in logs {
-        let line_string = format!("[{}] {}", prefix, line.to_string().trim());
+        let line_string = format!(
+            "[{}] [{}] {}",
+            prefix,
+            log_stream(line),
+            line.to_string().trim()
+        );
         match level {
             LevelFilter::Info => log::info!("{}", line_string),
             LevelFilter::Error => log::error!("{}", line_string),
@@ -320,6 +1300,29 @@ pub(crate) fn print_logs(prefix: &str, logs: &[LogOutput], level: LevelFilter) {
     }
 }
 
+/// Return last non-empty line a container wrote to stdout
+pub(crate) fn last_stdout_line(logs: &[LogOutput]) -> Option<String> {
+    logs.iter()
+        .filter(|line| matches!(line, LogOutput::StdOut { .. }))
+        .map(|line| line.to_string())
+        .collect::<String>()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .last()
+        .map(str::to_string)
+}
+
+/// Return name of the stream a log line was written to
+fn log_stream(line: &LogOutput) -> &'static str {
+    match line {
+        LogOutput::StdErr { .. } => "stderr",
+        LogOutput::StdOut { .. } => "stdout",
+        LogOutput::StdIn { .. } => "stdin",
+        LogOutput::Console { .. } => "console",
+    }
+}
+
 /// Return Mount representing backup directory
 pub fn get_backup_directory_mount(directory: String) -> Mount {
     Mount {
@@ -340,6 +1343,24 @@ pub fn get_backup_volume_mount(volume: String) -> Mount {
     }
 }
 
+/// Return read-only Mount representing backup directory, for restoring from
+pub fn get_read_only_backup_directory_mount(directory: String) -> Mount {
+    read_only_mount(get_backup_directory_mount(directory))
+}
+
+/// Return read-only Mount representing backup volume, for restoring from
+pub fn get_read_only_backup_volume_mount(volume: String) -> Mount {
+    read_only_mount(get_backup_volume_mount(volume))
+}
+
+/// Return copy of mount that containers can only read from
+pub fn read_only_mount(mount: Mount) -> Mount {
+    Mount {
+        read_only: Some(true),
+        ..mount
+    }
+}
+
 pub fn get_bind_mount(directory: String) -> Mount {
     Mount {
         source: Some(directory),
@@ -357,3 +1378,209 @@ pub fn get_volume_mount(volume: String) -> Mount {
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn is_retryable_pull_error_test() {
+        assert!(is_retryable_pull_error(
+            "error trying to connect: connection reset by peer"
+        ));
+        assert!(is_retryable_pull_error(
+            "Docker responded with status code 500: received unexpected HTTP status: 503 Service Unavailable"
+        ));
+        assert!(!is_retryable_pull_error(
+            "Docker responded with status code 404: manifest for alpine:nope not found: manifest unknown"
+        ));
+        assert!(!is_retryable_pull_error(
+            "pull access denied for private/image, repository does not exist or may require 'docker login'"
+        ));
+        assert!(!is_retryable_pull_error(
+            "unauthorized: authentication required"
+        ));
+    }
+
+    #[test]
+    fn retry_pull_test() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let attempts = AtomicUsize::new(0);
+        let result = rt.block_on(retry_pull("alpine", 3, Duration::from_millis(1), || {
+            let attempt = attempts.fetch_add(1, Relaxed);
+            async move {
+                if attempt < 2 {
+                    Err(anyhow!("connection reset by peer"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts.load(Relaxed), 3);
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> =
+            rt.block_on(retry_pull("alpine", 3, Duration::from_millis(1), || {
+                attempts.fetch_add(1, Relaxed);
+                async { Err(anyhow!("connection reset by peer")) }
+            }));
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Relaxed), 4);
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = rt.block_on(retry_pull(
+            "private/image",
+            3,
+            Duration::from_millis(1),
+            || {
+                attempts.fetch_add(1, Relaxed);
+                async { Err(anyhow!("unauthorized: authentication required")) }
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn parse_label_test() {
+        assert_eq!(
+            parse_label("com.example.policy=exempt").unwrap(),
+            ("com.example.policy".to_string(), "exempt".to_string())
+        );
+        assert_eq!(
+            parse_label("key=a=b").unwrap(),
+            ("key".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_label("empty=").unwrap(),
+            ("empty".to_string(), "".to_string())
+        );
+        assert!(parse_label("no-value").is_err());
+        assert!(parse_label("=value").is_err());
+    }
+
+    #[test]
+    fn read_only_backup_mount_test() {
+        let mount = get_read_only_backup_volume_mount("backups".to_string());
+        assert_eq!(mount.read_only, Some(true));
+        assert_eq!(mount.source, Some("backups".to_string()));
+        assert_eq!(mount.target, Some("/backup".to_string()));
+        assert_eq!(
+            get_backup_volume_mount("backups".to_string()).read_only,
+            None
+        );
+    }
+
+    #[test]
+    fn connection_error_message_test() {
+        assert!(
+            connection_error_message(ErrorKind::PermissionDenied, ContainerRuntime::Docker)
+                .unwrap()
+                .contains("docker group")
+        );
+        assert_eq!(
+            connection_error_message(ErrorKind::NotFound, ContainerRuntime::Docker).unwrap(),
+            "Unable to connect to unix:///var/run/docker.sock, is Docker running?"
+        );
+        assert!(
+            connection_error_message(ErrorKind::ConnectionRefused, ContainerRuntime::Podman)
+                .unwrap()
+                .ends_with("is Podman running?")
+        );
+        assert!(connection_error_message(ErrorKind::TimedOut, ContainerRuntime::Docker).is_none());
+    }
+
+    #[test]
+    fn find_docker_socket_test() {
+        let home = Path::new("/home/user");
+        let exists = |sockets: &'static [&'static str]| {
+            move |path: &Path| sockets.iter().any(|socket| path == Path::new(socket))
+        };
+        assert_eq!(
+            find_docker_socket(Some(home), exists(&["/var/run/docker.sock"])),
+            DockerSocket::Native
+        );
+        let desktop = find_docker_socket(
+            Some(home),
+            exists(&[
+                "/home/user/.docker/desktop/docker.sock",
+                "/home/user/.docker/run/docker.sock",
+            ]),
+        );
+        assert_eq!(desktop.uri(), "unix:///home/user/.docker/run/docker.sock");
+        assert_eq!(
+            find_docker_socket(
+                Some(home),
+                exists(&["/home/user/.docker/desktop/docker.sock"])
+            ),
+            DockerSocket::Desktop(PathBuf::from("/home/user/.docker/desktop/docker.sock"))
+        );
+        assert_eq!(find_docker_socket(None, exists(&[])), DockerSocket::Native);
+        assert_eq!(DockerSocket::Native.uri(), DEFAULT_DOCKER_SOCKET);
+    }
+
+    #[test]
+    fn connect_default_docker_test() {
+        connect_default_docker(Some("tcp://127.0.0.1:2375")).unwrap();
+        connect_default_docker(Some("unix:///run/user/1000/docker.sock")).unwrap();
+        assert_eq!(
+            connect_default_docker(Some("ssh://user@host"))
+                .unwrap_err()
+                .to_string(),
+            "Unsupported Docker host ssh://user@host"
+        );
+    }
+
+    #[test]
+    fn explain_connection_error_test() {
+        let error = anyhow::Error::new(std::io::Error::from(ErrorKind::NotFound))
+            .context("Unable to open archive");
+        assert!(explain_connection_error(&error).is_none());
+    }
+
+    #[test]
+    fn parse_api_version_test() {
+        assert_eq!(parse_api_version("1.41"), Some((1, 41)));
+        assert_eq!(parse_api_version("1.9"), Some((1, 9)));
+        assert!(parse_api_version("1.9").unwrap() < MIN_DOCKER_API_VERSION);
+        assert!(parse_api_version("1.40").unwrap() >= MIN_DOCKER_API_VERSION);
+        assert!(parse_api_version("2.0").unwrap() >= MIN_DOCKER_API_VERSION);
+        assert_eq!(parse_api_version("1"), None);
+        assert_eq!(parse_api_version("latest"), None);
+    }
+
+    #[test]
+    fn parse_blkio_weight_test() {
+        assert_eq!(parse_blkio_weight("10").unwrap(), 10);
+        assert_eq!(parse_blkio_weight("1000").unwrap(), 1000);
+        assert!(parse_blkio_weight("5").is_err());
+        assert!(parse_blkio_weight("1001").is_err());
+        assert!(parse_blkio_weight("low").is_err());
+    }
+
+    #[test]
+    fn qualify_image_test() {
+        assert_eq!(
+            qualify_image("alpine:latest", ContainerRuntime::Docker),
+            "alpine:latest"
+        );
+        assert_eq!(
+            qualify_image("alpine:latest", ContainerRuntime::Podman),
+            "docker.io/library/alpine:latest"
+        );
+        assert_eq!(
+            qualify_image("aig787/dockyard", ContainerRuntime::Podman),
+            "docker.io/aig787/dockyard"
+        );
+        assert_eq!(
+            qualify_image("quay.io/podman/stable", ContainerRuntime::Podman),
+            "quay.io/podman/stable"
+        );
+        assert_eq!(
+            qualify_image("localhost:5000/dockyard", ContainerRuntime::Podman),
+            "localhost:5000/dockyard"
+        );
+    }
+}
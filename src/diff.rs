@@ -0,0 +1,252 @@
+use crate::backup::ContainerBackup;
+use crate::restore::read_container_backup;
+use anyhow::{Context, Result};
+use bollard::container::InspectContainerOptions;
+use bollard::models::{ContainerInspectResponse, Mount};
+use bollard::Docker;
+use serde_json::{Map, Value};
+
+/// Difference between a backed up container config field and the running container
+///
+/// Changes describe what restoring the backup would do, so items are `added` if they are
+/// only in the backup and `removed` if they are only in the running container.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ConfigChange {
+    Changed {
+        field: String,
+        backup: Value,
+        current: Value,
+    },
+    Items {
+        field: String,
+        added: Vec<Value>,
+        removed: Vec<Value>,
+    },
+}
+
+/// Compare container backup against a running container
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Container backup file relative to backup mount
+/// * `container` - Name of running container
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn diff_container(
+    docker: &Docker,
+    backup_file: &str,
+    container: &str,
+    backup_mount: Mount,
+) -> Result<Vec<ConfigChange>> {
+    let backup = read_container_backup(docker, backup_file, &backup_mount).await?;
+    let current = docker
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+        .with_context(|| format!("Unable to inspect container {}", container))?;
+    diff_container_config(&backup, &current)
+}
+
+/// Compare `Config` and `HostConfig` of a container backup and a container inspection
+///
+/// # Arguments
+///
+/// * `backup` - Container backup
+/// * `current` - Inspection of running container
+///
+pub fn diff_container_config(
+    backup: &ContainerBackup,
+    current: &ContainerInspectResponse,
+) -> Result<Vec<ConfigChange>> {
+    let mut changes = vec![];
+    diff_fields(
+        "Config",
+        &serde_json::to_value(&backup.container_config)?,
+        &serde_json::to_value(&current.config)?,
+        &mut changes,
+    );
+    diff_fields(
+        "HostConfig",
+        &serde_json::to_value(&backup.host_config)?,
+        &serde_json::to_value(&current.host_config)?,
+        &mut changes,
+    );
+    Ok(changes)
+}
+
+fn diff_fields(section: &str, backup: &Value, current: &Value, changes: &mut Vec<ConfigChange>) {
+    let empty = Map::new();
+    let backup = backup.as_object().unwrap_or(&empty);
+    let current = current.as_object().unwrap_or(&empty);
+    let mut keys = backup.keys().chain(current.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let backup_value = backup.get(key).unwrap_or(&Value::Null);
+        let current_value = current.get(key).unwrap_or(&Value::Null);
+        if backup_value == current_value || (is_empty(backup_value) && is_empty(current_value)) {
+            continue;
+        }
+        let field = format!("{}.{}", section, key);
+        let items = match (backup_value, current_value) {
+            (Value::Array(b), Value::Array(c)) => Some((b.as_slice(), c.as_slice())),
+            (Value::Array(b), Value::Null) => Some((b.as_slice(), &[][..])),
+            (Value::Null, Value::Array(c)) => Some((&[][..], c.as_slice())),
+            _ => None,
+        };
+        match items {
+            Some((b, c)) => {
+                let added = b
+                    .iter()
+                    .filter(|i| !c.contains(i))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let removed = c
+                    .iter()
+                    .filter(|i| !b.contains(i))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if added.is_empty() && removed.is_empty() {
+                    // Same items in a different order
+                    changes.push(ConfigChange::Changed {
+                        field,
+                        backup: backup_value.clone(),
+                        current: current_value.clone(),
+                    });
+                } else {
+                    changes.push(ConfigChange::Items {
+                        field,
+                        added,
+                        removed,
+                    });
+                }
+            }
+            None => changes.push(ConfigChange::Changed {
+                field,
+                backup: backup_value.clone(),
+                current: current_value.clone(),
+            }),
+        }
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(a) => a.is_empty(),
+        Value::Object(o) => o.is_empty(),
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+/// Render changes for display
+pub fn format_changes(changes: &[ConfigChange]) -> String {
+    if changes.is_empty() {
+        return "No configuration differences".to_string();
+    }
+    let display = |v: &Value| {
+        v.as_str()
+            .map(String::from)
+            .unwrap_or_else(|| v.to_string())
+    };
+    let mut lines = vec![];
+    for change in changes {
+        match change {
+            ConfigChange::Changed {
+                field,
+                backup,
+                current,
+            } => lines.push(format!(
+                "~ {}: {} -> {}",
+                field,
+                display(current),
+                display(backup)
+            )),
+            ConfigChange::Items {
+                field,
+                added,
+                removed,
+            } => {
+                for item in added {
+                    lines.push(format!("+ {}: {}", field, display(item)));
+                }
+                for item in removed {
+                    lines.push(format!("- {}: {}", field, display(item)));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::CONTAINER_BACKUP_SCHEMA_VERSION;
+    use bollard::models::{ContainerConfig, HostConfig};
+
+    #[test]
+    fn diff_container_config_test() {
+        let backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "nginx".to_string(),
+            container_config: ContainerConfig {
+                image: Some("nginx:1.19".to_string()),
+                env: Some(vec!["A=1".to_string(), "B=2".to_string()]),
+                ..Default::default()
+            },
+            host_config: HostConfig {
+                privileged: Some(false),
+                ..Default::default()
+            },
+            mounts: vec![],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        let current = ContainerInspectResponse {
+            config: Some(ContainerConfig {
+                image: Some("nginx:1.20".to_string()),
+                env: Some(vec!["A=1".to_string(), "C=3".to_string()]),
+                ..Default::default()
+            }),
+            host_config: Some(HostConfig {
+                privileged: Some(false),
+                binds: Some(vec![]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let changes = diff_container_config(&backup, &current).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange::Items {
+                    field: "Config.Env".to_string(),
+                    added: vec![Value::from("B=2")],
+                    removed: vec![Value::from("C=3")],
+                },
+                ConfigChange::Changed {
+                    field: "Config.Image".to_string(),
+                    backup: Value::from("nginx:1.19"),
+                    current: Value::from("nginx:1.20"),
+                },
+            ]
+        );
+        assert_eq!(
+            format_changes(&changes),
+            "+ Config.Env: B=2\n- Config.Env: C=3\n~ Config.Image: nginx:1.20 -> nginx:1.19"
+        );
+    }
+
+    #[test]
+    fn format_no_changes_test() {
+        assert_eq!(format_changes(&[]), "No configuration differences");
+    }
+}
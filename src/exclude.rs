@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+
+/// A single gitignore-style pattern
+#[derive(Debug)]
+struct ExcludePattern {
+    matcher: GlobMatcher,
+    negated: bool,
+    directory_only: bool,
+}
+
+/// Gitignore-style patterns selecting paths to leave out of directory backups
+///
+/// Patterns are matched against paths relative to the directory being backed up. A pattern
+/// without a `/` matches at any depth, a trailing `/` only matches directories and a leading
+/// `!` re-includes paths excluded by an earlier pattern. The last matching pattern wins.
+///
+/// If subpaths are set, everything outside them is excluded as well.
+#[derive(Debug, Default)]
+pub struct ExcludePatterns {
+    patterns: Vec<ExcludePattern>,
+    subpaths: Vec<PathBuf>,
+}
+
+impl ExcludePatterns {
+    /// Compile `patterns`, skipping blank lines and `#` comments
+    ///
+    /// # Arguments
+    ///
+    /// * `patterns` - Gitignore-style patterns
+    ///
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut compiled = vec![];
+        for line in patterns {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (directory_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            let glob = if pattern.contains('/') {
+                pattern.trim_start_matches('/').to_string()
+            } else {
+                format!("**/{}", pattern)
+            };
+            let matcher = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid exclude pattern {}", line))?
+                .compile_matcher();
+            compiled.push(ExcludePattern {
+                matcher,
+                negated,
+                directory_only,
+            });
+        }
+        Ok(ExcludePatterns {
+            patterns: compiled,
+            subpaths: vec![],
+        })
+    }
+
+    /// Exclude everything except `subpaths` and the directories leading to them
+    ///
+    /// # Arguments
+    ///
+    /// * `subpaths` - Paths relative to the directory being backed up
+    ///
+    pub fn with_subpaths(self, subpaths: &[PathBuf]) -> Self {
+        ExcludePatterns {
+            subpaths: subpaths.to_vec(),
+            ..self
+        }
+    }
+
+    /// Return true if there are no patterns or subpaths
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.subpaths.is_empty()
+    }
+
+    /// Return true if `path` should be left out of the backup
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path relative to the directory being backed up
+    /// * `is_dir` - Whether `path` is a directory
+    ///
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let outside_subpaths = !self.subpaths.is_empty()
+            && !self
+                .subpaths
+                .iter()
+                .any(|subpath| path.starts_with(subpath) || subpath.starts_with(path));
+        outside_subpaths
+            || self
+                .patterns
+                .iter()
+                .rev()
+                .find(|p| (is_dir || !p.directory_only) && p.matcher.is_match(path))
+                .map_or(false, |p| !p.negated)
+    }
+}
+
+/// Parse a path within a volume or directory, relative to its root
+///
+/// A leading `/` is ignored, so `/var/log` and `var/log` are the same subpath.
+pub fn parse_subpath(subpath: &str) -> Result<PathBuf> {
+    let path = Path::new(subpath.trim_start_matches('/'));
+    let valid = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !valid || path.components().all(|c| c == Component::CurDir) {
+        return Err(anyhow!(
+            "Invalid subpath {}, expected a path inside the volume such as data/uploads",
+            subpath
+        ));
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Read gitignore-style patterns from `path`, one per line
+///
+/// # Arguments
+///
+/// * `path` - File of patterns
+///
+pub fn read_exclude_file(path: &Path) -> Result<Vec<String>> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Unable to read exclude file {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn exclude_file_test() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("excludes");
+        write(
+            &path,
+            "# caches and logs\n*.log\n!keep.log\n\ncache/\n/tmp/*.swp\n",
+        )
+        .unwrap();
+        let patterns = read_exclude_file(&path).unwrap();
+        assert_eq!(patterns, vec!["*.log", "!keep.log", "cache/", "/tmp/*.swp"]);
+
+        let excludes = ExcludePatterns::new(&patterns).unwrap();
+        assert!(excludes.is_excluded(Path::new("app.log"), false));
+        assert!(excludes.is_excluded(Path::new("var/app.log"), false));
+        assert!(!excludes.is_excluded(Path::new("var/keep.log"), false));
+        assert!(excludes.is_excluded(Path::new("var/cache"), true));
+        assert!(!excludes.is_excluded(Path::new("var/cache"), false));
+        assert!(excludes.is_excluded(Path::new("tmp/a.swp"), false));
+        assert!(!excludes.is_excluded(Path::new("var/tmp/a.swp"), false));
+        assert!(!excludes.is_excluded(Path::new("data/db"), false));
+    }
+
+    #[test]
+    fn subpaths_test() {
+        let subpaths = vec![
+            parse_subpath("/data/uploads").unwrap(),
+            parse_subpath("db").unwrap(),
+        ];
+        let excludes = ExcludePatterns::new(&["*.tmp".to_string()])
+            .unwrap()
+            .with_subpaths(&subpaths);
+        assert!(!excludes.is_excluded(Path::new("data"), true));
+        assert!(!excludes.is_excluded(Path::new("data/uploads/a.png"), false));
+        assert!(excludes.is_excluded(Path::new("data/uploads/a.tmp"), false));
+        assert!(excludes.is_excluded(Path::new("data/cache"), true));
+        assert!(!excludes.is_excluded(Path::new("db/pg_wal"), true));
+        assert!(excludes.is_excluded(Path::new("scratch"), true));
+        assert!(!ExcludePatterns::new(&[])
+            .unwrap()
+            .with_subpaths(&subpaths)
+            .is_empty());
+
+        assert!(parse_subpath("../etc").is_err());
+        assert!(parse_subpath("data/../../etc").is_err());
+        assert!(parse_subpath("/").is_err());
+    }
+
+    #[test]
+    fn invalid_pattern_test() {
+        assert!(ExcludePatterns::new(&["[".to_string()]).is_err());
+        assert!(ExcludePatterns::new(&[]).unwrap().is_empty());
+    }
+}
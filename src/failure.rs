@@ -0,0 +1,78 @@
+use crate::container::DOCKER_CONNECTION_EXIT_CODE;
+
+/// Category of error dockyard exits with, which decides its exit code
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// A command line argument was invalid
+    Usage,
+    /// The Docker daemon couldn't be reached
+    DockerConnection,
+    /// The command failed
+    Command,
+}
+
+impl FailureKind {
+    /// Return the exit code of this kind of failure
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::DockerConnection => DOCKER_CONNECTION_EXIT_CODE,
+            FailureKind::Usage | FailureKind::Command => 1,
+        }
+    }
+}
+
+/// Error dockyard exits with, as printed with `--errors-json`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub exit_code: i32,
+    /// The error followed by each error that caused it
+    pub errors: Vec<String>,
+    /// Trace ID of the dockyard run that failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+impl Failure {
+    /// Create failure from an error and the errors that caused it
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - Category of error
+    /// * `error` - Error to report
+    ///
+    pub fn new(kind: FailureKind, error: &anyhow::Error) -> Self {
+        Failure {
+            kind,
+            exit_code: kind.exit_code(),
+            errors: error.chain().map(ToString::to_string).collect(),
+            trace_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn failure_test() {
+        let error = Err::<(), _>(anyhow!("No such file or directory"))
+            .context("Unable to read backup.json")
+            .context("Failed to restore container web")
+            .unwrap_err();
+        let failure = Failure::new(FailureKind::Command, &error);
+        assert_eq!(
+            serde_json::to_string(&failure).unwrap(),
+            r#"{"kind":"command","exit_code":1,"errors":["Failed to restore container web","Unable to read backup.json","No such file or directory"]}"#
+        );
+        let mut failure = Failure::new(FailureKind::DockerConnection, &error);
+        assert_eq!(failure.exit_code, DOCKER_CONNECTION_EXIT_CODE);
+        failure.trace_id = Some("4f66ad9a0b2e".to_string());
+        assert!(serde_json::to_string(&failure)
+            .unwrap()
+            .ends_with(r#","trace_id":"4f66ad9a0b2e"}"#));
+    }
+}
@@ -1,21 +1,142 @@
 use anyhow::{Context, Result};
-use std::path::Path;
-use std::fs::File;
-use std::io::Write;
+use std::ffi::CString;
 use std::fs;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io;
+use std::io::Write;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// Permissions of the directories and files written to the backup destination
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileModes {
+    /// Mode of created directories
+    pub directory: u32,
+    /// Mode of created and overwritten files
+    pub file: u32,
+}
+
+impl Default for FileModes {
+    /// Owner only, container backup files can hold secrets from the container's environment
+    fn default() -> Self {
+        FileModes {
+            directory: 0o700,
+            file: 0o600,
+        }
+    }
+}
+
+impl FileModes {
+    /// Return arguments passing modes that aren't the default to a dockyard helper command
+    pub fn args(&self) -> Vec<String> {
+        let default = FileModes::default();
+        let mut args = vec![];
+        if self.directory != default.directory {
+            args.push(format!("--dir-mode={:04o}", self.directory));
+        }
+        if self.file != default.file {
+            args.push(format!("--file-mode={:04o}", self.file));
+        }
+        args
+    }
+
+    /// Create `path` and any missing parents, giving the directories created the directory mode
+    ///
+    /// Directories that already exist keep their permissions.
+    pub fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let missing = path
+            .ancestors()
+            .take_while(|dir| !dir.exists())
+            .collect::<Vec<_>>();
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.directory)
+            .create(path)
+            .with_context(|| format!("Unable to create directory {}", path.display()))?;
+        // The mode given to mkdir is masked by the umask
+        for dir in missing {
+            fs::set_permissions(dir, Permissions::from_mode(self.directory))?;
+        }
+        Ok(())
+    }
+
+    /// Create or truncate `path` for writing, with the file mode
+    pub fn create_file(&self, path: &Path) -> Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(self.file)
+            .open(path)
+            .with_context(|| format!("Unable to create file {}", path.display()))?;
+        // Files that already existed and the umask keep the mode from applying on open
+        file.set_permissions(Permissions::from_mode(self.file))?;
+        Ok(file)
+    }
 
-pub fn write_file(contents: &str, output: &str) -> Result<()> {
+    /// Write `contents` to `path` with the file mode
+    pub fn write<C: AsRef<[u8]>>(&self, path: &Path, contents: C) -> Result<()> {
+        self.create_file(path)?
+            .write_all(contents.as_ref())
+            .with_context(|| format!("Unable to write {}", path.display()))
+    }
+}
+
+/// Parse octal permissions such as 0700
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(parsed) if parsed <= 0o7777 => Ok(parsed),
+        _ => Err(anyhow!(
+            "Invalid mode {}, expected octal permissions such as 0700",
+            mode
+        )),
+    }
+}
+
+pub fn write_file(contents: &str, output: &str, modes: &FileModes) -> Result<()> {
     log::debug!("Writing contents to {}", output);
     let output_path = Path::new(output);
-    fs::create_dir_all(output_path.parent().unwrap())?;
-    let mut output_file = File::create(output_path)?;
-    output_file.write_all(contents.as_bytes())?;
+    modes.create_dir_all(output_path.parent().unwrap())?;
+    modes.write(output_path, contents)
+}
+
+/// Return `path` as a string, failing with an error naming it if it isn't valid UTF-8
+///
+/// Paths given to helper containers and the Docker API have to be strings.
+pub fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("Path {} is not valid UTF-8", path.display()))
+}
+
+/// Fail if `path` exists, for commands run with `--no-clobber`
+pub fn ensure_absent(path: &Path) -> Result<()> {
+    if path.symlink_metadata().is_ok() {
+        return Err(anyhow!(
+            "{} already exists, refusing to overwrite it with --no-clobber",
+            path.display()
+        ));
+    }
     Ok(())
 }
 
-pub fn decode_and_write_file(contents: &str, output: &str) -> Result<()> {
+/// Return the bytes available to unprivileged users on the filesystem holding `path`
+pub fn available_space(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // statvfs only writes to stat, which is read once it has succeeded
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Unable to get free space of {}", path.display()));
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+pub fn decode_and_write_file(contents: &str, output: &str, modes: &FileModes) -> Result<()> {
     log::debug!("Decoding input as base64");
-    write_file(&decode_b64(contents)?, output)
+    write_file(&decode_b64(contents)?, output, modes)
 }
 
 pub fn decode_b64(contents: &str) -> Result<String> {
@@ -36,6 +157,21 @@ pub fn read_and_encode_file(path: &str) -> Result<String> {
     Ok(base64::encode(contents))
 }
 
+/// Return sorted names of entries in directory, or nothing if it doesn't exist
+pub fn list_directory(path: &str) -> Result<Vec<String>> {
+    log::debug!("Listing {}", path);
+    let path = Path::new(path);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut entries = vec![];
+    for entry in fs::read_dir(path).with_context(|| format!("Failed to list {}", path.display()))? {
+        entries.push(entry?.file_name().to_string_lossy().to_string());
+    }
+    entries.sort();
+    Ok(entries)
+}
+
 
 #[cfg(test)]
 mod test {
@@ -53,7 +189,12 @@ mod test {
         let working_dir = TempDir::new().unwrap();
         let output = working_dir.path().join("out");
         let contents = rand_string();
-        write_file(&contents, output.as_path().to_str().unwrap()).unwrap();
+        write_file(
+            &contents,
+            output.as_path().to_str().unwrap(),
+            &FileModes::default(),
+        )
+        .unwrap();
         let written_contents = fs::read_to_string(output).unwrap();
         assert_eq!(written_contents, contents);
     }
@@ -64,11 +205,105 @@ mod test {
         let working_dir = TempDir::new().unwrap();
         let output = working_dir.path().join("out");
         let contents = rand_string();
-        decode_and_write_file(&base64::encode(&contents), output.as_path().to_str().unwrap()).unwrap();
+        decode_and_write_file(
+            &base64::encode(&contents),
+            output.as_path().to_str().unwrap(),
+            &FileModes::default(),
+        )
+        .unwrap();
         let written_contents = fs::read_to_string(output).unwrap();
         assert_eq!(written_contents, contents);
     }
 
+    #[test]
+    fn ensure_absent_test() {
+        let working_dir = TempDir::new().unwrap();
+        let output = working_dir.path().join("out");
+        ensure_absent(&output).unwrap();
+        write_file("", output.to_str().unwrap(), &FileModes::default()).unwrap();
+        assert!(ensure_absent(&output)
+            .unwrap_err()
+            .to_string()
+            .contains("refusing to overwrite"));
+    }
+
+    #[test]
+    fn list_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        for name in &["b", "a", "c"] {
+            write_file(
+                "",
+                working_dir.path().join(name).to_str().unwrap(),
+                &FileModes::default(),
+            )
+            .unwrap();
+        }
+        let entries = list_directory(working_dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(entries, vec!["a", "b", "c"]);
+        let missing = working_dir.path().join("missing");
+        assert!(list_directory(missing.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn path_str_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(
+            path_str(Path::new("/backup/a.tgz")).unwrap(),
+            "/backup/a.tgz"
+        );
+        let error = path_str(Path::new(OsStr::from_bytes(b"/backup/\xff.tgz"))).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Path /backup/\u{fffd}.tgz is not valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn file_modes_test() {
+        let working_dir = TempDir::new().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let modes = FileModes::default();
+        let output = working_dir.path().join("containers/web/backup.json");
+        write_file("{}", output.to_str().unwrap(), &modes).unwrap();
+        assert_eq!(mode(&output), 0o600);
+        assert_eq!(mode(&working_dir.path().join("containers/web")), 0o700);
+        assert_eq!(mode(&working_dir.path().join("containers")), 0o700);
+        assert!(modes.args().is_empty());
+
+        let modes = FileModes {
+            directory: 0o750,
+            file: 0o640,
+        };
+        fs::set_permissions(working_dir.path(), Permissions::from_mode(0o755)).unwrap();
+        let output = working_dir.path().join("volumes/data/archive.tgz");
+        write_file("", output.to_str().unwrap(), &modes).unwrap();
+        assert_eq!(mode(&output), 0o640);
+        assert_eq!(mode(&working_dir.path().join("volumes")), 0o750);
+        // Existing directories are left alone
+        assert_eq!(mode(working_dir.path()), 0o755);
+        assert_eq!(modes.args(), vec!["--dir-mode=0750", "--file-mode=0640"]);
+    }
+
+    #[test]
+    fn parse_mode_test() {
+        assert_eq!(parse_mode("0700").unwrap(), 0o700);
+        assert_eq!(parse_mode("640").unwrap(), 0o640);
+        assert!(parse_mode("0800").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rwx").is_err());
+    }
+
+    #[test]
+    fn available_space_test() {
+        let working_dir = TempDir::new().unwrap();
+        assert!(available_space(working_dir.path()).unwrap() > 0);
+        assert!(available_space(&working_dir.path().join("missing")).is_err());
+    }
+
     fn rand_string() -> String {
         thread_rng()
             .sample_iter(&Alphanumeric)
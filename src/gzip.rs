@@ -0,0 +1,212 @@
+use flate2::write::GzEncoder;
+use flate2::{Compress, Compression, Crc, FlushCompress, Status};
+use std::io::{Error, ErrorKind, Result, Write};
+
+/// Number of input bytes the rsyncable rolling hash covers, boundaries average this far apart
+const RSYNC_BITS: u32 = 12;
+const RSYNC_MASK: u32 = (1 << RSYNC_BITS) - 1;
+/// Hash value marking a block boundary
+const RSYNC_HIT: u32 = RSYNC_MASK >> 1;
+
+/// Size of the buffer compressed output is collected in before being written
+const OUTPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Gzip encoder used for archives
+pub(crate) enum ArchiveEncoder<W: Write> {
+    Standard(GzEncoder<W>),
+    Rsyncable(RsyncableGzEncoder<W>),
+}
+
+impl<W: Write> ArchiveEncoder<W> {
+    /// Create an encoder writing to `inner`
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Writer of compressed data
+    /// * `level` - Compression level
+    /// * `rsyncable` - Reset the compressor at content-defined boundaries
+    ///
+    pub(crate) fn new(inner: W, level: Compression, rsyncable: bool) -> Self {
+        if rsyncable {
+            ArchiveEncoder::Rsyncable(RsyncableGzEncoder::new(inner, level))
+        } else {
+            ArchiveEncoder::Standard(GzEncoder::new(inner, level))
+        }
+    }
+
+    /// Write the rest of the compressed data and the gzip trailer, returning the inner writer
+    pub(crate) fn finish(self) -> Result<W> {
+        match self {
+            ArchiveEncoder::Standard(encoder) => encoder.finish(),
+            ArchiveEncoder::Rsyncable(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for ArchiveEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            ArchiveEncoder::Standard(encoder) => encoder.write(buf),
+            ArchiveEncoder::Rsyncable(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            ArchiveEncoder::Standard(encoder) => encoder.flush(),
+            ArchiveEncoder::Rsyncable(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Gzip encoder producing rsyncable output, like `gzip --rsyncable` and `pigz --rsyncable`
+///
+/// A rolling hash of the last few input bytes picks block boundaries from the content itself,
+/// and the compressor is fully flushed at each one so later output never refers back past it.
+/// Input that is unchanged between runs then compresses to the same bytes once the first
+/// boundary after a change is reached, which lets rsync and deduplicating backup tools transfer
+/// only the changed parts. Output is slightly larger than with `GzEncoder`.
+pub(crate) struct RsyncableGzEncoder<W: Write> {
+    inner: W,
+    compress: Compress,
+    crc: Crc,
+    hash: u32,
+    header_written: bool,
+}
+
+impl<W: Write> RsyncableGzEncoder<W> {
+    /// Create an encoder writing to `inner`
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Writer of compressed data
+    /// * `level` - Compression level
+    ///
+    pub(crate) fn new(inner: W, level: Compression) -> Self {
+        RsyncableGzEncoder {
+            inner,
+            compress: Compress::new(level, false),
+            crc: Crc::new(),
+            hash: 0,
+            header_written: false,
+        }
+    }
+
+    /// Write the rest of the compressed data and the gzip trailer, returning the inner writer
+    pub(crate) fn finish(mut self) -> Result<W> {
+        self.deflate(&[], FlushCompress::Finish)?;
+        self.inner.write_all(&self.crc.sum().to_le_bytes())?;
+        self.inner.write_all(&self.crc.amount().to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Compress `input`, writing all output produced so far with `flush`
+    fn deflate(&mut self, mut input: &[u8], flush: FlushCompress) -> Result<()> {
+        if !self.header_written {
+            // No file name or modification time, so identical input gives identical archives
+            self.inner
+                .write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+            self.header_written = true;
+        }
+        loop {
+            let mut output = Vec::with_capacity(OUTPUT_BUFFER_SIZE);
+            let before = self.compress.total_in();
+            let status = self
+                .compress
+                .compress_vec(input, &mut output, flush)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+            self.inner.write_all(&output)?;
+            let output_full = output.len() == output.capacity();
+            if status == Status::StreamEnd || (input.is_empty() && !output_full) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RsyncableGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut start = 0;
+        for (i, byte) in buf.iter().enumerate() {
+            self.hash = ((self.hash << 1) ^ u32::from(*byte)) & RSYNC_MASK;
+            if self.hash == RSYNC_HIT {
+                self.deflate(&buf[start..=i], FlushCompress::Full)?;
+                start = i + 1;
+            }
+        }
+        self.deflate(&buf[start..], FlushCompress::None)?;
+        self.crc.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.deflate(&[], FlushCompress::Sync)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use rand::{Rng, SeedableRng};
+    use std::io::Read;
+
+    fn compress(input: &[u8]) -> Vec<u8> {
+        let mut encoder = RsyncableGzEncoder::new(vec![], Compression::default());
+        // Write in uneven pieces to check boundaries don't depend on how input is split
+        for chunk in input.chunks(1000) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    fn sample(seed: u64) -> Vec<u8> {
+        // Few distinct bytes, so the input compresses
+        rand::rngs::StdRng::seed_from_u64(seed)
+            .sample_iter(rand::distributions::Uniform::new(0u8, 16))
+            .take(256 * 1024)
+            .collect()
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = vec![];
+        GzDecoder::new(compressed)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn rsyncable_round_trip_test() {
+        let input = sample(1);
+        assert_eq!(decompress(&compress(&input)), input);
+        assert!(decompress(&compress(b"")).is_empty());
+    }
+
+    #[test]
+    fn rsyncable_shared_output_test() {
+        let original = sample(2);
+        let mut changed = original.clone();
+        changed[1000] ^= 0xf;
+
+        // Output after the first boundary following the change is the same, apart from the
+        // trailer's checksum of all input
+        let body = |input: &[u8]| {
+            let compressed = compress(input);
+            compressed[..compressed.len() - 8].to_vec()
+        };
+        let original = body(&original);
+        let changed = body(&changed);
+        assert_ne!(original, changed);
+        let shared = original
+            .iter()
+            .rev()
+            .zip(changed.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(shared > original.len() * 3 / 4);
+    }
+}
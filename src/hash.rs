@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Algorithm used for archive checksums and the file hashes of incremental backups
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    /// Much faster than the SHA-2 algorithms on large archives
+    Blake3,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        HashAlgorithm::Sha256
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(anyhow!(
+                "Invalid hash algorithm {}, expected sha256, sha512 or blake3",
+                s
+            )),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// Return the name the algorithm is given on the command line and in backup files
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Return true for the algorithm backups used before it could be chosen
+    pub fn is_default(&self) -> bool {
+        *self == HashAlgorithm::default()
+    }
+
+    /// Return a hasher computing digests with this algorithm
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(ring::digest::Context::new(&ring::digest::SHA256)),
+            HashAlgorithm::Sha512 => Box::new(ring::digest::Context::new(&ring::digest::SHA512)),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+
+    /// Return the hex-encoded digest of `data`
+    pub fn digest(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Return the hex-encoded digest of the contents of a file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file
+    ///
+    pub fn digest_file(self, path: &Path) -> Result<String> {
+        let mut file =
+            File::open(path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut hasher = self.hasher();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// Reader hashing the bytes read through it, so data can be hashed as it is archived
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Box<dyn Hasher>,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Return a reader hashing what is read from `inner` with `algorithm`
+    pub fn new(inner: R, algorithm: HashAlgorithm) -> Self {
+        HashingReader {
+            inner,
+            hasher: algorithm.hasher(),
+        }
+    }
+
+    /// Return the hex-encoded digest of everything read
+    pub fn finish(self) -> String {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Incremental digest computation, shared by every `HashAlgorithm`
+pub trait Hasher {
+    /// Add `data` to the digest
+    fn update(&mut self, data: &[u8]);
+
+    /// Return the hex-encoded digest of everything added
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for ring::digest::Context {
+    fn update(&mut self, data: &[u8]) {
+        ring::digest::Context::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        hex::encode(ring::digest::Context::finish(*self))
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn digest_test() {
+        assert_eq!(
+            HashAlgorithm::Sha256.digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha512.digest(b"abc"),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+        assert_eq!(
+            HashAlgorithm::Blake3.digest(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    fn digest_file_test() {
+        let working_dir = TempDir::new().unwrap();
+        let path = working_dir.path().join("file");
+        // Larger than the read buffer, so it is hashed in several updates
+        let contents = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        write(&path, &contents).unwrap();
+        for algorithm in &[
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Blake3,
+        ] {
+            assert_eq!(
+                algorithm.digest_file(&path).unwrap(),
+                algorithm.digest(&contents)
+            );
+            assert_eq!(
+                algorithm.name().parse::<HashAlgorithm>().unwrap(),
+                *algorithm
+            );
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn hashing_reader_test() {
+        let contents = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut reader = HashingReader::new(contents.as_slice(), HashAlgorithm::Blake3);
+        let mut copy = vec![];
+        io::copy(&mut reader, &mut copy).unwrap();
+        assert_eq!(copy, contents);
+        assert_eq!(reader.finish(), HashAlgorithm::Blake3.digest(&contents));
+    }
+}
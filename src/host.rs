@@ -0,0 +1,497 @@
+use crate::backup::{
+    backup_container, backup_volume, list_volumes, store_backup_file, BackupOptions,
+    VolumeSelection,
+};
+use crate::cleanup::get_all_containers;
+use crate::container::{download_from_mount, get_volume_mount, read_only_mount};
+use crate::file::path_str;
+use crate::hash::HashAlgorithm;
+use crate::list::{list_backup_directory, sort_by_timestamp};
+use crate::restore::{restore_container, restore_volume, RestoreOptions};
+use crate::watch::{should_back_up, LabelSelector};
+use anyhow::{Context, Result};
+use bollard::container::InspectContainerOptions;
+use bollard::models::{Mount, MountTypeEnum};
+use bollard::volume::CreateVolumeOptions;
+use bollard::Docker;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// File name of the index tying together the volume and container backups of a host backup
+pub const HOST_MANIFEST: &str = "host-manifest.json";
+
+/// Volume backed up by `backup_host`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HostVolume {
+    pub name: String,
+    /// Archive relative to the backup location
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+/// Container backed up by `backup_host`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HostContainer {
+    pub name: String,
+    /// Container backup file relative to the backup location
+    pub backup_file: PathBuf,
+    /// Volumes of the host backup the container mounts, which its backup file leaves out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+}
+
+/// Index of the volumes and containers backed up together by `backup_host`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct HostManifest {
+    pub volumes: Vec<HostVolume>,
+    pub containers: Vec<HostContainer>,
+}
+
+/// Outcome of `backup_host`
+#[derive(Serialize, Debug, Default)]
+pub struct HostBackupReport {
+    /// Host manifest relative to the backup location
+    pub manifest: PathBuf,
+    pub volumes: Vec<String>,
+    pub containers: Vec<String>,
+    /// Volumes mounted by more than one container, with the containers mounting them
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub shared_volumes: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_volumes: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_containers: Vec<String>,
+}
+
+impl HostBackupReport {
+    /// Return true if any volume or container failed to back up
+    pub fn has_failures(&self) -> bool {
+        !self.failed_volumes.is_empty() || !self.failed_containers.is_empty()
+    }
+}
+
+/// Outcome of `restore_host`
+#[derive(Serialize, Debug, Default)]
+pub struct HostRestoreReport {
+    pub volumes: Vec<String>,
+    pub containers: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_volumes: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_containers: Vec<String>,
+}
+
+impl HostRestoreReport {
+    /// Return true if any volume or container failed to restore
+    pub fn has_failures(&self) -> bool {
+        !self.failed_volumes.is_empty() || !self.failed_containers.is_empty()
+    }
+}
+
+/// Back up every volume and container on the Docker host, tied together by a host manifest
+///
+/// Volumes are backed up first, each once even if several containers mount it, and left out of
+/// the container backups, which the host manifest links to the volumes they mount. Volumes and
+/// containers that fail to back up don't stop the others, they are listed in the report and the
+/// manifest is written with everything that succeeded. The manifest is written to
+/// `dockyard/hosts/<timestamp>/host-manifest.json`.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `selector` - Only back up containers and volumes with this label, and volumes they mount
+/// * `backup_mount` - Mount representing backup destination
+/// * `options` - Backup options
+///
+pub async fn backup_host(
+    docker: &Docker,
+    selector: Option<&LabelSelector>,
+    backup_mount: Mount,
+    options: &BackupOptions,
+) -> Result<HostBackupReport> {
+    let mut containers = vec![];
+    for container in get_all_containers(docker, true).await? {
+        if !should_back_up(&container)
+            || !selector.map_or(true, |s| s.matches(container.labels.as_ref()))
+        {
+            continue;
+        }
+        let name = container.names.unwrap().first().unwrap().replace("/", "");
+        let volumes = get_container_volumes(docker, &name).await?;
+        containers.push((name, volumes));
+    }
+    let usage = volume_usage(&containers);
+
+    let destination = match backup_mount.typ {
+        Some(MountTypeEnum::VOLUME) => backup_mount.source.clone(),
+        _ => None,
+    };
+    let mut report = HostBackupReport::default();
+    let mut manifest = HostManifest::default();
+    for volume in list_volumes(docker, VolumeSelection::All).await? {
+        if destination.as_ref() == Some(&volume) || options.exclude_volumes.contains(&volume) {
+            log::debug!("Skipping volume {}", volume);
+            continue;
+        }
+        let labels = docker.inspect_volume(&volume).await?.labels;
+        if !usage.contains_key(&volume) && !selector.map_or(true, |s| s.matches(Some(&labels))) {
+            log::debug!("Skipping volume {} not matching label selector", volume);
+            continue;
+        }
+        match backup_volume(docker, volume.clone(), backup_mount.clone(), options).await {
+            Ok(archive) => {
+                log::info!(
+                    "Successfully backed up volume {} to {}",
+                    volume,
+                    archive.path.display()
+                );
+                report.volumes.push(volume.clone());
+                manifest.volumes.push(HostVolume {
+                    name: volume,
+                    path: archive.path,
+                    checksum: archive.checksum,
+                    hash_algorithm: options.hash_algorithm,
+                    labels,
+                });
+            }
+            Err(e) => {
+                log::error!("Failed to back up volume {}: {:#}", volume, e);
+                report.failed_volumes.push(volume);
+            }
+        }
+    }
+
+    // Volumes were backed up above, or failed to, so container backups leave them out
+    let mut container_options = options.clone();
+    container_options.exclude_volumes.extend(
+        report
+            .volumes
+            .iter()
+            .chain(report.failed_volumes.iter())
+            .cloned(),
+    );
+    for (name, volumes) in containers {
+        match backup_container(docker, &name, backup_mount.clone(), &container_options).await {
+            Ok(result) => {
+                log::info!(
+                    "Successfully backed up container {} to {}",
+                    name,
+                    result.path.display()
+                );
+                report.containers.push(name.clone());
+                manifest.containers.push(HostContainer {
+                    name,
+                    backup_file: result.path,
+                    volumes: volumes
+                        .into_iter()
+                        .filter(|v| report.volumes.contains(v))
+                        .collect(),
+                });
+            }
+            Err(e) => {
+                log::error!("Failed to back up container {}: {:#}", name, e);
+                report.failed_containers.push(name);
+            }
+        }
+    }
+    report.shared_volumes = usage
+        .into_iter()
+        .filter(|(_, containers)| containers.len() > 1)
+        .collect();
+
+    report.manifest = Path::new("dockyard/hosts")
+        .join(options.timezone.timestamp())
+        .join(HOST_MANIFEST);
+    log::info!("Writing host manifest {}", report.manifest.display());
+    let contents = serde_json::to_string_pretty(&manifest)?;
+    store_backup_file(
+        docker,
+        backup_mount,
+        &report.manifest,
+        contents.as_bytes(),
+        options,
+    )
+    .await?;
+    Ok(report)
+}
+
+/// Return names of the volumes mounted by a container
+async fn get_container_volumes(docker: &Docker, container: &str) -> Result<Vec<String>> {
+    let info = docker
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+        .with_context(|| format!("Unable to inspect container {}", container))?;
+    Ok(info
+        .mounts
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|m| m.typ.as_deref() == Some("volume"))
+        .filter_map(|m| m.name)
+        .collect())
+}
+
+/// Return the containers mounting each volume
+///
+/// # Arguments
+///
+/// * `containers` - Names of containers with the volumes they mount
+///
+fn volume_usage(containers: &[(String, Vec<String>)]) -> BTreeMap<String, Vec<String>> {
+    let mut usage = BTreeMap::new();
+    for (container, volumes) in containers {
+        for volume in volumes {
+            usage
+                .entry(volume.clone())
+                .or_insert_with(Vec::new)
+                .push(container.clone());
+        }
+    }
+    usage
+}
+
+/// Return the most recent host manifest in the backup location
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn find_latest_host_manifest(docker: &Docker, backup_mount: Mount) -> Result<PathBuf> {
+    let source = backup_mount.source.clone().unwrap();
+    let directory = Path::new("dockyard/hosts");
+    let entries = list_backup_directory(docker, directory, backup_mount).await?;
+    sort_by_timestamp(entries.into_iter().map(|e| directory.join(e)).collect())
+        .pop()
+        .map(|d| d.join(HOST_MANIFEST))
+        .ok_or_else(|| anyhow!("No host backups found in {}", source))
+}
+
+/// Restore every volume and container of a host backup
+///
+/// Volumes are restored first, with the labels they were backed up with, so that restored
+/// containers mount them. Volumes and containers that fail to restore don't stop the others,
+/// they are listed in the report.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `manifest_file` - Host manifest relative to `backup_mount`
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn restore_host(
+    docker: &Docker,
+    manifest_file: &Path,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<HostRestoreReport> {
+    let contents =
+        download_from_mount(docker, read_only_mount(backup_mount.clone()), manifest_file)
+            .await
+            .with_context(|| format!("Unable to read {}", manifest_file.display()))?;
+    let manifest: HostManifest = serde_json::from_slice(&contents)
+        .with_context(|| format!("Unable to parse {}", manifest_file.display()))?;
+    let mut report = HostRestoreReport::default();
+    for volume in manifest.volumes {
+        match restore_host_volume(docker, &volume, backup_mount.clone(), options).await {
+            Ok(_) => {
+                log::info!("Successfully restored volume {}", volume.name);
+                report.volumes.push(volume.name);
+            }
+            Err(e) => {
+                log::error!("Failed to restore volume {}: {:#}", volume.name, e);
+                report.failed_volumes.push(volume.name);
+            }
+        }
+    }
+    for container in manifest.containers {
+        let backup_file = match path_str(&container.backup_file) {
+            Ok(backup_file) => backup_file,
+            Err(e) => {
+                log::error!("Failed to restore container {}: {:#}", container.name, e);
+                report.failed_containers.push(container.name);
+                continue;
+            }
+        };
+        match restore_container(
+            docker,
+            backup_file,
+            Some(&container.name),
+            backup_mount.clone(),
+            options,
+        )
+        .await
+        {
+            Ok(name) => {
+                log::info!("Successfully restored container {}", name);
+                report.containers.push(name);
+            }
+            Err(e) => {
+                log::error!("Failed to restore container {}: {:#}", container.name, e);
+                report.failed_containers.push(container.name);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Create a volume of a host backup with its labels and restore its contents
+async fn restore_host_volume(
+    docker: &Docker,
+    volume: &HostVolume,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<()> {
+    docker
+        .create_volume(CreateVolumeOptions {
+            name: volume.name.clone(),
+            driver: "local".to_string(),
+            driver_opts: Default::default(),
+            labels: volume.labels.clone(),
+        })
+        .await
+        .with_context(|| format!("Failed to create volume {}", volume.name))?;
+    restore_volume(
+        docker,
+        path_str(&volume.path)?.to_string(),
+        backup_mount,
+        get_volume_mount(volume.name.clone()),
+        options,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::container::{check_image, get_backup_directory_mount};
+    use bollard::container::{Config, CreateContainerOptions, RemoveContainerOptions};
+    use bollard::models::HostConfig;
+    use bollard::volume::RemoveVolumeOptions;
+    use log::LevelFilter;
+    use simple_logger::SimpleLogger;
+    use std::fs::read_to_string;
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    #[test]
+    fn volume_usage_test() {
+        let containers = vec![
+            (
+                "a".to_string(),
+                vec!["shared".to_string(), "own".to_string()],
+            ),
+            ("b".to_string(), vec!["shared".to_string()]),
+            ("c".to_string(), vec![]),
+        ];
+        let usage = volume_usage(&containers);
+        assert_eq!(usage["shared"], vec!["a", "b"]);
+        assert_eq!(usage["own"], vec!["a"]);
+        assert_eq!(usage.len(), 2);
+    }
+
+    #[test]
+    fn backup_and_restore_host_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let test_id = Uuid::new_v4().to_string();
+        let label = format!("com.github.aig787.dockyard.test={}", test_id);
+        let selector = label.parse::<LabelSelector>().unwrap();
+        let volume = format!("host_test_volume_{}", test_id);
+        let containers = vec![
+            format!("host_test_container_a_{}", test_id),
+            format!("host_test_container_b_{}", test_id),
+        ];
+        let working_dir = TempDir::new().unwrap();
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let report = rt.block_on(async {
+            check_image(&docker, "alpine:latest").await.unwrap();
+            for container in &containers {
+                let mut labels = HashMap::new();
+                labels.insert(selector.key.as_str(), test_id.as_str());
+                docker
+                    .create_container(
+                        Some(CreateContainerOptions {
+                            name: container.as_str(),
+                        }),
+                        Config {
+                            image: Some("alpine:latest"),
+                            labels: Some(labels),
+                            host_config: Some(HostConfig {
+                                binds: Some(vec![format!("{}:/data", volume)]),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                    )
+                    .await
+                    .unwrap();
+            }
+            let report = backup_host(
+                &docker,
+                Some(&selector),
+                backup_mount.clone(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            remove_containers_and_volume(&docker, &containers, &volume).await;
+            report
+        });
+        assert!(!report.has_failures());
+        assert_eq!(report.volumes, vec![volume.clone()]);
+        assert_eq!(report.containers.len(), 2);
+        assert_eq!(report.shared_volumes[&volume].len(), 2);
+        let manifest: HostManifest = serde_json::from_str(
+            &read_to_string(working_dir.path().join(&report.manifest)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest.volumes.len(), 1);
+        assert!(manifest
+            .containers
+            .iter()
+            .all(|c| c.volumes == vec![volume.clone()]));
+
+        rt.block_on(async {
+            let latest = find_latest_host_manifest(&docker, backup_mount.clone())
+                .await
+                .unwrap();
+            assert_eq!(latest, report.manifest);
+            let restored = restore_host(&docker, &latest, backup_mount, &Default::default())
+                .await
+                .unwrap();
+            assert!(!restored.has_failures());
+            assert_eq!(restored.containers.len(), 2);
+            docker.inspect_volume(&volume).await.unwrap();
+            remove_containers_and_volume(&docker, &containers, &volume).await;
+        });
+    }
+
+    async fn remove_containers_and_volume(docker: &Docker, containers: &[String], volume: &str) {
+        for container in containers {
+            docker
+                .remove_container(
+                    container,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap();
+        }
+        docker
+            .remove_volume(volume, Some(RemoveVolumeOptions { force: true }))
+            .await
+            .unwrap();
+    }
+}
@@ -15,6 +15,8 @@
 //! cargo install dockyard
 //! ```
 //!
+//! Dockyard needs Docker 1.13 (API version 1.25) or later, and refuses to run against older daemons.
+//!
 //! ## Usage
 //!
 //! ### docker-compose
@@ -66,8 +68,216 @@
 //!
 //! # Monitor and back up all containers
 //! dockyard watch --exclude-volumes <volumes> --exclude-containers <containers>
+//!
+//! # Restore container to another Docker host
+//! dockyard restore --host tcp://<host>:2375 container <relative-backup-file> <backup-directory> <container>
+//!
+//! # Show progress of a running watch
+//! dockyard status --follow
+//!
+//! # Restore most recent backup of container
+//! dockyard restore container --latest <container> <backup-directory> <new-container>
+//!
+//! # Back up container with its image and restore it without pulling
+//! dockyard backup container --include-image <container> <backup-directory>
+//! dockyard restore container --no-pull <relative-backup-file> <backup-directory> <new-container>
+//!
+//! # Show config changes restoring a backup would make to a running container
+//! dockyard diff container <relative-backup-file> <backup-directory> <container>
+//!
+//! # Back up only files changed since the last full backup
+//! dockyard backup volume --incremental <volume> <backup-directory>
+//!
+//! # Roll back a volume mounted in an existing container, stopping it while restoring
+//! dockyard restore volume-into --stop --clean <container> <mount-target> <relative_archive_path> <backup-directory>
+//!
+//! # Back up container with a gzipped container backup file
+//! dockyard backup container --compress-manifest <container> <backup-directory>
+//!
+//! # Monitor and back up containers whose names match a regular expression
+//! dockyard watch --match 'myapp-.*' <backup-directory>
+//!
+//! # Run commands in the container around backing up its mounts
+//! dockyard backup container --pre-backup-hook 'redis-cli SAVE' --post-backup-hook 'rm -f /data/backup.lock' <container> <backup-directory>
+//!
+//! # Move the latest backup of a container to another host as a single file
+//! dockyard pack --latest <container> <backup-directory> <container>.tar
+//! dockyard unpack <container>.tar <backup-directory>
+//!
+//! # Deprioritize backup disk IO, needs a host with cgroup blkio/io weight support (e.g. the BFQ scheduler)
+//! dockyard watch --blkio-weight 100 <backup-directory>
+//!
+//! # Restore volume archive to a host directory
+//! dockyard restore volume --volume-type directory <relative_archive_path> <backup-directory> <directory>
+//!
+//! # Fail instead of overwriting existing archives
+//! dockyard backup volume --no-clobber <volume> <backup-directory>
+//!
+//! # Back up databases hourly and everything else daily
+//! dockyard watch --cron '0 0 * * * * *' --label com.example.tier=db --cron '0 0 0 * * * *' <backup-directory>
+//!
+//! # Leave paths matching a gitignore-style pattern file out of volume archives
+//! dockyard backup volume --exclude-from .dockyardignore <volume> <backup-directory>
+//!
+//! # Give up on volumes that take longer than 30 minutes to back up, e.g. a hung NFS mount
+//! dockyard watch --backup-timeout 30m <backup-directory>
+//!
+//! # Remove all dockyard containers, printing what was removed as JSON
+//! dockyard cleanup --json
+//!
+//! # Fail if the image tag now points at a different image than when the container was backed up
+//! dockyard restore container --strict-image <relative-backup-file> <backup-directory> <new-container>
+//!
+//! # Restore a volume for a container running as a non-root user
+//! dockyard restore volume --chown 999:999 <relative_archive_path> <backup-directory> <volume>
+//!
+//! # Restore a copy of a container next to the original, with its volumes renamed
+//! dockyard restore container --volume-prefix copy_ <relative-backup-file> <backup-directory> <new-container>
+//!
+//! # Never overwrite or delete anything in the backup location, old backups must be expired outside dockyard (e.g. storage lifecycle rules)
+//! dockyard watch --append-only <backup-directory>
+//!
+//! # Compress volumes under 1MB in memory instead of starting a helper container for each
+//! dockyard watch --inline-threshold 1M <backup-directory>
+//!
+//! # Keep a secret out of the container backup file and set it again from a dotenv file on restore
+//! dockyard backup container --redact-env DB_PASSWORD <container> <backup-directory>
+//! dockyard restore container --env-from-file .env <relative_container_backup_path> <backup-directory> <container>
+//!
+//! # Keep logs of an unattended watch, including helper container output, in a file rotated at 10MB
+//! dockyard watch --log-file /var/log/dockyard.log --log-file-max-size 10M --log-file-keep 5 <backup-directory>
+//!
+//! # Restore a container into a test environment under a generated name
+//! dockyard restore container --latest --name-template '{original}-restored-{timestamp}' <container> <backup-directory>
+//!
+//! # Back up volumes not used by any container
+//! dockyard backup volumes --dangling <backup-directory>
+//!
+//! # Restore a container booting into a shell instead of its entrypoint, e.g. to repair its data
+//! dockyard restore container --entrypoint /bin/sh <relative_container_backup_path> <backup-directory> <container> --cmd -c 'sleep infinity'
+//!
+//! # List container backups and verify archive checksums, exiting with 1 if any are corrupt or missing
+//! dockyard list --verify <backup-directory>
+//!
+//! # Back up a container, then remove all but its 7 most recent backups and the archives only they reference
+//! dockyard backup container --keep-last 7 <container> <backup-directory>
+//!
+//! # Back up a directory containing FIFOs or device nodes, which are skipped by default
+//! dockyard backup directory --include-special <directory> <backup-directory>
+//!
+//! # Remove backups taken before a date, along with archives no remaining backup references
+//! dockyard prune --until 2020-10-22 <backup-directory>
+//!
+//! # Back up a container with archives named *.tar.gz
+//! dockyard backup container --extension tar.gz <container> <backup-directory>
+//!
+//! # Restore a rehearsal copy of a container alongside the original, then remove it
+//! dockyard restore container --rehearse <relative-backup-file> <backup-directory>
+//! dockyard cleanup-rehearsal
+//!
+//! # Back up a container with archives that sync efficiently with rsync or restic
+//! dockyard backup container --rsyncable <container> <backup-directory>
+//!
+//! # Back up a container and print which mounts were backed up and why others were skipped, logs go to stderr with --json
+//! dockyard backup container --json <container> <backup-directory>
+//!
+//! # Back up a container to an rclone or other FUSE mount only visible on the host
+//! dockyard backup container --destination-on-host <container> /mnt/rclone/backups
+//!
+//! # Back up a container into a self-contained <container>/<timestamp>/ directory
+//! dockyard backup container --layout flat <container> <backup-directory>
+//!
+//! # Back up every volume and container on the host, then restore them all on a new host
+//! dockyard backup host <backup-directory>
+//! dockyard restore host --latest <backup-directory>
+//!
+//! # Back up a container with BLAKE3 checksums, which are faster to compute and verify
+//! dockyard backup container --hash-algo blake3 <container> <backup-directory>
+//!
+//! # Restore a container onto a smaller host with lower resource limits
+//! dockyard restore container --memory 512M --cpus 1.5 <relative-backup-file> <backup-directory> <container>
+//!
+//! # Pause scheduled backups of a running watch, then resume them (USR1 toggles), the PID is shown by dockyard status
+//! kill -USR1 <watch-pid>
+//! kill -USR2 <watch-pid>
+//!
+//! # Restore bind mounts that were under /srv/data on the original host to /mnt/data
+//! dockyard restore container --bind-remap /srv/data=/mnt/data <relative-backup-file> <backup-directory> <container>
+//!
+//! # Back up a container with backups readable by the backup group
+//! dockyard backup container --dir-mode 0750 --file-mode 0640 <container> <backup-directory>
+//!
+//! # Check a container backup can be restored under a name without restoring it, exiting with 1 if it can't
+//! dockyard restore container --check-only <relative-backup-file> <backup-directory> <container>
+//!
+//! # Print failures as JSON on stderr for scripts, e.g. {"kind":"docker_connection","exit_code":3,"errors":[...]}
+//! dockyard --errors-json backup container <container> <backup-directory>
+//!
+//! # Back up only one directory of a volume, restored to the same place within the volume
+//! dockyard backup volume --volume-subpath data/uploads <volume> <backup-directory>
+//!
+//! # Back up a volume from a btrfs snapshot
+//! dockyard backup volume --snapshot <volume> <backup-directory>
+//!
+//! # Pull the latest image for the backed up tag before restoring
+//! dockyard restore container --refresh-image <relative-backup-file> <backup-directory> <new-container>
+//!
+//! # Build a catalog that list, prune and --latest query instead of reading every backup
+//! dockyard reindex <backup-directory>
+//!
+//! # Restore into a new volume and recreate the container with it, keeping the old volume for rollback
+//! dockyard restore volume-into --atomic-swap <container> <mount-target> <relative_archive_path> <backup-directory>
+//!
+//! # Name backups with local time instead of UTC
+//! dockyard backup container --timezone local <container> <backup-directory>
+//!
+//! # Alert if any container hasn't been backed up in the last day
+//! dockyard list --stale-after 24h <backup-directory>
+//!
+//! # Run helper containers without a network, which they don't need
+//! dockyard --helper-network none backup container <container> <backup-directory>
+//!
+//! # Restore a volume over partially recovered data, keeping files that are newer than the backup
+//! dockyard restore volume --newer <relative-archive-path> <backup-directory> <volume>
+//!
+//! # Print the docker commands a restore would run as a shell script, to review or customize it
+//! dockyard restore container --print-script <relative-backup-file> <backup-directory> <container> > restore.sh
+//!
+//! # Correlate log lines and helper containers of one run, set as the com.github.aig787.dockyard.trace-id label
+//! dockyard --trace-id nightly-web backup container <container> <backup-directory>
+//!
+//! # Compare archive sizes and times of each codec and compression level on sample data
+//! dockyard bench-compress <directory>
+//!
+//! # Only watch containers opted in with your own label instead of every container that doesn't opt out
+//! dockyard watch --enable-label mycompany.backup=true <backup-directory>
+//!
+//! # Finish an interrupted backup of a container, reusing the archives it already wrote
+//! dockyard backup container --resume <container> <backup-directory>
+//!
+//! # Back up from the rootful daemon and restore into a rootless one
+//! dockyard backup --host unix:///var/run/docker.sock container <container> <backup-directory>
+//! dockyard restore --host unix:///run/user/1000/docker.sock container <relative-backup-file> <backup-directory> <container>
+//!
+//! # Record file hashes at backup time and check every restored file against them
+//! dockyard backup volume --file-manifest <volume> <backup-directory>
+//! dockyard restore volume --verify-files <relative_archive_path> <backup-directory> <volume>
+//!
+//! # Skip regenerable build cache volumes, including your own ccache volumes
+//! dockyard watch --skip-cache-volumes --cache-volume-pattern ccache <backup-directory>
 //! ```
 //!
+//! ### Podman
+//! Dockyard can talk to Podman through its Docker-compatible API with `--runtime podman`. The
+//! socket defaults to `$XDG_RUNTIME_DIR/podman/podman.sock` (`/run/user/<uid>/podman/podman.sock`
+//! for rootless Podman), falling back to `/run/podman/podman.sock`. Start it with
+//! `systemctl --user start podman.socket`.
+//!
+//! Known limitations:
+//! * Short image names are pulled from `docker.io`, regardless of `unqualified-search-registries`
+//! * Rootless Podman can only back up and restore volumes the user has access to
+//! * Containers in pods are restored as standalone containers
+//!
 //! ## Example Back Up and Restore
 //! ```shell
 //! ❯ dockyard backup container nginx /tmp
@@ -87,6 +297,16 @@
 //! 2020-10-22 16:10:51,412 INFO  [dockyard::restore] Successfully restored mount hello
 //! 2020-10-22 16:10:51,485 INFO  [dockyard::restore] Successfully restored container nginx-restore
 //! ```
+//! ## Append-Only Backups
+//!
+//! With `--append-only` dockyard never overwrites or deletes anything in the backup location. Existing
+//! archives and container backup files cause the backup to fail, partial archives from failed backups are
+//! left in place and `--incremental` and `--skip-unchanged` are refused because they rewrite state files.
+//! Backups aren't recorded in the `dockyard.db` catalog, so run `dockyard reindex` from a job with write access to
+//! keep using it.
+//! Restores always mount the backup location read-only. Dockyard has no retention of its own in this mode,
+//! so expire old backups outside of it, e.g. with storage lifecycle rules or a separate job with write access.
+//!
 //! ## Building the docs
 //! ```shell
 //! make docs
@@ -105,11 +325,34 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate serde;
 
 pub mod backup;
+pub mod bench;
+pub mod catalog;
+pub mod check;
 pub mod cleanup;
 pub mod container;
+pub mod diff;
+pub mod exclude;
+pub mod failure;
 pub mod file;
+pub mod gzip;
+pub mod hash;
+pub mod host;
+pub mod list;
+pub mod logfile;
+pub mod mount;
+pub mod network;
+pub mod pack;
+pub mod progress;
+pub mod prune;
 pub mod restore;
+pub mod resume;
+pub mod script;
+pub mod snapshot;
+pub mod status;
+pub mod swarm;
 pub mod watch;
@@ -0,0 +1,1012 @@
+use crate::backup::{archive_checksum, FLAT_CONTAINER_BACKUP, POINTER_EXTENSION};
+use crate::catalog::{read_catalog_entries, CatalogEntry};
+use crate::container::{
+    handle_container_output, last_stdout_line, read_only_mount, run_dockyard_command,
+};
+use crate::file::path_str;
+use crate::hash::HashAlgorithm;
+use crate::restore::{read_container_backup, read_incremental_info};
+use anyhow::{Context, Result};
+use bollard::models::Mount;
+use bollard::Docker;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Range of backup timestamps, inclusive at both ends
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TimeWindow {
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+}
+
+impl TimeWindow {
+    /// Return true if `timestamp` falls within the window
+    pub fn contains(&self, timestamp: &DateTime<FixedOffset>) -> bool {
+        self.since.map_or(true, |since| *timestamp >= since)
+            && self.until.map_or(true, |until| *timestamp <= until)
+    }
+
+    /// Return true if the backup at `path` was taken within the window
+    ///
+    /// Paths not named with a timestamp are never within a window.
+    pub fn contains_backup(&self, path: &Path) -> bool {
+        parse_backup_timestamp(path).map_or(false, |t| self.contains(&t))
+    }
+}
+
+/// Parse a date bounding a `TimeWindow`
+///
+/// Accepts RFC3339 timestamps, timestamps without an offset, which are taken as UTC, and dates.
+/// A date stands for the start of that day in UTC, or its last instant with `end_of_day`, so
+/// that `--until` a date includes the backups taken on it.
+///
+/// # Arguments
+///
+/// * `date` - Date or timestamp
+/// * `end_of_day` - Whether a date stands for the end of the day
+///
+pub fn parse_window_date(date: &str, end_of_day: bool) -> Result<DateTime<FixedOffset>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(date) {
+        return Ok(timestamp);
+    }
+    let utc = FixedOffset::east(0);
+    if let Ok(timestamp) = NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Ok(utc.from_utc_datetime(&timestamp));
+    }
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(day) if end_of_day => {
+            Ok(utc.from_utc_datetime(&day.and_hms_nano(23, 59, 59, 999_999_999)))
+        }
+        Ok(day) => Ok(utc.from_utc_datetime(&day.and_hms(0, 0, 0))),
+        Err(_) => Err(anyhow!(
+            "Invalid date {}, expected YYYY-MM-DD or an RFC3339 timestamp",
+            date
+        )),
+    }
+}
+
+/// Result of checking an archive against the checksum recorded for it
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveStatus {
+    Ok,
+    Corrupt,
+    Missing,
+    /// No checksum was recorded, as for image archives
+    Unverified,
+}
+
+impl fmt::Display for ArchiveStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            ArchiveStatus::Ok => "OK",
+            ArchiveStatus::Corrupt => "CORRUPT",
+            ArchiveStatus::Missing => "MISSING",
+            ArchiveStatus::Unverified => "UNVERIFIED",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Archive referenced by a container backup
+#[derive(Serialize, Debug)]
+pub struct ListedArchive {
+    pub path: PathBuf,
+    pub checksum: Option<String>,
+    #[serde(skip_serializing_if = "HashAlgorithm::is_default")]
+    pub algorithm: HashAlgorithm,
+    /// Set when the listing was verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ArchiveStatus>,
+}
+
+/// Checksums computed by the `checksum` helper
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ArchiveChecksums {
+    /// Checksum of each archive, null for archives that don't exist
+    pub checksums: BTreeMap<String, Option<String>>,
+    /// Archives that restoring an archive also reads, the archive a pointer refers to followed by
+    /// the bases of incremental archives
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, Vec<String>>,
+}
+
+/// Container backup file and the archives it references
+#[derive(Serialize, Debug)]
+pub struct ListedBackup {
+    pub container: String,
+    pub backup_file: PathBuf,
+    pub archives: Vec<ListedArchive>,
+}
+
+impl From<CatalogEntry> for ListedBackup {
+    fn from(entry: CatalogEntry) -> Self {
+        let algorithm = entry.algorithm;
+        ListedBackup {
+            container: entry.container,
+            backup_file: entry.backup_file,
+            archives: entry
+                .archives
+                .into_iter()
+                .map(|a| ListedArchive {
+                    path: a.path,
+                    checksum: a.checksum,
+                    algorithm,
+                    status: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Number of archives with each status in a verified listing
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ListingSummary {
+    pub ok: usize,
+    pub corrupt: usize,
+    pub missing: usize,
+    pub unverified: usize,
+}
+
+impl ListingSummary {
+    /// Return true if any archive is corrupt or missing
+    pub fn has_failures(&self) -> bool {
+        self.corrupt > 0 || self.missing > 0
+    }
+}
+
+/// List entries of a directory in the backup destination
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `directory` - Directory relative to `backup_mount`
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn list_backup_directory(
+    docker: &Docker,
+    directory: &Path,
+    backup_mount: Mount,
+) -> Result<Vec<String>> {
+    let mounted_directory = Path::new(backup_mount.target.as_ref().unwrap()).join(directory);
+    let log_prefix = format!("list {}", directory.display());
+    let (exit_code, logs) = run_dockyard_command(
+        docker,
+        Some(vec![read_only_mount(backup_mount)]),
+        vec!["ls", path_str(&mounted_directory)?],
+    )
+    .await?;
+    handle_container_output(exit_code, &log_prefix, &logs)?;
+    let entries = last_stdout_line(&logs).unwrap_or_else(|| "[]".to_string());
+    serde_json::from_str(&entries)
+        .with_context(|| format!("Unable to parse listing of {}", directory.display()))
+}
+
+/// Return true if `path` exists in the backup destination
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `path` - Path relative to `backup_mount`
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn backup_file_exists(docker: &Docker, path: &Path, backup_mount: Mount) -> Result<bool> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Ok(false),
+    };
+    Ok(list_backup_directory(docker, directory, backup_mount)
+        .await?
+        .contains(&name))
+}
+
+/// Return container backup files for a container, oldest first
+///
+/// Backups in both the nested layout, under `dockyard/containers/<container>`, and the flat
+/// layout, in `<container>/<timestamp>`, are returned. They are read from the catalog if the
+/// backup location has an up to date one.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of backed up container
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn find_container_backups(
+    docker: &Docker,
+    container_name: &str,
+    backup_mount: Mount,
+) -> Result<Vec<PathBuf>> {
+    if let Some(entries) =
+        read_catalog_entries(docker, Some(container_name), backup_mount.clone()).await?
+    {
+        return Ok(sort_by_timestamp(
+            entries.into_iter().map(|e| e.backup_file).collect(),
+        ));
+    }
+    scan_container_backups(docker, container_name, backup_mount).await
+}
+
+/// Return container backup files for a container by listing backup directories, oldest first
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of backed up container
+/// * `backup_mount` - Mount representing backup location
+///
+async fn scan_container_backups(
+    docker: &Docker,
+    container_name: &str,
+    backup_mount: Mount,
+) -> Result<Vec<PathBuf>> {
+    let directory = Path::new("dockyard/containers").join(container_name);
+    let entries = list_backup_directory(docker, &directory, backup_mount.clone()).await?;
+    let mut backup_files = entries
+        .into_iter()
+        .filter(|e| e.ends_with(".json"))
+        .map(|e| directory.join(e))
+        .collect::<Vec<_>>();
+    let flat_directory = Path::new(container_name);
+    let flat_entries = list_backup_directory(docker, flat_directory, backup_mount).await?;
+    backup_files.extend(
+        flat_entries
+            .into_iter()
+            .filter(|e| DateTime::parse_from_rfc3339(e).is_ok())
+            .map(|e| flat_directory.join(e).join(FLAT_CONTAINER_BACKUP)),
+    );
+    Ok(sort_by_timestamp(backup_files))
+}
+
+/// Return the most recent container backup file for a container
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container_name` - Name of backed up container
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn find_latest_container_backup(
+    docker: &Docker,
+    container_name: &str,
+    backup_mount: Mount,
+) -> Result<PathBuf> {
+    let source = backup_mount.source.clone().unwrap();
+    find_container_backups(docker, container_name, backup_mount)
+        .await?
+        .pop()
+        .ok_or_else(|| {
+            anyhow!(
+                "No backups found for container {} in {}",
+                container_name,
+                source
+            )
+        })
+}
+
+/// List container backups in the backup destination taken within `window`, oldest first per
+/// container
+///
+/// Backups are read from the catalog if the backup location has an up to date one, otherwise
+/// every container backup file is read. With `verify`, the checksum of each archive is
+/// recomputed in a single helper container and compared against the checksum recorded when it
+/// was backed up, along with the archives pointers refer to and incremental archives are based on.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup location
+/// * `window` - Time window of backups to list
+/// * `verify` - Whether to check archives against their recorded checksums
+///
+pub async fn list_container_backups(
+    docker: &Docker,
+    backup_mount: Mount,
+    window: &TimeWindow,
+    verify: bool,
+) -> Result<Vec<ListedBackup>> {
+    let mut listed = match read_catalog_entries(docker, None, backup_mount.clone()).await? {
+        Some(entries) => entries
+            .into_iter()
+            .filter(|e| window.contains_backup(&e.backup_file))
+            .map(ListedBackup::from)
+            .collect(),
+        None => read_container_backups(docker, backup_mount.clone(), window).await?,
+    };
+    if verify {
+        verify_archives(docker, &mut listed, backup_mount).await?;
+    }
+    Ok(listed)
+}
+
+/// List container backups taken within `window` by reading every container backup file
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup location
+/// * `window` - Time window of backups to list
+///
+async fn read_container_backups(
+    docker: &Docker,
+    backup_mount: Mount,
+    window: &TimeWindow,
+) -> Result<Vec<ListedBackup>> {
+    let containers = list_backup_directory(
+        docker,
+        Path::new("dockyard/containers"),
+        backup_mount.clone(),
+    )
+    .await?;
+    let mut listed = vec![];
+    for container in containers {
+        let backup_files = scan_container_backups(docker, &container, backup_mount.clone()).await?;
+        for backup_file in backup_files
+            .into_iter()
+            .filter(|f| window.contains_backup(f))
+        {
+            let backup = read_container_backup(docker, path_str(&backup_file)?, &backup_mount)
+                .await
+                .with_context(|| format!("Unable to read {}", backup_file.display()))?;
+            let algorithm = backup.hash_algorithm;
+            let mut archives = backup
+                .mounts
+                .into_iter()
+                .map(|m| ListedArchive {
+                    path: m.path,
+                    checksum: m.checksum,
+                    algorithm,
+                    status: None,
+                })
+                .collect::<Vec<_>>();
+            if let Some(path) = backup.image_archive {
+                archives.push(ListedArchive {
+                    path,
+                    checksum: None,
+                    algorithm,
+                    status: None,
+                });
+            }
+            listed.push(ListedBackup {
+                container: container.clone(),
+                backup_file,
+                archives,
+            });
+        }
+    }
+    Ok(listed)
+}
+
+/// Set the status of every listed archive by checksumming archives in a helper container
+async fn verify_archives(
+    docker: &Docker,
+    listed: &mut [ListedBackup],
+    backup_mount: Mount,
+) -> Result<()> {
+    let target = PathBuf::from(backup_mount.target.as_ref().unwrap());
+    // Archives are checksummed with the algorithm their backup used, one helper per algorithm
+    let mut paths = BTreeMap::<&str, BTreeSet<String>>::new();
+    for archive in listed
+        .iter()
+        .flat_map(|b| b.archives.iter())
+        .filter(|a| a.checksum.is_some() || is_pointer(&a.path))
+    {
+        paths
+            .entry(archive.algorithm.name())
+            .or_default()
+            .insert(target.join(&archive.path).to_string_lossy().to_string());
+    }
+    let mut checksums = ArchiveChecksums::default();
+    for (algorithm, paths) in paths {
+        let mut cmd = vec!["checksum", "--hash-algo", algorithm];
+        cmd.extend(paths.iter().map(String::as_str));
+        let (exit_code, logs) = run_dockyard_command(
+            docker,
+            Some(vec![read_only_mount(backup_mount.clone())]),
+            cmd,
+        )
+        .await?;
+        handle_container_output(exit_code, "checksum archives", &logs)?;
+        let output = last_stdout_line(&logs).unwrap_or_else(|| "{}".to_string());
+        let computed = serde_json::from_str::<ArchiveChecksums>(&output)
+            .context("Unable to parse archive checksums")?;
+        checksums.checksums.extend(computed.checksums);
+        checksums.dependencies.extend(computed.dependencies);
+    }
+    // Archives a pointer refers to or an incremental archive is based on are checked against the
+    // checksum recorded by the backup that wrote them, if it's listed
+    let recorded = listed
+        .iter()
+        .flat_map(|b| b.archives.iter())
+        .filter_map(|a| {
+            let path = target.join(&a.path).to_string_lossy().to_string();
+            a.checksum.clone().map(|checksum| (path, checksum))
+        })
+        .collect::<BTreeMap<_, _>>();
+    for archive in listed.iter_mut().flat_map(|b| b.archives.iter_mut()) {
+        let path = target.join(&archive.path).to_string_lossy().to_string();
+        let dependencies = checksums
+            .dependencies
+            .get(&path)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let own = if is_pointer(&archive.path) {
+            // A pointer is only as good as the archive it refers to
+            match dependencies.first() {
+                Some(_) => ArchiveStatus::Ok,
+                None => ArchiveStatus::Missing,
+            }
+        } else {
+            archive_status(archive.checksum.as_deref(), checksums.checksums.get(&path))
+        };
+        let status = dependencies
+            .iter()
+            .map(|dependency| match checksums.checksums.get(dependency) {
+                Some(Some(_)) => archive_status(
+                    recorded.get(dependency).map(String::as_str),
+                    checksums.checksums.get(dependency),
+                ),
+                _ => ArchiveStatus::Missing,
+            })
+            .fold(own, worst_status);
+        archive.status = Some(status);
+    }
+    Ok(())
+}
+
+/// Return true if `path` is a pointer written for an unchanged directory
+fn is_pointer(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(POINTER_EXTENSION)
+}
+
+/// Return the worse of two statuses, so an archive is flagged if anything it depends on is
+///
+/// # Arguments
+///
+/// * `a` - Status of an archive
+/// * `b` - Status of another archive
+///
+fn worst_status(a: ArchiveStatus, b: ArchiveStatus) -> ArchiveStatus {
+    let severity = |status: &ArchiveStatus| match status {
+        ArchiveStatus::Ok => 0,
+        ArchiveStatus::Unverified => 1,
+        ArchiveStatus::Corrupt => 2,
+        ArchiveStatus::Missing => 3,
+    };
+    if severity(&b) > severity(&a) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Compare a recorded checksum against the checksum computed for an archive
+///
+/// # Arguments
+///
+/// * `expected` - Checksum recorded at backup time
+/// * `actual` - Computed checksum, `Some(None)` if the archive doesn't exist
+///
+fn archive_status(expected: Option<&str>, actual: Option<&Option<String>>) -> ArchiveStatus {
+    match (expected, actual) {
+        (None, _) => ArchiveStatus::Unverified,
+        (Some(expected), Some(Some(actual))) if expected == actual => ArchiveStatus::Ok,
+        (Some(_), Some(Some(_))) => ArchiveStatus::Corrupt,
+        (Some(_), _) => ArchiveStatus::Missing,
+    }
+}
+
+/// Return the checksum of each archive keyed by path, or null for archives that don't exist
+///
+/// Pointers are resolved and incremental archives are followed back to their full archive, and
+/// the archives found are checksummed too.
+///
+/// # Arguments
+///
+/// * `paths` - Paths of archives
+/// * `algorithm` - Algorithm the checksums were recorded with
+///
+pub fn checksum_archives(paths: &[&str], algorithm: HashAlgorithm) -> Result<ArchiveChecksums> {
+    let mut checksums = ArchiveChecksums::default();
+    for path in paths {
+        let dependencies = archive_dependencies(Path::new(path));
+        for archive in
+            std::iter::once(Path::new(path)).chain(dependencies.iter().map(PathBuf::as_path))
+        {
+            let key = path_str(archive)?.to_string();
+            if checksums.checksums.contains_key(&key) {
+                continue;
+            }
+            let checksum = if archive.exists() {
+                archive_checksum(archive, algorithm)?
+            } else {
+                None
+            };
+            checksums.checksums.insert(key, checksum);
+        }
+        if !dependencies.is_empty() {
+            checksums.dependencies.insert(
+                path.to_string(),
+                dependencies
+                    .iter()
+                    .map(|d| path_str(d).map(str::to_string))
+                    .collect::<Result<_>>()?,
+            );
+        }
+    }
+    Ok(checksums)
+}
+
+/// Return the archives restoring `path` also reads
+///
+/// That's the archive a pointer refers to, followed by the base of each incremental archive until
+/// a full archive is reached. The chain stops at the first archive that can't be read, which is
+/// flagged when it's checksummed.
+///
+/// # Arguments
+///
+/// * `path` - Path of archive or pointer
+///
+fn archive_dependencies(path: &Path) -> Vec<PathBuf> {
+    let mut dependencies = vec![];
+    let mut archive = path.to_path_buf();
+    if is_pointer(path) {
+        match read_to_string(path) {
+            Ok(target) => archive = path.with_file_name(target.trim()),
+            Err(_) => return dependencies,
+        }
+        dependencies.push(archive.clone());
+    }
+    while archive.exists() {
+        let base = match read_incremental_info(&archive) {
+            Ok(Some(info)) => archive.with_file_name(&info.base),
+            _ => break,
+        };
+        // Guard against a base that refers back into the chain
+        if base == path || dependencies.contains(&base) {
+            break;
+        }
+        dependencies.push(base.clone());
+        archive = base;
+    }
+    dependencies
+}
+
+/// Count archives by status in a verified listing
+pub fn summarize_listing(listed: &[ListedBackup]) -> ListingSummary {
+    let mut summary = ListingSummary::default();
+    for archive in listed.iter().flat_map(|b| b.archives.iter()) {
+        match archive.status {
+            Some(ArchiveStatus::Ok) => summary.ok += 1,
+            Some(ArchiveStatus::Corrupt) => summary.corrupt += 1,
+            Some(ArchiveStatus::Missing) => summary.missing += 1,
+            Some(ArchiveStatus::Unverified) => summary.unverified += 1,
+            None => {}
+        }
+    }
+    summary
+}
+
+/// Render listing for display, ending with a summary if it was verified
+pub fn format_listing(listed: &[ListedBackup]) -> String {
+    if listed.is_empty() {
+        return "No container backups found".to_string();
+    }
+    let mut lines = vec![];
+    let mut verified = false;
+    for backup in listed {
+        lines.push(format!(
+            "{} {}",
+            backup.container,
+            backup.backup_file.display()
+        ));
+        for archive in &backup.archives {
+            let mut line = format!("  {}", archive.path.display());
+            if let Some(checksum) = &archive.checksum {
+                line.push_str(&format!(" {}:{}", archive.algorithm, checksum));
+            }
+            if let Some(status) = archive.status {
+                verified = true;
+                line.push_str(&format!(" [{}]", status));
+            }
+            lines.push(line);
+        }
+    }
+    if verified {
+        let summary = summarize_listing(listed);
+        lines.push(format!(
+            "{} OK, {} corrupt, {} missing, {} unverified",
+            summary.ok, summary.corrupt, summary.missing, summary.unverified
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Container whose most recent backup is older than the staleness threshold
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StaleContainer {
+    pub container: String,
+    /// Most recent backup file of the container
+    pub last_backup: PathBuf,
+    /// Seconds since the most recent backup was taken
+    pub age_secs: i64,
+}
+
+/// Return the containers whose most recent backup was taken more than `stale_after` before `now`
+///
+/// Backup files not named with a timestamp are ignored.
+///
+/// # Arguments
+///
+/// * `listed` - Container backups to check
+/// * `stale_after` - Maximum age of the most recent backup of each container
+/// * `now` - Time to measure ages from
+///
+pub fn find_stale_containers(
+    listed: &[ListedBackup],
+    stale_after: Duration,
+    now: DateTime<Utc>,
+) -> Vec<StaleContainer> {
+    let mut latest: BTreeMap<&str, (DateTime<FixedOffset>, &Path)> = BTreeMap::new();
+    for backup in listed {
+        let timestamp = match parse_backup_timestamp(&backup.backup_file) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+        let file = backup.backup_file.as_path();
+        let entry = latest
+            .entry(backup.container.as_str())
+            .or_insert((timestamp, file));
+        if timestamp > entry.0 {
+            *entry = (timestamp, file);
+        }
+    }
+    let stale_after =
+        chrono::Duration::from_std(stale_after).unwrap_or_else(|_| chrono::Duration::max_value());
+    latest
+        .into_iter()
+        .map(|(container, (timestamp, path))| {
+            (container, path, now.signed_duration_since(timestamp))
+        })
+        .filter(|(_, _, age)| *age > stale_after)
+        .map(|(container, path, age)| StaleContainer {
+            container: container.to_string(),
+            last_backup: path.to_path_buf(),
+            age_secs: age.num_seconds(),
+        })
+        .collect()
+}
+
+/// Render stale containers for display with the age of their most recent backup
+pub fn format_stale_containers(stale: &[StaleContainer]) -> String {
+    if stale.is_empty() {
+        return "No stale container backups".to_string();
+    }
+    stale
+        .iter()
+        .map(|s| {
+            format!(
+                "{} last backed up {} ago {}",
+                s.container,
+                format_age(s.age_secs),
+                s.last_backup.display()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render an age in seconds with its two largest units, e.g. `2d 3h`
+fn format_age(secs: i64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let mut remaining = secs.max(0);
+    let mut parts = vec![];
+    for (unit, unit_secs) in units.iter() {
+        let value = remaining / unit_secs;
+        remaining %= unit_secs;
+        if value > 0 || !parts.is_empty() {
+            parts.push(format!("{}{}", value, unit));
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// Parse the RFC3339 timestamp a backup file is named with
+///
+/// Flat layout container backup files are named after the directory they are in.
+///
+/// # Arguments
+///
+/// * `path` - Path to backup file or archive
+///
+pub fn parse_backup_timestamp(path: &Path) -> Option<DateTime<FixedOffset>> {
+    if path.file_name()? == FLAT_CONTAINER_BACKUP {
+        return parse_backup_timestamp(path.parent()?);
+    }
+    let mut stem = path.file_name()?.to_str()?;
+    // Archives can have custom extensions with several parts, e.g. `.tar.gz.ref`
+    for _ in 0..4 {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(stem) {
+            return Some(timestamp);
+        }
+        stem = &stem[..stem.rfind('.')?];
+    }
+    None
+}
+
+/// Sort backup paths by timestamp, oldest first, dropping paths without one
+pub(crate) fn sort_by_timestamp(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut timestamped = paths
+        .into_iter()
+        .filter_map(|p| parse_backup_timestamp(&p).map(|t| (t, p)))
+        .collect::<Vec<_>>();
+    timestamped.sort_by(|a, b| a.0.cmp(&b.0));
+    timestamped.into_iter().map(|(_, p)| p).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::{backup_directory, BackupOptions};
+
+    #[test]
+    fn sort_by_timestamp_test() {
+        let paths = vec![
+            PathBuf::from("c/2020-10-22T23:09:10.960344+00:00.json"),
+            PathBuf::from("c/not-a-backup.json"),
+            PathBuf::from("c/2020-10-23T01:00:00+02:00.json"),
+            PathBuf::from("c/2020-10-21T23:09:10.960344+00:00.json"),
+        ];
+        assert_eq!(
+            sort_by_timestamp(paths),
+            vec![
+                PathBuf::from("c/2020-10-21T23:09:10.960344+00:00.json"),
+                PathBuf::from("c/2020-10-23T01:00:00+02:00.json"),
+                PathBuf::from("c/2020-10-22T23:09:10.960344+00:00.json"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_backup_timestamp_test() {
+        assert!(
+            parse_backup_timestamp(Path::new("a/2020-10-22T23:09:02.555772+00:00.tgz")).is_some()
+        );
+        assert!(parse_backup_timestamp(Path::new("a/latest.tgz")).is_none());
+        assert_eq!(
+            parse_backup_timestamp(Path::new("a/2020-10-22T23:09:02.555772+00:00.tar.gz.ref")),
+            parse_backup_timestamp(Path::new("a/2020-10-22T23:09:02.555772+00:00"))
+        );
+        assert!(parse_backup_timestamp(Path::new("a/2020-10-22T23:09:02+00:00.tgz")).is_some());
+        assert_eq!(
+            parse_backup_timestamp(Path::new("c/2020-10-22T23:09:02+00:00/container.json")),
+            parse_backup_timestamp(Path::new("a/2020-10-22T23:09:02+00:00.tgz"))
+        );
+        assert!(parse_backup_timestamp(Path::new("c/latest/container.json")).is_none());
+    }
+
+    #[test]
+    fn archive_status_test() {
+        let checksum = Some("abc".to_string());
+        assert_eq!(archive_status(None, None), ArchiveStatus::Unverified);
+        assert_eq!(
+            archive_status(Some("abc"), Some(&checksum)),
+            ArchiveStatus::Ok
+        );
+        assert_eq!(
+            archive_status(Some("def"), Some(&checksum)),
+            ArchiveStatus::Corrupt
+        );
+        assert_eq!(
+            archive_status(Some("abc"), Some(&None)),
+            ArchiveStatus::Missing
+        );
+        assert_eq!(archive_status(Some("abc"), None), ArchiveStatus::Missing);
+    }
+
+    #[test]
+    fn time_window_test() {
+        let window = TimeWindow {
+            since: Some(parse_window_date("2020-10-21", false).unwrap()),
+            until: Some(parse_window_date("2020-10-22", true).unwrap()),
+        };
+        let contains = |name: &str| window.contains_backup(Path::new(name));
+        assert!(!contains("c/2020-10-20T23:59:59.999999+00:00.json"));
+        assert!(contains("c/2020-10-21T00:00:00+00:00.json"));
+        assert!(contains("c/2020-10-22T23:59:59.999999+00:00.json"));
+        assert!(!contains("c/2020-10-23T00:00:00+00:00.json"));
+        // Offsets are taken into account
+        assert!(contains("c/2020-10-23T01:00:00+02:00.tgz"));
+        assert!(!contains("c/2020-10-22T23:00:00-02:00.tgz"));
+        assert!(!contains("c/latest.tgz"));
+
+        // Full timestamps are inclusive bounds as given
+        let until = parse_window_date("2020-10-22T12:00:00Z", true).unwrap();
+        let window = TimeWindow {
+            since: None,
+            until: Some(until),
+        };
+        assert!(window.contains(&until));
+        assert!(!window.contains(&(until + chrono::Duration::nanoseconds(1))));
+        assert!(TimeWindow::default().contains(&until));
+    }
+
+    #[test]
+    fn parse_window_date_test() {
+        assert_eq!(
+            parse_window_date("2020-10-22T12:00:00", false).unwrap(),
+            DateTime::parse_from_rfc3339("2020-10-22T12:00:00+00:00").unwrap()
+        );
+        assert_eq!(
+            parse_window_date("2020-10-22T12:00:00+02:00", true).unwrap(),
+            DateTime::parse_from_rfc3339("2020-10-22T10:00:00Z").unwrap()
+        );
+        assert!(parse_window_date("22/10/2020", false).is_err());
+        assert!(parse_window_date("last week", false).is_err());
+    }
+
+    #[test]
+    fn format_listing_test() {
+        let archive = |path: &str, checksum: Option<&str>, status| ListedArchive {
+            path: PathBuf::from(path),
+            checksum: checksum.map(String::from),
+            algorithm: Default::default(),
+            status,
+        };
+        let mut listed = vec![ListedBackup {
+            container: "web".to_string(),
+            backup_file: PathBuf::from("dockyard/containers/web/2020-10-22T23:09:10+00:00.json"),
+            archives: vec![
+                archive("dockyard/volumes/data/a.tgz", Some("aa"), None),
+                archive("dockyard/images/web.tar", None, None),
+            ],
+        }];
+        assert_eq!(
+            format_listing(&listed),
+            "web dockyard/containers/web/2020-10-22T23:09:10+00:00.json\n  \
+             dockyard/volumes/data/a.tgz sha256:aa\n  dockyard/images/web.tar"
+        );
+
+        listed[0].archives = vec![
+            archive("a.tgz", Some("aa"), Some(ArchiveStatus::Ok)),
+            archive("b.tgz", Some("bb"), Some(ArchiveStatus::Corrupt)),
+            archive("c.tar", None, Some(ArchiveStatus::Unverified)),
+        ];
+        let summary = summarize_listing(&listed);
+        assert!(summary.has_failures());
+        assert_eq!(
+            format_listing(&listed),
+            "web dockyard/containers/web/2020-10-22T23:09:10+00:00.json\n  \
+             a.tgz sha256:aa [OK]\n  b.tgz sha256:bb [CORRUPT]\n  c.tar [UNVERIFIED]\n\
+             1 OK, 1 corrupt, 0 missing, 1 unverified"
+        );
+        assert_eq!(format_listing(&[]), "No container backups found");
+    }
+
+    #[test]
+    fn find_stale_containers_test() {
+        let backup = |container: &str, file: &str| ListedBackup {
+            container: container.to_string(),
+            backup_file: PathBuf::from(format!("dockyard/containers/{}/{}", container, file)),
+            archives: vec![],
+        };
+        let listed = vec![
+            backup("web", "2020-10-20T00:00:00+00:00.json"),
+            backup("web", "2020-10-22T00:00:00+00:00.json"),
+            backup("db", "2020-10-21T12:00:00+00:00.json"),
+            backup("db", "backup.json"),
+            backup("cache", "2020-10-22T02:00:00+02:00.json"),
+        ];
+        let now = Utc.ymd(2020, 10, 22).and_hms(12, 0, 0);
+        let stale = find_stale_containers(&listed, Duration::from_secs(12 * 60 * 60), now);
+        assert_eq!(
+            stale,
+            vec![StaleContainer {
+                container: "db".to_string(),
+                last_backup: PathBuf::from("dockyard/containers/db/2020-10-21T12:00:00+00:00.json"),
+                age_secs: 24 * 60 * 60,
+            }]
+        );
+        assert_eq!(
+            format_stale_containers(&stale),
+            "db last backed up 1d 0h ago dockyard/containers/db/2020-10-21T12:00:00+00:00.json"
+        );
+        assert!(
+            find_stale_containers(&listed, Duration::from_secs(2 * 24 * 60 * 60), now).is_empty()
+        );
+        assert_eq!(format_stale_containers(&[]), "No stale container backups");
+    }
+
+    #[test]
+    fn format_age_test() {
+        assert_eq!(format_age(0), "0s");
+        assert_eq!(format_age(42), "42s");
+        assert_eq!(format_age(3 * 60 * 60 + 125), "3h 2m");
+        assert_eq!(format_age(2 * 24 * 60 * 60 + 7), "2d 0h");
+    }
+
+    #[test]
+    fn checksum_archives_test() {
+        let working_dir = tempfile::TempDir::new().unwrap();
+        let archive = working_dir.path().join("a.tgz");
+        std::fs::write(&archive, "archive").unwrap();
+        let missing = working_dir.path().join("b.tgz");
+        let checksums = checksum_archives(
+            &[archive.to_str().unwrap(), missing.to_str().unwrap()],
+            HashAlgorithm::Blake3,
+        )
+        .unwrap();
+        assert_eq!(
+            checksums.checksums[archive.to_str().unwrap()],
+            archive_checksum(&archive, HashAlgorithm::Blake3).unwrap()
+        );
+        assert_eq!(checksums.checksums[missing.to_str().unwrap()], None);
+        assert!(checksums.dependencies.is_empty());
+    }
+
+    #[test]
+    fn checksum_archives_chain_test() {
+        let working_dir = tempfile::TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        let output = working_dir.path().join("output");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("file"), "before").unwrap();
+        let options = BackupOptions {
+            incremental: true,
+            ..Default::default()
+        };
+        let backup = || {
+            let archive =
+                backup_directory(input.to_str().unwrap(), output.to_str().unwrap(), &options)
+                    .unwrap();
+            output.join(archive)
+        };
+        let full = backup();
+        std::fs::write(input.join("file"), "after").unwrap();
+        let incremental = backup();
+        let pointer = output.join("unchanged.tgz.ref");
+        std::fs::write(&pointer, incremental.file_name().unwrap().to_str().unwrap()).unwrap();
+        std::fs::remove_file(&full).unwrap();
+
+        let checksums =
+            checksum_archives(&[pointer.to_str().unwrap()], HashAlgorithm::Sha256).unwrap();
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        assert_eq!(
+            checksums.dependencies[&path(&pointer)],
+            vec![path(&incremental), path(&full)]
+        );
+        assert_eq!(
+            checksums.checksums[&path(&incremental)],
+            archive_checksum(&incremental, HashAlgorithm::Sha256).unwrap()
+        );
+        assert_eq!(checksums.checksums[&path(&full)], None);
+    }
+
+    #[test]
+    fn worst_status_test() {
+        assert_eq!(
+            worst_status(ArchiveStatus::Ok, ArchiveStatus::Unverified),
+            ArchiveStatus::Unverified
+        );
+        assert_eq!(
+            worst_status(ArchiveStatus::Corrupt, ArchiveStatus::Ok),
+            ArchiveStatus::Corrupt
+        );
+        assert_eq!(
+            worst_status(ArchiveStatus::Corrupt, ArchiveStatus::Missing),
+            ArchiveStatus::Missing
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cmp::max;
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File that is rotated once writing to it would grow it past a maximum size
+///
+/// Rotating renames `<path>` to `<path>.1`, `<path>.1` to `<path>.2` and so on, keeping at
+/// most `keep` rotated files.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending, creating it if it doesn't exist
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - File to write
+    /// * `max_size` - Size in bytes the file is rotated at
+    /// * `keep` - Number of rotated files to keep
+    ///
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// Append `line` and a newline, rotating the file first if it would grow too large
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - Line to write
+    ///
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open log file {}", path.display()))
+}
+
+/// Logger writing records to a `RotatingFile`, and to the console if given a console logger
+///
+/// Records from dockyard modules, including the output of helper containers, are written at
+/// `module_level` and records from dependencies at `level`.
+pub struct FileLogger {
+    file: Mutex<RotatingFile>,
+    console: Option<Box<dyn Log>>,
+    level: LevelFilter,
+    module_level: LevelFilter,
+}
+
+impl FileLogger {
+    /// Create logger
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - File to write records to
+    /// * `console` - Logger to also pass records to, if any
+    /// * `level` - Level of records from other crates
+    /// * `module_level` - Level of records from dockyard
+    ///
+    pub fn new(
+        file: RotatingFile,
+        console: Option<Box<dyn Log>>,
+        level: LevelFilter,
+        module_level: LevelFilter,
+    ) -> Self {
+        FileLogger {
+            file: Mutex::new(file),
+            console,
+            level,
+            module_level,
+        }
+    }
+
+    /// Install as the global logger
+    pub fn init(self) -> Result<()> {
+        log::set_max_level(max(self.level, self.module_level));
+        log::set_boxed_logger(Box::new(self)).map_err(|e| anyhow!("{}", e))
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        level_enabled(metadata, self.level, self.module_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(console) = &self.console {
+            console.log(record);
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // There is nowhere to report a failure to log
+        let _ = self.file.lock().unwrap().write_line(&format_record(record));
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().file.flush();
+    }
+}
+
+/// Logger writing records to stderr, for commands that print JSON to stdout
+///
+/// Records are formatted like those `SimpleLogger` prints to stdout, without colours.
+pub struct StderrLogger {
+    level: LevelFilter,
+    module_level: LevelFilter,
+}
+
+impl StderrLogger {
+    /// Create logger
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - Level of records from other crates
+    /// * `module_level` - Level of records from dockyard
+    ///
+    pub fn new(level: LevelFilter, module_level: LevelFilter) -> Self {
+        StderrLogger {
+            level,
+            module_level,
+        }
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        level_enabled(metadata, self.level, self.module_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", format_record(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Return true if a record is logged at `module_level` for dockyard modules or `level` for
+/// other crates
+fn level_enabled(metadata: &Metadata, level: LevelFilter, module_level: LevelFilter) -> bool {
+    let level = if metadata.target().starts_with("dockyard") {
+        module_level
+    } else {
+        level
+    };
+    metadata.level() <= level
+}
+
+/// Return a record as a line with its time, level and target
+fn format_record(record: &Record) -> String {
+    format!(
+        "{} {:<5} [{}] {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Logger prefixing every record with a trace ID before passing it to another logger
+///
+/// The trace ID correlates the records of one dockyard run, including the output of its helper
+/// containers, when several runs log to the same place.
+pub struct TraceLogger {
+    trace_id: String,
+    logger: Box<dyn Log>,
+}
+
+impl TraceLogger {
+    /// Create logger
+    ///
+    /// # Arguments
+    ///
+    /// * `trace_id` - ID to prefix records with
+    /// * `logger` - Logger to pass prefixed records to
+    ///
+    pub fn new(trace_id: String, logger: Box<dyn Log>) -> Self {
+        TraceLogger { trace_id, logger }
+    }
+
+    /// Install as the global logger
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - Most verbose level any record is logged at
+    ///
+    pub fn init(self, level: LevelFilter) -> Result<()> {
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(self)).map_err(|e| anyhow!("{}", e))
+    }
+}
+
+impl Log for TraceLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger.log(
+            &Record::builder()
+                .args(format_args!("[{}] {}", self.trace_id, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile::TempDir;
+
+    #[test]
+    fn rotating_file_test() {
+        let working_dir = TempDir::new().unwrap();
+        let path = working_dir.path().join("dockyard.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(read_to_string(file.rotated_path(1)).unwrap(), "third\n");
+        assert_eq!(read_to_string(file.rotated_path(2)).unwrap(), "second\n");
+        assert!(!file.rotated_path(3).exists());
+
+        // Reopening appends to the existing file
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        file.write_line("fifth").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "fourth\nfifth\n");
+    }
+
+    fn metadata(target: &str, level: log::Level) -> Metadata<'_> {
+        Metadata::builder().target(target).level(level).build()
+    }
+
+    #[test]
+    fn file_logger_level_test() {
+        let working_dir = TempDir::new().unwrap();
+        let file = RotatingFile::open(&working_dir.path().join("dockyard.log"), 1024, 1).unwrap();
+        let logger = FileLogger::new(file, None, LevelFilter::Warn, LevelFilter::Debug);
+        assert!(logger.enabled(&metadata("dockyard::backup", log::Level::Debug)));
+        assert!(!logger.enabled(&metadata("dockyard::backup", log::Level::Trace)));
+        assert!(logger.enabled(&metadata("bollard::docker", log::Level::Warn)));
+        assert!(!logger.enabled(&metadata("bollard::docker", log::Level::Info)));
+    }
+
+    #[test]
+    fn stderr_logger_level_test() {
+        let logger = StderrLogger::new(LevelFilter::Warn, LevelFilter::Info);
+        assert!(logger.enabled(&metadata("dockyard::list", log::Level::Info)));
+        assert!(!logger.enabled(&metadata("dockyard::list", log::Level::Debug)));
+        assert!(!logger.enabled(&metadata("hyper::client", log::Level::Info)));
+    }
+
+    #[test]
+    fn trace_logger_test() {
+        let working_dir = TempDir::new().unwrap();
+        let path = working_dir.path().join("dockyard.log");
+        let file = RotatingFile::open(&path, 1024, 1).unwrap();
+        let logger = TraceLogger::new(
+            "4f66ad9a0b2e".to_string(),
+            Box::new(FileLogger::new(
+                file,
+                None,
+                LevelFilter::Warn,
+                LevelFilter::Info,
+            )),
+        );
+        assert!(!logger.enabled(&metadata("dockyard::backup", log::Level::Debug)));
+        logger.log(
+            &Record::builder()
+                .args(format_args!("Backing up volume data"))
+                .target("dockyard::backup")
+                .level(log::Level::Info)
+                .build(),
+        );
+        logger.flush();
+        assert!(read_to_string(&path)
+            .unwrap()
+            .ends_with(" INFO  [dockyard::backup] [4f66ad9a0b2e] Backing up volume data\n"));
+    }
+}
@@ -3,29 +3,65 @@ use std::process::exit;
 #[macro_use]
 extern crate clap;
 
-#[macro_use]
-extern crate lazy_static;
-
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bollard::Docker;
+use chrono::Utc;
 use clap::{App, ArgMatches};
-use dockyard::backup::{backup_container, backup_directory, backup_volume};
-use dockyard::cleanup::{cleanup_child_containers, cleanup_dockyard_containers};
+use dockyard::backup::{
+    archive_checksum, backup_container, backup_directory, backup_volume, backup_volumes,
+    cache_volume_patterns, parse_compression_level, parse_duration, parse_extension, parse_size,
+    BackupOptions, Codec, Layout, Timezone, VolumeSelection,
+};
+use dockyard::bench::{bench_compression, format_compression_results};
+use dockyard::catalog::{query_catalog, rebuild_catalog, record_backup, reindex, CATALOG_DB};
+use dockyard::check::{check_restore, format_checks, space_report};
+use dockyard::cleanup::{
+    cleanup_child_containers, cleanup_dockyard_containers, cleanup_rehearsals,
+    reap_orphaned_containers,
+};
 use dockyard::container::{
-    get_backup_directory_mount, get_backup_volume_mount, get_bind_mount, get_volume_mount,
-    set_command_verbosity,
+    check_api_version, connect_docker, explain_connection_error, get_backup_directory_mount,
+    get_backup_volume_mount, get_read_only_backup_directory_mount,
+    get_read_only_backup_volume_mount, get_trace_id, get_volume_mount, new_trace_id,
+    parse_blkio_weight, parse_label, remove_abandoned_helpers, set_command_verbosity,
+    set_container_runtime, set_helper_blkio_weight, set_helper_labels, set_helper_network,
+    set_jobs, set_pull_retries, set_trace_id, ContainerRuntime,
+};
+use dockyard::diff::{diff_container, format_changes};
+use dockyard::exclude::{parse_subpath, read_exclude_file};
+use dockyard::failure::{Failure, FailureKind};
+use dockyard::file::{
+    decode_and_write_file, ensure_absent, list_directory, parse_mode, path_str,
+    read_and_encode_file, read_file, write_file, FileModes,
+};
+use dockyard::hash::HashAlgorithm;
+use dockyard::host::{backup_host, find_latest_host_manifest, restore_host};
+use dockyard::list::{
+    checksum_archives, find_latest_container_backup, find_stale_containers, format_listing,
+    format_stale_containers, list_container_backups, parse_window_date, summarize_listing,
+    TimeWindow,
+};
+use dockyard::logfile::{FileLogger, RotatingFile, StderrLogger, TraceLogger};
+use dockyard::pack::{pack_container_backup, unpack_container_backup};
+use dockyard::progress::set_progress;
+use dockyard::prune::{parse_keep, prune_backups, remove_backups_in_window, remove_old_backups};
+use dockyard::restore::{
+    parse_bind_remap, parse_cpus, parse_memory_swap, parse_name_template, parse_owner,
+    read_env_file, restore_container, restore_container_script, restore_directory, restore_volume,
+    restore_volume_into, restore_volume_to_directory, swap_volume_into, MergeMode, RestoreOptions,
+};
+use dockyard::status::{format_status, read_status, DEFAULT_STATUS_FILE};
+use dockyard::watch::{
+    backup_on_interval, handle_pause_signals, parse_name_filter, parse_schedules, LabelSelector,
+    WatchOptions,
 };
-use dockyard::file::{decode_and_write_file, read_and_encode_file, read_file, write_file};
-use dockyard::restore::{restore_container, restore_directory, restore_volume};
-use dockyard::watch::backup_on_interval;
-use log::LevelFilter;
+use log::{LevelFilter, Log};
 use simple_logger::SimpleLogger;
+use std::cmp::max;
 use std::collections::HashSet;
 use std::iter::FromIterator;
-
-lazy_static! {
-    static ref DOCKER: Docker = Docker::connect_with_unix_defaults().unwrap();
-}
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -34,6 +70,7 @@ async fn main() -> Result<()> {
         .version(env!("VERGEN_SEMVER"))
         .get_matches();
 
+    let errors_json = args.is_present("errors_json");
     let verbosity = args.occurrences_of("verbose");
     set_command_verbosity(verbosity as u8);
     let (global_level, module_level) = match verbosity {
@@ -43,45 +80,186 @@ async fn main() -> Result<()> {
         _ => (LevelFilter::Debug, LevelFilter::Trace),
     };
 
-    SimpleLogger::new()
-        .with_module_level("dockyard", module_level)
-        .with_level(global_level)
-        .init()
+    let trace_id = args
+        .value_of("trace_id")
+        .map(str::to_string)
+        .unwrap_or_else(new_trace_id);
+    set_trace_id(Some(trace_id.clone()));
+    let console: Box<dyn Log> = if prints_json(&args) {
+        // Keep stdout for the JSON output
+        Box::new(StderrLogger::new(global_level, module_level))
+    } else {
+        Box::new(
+            SimpleLogger::new()
+                .with_module_level("dockyard", module_level)
+                .with_level(global_level),
+        )
+    };
+    let logger: Box<dyn Log> = match args.value_of("log_file") {
+        Some(path) => match file_logger(&args, path, console, global_level, module_level) {
+            Ok(logger) => Box::new(logger),
+            Err(e) => {
+                if errors_json {
+                    fail(FailureKind::Usage, e, None, errors_json)
+                }
+                eprintln!("{:#}", e);
+                exit(1)
+            }
+        },
+        None => console,
+    };
+    TraceLogger::new(trace_id, logger)
+        .init(max(global_level, module_level))
         .unwrap();
 
-    let _signal_handler = tokio::spawn(async {
+    let helper_labels = args
+        .values_of("helper_label")
+        .map(|labels| labels.map(parse_label).collect::<Result<Vec<_>>>())
+        .transpose();
+    match helper_labels {
+        Ok(labels) => set_helper_labels(labels.unwrap_or_default()),
+        Err(e) => fail(FailureKind::Usage, e, None, errors_json),
+    }
+
+    match args
+        .value_of("runtime")
+        .unwrap()
+        .parse::<ContainerRuntime>()
+    {
+        Ok(runtime) => set_container_runtime(runtime),
+        Err(e) => fail(FailureKind::Usage, e, None, errors_json),
+    }
+
+    match args.value_of("jobs").unwrap().parse::<usize>() {
+        Ok(jobs) if jobs > 0 => set_jobs(jobs),
+        _ => fail(
+            FailureKind::Usage,
+            anyhow!(
+                "Invalid --jobs {}, expected a positive number",
+                args.value_of("jobs").unwrap()
+            ),
+            None,
+            errors_json,
+        ),
+    }
+
+    match args
+        .value_of("blkio_weight")
+        .map(parse_blkio_weight)
+        .transpose()
+    {
+        Ok(weight) => set_helper_blkio_weight(weight),
+        Err(e) => fail(FailureKind::Usage, e, None, errors_json),
+    }
+
+    set_helper_network(args.value_of("helper_network").map(str::to_string));
+
+    match args.value_of("pull_retries").unwrap().parse::<u8>() {
+        Ok(retries) => set_pull_retries(retries),
+        Err(_) => fail(
+            FailureKind::Usage,
+            anyhow!(
+                "Invalid --pull-retries {}, expected a number from 0 to 255",
+                args.value_of("pull_retries").unwrap()
+            ),
+            None,
+            errors_json,
+        ),
+    }
+
+    // Progress is for interactive backups and restores, not watches or helper commands
+    set_progress(
+        matches!(args.subcommand_name(), Some("backup") | Some("restore"))
+            && !args.is_present("no_progress")
+            && !args.is_present("log_file_only"),
+    );
+
+    if operation_host(&args).is_some() && !uses_docker(&args) {
+        fail(
+            FailureKind::Usage,
+            anyhow!(
+                "--host doesn't apply to {} directory, which doesn't use Docker",
+                args.subcommand_name().unwrap()
+            ),
+            None,
+            errors_json,
+        )
+    }
+    // Helper commands don't talk to Docker, and their output may be read by the parent dockyard
+    let connected = if uses_docker(&args) {
+        connect_docker(operation_host(&args).or_else(|| args.value_of("docker_host")))
+    } else {
+        Docker::connect_with_unix_defaults().map_err(anyhow::Error::from)
+    };
+    let docker = match connected {
+        Ok(docker) => docker,
+        Err(e) => fail(FailureKind::DockerConnection, e, None, errors_json),
+    };
+
+    if uses_docker(&args) {
+        if let Err(e) = check_api_version(&docker).await {
+            fail_command(e, errors_json)
+        }
+    }
+
+    if args.is_present("reap_orphans") {
+        if let Err(e) = reap_orphaned_containers(&docker).await {
+            log::warn!("Failed to remove orphaned helper containers: {:#}", e);
+        }
+    }
+
+    let signal_docker = docker.clone();
+    let _signal_handler = tokio::spawn(async move {
         tokio::signal::ctrl_c().await.unwrap();
         log::info!("Received Ctrl-C, stopping and removing all child containers");
-        match cleanup_child_containers(&DOCKER).await {
-            Ok(_) => {
+        match cleanup_child_containers(&signal_docker).await {
+            Ok(report) if report.failed.is_empty() => {
                 log::info!("Successfully cleaned up child containers");
                 exit(0)
             }
+            Ok(report) => {
+                log::error!("Failed to remove {} child containers", report.failed.len());
+                exit(1)
+            }
             Err(e) => {
                 log::error!("Error cleaning up child containers: {}", e);
                 exit(1)
             }
         }
     });
+    if args.subcommand_name() == Some("watch") {
+        tokio::spawn(async {
+            if let Err(e) = handle_pause_signals().await {
+                log::warn!("Unable to handle pause signals: {:#}", e);
+            }
+        });
+    }
 
     let result = match args.subcommand() {
-        ("watch", Some(subargs)) => run_watch(&DOCKER, subargs).await,
-        ("cleanup", _) => {
-            log::info!("Cleaning up all dockyard containers");
-            cleanup_dockyard_containers(&DOCKER).await.map(|_| {
-                log::info!("Successfully cleaned up all dockyard containers");
-                0
-            })
-        }
+        ("watch", Some(subargs)) => run_watch(&docker, subargs).await,
+        ("status", Some(subargs)) => run_status(subargs).await,
+        ("cleanup", Some(subargs)) => run_cleanup(&docker, subargs).await,
+        ("cleanup-rehearsal", Some(subargs)) => run_cleanup_rehearsal(&docker, subargs).await,
         ("write", Some(subargs)) => {
             let contents = subargs.value_of("contents").unwrap();
             let file = subargs.value_of("file").unwrap();
-            if subargs.is_present("encoded") {
-                decode_and_write_file(contents, file)
-            } else {
-                write_file(contents, file)
-            }
-            .map(|_| 0)
+            get_file_modes(subargs).and_then(|modes| {
+                if subargs.is_present("no_clobber") {
+                    ensure_absent(Path::new(file))?;
+                }
+                if subargs.is_present("encoded") {
+                    decode_and_write_file(contents, file, &modes)
+                } else {
+                    write_file(contents, file, &modes)
+                }?;
+                if let Some(root) = subargs.value_of("catalog") {
+                    let root = Path::new(root);
+                    if let Ok(backup_file) = Path::new(file).strip_prefix(root) {
+                        record_backup(root, backup_file);
+                    }
+                }
+                Ok(0)
+            })
         }
         ("cat", Some(subargs)) => {
             let file = subargs.value_of("file").unwrap();
@@ -95,18 +273,203 @@ async fn main() -> Result<()> {
                 0
             })
         }
-        ("backup", Some(subcommand)) => run_backup(&DOCKER, subcommand).await,
-        ("restore", Some(subcommand)) => run_restore(&DOCKER, subcommand).await,
+        ("ls", Some(subargs)) => {
+            let directory = subargs.value_of("directory").unwrap();
+            list_directory(directory).map(|entries| {
+                println!("{}", serde_json::to_string(&entries).unwrap());
+                0
+            })
+        }
+        ("mkdir", Some(subargs)) => get_file_modes(subargs)
+            .and_then(|modes| {
+                modes.create_dir_all(Path::new(subargs.value_of("directory").unwrap()))
+            })
+            .map(|_| 0),
+        ("checksum", Some(subargs)) => {
+            let files = subargs.values_of("FILE").unwrap().collect::<Vec<_>>();
+            let algorithm = subargs.value_of("hash_algo").unwrap().parse()?;
+            checksum_archives(&files, algorithm).map(|checksums| {
+                println!("{}", serde_json::to_string(&checksums).unwrap());
+                0
+            })
+        }
+        ("space", Some(subargs)) => {
+            let files = subargs
+                .values_of("FILE")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            space_report(&files).map(|report| {
+                println!("{}", serde_json::to_string(&report).unwrap());
+                0
+            })
+        }
+        ("trim", Some(subargs)) => parse_keep(subargs.value_of("keep").unwrap())
+            .and_then(|keep| {
+                remove_old_backups(
+                    Path::new(subargs.value_of("root").unwrap()),
+                    Path::new(subargs.value_of("target").unwrap()),
+                    keep,
+                )
+            })
+            .map(|removed| {
+                println!("{}", serde_json::to_string(&removed).unwrap());
+                0
+            }),
+        ("expire", Some(subargs)) => get_time_window(subargs)
+            .and_then(|window| {
+                remove_backups_in_window(
+                    Path::new(subargs.value_of("root").unwrap()),
+                    &window,
+                    subargs.is_present("dry_run"),
+                )
+            })
+            .map(|removed| {
+                println!("{}", serde_json::to_string(&removed).unwrap());
+                0
+            }),
+        ("catalog", Some(subargs)) => {
+            let root = Path::new(subargs.value_of("root").unwrap());
+            if subargs.is_present("rebuild") {
+                rebuild_catalog(root).map(|count| {
+                    println!("{}", count);
+                    0
+                })
+            } else {
+                query_catalog(root, subargs.value_of("container")).map(|entries| {
+                    println!("{}", serde_json::to_string(&entries).unwrap());
+                    0
+                })
+            }
+        }
+        ("list", Some(subargs)) => run_list(&docker, subargs).await,
+        ("reindex", Some(subargs)) => run_reindex(&docker, subargs).await,
+        ("prune", Some(subargs)) => run_prune(&docker, subargs).await,
+        ("backup", Some(subcommand)) => run_backup(&docker, subcommand).await,
+        ("pack", Some(subargs)) => run_pack(&docker, subargs).await,
+        ("unpack", Some(subargs)) => run_unpack(&docker, subargs).await,
+        ("diff", Some(subcommand)) => run_diff(&docker, subcommand).await,
+        ("bench-compress", Some(subargs)) => run_bench_compress(subargs),
+        ("restore", Some(subcommand)) => run_restore(&docker, subcommand).await,
         _ => print_usage(&args),
     };
 
+    // Commands dropped before they finished, e.g. when another backup failed, leave helpers behind
+    if uses_docker(&args) {
+        if let Err(e) = remove_abandoned_helpers(&docker).await {
+            log::warn!("Unable to remove helper containers: {:#}", e);
+        }
+    }
+
     match result {
         Ok(i) => exit(i),
-        Err(e) => {
-            log::error!("Command failed: {:#}", e);
-            exit(1)
+        Err(e) => fail_command(e, errors_json),
+    };
+}
+
+/// Return the Docker host given to `backup` or `restore`, which replaces the default connection
+/// and the global `--host` for that operation
+fn operation_host<'a>(args: &'a ArgMatches<'_>) -> Option<&'a str> {
+    match args.subcommand() {
+        ("backup", Some(subcommand)) | ("restore", Some(subcommand)) => subcommand.value_of("host"),
+        _ => None,
+    }
+}
+
+/// Return true if the subcommand talks to the Docker daemon, rather than only to files as
+/// helper commands do
+fn uses_docker(args: &ArgMatches<'_>) -> bool {
+    match args.subcommand() {
+        ("backup", Some(subcommand)) | ("restore", Some(subcommand)) => {
+            subcommand.subcommand_name() != Some("directory")
+        }
+        (name, _) => !matches!(
+            name,
+            "" | "status"
+                | "write"
+                | "cat"
+                | "ls"
+                | "mkdir"
+                | "checksum"
+                | "space"
+                | "trim"
+                | "expire"
+                | "catalog"
+                | "bench-compress"
+        ),
+    }
+}
+
+/// Exit after a command failed, explaining failures to reach the Docker daemon
+fn fail_command(error: anyhow::Error, errors_json: bool) -> ! {
+    match explain_connection_error(&error) {
+        Some(message) => {
+            log::debug!("{:#}", error);
+            let error = error.context(message.clone());
+            fail(
+                FailureKind::DockerConnection,
+                error,
+                Some(message),
+                errors_json,
+            )
+        }
+        None => {
+            let message = format!("Command failed: {:#}", error);
+            fail(FailureKind::Command, error, Some(message), errors_json)
         }
+    }
+}
+
+/// Exit with the exit code of `kind`, printing `error` and its causes to stderr as JSON with
+/// `--errors-json` and logging `message`, or `error` if not given, otherwise
+fn fail(kind: FailureKind, error: anyhow::Error, message: Option<String>, errors_json: bool) -> ! {
+    if errors_json {
+        let mut failure = Failure::new(kind, &error);
+        failure.trace_id = get_trace_id();
+        eprintln!("{}", serde_json::to_string(&failure).unwrap());
+    } else {
+        log::error!("{}", message.unwrap_or_else(|| format!("{:#}", error)));
+    }
+    exit(kind.exit_code())
+}
+
+/// Return true if the subcommand was asked to print JSON to stdout with `--json`
+fn prints_json(args: &ArgMatches<'_>) -> bool {
+    match args.subcommand() {
+        (_, Some(subargs)) => subargs.is_present("json") || prints_json(subargs),
+        _ => false,
+    }
+}
+
+/// Return logger writing to `--log-file`, and to `console` unless `--log-file-only` is set
+fn file_logger(
+    args: &ArgMatches<'_>,
+    path: &str,
+    console: Box<dyn Log>,
+    level: LevelFilter,
+    module_level: LevelFilter,
+) -> Result<FileLogger> {
+    let max_size = parse_size(args.value_of("log_file_max_size").unwrap())?;
+    let keep = args.value_of("log_file_keep").unwrap();
+    let keep = keep
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid --log-file-keep {}, expected a number", keep))?;
+    let file = RotatingFile::open(Path::new(path), max_size, keep)?;
+    let console = if args.is_present("log_file_only") {
+        None
+    } else {
+        Some(console)
     };
+    Ok(FileLogger::new(file, console, level, module_level))
+}
+
+fn run_bench_compress(args: &ArgMatches<'_>) -> Result<i32> {
+    let results = bench_compression(args.value_of("INPUT").unwrap())?;
+    if args.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        println!("{}", format_compression_results(&results));
+    }
+    Ok(0)
 }
 
 fn print_usage(args: &ArgMatches<'_>) -> Result<i32> {
@@ -119,36 +482,146 @@ async fn run_restore(docker: &Docker, subcommand: &ArgMatches<'_>) -> Result<i32
         ("directory", Some(subargs)) => {
             let archive = subargs.value_of("ARCHIVE").unwrap();
             let output = subargs.value_of("OUTPUT").unwrap();
-            restore_directory(archive, output).map(|_| 0)
+            restore_directory(archive, output, &get_restore_options(subargs)?).map(|_| 0)
         }
         ("volume", Some(subargs)) => {
             let archive = subargs.value_of("ARCHIVE").unwrap();
             let input = subargs.value_of("INPUT").unwrap();
             let volume = subargs.value_of("VOLUME").unwrap();
-            let volume_mount = if subargs.value_of("volume_type").unwrap() == "directory" {
-                get_bind_mount(volume.to_string())
+            let backup_mount = if subargs.value_of("input_type").unwrap() == "directory" {
+                get_read_only_backup_directory_mount(input.to_string())
             } else {
-                get_volume_mount(volume.to_string())
+                get_read_only_backup_volume_mount(input.to_string())
             };
+            let options = get_restore_options(subargs)?;
+            let restored = if subargs.value_of("volume_type").unwrap() == "volume" {
+                let volume_mount = get_volume_mount(volume.to_string());
+                restore_volume(
+                    &docker,
+                    archive.to_string(),
+                    backup_mount,
+                    volume_mount,
+                    &options,
+                )
+                .await
+            } else {
+                restore_volume_to_directory(
+                    &docker,
+                    archive.to_string(),
+                    backup_mount,
+                    Path::new(volume),
+                    &options,
+                )
+                .await
+            };
+            restored.map(|_| 0)
+        }
+        ("volume-into", Some(subargs)) => {
+            let container = subargs.value_of("CONTAINER").unwrap();
+            let target = subargs.value_of("TARGET").unwrap();
+            let archive = subargs.value_of("ARCHIVE").unwrap();
+            let input = subargs.value_of("INPUT").unwrap();
             let backup_mount = if subargs.value_of("input_type").unwrap() == "directory" {
-                get_backup_directory_mount(input.to_string())
+                get_read_only_backup_directory_mount(input.to_string())
             } else {
-                get_backup_volume_mount(input.to_string())
+                get_read_only_backup_volume_mount(input.to_string())
             };
-            restore_volume(&docker, archive.to_string(), backup_mount, volume_mount)
+            let options = get_restore_options(subargs)?;
+            if subargs.is_present("atomic_swap") {
+                return swap_volume_into(
+                    &docker,
+                    container,
+                    target,
+                    archive.to_string(),
+                    backup_mount,
+                    &options,
+                )
                 .await
-                .map(|_| 0)
+                .map(|_| 0);
+            }
+            restore_volume_into(
+                &docker,
+                container,
+                target,
+                archive.to_string(),
+                backup_mount,
+                subargs.is_present("stop"),
+                &options,
+            )
+            .await
+            .map(|_| 0)
+        }
+        ("host", Some(subargs)) => {
+            let input = subargs.value_of("INPUT").unwrap();
+            let backup_mount = if subargs.value_of("input_type").unwrap() == "directory" {
+                get_read_only_backup_directory_mount(input.to_string())
+            } else {
+                get_read_only_backup_volume_mount(input.to_string())
+            };
+            let file = match subargs.value_of("FILE") {
+                Some(file) => PathBuf::from(file),
+                None => find_latest_host_manifest(&docker, backup_mount.clone()).await?,
+            };
+            let options = get_restore_options(subargs)?;
+            let report = restore_host(&docker, &file, backup_mount, &options).await?;
+            let exit_code = if report.has_failures() { 1 } else { 0 };
+            if subargs.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(exit_code);
+            }
+            log::info!(
+                "Restored {} volumes and {} containers from {}",
+                report.volumes.len(),
+                report.containers.len(),
+                file.display()
+            );
+            if report.has_failures() {
+                return Err(anyhow!(
+                    "Failed to restore volumes [{}] and containers [{}]",
+                    report.failed_volumes.join(", "),
+                    report.failed_containers.join(", ")
+                ));
+            }
+            Ok(exit_code)
         }
         ("container", Some(subargs)) => {
             let file = subargs.value_of("FILE").unwrap();
             let input = subargs.value_of("INPUT").unwrap();
-            let name = subargs.value_of("NAME").unwrap();
+            let name = subargs.value_of("NAME");
             let backup_mount = if subargs.value_of("input_type").unwrap() == "directory" {
-                get_backup_directory_mount(input.to_string())
+                get_read_only_backup_directory_mount(input.to_string())
+            } else {
+                get_read_only_backup_volume_mount(input.to_string())
+            };
+            let file = if subargs.is_present("latest") {
+                find_latest_container_backup(docker, file, backup_mount.clone()).await?
             } else {
-                get_backup_volume_mount(input.to_string())
+                PathBuf::from(file)
             };
-            restore_container(&docker, file, name, backup_mount)
+            let options = get_restore_options(subargs)?;
+            if subargs.is_present("print_script") {
+                let script = restore_container_script(
+                    docker,
+                    path_str(&file)?,
+                    name,
+                    backup_mount,
+                    &options,
+                )
+                .await?;
+                print!("{}", script);
+                return Ok(0);
+            }
+            if subargs.is_present("check_only") {
+                let checks =
+                    check_restore(docker, path_str(&file)?, name, backup_mount, &options).await;
+                println!("{}", format_checks(&checks));
+                return Ok(if checks.iter().all(|c| c.passed) {
+                    0
+                } else {
+                    1
+                });
+            }
+            restore_container(docker, path_str(&file)?, name, backup_mount, &options)
                 .await
                 .map(|_| 0)
         }
@@ -156,8 +629,152 @@ async fn run_restore(docker: &Docker, subcommand: &ArgMatches<'_>) -> Result<i32
     }
 }
 
+async fn run_pack(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    let file = args.value_of("FILE").unwrap();
+    let input = args.value_of("INPUT").unwrap();
+    let output = Path::new(args.value_of("OUTPUT").unwrap());
+    let backup_mount = if args.value_of("input_type").unwrap() == "directory" {
+        get_read_only_backup_directory_mount(input.to_string())
+    } else {
+        get_read_only_backup_volume_mount(input.to_string())
+    };
+    let file = if args.is_present("latest") {
+        find_latest_container_backup(&docker, file, backup_mount.clone()).await?
+    } else {
+        PathBuf::from(file)
+    };
+    let index = pack_container_backup(&docker, &file, backup_mount, output).await?;
+    log::info!(
+        "Successfully packed {} files to {}",
+        index.files.len(),
+        output.display()
+    );
+    Ok(0)
+}
+
+async fn run_unpack(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    let archive = Path::new(args.value_of("ARCHIVE").unwrap());
+    let output = args.value_of("OUTPUT").unwrap();
+    let backup_mount = if args.value_of("output_type").unwrap() == "directory" {
+        get_backup_directory_mount(output.to_string())
+    } else {
+        get_backup_volume_mount(output.to_string())
+    };
+    let index = unpack_container_backup(
+        &docker,
+        archive,
+        backup_mount,
+        args.is_present("append_only"),
+        &get_file_modes(args)?,
+    )
+    .await?;
+    log::info!(
+        "Successfully unpacked {}, restore it with {}",
+        archive.display(),
+        index.container_backup.display()
+    );
+    Ok(0)
+}
+
+async fn run_list(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    let input = args.value_of("INPUT").unwrap();
+    let backup_mount = if args.value_of("input_type").unwrap() == "directory" {
+        get_read_only_backup_directory_mount(input.to_string())
+    } else {
+        get_read_only_backup_volume_mount(input.to_string())
+    };
+    let window = get_time_window(args)?;
+    let stale_after = args
+        .value_of("stale_after")
+        .map(parse_duration)
+        .transpose()?;
+    let listed =
+        list_container_backups(&docker, backup_mount, &window, args.is_present("verify")).await?;
+    let failed = summarize_listing(&listed).has_failures();
+    if let Some(stale_after) = stale_after {
+        let stale = find_stale_containers(&listed, stale_after, Utc::now());
+        if args.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&stale)?);
+        } else {
+            println!("{}", format_stale_containers(&stale));
+        }
+        return Ok(if failed || !stale.is_empty() { 1 } else { 0 });
+    }
+    if args.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+    } else {
+        println!("{}", format_listing(&listed));
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+async fn run_prune(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    let input = args.value_of("INPUT").unwrap();
+    let backup_mount = if args.value_of("input_type").unwrap() == "directory" {
+        get_backup_directory_mount(input.to_string())
+    } else {
+        get_backup_volume_mount(input.to_string())
+    };
+    let dry_run = args.is_present("dry_run");
+    let removed = prune_backups(&docker, &get_time_window(args)?, dry_run, backup_mount).await?;
+    let action = if dry_run { "Would remove" } else { "Removed" };
+    for path in &removed {
+        log::info!("{} {}", action, path.display());
+    }
+    log::info!("{} {} files", action, removed.len());
+    Ok(0)
+}
+
+async fn run_reindex(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    let input = args.value_of("INPUT").unwrap();
+    let backup_mount = if args.value_of("input_type").unwrap() == "directory" {
+        get_backup_directory_mount(input.to_string())
+    } else {
+        get_backup_volume_mount(input.to_string())
+    };
+    let count = reindex(&docker, backup_mount).await?;
+    log::info!("Indexed {} container backups in {}", count, CATALOG_DB);
+    Ok(0)
+}
+
+/// Return window of backups selected by `--since` and `--until`
+fn get_time_window(args: &ArgMatches<'_>) -> Result<TimeWindow> {
+    Ok(TimeWindow {
+        since: args
+            .value_of("since")
+            .map(|date| parse_window_date(date, false))
+            .transpose()?,
+        until: args
+            .value_of("until")
+            .map(|date| parse_window_date(date, true))
+            .transpose()?,
+    })
+}
+
+async fn run_diff(docker: &Docker, subcommand: &ArgMatches<'_>) -> Result<i32> {
+    match subcommand.subcommand() {
+        ("container", Some(subargs)) => {
+            let file = subargs.value_of("FILE").unwrap();
+            let input = subargs.value_of("INPUT").unwrap();
+            let name = subargs.value_of("NAME").unwrap();
+            let backup_mount = if subargs.value_of("input_type").unwrap() == "directory" {
+                get_read_only_backup_directory_mount(input.to_string())
+            } else {
+                get_read_only_backup_volume_mount(input.to_string())
+            };
+            let changes = diff_container(&docker, file, name, backup_mount).await?;
+            if subargs.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&changes)?);
+            } else {
+                println!("{}", format_changes(&changes));
+            }
+            Ok(0)
+        }
+        _ => print_usage(subcommand),
+    }
+}
+
 async fn run_watch(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
-    let cron = args.value_of("cron").unwrap();
     let output = args.value_of("OUTPUT").unwrap();
     let backup_mount = if args.value_of("output_type").unwrap() == "directory" {
         get_backup_directory_mount(output.to_string())
@@ -166,32 +783,285 @@ async fn run_watch(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
     };
     let exclude_containers =
         HashSet::from_iter(args.values_of_lossy("exclude_containers").unwrap_or_default());
-    let exclude_volumes =
-        HashSet::from_iter(args.values_of_lossy("exclude_volumes").unwrap_or_default());
+    let watch_options = WatchOptions {
+        schedules: parse_schedules(indexed_values(args, "cron"), indexed_values(args, "label"))?,
+        exclude_containers,
+        name_filter: args.value_of("match").map(parse_name_filter).transpose()?,
+        enable_label: args
+            .value_of("enable_label")
+            .map(str::parse::<LabelSelector>)
+            .transpose()?,
+        include_stopped: args.is_present("include_stopped"),
+        min_interval: args
+            .value_of("min_interval")
+            .map(parse_duration)
+            .transpose()?,
+    };
+    let options = get_backup_options(args)?;
+    let status_file = Path::new(args.value_of("status_file").unwrap_or(DEFAULT_STATUS_FILE));
     backup_on_interval(
         &docker,
-        cron,
         backup_mount,
-        &exclude_containers,
-        &exclude_volumes,
+        &watch_options,
+        &options,
+        Some(status_file),
     )
     .await
     .map(|_| 0)
 }
 
+/// Return values of a repeated argument with their positions on the command line
+fn indexed_values(args: &ArgMatches<'_>, name: &str) -> Vec<(usize, String)> {
+    match (args.indices_of(name), args.values_of(name)) {
+        (Some(indices), Some(values)) => indices.zip(values.map(String::from)).collect(),
+        _ => vec![],
+    }
+}
+
+async fn run_status(args: &ArgMatches<'_>) -> Result<i32> {
+    let status_file = Path::new(args.value_of("status_file").unwrap_or(DEFAULT_STATUS_FILE));
+    let mut status = read_status(status_file)?;
+    println!("{}", format_status(&status));
+    if args.is_present("follow") {
+        loop {
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+            let latest = read_status(status_file)?;
+            if latest != status {
+                println!("\n{}", format_status(&latest));
+                status = latest;
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn get_restore_options(args: &ArgMatches<'_>) -> Result<RestoreOptions> {
+    Ok(RestoreOptions {
+        no_pull: args.is_present("no_pull"),
+        refresh_image: args.is_present("refresh_image"),
+        strip_components: args
+            .value_of("strip_components")
+            .map(|count| {
+                count
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("Invalid --strip-components {}", count))
+            })
+            .transpose()?
+            .unwrap_or_default(),
+        allow_absolute: args.is_present("allow_absolute"),
+        clean: args.is_present("clean"),
+        strict_image: args.is_present("strict_image"),
+        owner: args.value_of("chown").map(parse_owner).transpose()?,
+        volume_prefix: args.value_of("volume_prefix").map(String::from),
+        env: args
+            .value_of("env_from_file")
+            .map(|path| read_env_file(Path::new(path)))
+            .transpose()?
+            .unwrap_or_default(),
+        name_template: args
+            .value_of("name_template")
+            .map(parse_name_template)
+            .transpose()?,
+        entrypoint: args.value_of("entrypoint").map(|e| vec![e.to_string()]),
+        cmd: args.values_of_lossy("cmd"),
+        rehearse: args.is_present("rehearse"),
+        memory: args
+            .value_of("memory")
+            .map(|memory| parse_size(memory).map(|bytes| bytes as i64))
+            .transpose()?,
+        memory_swap: args
+            .value_of("memory_swap")
+            .map(parse_memory_swap)
+            .transpose()?,
+        nano_cpus: args.value_of("cpus").map(parse_cpus).transpose()?,
+        ignore_swarm_references: args.is_present("ignore_swarm_references"),
+        bind_remap: args
+            .values_of("bind_remap")
+            .map(|remaps| remaps.map(parse_bind_remap).collect::<Result<Vec<_>>>())
+            .transpose()?
+            .unwrap_or_default(),
+        merge: if args.is_present("skip_existing") {
+            MergeMode::SkipExisting
+        } else if args.is_present("newer") {
+            MergeMode::Newer
+        } else {
+            MergeMode::Overwrite
+        },
+        verify_files: args.is_present("verify_files"),
+    })
+}
+
+fn get_backup_options(args: &ArgMatches<'_>) -> Result<BackupOptions> {
+    let options = BackupOptions {
+        exclude_volumes: HashSet::from_iter(
+            args.values_of_lossy("exclude_volumes").unwrap_or_default(),
+        ),
+        skip_unchanged: args.is_present("skip_unchanged"),
+        compression_level: args
+            .value_of("compression_level")
+            .map(|level| parse_compression_level(Codec::Gzip, level))
+            .transpose()?,
+        extension: args
+            .value_of("extension")
+            .map(parse_extension)
+            .transpose()?,
+        rsyncable: args.is_present("rsyncable"),
+        snapshot: args.is_present("snapshot"),
+        destination_on_host: args.is_present("destination_on_host"),
+        include_image: args.is_present("include_image"),
+        preserve_hardlinks: args.is_present("preserve_hardlinks"),
+        include_special: args.is_present("include_special"),
+        incremental: args.is_present("incremental"),
+        no_clobber: args.is_present("no_clobber"),
+        compress_manifest: args.is_present("compress_manifest"),
+        pre_backup_hook: args.value_of("pre_backup_hook").map(String::from),
+        post_backup_hook: args.value_of("post_backup_hook").map(String::from),
+        exclude: get_exclude_patterns(args)?,
+        subpaths: args
+            .values_of("subpath")
+            .unwrap_or_default()
+            .map(parse_subpath)
+            .collect::<Result<_>>()?,
+        timeout: args
+            .value_of("backup_timeout")
+            .map(parse_duration)
+            .transpose()?,
+        append_only: args.is_present("append_only"),
+        inline_threshold: args
+            .value_of("inline_threshold")
+            .map(parse_size)
+            .transpose()?,
+        redact_env: args.values_of_lossy("redact_env").unwrap_or_default(),
+        keep_last: args.value_of("keep_last").map(parse_keep).transpose()?,
+        layout: args
+            .value_of("layout")
+            .map(str::parse::<Layout>)
+            .transpose()?
+            .unwrap_or_default(),
+        archive_stem: args.value_of("archive_stem").map(String::from),
+        timezone: args
+            .value_of("timezone")
+            .map(str::parse::<Timezone>)
+            .transpose()?
+            .unwrap_or_default(),
+        hash_algorithm: args
+            .value_of("hash_algo")
+            .map(str::parse::<HashAlgorithm>)
+            .transpose()?
+            .unwrap_or_default(),
+        modes: get_file_modes(args)?,
+        resume: args.is_present("resume"),
+        file_manifest: args.is_present("file_manifest"),
+        cache_volumes: if args.is_present("skip_cache_volumes") {
+            cache_volume_patterns(
+                &args
+                    .values_of("cache_volume_pattern")
+                    .unwrap_or_default()
+                    .collect::<Vec<_>>(),
+            )?
+        } else {
+            vec![]
+        },
+    };
+    options.check_append_only()?;
+    options.check_layout()?;
+    Ok(options)
+}
+
+/// Return permissions from `--dir-mode` and `--file-mode`, owner only if not given
+fn get_file_modes(args: &ArgMatches<'_>) -> Result<FileModes> {
+    let default = FileModes::default();
+    Ok(FileModes {
+        directory: args
+            .value_of("dir_mode")
+            .map(parse_mode)
+            .transpose()?
+            .unwrap_or(default.directory),
+        file: args
+            .value_of("file_mode")
+            .map(parse_mode)
+            .transpose()?
+            .unwrap_or(default.file),
+    })
+}
+
+/// Return patterns from `--exclude-from` followed by those passed with `--exclude`
+fn get_exclude_patterns(args: &ArgMatches<'_>) -> Result<Vec<String>> {
+    let mut patterns = match args.value_of("exclude_from") {
+        Some(path) => read_exclude_file(Path::new(path))?,
+        None => vec![],
+    };
+    patterns.extend(args.values_of_lossy("exclude").unwrap_or_default());
+    Ok(patterns)
+}
+
+async fn run_cleanup(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    log::info!("Cleaning up all dockyard containers");
+    let report = cleanup_dockyard_containers(docker).await?;
+    let exit_code = if report.failed.is_empty() { 0 } else { 1 };
+    if args.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(exit_code);
+    }
+    if report.failed.is_empty() {
+        log::info!("Successfully cleaned up all dockyard containers");
+        Ok(exit_code)
+    } else {
+        Err(anyhow!(
+            "Failed to remove {} dockyard containers",
+            report.failed.len()
+        ))
+    }
+}
+
+async fn run_cleanup_rehearsal(docker: &Docker, args: &ArgMatches<'_>) -> Result<i32> {
+    log::info!("Cleaning up rehearsal containers");
+    let report = cleanup_rehearsals(docker).await?;
+    let failures = report.failed.len() + report.failed_volumes.len();
+    let exit_code = if failures == 0 { 0 } else { 1 };
+    if args.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(exit_code);
+    }
+    if failures == 0 {
+        log::info!(
+            "Successfully removed {} rehearsal containers and {} volumes",
+            report.removed.len(),
+            report.removed_volumes.len()
+        );
+        Ok(exit_code)
+    } else {
+        Err(anyhow!(
+            "Failed to remove {} rehearsal containers and {} volumes",
+            report.failed.len(),
+            report.failed_volumes.len()
+        ))
+    }
+}
+
 async fn run_backup(docker: &Docker, subcommand: &ArgMatches<'_>) -> Result<i32> {
     match subcommand.subcommand() {
         ("directory", Some(subargs)) => {
             let input = subargs.value_of("INPUT").unwrap();
             let output = subargs.value_of("OUTPUT").unwrap();
-            backup_directory(input, output).map(|p| {
-                log::info!(
+            let options = get_backup_options(subargs)?;
+            let path = backup_directory(input, output, &options)?;
+            // The archive path must stay last, it's parsed from helper output
+            match archive_checksum(&Path::new(output).join(&path), options.hash_algorithm)? {
+                Some(checksum) => log::info!(
+                    "Successfully backed up directory {} ({} {}) to {}",
+                    input,
+                    options.hash_algorithm,
+                    checksum,
+                    path.display()
+                ),
+                None => log::info!(
                     "Successfully backed up directory {} to {}",
                     input,
-                    p.display()
-                );
-                0
-            })
+                    path.display()
+                ),
+            }
+            Ok(0)
         }
         (subcommand, Some(subargs)) if subcommand == "container" || subcommand == "volume" => {
             let resource_name = subargs.value_of("NAME").unwrap();
@@ -201,35 +1071,100 @@ async fn run_backup(docker: &Docker, subcommand: &ArgMatches<'_>) -> Result<i32>
             } else {
                 get_backup_volume_mount(output.to_string())
             };
+            let options = get_backup_options(subargs)?;
             match subcommand {
-                "volume" => backup_volume(&docker, resource_name.to_string(), backup_mount)
-                    .await
-                    .map(|p| {
-                        log::info!(
-                            "Successfully backed up volume {} to {}",
-                            resource_name,
-                            p.display()
-                        );
-                        0
-                    }),
-                "container" => {
-                    let exclude_volumes: HashSet<String> = HashSet::from_iter(
-                        subargs.values_of_lossy("exclude_volumes").unwrap_or_default(),
-                    );
-                    backup_container(&docker, resource_name, backup_mount, &exclude_volumes)
+                "volume" => {
+                    backup_volume(&docker, resource_name.to_string(), backup_mount, &options)
                         .await
                         .map(|p| {
                             log::info!(
-                                "Successfully backed up container {} to {}",
+                                "Successfully backed up volume {} to {}",
                                 resource_name,
-                                p.display()
+                                p.path.display()
                             );
                             0
                         })
                 }
+                "container" => {
+                    let result =
+                        backup_container(&docker, resource_name, backup_mount, &options).await?;
+                    log::info!(
+                        "Successfully backed up container {} to {}",
+                        resource_name,
+                        result.path.display()
+                    );
+                    if subargs.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&result)?);
+                    }
+                    Ok(0)
+                }
                 _ => print_usage(subargs),
             }
         }
+        ("host", Some(subargs)) => {
+            let output = subargs.value_of("OUTPUT").unwrap();
+            let backup_mount = if subargs.value_of("output_type").unwrap() == "directory" {
+                get_backup_directory_mount(output.to_string())
+            } else {
+                get_backup_volume_mount(output.to_string())
+            };
+            let selector = subargs
+                .value_of("label")
+                .map(str::parse::<LabelSelector>)
+                .transpose()?;
+            let options = get_backup_options(subargs)?;
+            let report = backup_host(&docker, selector.as_ref(), backup_mount, &options).await?;
+            let exit_code = if report.has_failures() { 1 } else { 0 };
+            if subargs.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(exit_code);
+            }
+            log::info!(
+                "Backed up {} volumes ({} shared) and {} containers to {}",
+                report.volumes.len(),
+                report.shared_volumes.len(),
+                report.containers.len(),
+                report.manifest.display()
+            );
+            if report.has_failures() {
+                return Err(anyhow!(
+                    "Failed to back up volumes [{}] and containers [{}]",
+                    report.failed_volumes.join(", "),
+                    report.failed_containers.join(", ")
+                ));
+            }
+            Ok(exit_code)
+        }
+        ("volumes", Some(subargs)) => {
+            let output = subargs.value_of("OUTPUT").unwrap();
+            let backup_mount = if subargs.value_of("output_type").unwrap() == "directory" {
+                get_backup_directory_mount(output.to_string())
+            } else {
+                get_backup_volume_mount(output.to_string())
+            };
+            let selection = if subargs.is_present("dangling") {
+                VolumeSelection::Dangling
+            } else {
+                VolumeSelection::All
+            };
+            let name_filter = subargs
+                .value_of("match")
+                .map(parse_name_filter)
+                .transpose()?;
+            let options = get_backup_options(subargs)?;
+            backup_volumes(
+                &docker,
+                selection,
+                name_filter.as_ref(),
+                backup_mount,
+                &options,
+            )
+            .await
+            .map(|archives| {
+                log::info!("Successfully backed up {} volumes", archives.len());
+                0
+            })
+        }
         _ => print_usage(subcommand),
     }
 }
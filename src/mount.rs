@@ -0,0 +1,411 @@
+use crate::backup::{
+    archive_volume, backup_directory_to_mount, backup_volume, BackupOptions, MountBackup,
+};
+use crate::container::get_volume_mount;
+use crate::file::path_str;
+use crate::restore::{restore_directory_from_mount, restore_volume, RestoreOptions};
+use anyhow::{Context, Result};
+use bollard::models::{Mount, MountPoint};
+use bollard::volume::CreateVolumeOptions;
+use bollard::Docker;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Back up the contents of a container mount
+///
+/// Bind mounts are archived to `dockyard/binds/<source>` and volumes to
+/// `dockyard/volumes/<name>` on the backup location. With `flat_directory`, both are archived
+/// to that directory instead, as `bind-<source>` and `volume-<name>`. The labels of volumes are
+/// recorded too.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount` - Container mount to back up
+/// * `backup_mount` - Mount representing backup destination
+/// * `flat_directory` - Directory of a flat layout backup, relative to the backup destination
+/// * `options` - Backup options
+///
+pub async fn backup_mount(
+    docker: &Docker,
+    mount: MountPoint,
+    backup_mount: &Mount,
+    flat_directory: Option<&Path>,
+    options: &BackupOptions,
+) -> Result<MountBackup> {
+    let mut resolved_source = None;
+    let (archive, volume_labels) = match mount.typ.as_deref() {
+        Some("bind") => {
+            let directory = mount.source.clone().unwrap();
+            resolved_source = resolve_bind_source(&directory)?;
+            let (output, options) = match flat_directory {
+                Some(output) => {
+                    let stem = directory.trim_start_matches('/').replace("/", ":");
+                    let output = path_str(output)?.to_string();
+                    (output, flat_options(options, format!("bind-{}", stem)))
+                }
+                None => {
+                    let output = format!("dockyard/binds/{}", directory.replace("/", ":"));
+                    (output, options.clone())
+                }
+            };
+            let archive = backup_directory_to_mount(
+                docker,
+                resolved_source.clone().unwrap_or(directory),
+                output,
+                backup_mount.clone(),
+                &options,
+            )
+            .await?;
+            (archive, HashMap::new())
+        }
+        Some("volume") => {
+            let volume = mount.name.clone().unwrap();
+            let labels = docker.inspect_volume(&volume).await?.labels;
+            let archive = match flat_directory {
+                Some(output) => {
+                    let options = flat_options(options, format!("volume-{}", volume));
+                    archive_volume(
+                        docker,
+                        volume,
+                        output.to_path_buf(),
+                        backup_mount.clone(),
+                        &options,
+                    )
+                    .await?
+                }
+                None => backup_volume(docker, volume, backup_mount.clone(), options).await?,
+            };
+            (archive, labels)
+        }
+        typ => {
+            return Err(anyhow!(
+                "Unable to back up {} mount at {}",
+                typ.unwrap_or("unknown"),
+                mount.destination.as_deref().unwrap_or_default()
+            ))
+        }
+    };
+    log::info!("Successfully backed up to {}", archive.path.display());
+    Ok(MountBackup {
+        path: archive.path,
+        mount,
+        checksum: archive.checksum,
+        volume_labels,
+        resolved_source,
+    })
+}
+
+/// Return the directory a bind mount source resolves to if it is, or is inside, a symlink
+///
+/// Docker follows symlinks when it bind mounts a directory, so a dangling one would be
+/// backed up as an empty directory. Sources this process can't see, e.g. on a remote Docker
+/// host, are used as they are.
+///
+/// # Arguments
+///
+/// * `source` - Bind mount source on the Docker host
+///
+fn resolve_bind_source(source: &str) -> Result<Option<String>> {
+    let path = Path::new(source);
+    if path.symlink_metadata().is_err() {
+        return Ok(None);
+    }
+    let target = path
+        .canonicalize()
+        .with_context(|| format!("Bind source {} is a dangling symlink", source))?;
+    if target == path {
+        return Ok(None);
+    }
+    log::info!(
+        "Bind source {} is a symlink, backing up {}",
+        source,
+        target.display()
+    );
+    Ok(Some(path_str(&target)?.to_string()))
+}
+
+/// Return `options` naming archives `stem`, for flat layout backups
+fn flat_options(options: &BackupOptions, stem: String) -> BackupOptions {
+    BackupOptions {
+        archive_stem: Some(stem),
+        ..options.clone()
+    }
+}
+
+/// Restore a mount backup to the volume or host directory it was taken from
+///
+/// Volumes are created with the labels recorded when they were backed up.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `mount_backup` - Backup of the mount
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn restore_mount(
+    docker: &Docker,
+    mount_backup: &MountBackup,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let archive = path_str(&mount_backup.path)?.to_string();
+    let mount = &mount_backup.mount;
+    let name = match mount.typ.as_deref() {
+        Some("bind") => {
+            let directory = options.bind_source(mount.source.as_ref().unwrap());
+            restore_directory_from_mount(docker, archive, backup_mount, directory.clone(), options)
+                .await
+                .with_context(|| format!("Failed to restore mount {}", directory))?;
+            directory
+        }
+        Some("volume") => {
+            let volume = options.volume_name(mount.name.as_ref().unwrap());
+            if !mount_backup.volume_labels.is_empty() {
+                // Creating the volume first, restore_volume then reuses it
+                docker
+                    .create_volume(CreateVolumeOptions {
+                        name: volume.clone(),
+                        driver: "local".to_string(),
+                        driver_opts: Default::default(),
+                        labels: mount_backup.volume_labels.clone(),
+                    })
+                    .await
+                    .with_context(|| format!("Failed to create volume {}", volume))?;
+            }
+            let volume_mount = get_volume_mount(volume.clone());
+            restore_volume(docker, archive, backup_mount, volume_mount, options)
+                .await
+                .with_context(|| format!("Failed to restore mount {}", volume))?;
+            volume
+        }
+        typ => {
+            return Err(anyhow!(
+                "Unable to restore {} mount at {}",
+                typ.unwrap_or("unknown"),
+                mount.destination.as_deref().unwrap_or_default()
+            ))
+        }
+    };
+    log::info!("Successfully restored mount {}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::archive_checksum;
+    use crate::container::get_backup_directory_mount;
+    use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions};
+    use log::LevelFilter;
+    use simple_logger::SimpleLogger;
+    use std::fs::{create_dir, read_to_string, write};
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    #[test]
+    fn backup_and_restore_bind_mount_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let source = working_dir.path().join("source");
+        let output = working_dir.path().join("output");
+        create_dir(&source).unwrap();
+        create_dir(&output).unwrap();
+        write(source.join("file"), "bind contents").unwrap();
+        let backup_location = get_backup_directory_mount(output.to_str().unwrap().to_string());
+        let mount = MountPoint {
+            typ: Some("bind".to_string()),
+            source: Some(source.to_str().unwrap().to_string()),
+            destination: Some("/data".to_string()),
+            ..Default::default()
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let mount_backup = rt
+            .block_on(backup_mount(
+                &docker,
+                mount,
+                &backup_location,
+                None,
+                &Default::default(),
+            ))
+            .unwrap();
+        assert!(mount_backup.path.starts_with(format!(
+            "dockyard/binds/{}",
+            source.to_str().unwrap().replace("/", ":")
+        )));
+        assert!(output.join(&mount_backup.path).exists());
+        assert_eq!(
+            mount_backup.checksum,
+            archive_checksum(&output.join(&mount_backup.path), Default::default()).unwrap()
+        );
+
+        write(source.join("file"), "changed").unwrap();
+        rt.block_on(restore_mount(
+            &docker,
+            &mount_backup,
+            backup_location,
+            &Default::default(),
+        ))
+        .unwrap();
+        assert_eq!(
+            read_to_string(source.join("file")).unwrap(),
+            "bind contents"
+        );
+    }
+
+    #[test]
+    fn resolve_bind_source_test() {
+        let working_dir = TempDir::new().unwrap();
+        let source = working_dir.path().canonicalize().unwrap().join("source");
+        let link = working_dir.path().join("link");
+        create_dir(&source).unwrap();
+        symlink(&source, &link).unwrap();
+        symlink(
+            working_dir.path().join("missing"),
+            working_dir.path().join("dangling"),
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_bind_source(link.to_str().unwrap()).unwrap(),
+            Some(source.to_str().unwrap().to_string())
+        );
+        assert_eq!(
+            resolve_bind_source(link.join("..").join("source").to_str().unwrap()).unwrap(),
+            Some(source.to_str().unwrap().to_string())
+        );
+        assert_eq!(resolve_bind_source(source.to_str().unwrap()).unwrap(), None);
+        assert_eq!(resolve_bind_source("/not/on/this/host").unwrap(), None);
+        assert!(
+            resolve_bind_source(working_dir.path().join("dangling").to_str().unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn backup_and_restore_symlinked_bind_mount_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let source = working_dir.path().canonicalize().unwrap().join("source");
+        let link = working_dir.path().join("link");
+        let output = working_dir.path().join("output");
+        create_dir(&source).unwrap();
+        create_dir(&output).unwrap();
+        write(source.join("file"), "bind contents").unwrap();
+        symlink(&source, &link).unwrap();
+        let backup_location = get_backup_directory_mount(output.to_str().unwrap().to_string());
+        let mount = MountPoint {
+            typ: Some("bind".to_string()),
+            source: Some(link.to_str().unwrap().to_string()),
+            destination: Some("/data".to_string()),
+            ..Default::default()
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let mount_backup = rt
+            .block_on(backup_mount(
+                &docker,
+                mount,
+                &backup_location,
+                None,
+                &Default::default(),
+            ))
+            .unwrap();
+        assert!(mount_backup.path.starts_with(format!(
+            "dockyard/binds/{}",
+            link.to_str().unwrap().replace("/", ":")
+        )));
+        assert_eq!(mount_backup.resolved_source.as_deref(), source.to_str());
+
+        write(source.join("file"), "changed").unwrap();
+        rt.block_on(restore_mount(
+            &docker,
+            &mount_backup,
+            backup_location,
+            &Default::default(),
+        ))
+        .unwrap();
+        assert_eq!(
+            read_to_string(source.join("file")).unwrap(),
+            "bind contents"
+        );
+    }
+
+    #[test]
+    fn backup_and_restore_volume_mount_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let volume_name = format!("mount_test_volume_{}", Uuid::new_v4());
+        let working_dir = TempDir::new().unwrap();
+        let backup_location =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mount = MountPoint {
+            typ: Some("volume".to_string()),
+            name: Some(volume_name.clone()),
+            destination: Some("/data".to_string()),
+            ..Default::default()
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let mount_backup = rt.block_on(async {
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume_name.as_str(),
+                    driver: "local",
+                    driver_opts: Default::default(),
+                    labels: Default::default(),
+                })
+                .await
+                .unwrap();
+            let mount_backup =
+                backup_mount(&docker, mount, &backup_location, None, &Default::default())
+                    .await
+                    .unwrap();
+            docker
+                .remove_volume(&volume_name, None::<RemoveVolumeOptions>)
+                .await
+                .unwrap();
+            mount_backup
+        });
+        assert!(mount_backup
+            .path
+            .starts_with(format!("dockyard/volumes/{}", volume_name)));
+
+        rt.block_on(async {
+            restore_mount(&docker, &mount_backup, backup_location, &Default::default())
+                .await
+                .unwrap();
+            // Restoring recreates the volume
+            docker.inspect_volume(&volume_name).await.unwrap();
+            docker
+                .remove_volume(&volume_name, None::<RemoveVolumeOptions>)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn backup_unsupported_mount_test() {
+        let mount = MountPoint {
+            typ: Some("tmpfs".to_string()),
+            destination: Some("/run".to_string()),
+            ..Default::default()
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let error = rt
+            .block_on(backup_mount(
+                &docker,
+                mount,
+                &get_backup_directory_mount("/tmp".to_string()),
+                None,
+                &Default::default(),
+            ))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Unable to back up tmpfs mount at /run");
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use bollard::models::{ContainerInspectResponse, EndpointSettings};
+use bollard::network::{ConnectNetworkOptions, DisconnectNetworkOptions};
+use bollard::Docker;
+use std::collections::BTreeMap;
+
+/// Network modes Docker provides, which never have to be created
+pub(crate) const BUILTIN_NETWORK_MODES: &[&str] = &["default", "bridge", "host", "none"];
+
+/// Length of the short container ID Docker adds as an alias on user-defined networks
+const SHORT_ID_LENGTH: usize = 12;
+
+/// Endpoint of a container on a user-defined network
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct NetworkEndpoint {
+    /// Names other containers on the network can reach the container by, besides its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+}
+
+/// Return the user-defined networks an inspected container is connected to
+///
+/// Docker adds the short container ID as an alias on every user-defined network, it's left out
+/// since the restored container gets a new ID.
+///
+/// # Arguments
+///
+/// * `info` - Container inspect response
+///
+pub(crate) fn find_network_endpoints(
+    info: &ContainerInspectResponse,
+) -> BTreeMap<String, NetworkEndpoint> {
+    let short_id = info
+        .id
+        .as_deref()
+        .map(|id| &id[..id.len().min(SHORT_ID_LENGTH)]);
+    info.network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| !BUILTIN_NETWORK_MODES.contains(&name.as_str()))
+        .map(|(name, endpoint)| {
+            let aliases = endpoint
+                .aliases
+                .iter()
+                .flatten()
+                .filter(|alias| Some(alias.as_str()) != short_id)
+                .cloned()
+                .collect();
+            (name.clone(), NetworkEndpoint { aliases })
+        })
+        .collect()
+}
+
+/// Connect a restored container to its backed up networks, with their aliases
+///
+/// Docker connects a new container to the network of its network mode without aliases, so the
+/// container is disconnected from it and connected again when it had any.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Name of restored container
+/// * `network_mode` - Network mode the container was created with
+/// * `networks` - Backed up networks of the container
+/// * `with_aliases` - Whether to set aliases, rehearsals leave them to the original container
+///
+pub(crate) async fn connect_networks(
+    docker: &Docker,
+    container: &str,
+    network_mode: Option<&str>,
+    networks: &BTreeMap<String, NetworkEndpoint>,
+    with_aliases: bool,
+) -> Result<()> {
+    for (name, endpoint) in networks {
+        let aliases = if with_aliases && !endpoint.aliases.is_empty() {
+            Some(endpoint.aliases.clone())
+        } else {
+            None
+        };
+        if network_mode == Some(name.as_str()) {
+            if aliases.is_none() {
+                continue;
+            }
+            docker
+                .disconnect_network(
+                    name,
+                    DisconnectNetworkOptions {
+                        container,
+                        force: false,
+                    },
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to disconnect container {} from network {}",
+                        container, name
+                    )
+                })?;
+        }
+        log::info!("Connecting container {} to network {}", container, name);
+        docker
+            .connect_network(
+                name,
+                ConnectNetworkOptions {
+                    container,
+                    endpoint_config: EndpointSettings {
+                        aliases,
+                        ..Default::default()
+                    },
+                },
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to connect container {} to network {}",
+                    container, name
+                )
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bollard::models::NetworkSettings;
+    use std::collections::HashMap;
+
+    #[test]
+    fn find_network_endpoints_test() {
+        let id = "4f66ad9a0b2e5a1f8c7d3e2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f";
+        let endpoint = |aliases: &[&str]| EndpointSettings {
+            aliases: Some(aliases.iter().map(|alias| alias.to_string()).collect()),
+            ..Default::default()
+        };
+        let mut networks = HashMap::new();
+        networks.insert("bridge".to_string(), endpoint(&[]));
+        networks.insert("backend".to_string(), endpoint(&["db", "4f66ad9a0b2e"]));
+        networks.insert("frontend".to_string(), EndpointSettings::default());
+        let info = ContainerInspectResponse {
+            id: Some(id.to_string()),
+            network_settings: Some(NetworkSettings {
+                networks: Some(networks),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "backend".to_string(),
+            NetworkEndpoint {
+                aliases: vec!["db".to_string()],
+            },
+        );
+        expected.insert("frontend".to_string(), NetworkEndpoint::default());
+        assert_eq!(find_network_endpoints(&info), expected);
+        assert!(find_network_endpoints(&ContainerInspectResponse::default()).is_empty());
+    }
+}
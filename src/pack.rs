@@ -0,0 +1,537 @@
+use crate::backup::{Codec, POINTER_EXTENSION};
+use crate::container::{download_from_mount_to_file, read_only_mount, upload_file_to_mount};
+use crate::file::{path_str, FileModes};
+use crate::hash::{HashAlgorithm, HashingReader};
+use crate::list::backup_file_exists;
+use crate::restore::{parse_incremental_info, read_container_backup};
+use anyhow::{Context, Result};
+use bollard::models::Mount;
+use bollard::Docker;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::{Archive, Builder, Header};
+
+/// Name of the index member written first in packed backups
+pub const PACK_INDEX: &str = "dockyard-pack.json";
+
+/// Contents of a packed backup
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PackIndex {
+    /// Container backup file, relative to the backup location
+    pub container_backup: PathBuf,
+    /// Algorithm of the checksums in `files`
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+    pub hash_algorithm: HashAlgorithm,
+    /// Every file in the pack, in the order they are packed
+    pub files: Vec<PackedFile>,
+}
+
+/// File in a packed backup
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PackedFile {
+    /// Path of the file relative to the backup location
+    pub path: PathBuf,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Checksum of the file with the `hash_algorithm` of the index
+    pub checksum: String,
+}
+
+/// Bundle a container backup and every archive it references into a single tar
+///
+/// Pointers written for unchanged directories and the full archives incremental archives
+/// were taken against are followed, so the pack can be restored on its own. Each file is
+/// downloaded to a temporary file and streamed into the pack, so it isn't held in memory.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Container backup file relative to `backup_mount`
+/// * `backup_mount` - Mount representing backup location
+/// * `output` - Path of tar to write
+///
+pub async fn pack_container_backup(
+    docker: &Docker,
+    backup_file: &Path,
+    backup_mount: Mount,
+    output: &Path,
+) -> Result<PackIndex> {
+    let backup_mount = read_only_mount(backup_mount);
+    let container_backup =
+        read_container_backup(docker, path_str(backup_file)?, &backup_mount).await?;
+    let mut pending = container_backup
+        .mounts
+        .iter()
+        .map(|m| m.path.clone())
+        .chain(container_backup.image_archive.clone())
+        .collect::<Vec<_>>();
+    pending.push(backup_file.to_path_buf());
+    pending.reverse();
+
+    let hash_algorithm = HashAlgorithm::default();
+    let mut files: Vec<PackedFile> = vec![];
+    let mut contents = vec![];
+    while let Some(path) = pending.pop() {
+        if files.iter().any(|f| f.path == path) {
+            continue;
+        }
+        log::info!("Packing {}", path.display());
+        let mut file = download_from_mount_to_file(docker, backup_mount.clone(), &path).await?;
+        pending.extend(referenced_archive(&path, &mut file)?);
+        let mut reader = HashingReader::new(&mut file, hash_algorithm);
+        let size = io::copy(&mut reader, &mut io::sink())?;
+        let checksum = reader.finish();
+        file.seek(SeekFrom::Start(0))?;
+        files.push(PackedFile {
+            path,
+            size,
+            checksum,
+        });
+        contents.push(file);
+    }
+
+    let index = PackIndex {
+        container_backup: backup_file.to_path_buf(),
+        hash_algorithm,
+        files,
+    };
+    let mut builder = Builder::new(
+        File::create(output).with_context(|| format!("Unable to create {}", output.display()))?,
+    );
+    let index_contents = serde_json::to_string_pretty(&index)?;
+    append_file(
+        &mut builder,
+        Path::new(PACK_INDEX),
+        index_contents.len() as u64,
+        index_contents.as_bytes(),
+    )?;
+    for (packed, file) in index.files.iter().zip(contents) {
+        append_file(&mut builder, &packed.path, packed.size, file)?;
+    }
+    builder.into_inner()?.sync_all()?;
+    Ok(index)
+}
+
+/// Return the archive `path` depends on, if it is a pointer or an incremental archive
+///
+/// `file` is rewound to its start afterwards.
+///
+/// # Arguments
+///
+/// * `path` - Path of archive relative to the backup location
+/// * `file` - Contents of archive
+///
+fn referenced_archive(path: &Path, file: &mut File) -> Result<Option<PathBuf>> {
+    let referenced = if path.extension().and_then(|e| e.to_str()) == Some(POINTER_EXTENSION) {
+        let mut target = String::new();
+        file.read_to_string(&mut target)?;
+        Some(path.with_file_name(target.trim()))
+    } else {
+        // Archives may have custom extensions, so look at their contents
+        let mut header = vec![];
+        file.take(2).read_to_end(&mut header)?;
+        file.seek(SeekFrom::Start(0))?;
+        if Codec::detect(&header) == Some(Codec::Gzip) {
+            parse_incremental_info(&mut *file)?.map(|info| path.with_file_name(info.base))
+        } else {
+            None
+        }
+    };
+    file.seek(SeekFrom::Start(0))?;
+    Ok(referenced)
+}
+
+fn append_file<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    size: u64,
+    contents: impl Read,
+) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    builder.append_data(&mut header, path, contents.take(size))?;
+    Ok(())
+}
+
+/// Write the files of a packed backup back into a backup location
+///
+/// Every file is copied to a temporary file and checked against the size and checksum
+/// recorded in the index before any is written, so a pack with a file that doesn't match
+/// leaves the backup location untouched.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `input` - Path of tar written by `pack_container_backup`
+/// * `backup_mount` - Mount representing backup location to unpack into
+/// * `append_only` - Fail instead of overwriting files that already exist in the backup location
+/// * `modes` - Permissions of written files and the directories leading to them
+///
+pub async fn unpack_container_backup(
+    docker: &Docker,
+    input: &Path,
+    backup_mount: Mount,
+    append_only: bool,
+    modes: &FileModes,
+) -> Result<PackIndex> {
+    let mut archive = Archive::new(
+        File::open(input).with_context(|| format!("Unable to open {}", input.display()))?,
+    );
+    let mut entries = archive.entries()?;
+    let index: PackIndex = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()? != Path::new(PACK_INDEX) {
+                return Err(anyhow!(
+                    "{} is not a dockyard pack, missing {}",
+                    input.display(),
+                    PACK_INDEX
+                ));
+            }
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid {} in {}", PACK_INDEX, input.display()))?
+        }
+        None => return Err(anyhow!("{} is empty", input.display())),
+    };
+    let mut expected = index.files.iter();
+    let mut verified = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        let packed = match expected.next() {
+            Some(packed) if packed.path == path && is_relative_member(&path) => packed,
+            _ => {
+                return Err(anyhow!(
+                    "Refusing to unpack {}, it is not the next file listed in {}",
+                    path.display(),
+                    PACK_INDEX
+                ))
+            }
+        };
+        if append_only && backup_file_exists(docker, &path, backup_mount.clone()).await? {
+            return Err(anyhow!(
+                "{} already exists, refusing to overwrite it with --append-only",
+                path.display()
+            ));
+        }
+        log::info!("Verifying {}", path.display());
+        let mut file = tempfile::tempfile()?;
+        let mut reader = HashingReader::new(entry, index.hash_algorithm);
+        let size = io::copy(&mut reader, &mut file)?;
+        verify_packed_file(packed, size, &reader.finish(), index.hash_algorithm)?;
+        file.seek(SeekFrom::Start(0))?;
+        verified.push((packed, file));
+    }
+    if let Some(packed) = expected.next() {
+        return Err(anyhow!(
+            "{} is listed in {} but missing from {}",
+            packed.path.display(),
+            PACK_INDEX,
+            input.display()
+        ));
+    }
+    for (packed, file) in verified {
+        log::info!("Unpacking {}", packed.path.display());
+        upload_file_to_mount(
+            docker,
+            backup_mount.clone(),
+            &packed.path,
+            file,
+            packed.size,
+            modes,
+        )
+        .await?;
+    }
+    Ok(index)
+}
+
+/// Fail if the size and checksum of a file don't match those `packed` was recorded with
+///
+/// # Arguments
+///
+/// * `packed` - Index entry of the file
+/// * `size` - Size of the file in the pack
+/// * `checksum` - Checksum of the file in the pack
+/// * `algorithm` - Algorithm of the checksums
+///
+fn verify_packed_file(
+    packed: &PackedFile,
+    size: u64,
+    checksum: &str,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    if size != packed.size {
+        Err(anyhow!(
+            "{} is {} bytes, expected {} bytes",
+            packed.path.display(),
+            size,
+            packed.size
+        ))
+    } else if checksum != packed.checksum {
+        Err(anyhow!(
+            "{} doesn't match its {} checksum in {}",
+            packed.path.display(),
+            algorithm,
+            PACK_INDEX
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Return whether `path` stays inside the directory it is unpacked into
+fn is_relative_member(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::{ContainerBackup, MountBackup, CONTAINER_BACKUP_SCHEMA_VERSION};
+    use crate::container::get_backup_directory_mount;
+    use std::fs::{create_dir_all, read, write};
+    use std::io::Write;
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn is_relative_member_test() {
+        assert!(is_relative_member(Path::new("dockyard/volumes/a/1.tgz")));
+        assert!(!is_relative_member(Path::new("/etc/passwd")));
+        assert!(!is_relative_member(Path::new("dockyard/../../etc/passwd")));
+    }
+
+    #[test]
+    fn referenced_archive_test() {
+        let file = |contents: &[u8]| {
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(contents).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file
+        };
+        let pointer = Path::new("dockyard/volumes/a/2.tgz.ref");
+        let mut pointer_file = file(b"1.tgz\n");
+        assert_eq!(
+            referenced_archive(pointer, &mut pointer_file).unwrap(),
+            Some(PathBuf::from("dockyard/volumes/a/1.tgz"))
+        );
+        let mut contents = String::new();
+        pointer_file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "1.tgz\n");
+        assert_eq!(
+            referenced_archive(Path::new("dockyard/images/alpine.tar"), &mut file(b"")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn unpack_mismatched_file_test() {
+        let working_dir = TempDir::new().unwrap();
+        let pack = working_dir.path().join("packed.tar");
+        let write_pack = |checksum: String, size: u64, contents: &[u8]| {
+            let index = PackIndex {
+                container_backup: PathBuf::from("dockyard/containers/packed/1.json"),
+                hash_algorithm: Default::default(),
+                files: vec![PackedFile {
+                    path: PathBuf::from("dockyard/containers/packed/1.json"),
+                    size,
+                    checksum,
+                }],
+            };
+            let index = serde_json::to_string(&index).unwrap();
+            let mut builder = Builder::new(File::create(&pack).unwrap());
+            append_file(
+                &mut builder,
+                Path::new(PACK_INDEX),
+                index.len() as u64,
+                index.as_bytes(),
+            )
+            .unwrap();
+            append_file(
+                &mut builder,
+                Path::new("dockyard/containers/packed/1.json"),
+                contents.len() as u64,
+                contents,
+            )
+            .unwrap();
+            builder.into_inner().unwrap();
+        };
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let mount = get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let unpack = |rt: &mut Runtime| {
+            rt.block_on(unpack_container_backup(
+                &docker,
+                &pack,
+                mount.clone(),
+                false,
+                &Default::default(),
+            ))
+            .unwrap_err()
+            .to_string()
+        };
+
+        write_pack(HashAlgorithm::default().digest(b"{}"), 2, b"{]");
+        assert_eq!(
+            unpack(&mut rt),
+            "dockyard/containers/packed/1.json doesn't match its sha256 checksum in dockyard-pack.json"
+        );
+        write_pack(HashAlgorithm::default().digest(b"{}"), 2, b"{}\n");
+        assert_eq!(
+            unpack(&mut rt),
+            "dockyard/containers/packed/1.json is 3 bytes, expected 2 bytes"
+        );
+    }
+
+    #[test]
+    fn unpack_verifies_every_file_first_test() {
+        let working_dir = TempDir::new().unwrap();
+        let pack = working_dir.path().join("packed.tar");
+        let files = vec![
+            ("dockyard/containers/packed/1.json", &b"{}"[..], &b"{}"[..]),
+            (
+                "dockyard/volumes/data/1.tgz",
+                &b"archive"[..],
+                &b"corrupt"[..],
+            ),
+        ];
+        let index = PackIndex {
+            container_backup: PathBuf::from(files[0].0),
+            hash_algorithm: Default::default(),
+            files: files
+                .iter()
+                .map(|(path, recorded, _)| PackedFile {
+                    path: PathBuf::from(path),
+                    size: recorded.len() as u64,
+                    checksum: HashAlgorithm::default().digest(recorded),
+                })
+                .collect(),
+        };
+        let index = serde_json::to_string(&index).unwrap();
+        let mut builder = Builder::new(File::create(&pack).unwrap());
+        append_file(
+            &mut builder,
+            Path::new(PACK_INDEX),
+            index.len() as u64,
+            index.as_bytes(),
+        )
+        .unwrap();
+        for (path, _, packed) in &files {
+            append_file(&mut builder, Path::new(path), packed.len() as u64, *packed).unwrap();
+        }
+        builder.into_inner().unwrap();
+
+        let destination = working_dir.path().join("destination");
+        create_dir_all(&destination).unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let error = rt
+            .block_on(unpack_container_backup(
+                &docker,
+                &pack,
+                get_backup_directory_mount(destination.to_str().unwrap().to_string()),
+                false,
+                &Default::default(),
+            ))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "dockyard/volumes/data/1.tgz doesn't match its sha256 checksum in dockyard-pack.json"
+        );
+        assert!(!destination.join(files[0].0).exists());
+    }
+
+    #[test]
+    fn pack_and_unpack_test() {
+        let source = TempDir::new().unwrap();
+        let volume_dir = source.path().join("dockyard/volumes/data");
+        create_dir_all(&volume_dir).unwrap();
+        let archive = {
+            let mut builder = Builder::new(flate2::write::GzEncoder::new(
+                vec![],
+                flate2::Compression::default(),
+            ));
+            append_file(&mut builder, Path::new("file"), 8, &b"contents"[..]).unwrap();
+            builder.into_inner().unwrap().finish().unwrap()
+        };
+        write(volume_dir.join("1.tgz"), &archive).unwrap();
+        write(volume_dir.join("2.tgz.ref"), "1.tgz").unwrap();
+        let container_backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "packed".to_string(),
+            container_config: Default::default(),
+            host_config: Default::default(),
+            mounts: vec![MountBackup {
+                path: PathBuf::from("dockyard/volumes/data/2.tgz.ref"),
+                mount: Default::default(),
+                checksum: None,
+                volume_labels: Default::default(),
+                resolved_source: None,
+            }],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        let backup_file = Path::new("dockyard/containers/packed/1.json");
+        create_dir_all(source.path().join("dockyard/containers/packed")).unwrap();
+        write(
+            source.path().join(backup_file),
+            serde_json::to_string(&container_backup).unwrap(),
+        )
+        .unwrap();
+
+        let working_dir = TempDir::new().unwrap();
+        let pack = working_dir.path().join("packed.tar");
+        let destination = TempDir::new().unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        let index = rt
+            .block_on(pack_container_backup(
+                &docker,
+                backup_file,
+                get_backup_directory_mount(source.path().to_str().unwrap().to_string()),
+                &pack,
+            ))
+            .unwrap();
+        assert_eq!(
+            index
+                .files
+                .iter()
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                backup_file.to_path_buf(),
+                PathBuf::from("dockyard/volumes/data/2.tgz.ref"),
+                PathBuf::from("dockyard/volumes/data/1.tgz"),
+            ]
+        );
+
+        let unpacked = rt
+            .block_on(unpack_container_backup(
+                &docker,
+                &pack,
+                get_backup_directory_mount(destination.path().to_str().unwrap().to_string()),
+                false,
+                &Default::default(),
+            ))
+            .unwrap();
+        assert_eq!(unpacked, index);
+        for file in &index.files {
+            let contents = read(source.path().join(&file.path)).unwrap();
+            assert_eq!(file.size, contents.len() as u64);
+            assert_eq!(file.checksum, index.hash_algorithm.digest(&contents));
+            assert_eq!(read(destination.path().join(&file.path)).unwrap(), contents);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+static PROGRESS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Show progress bars for container backups and restores
+///
+/// Bars are drawn to stderr, and only when it is a terminal, so enabling them never changes
+/// output that is piped or redirected.
+pub fn set_progress(enabled: bool) {
+    PROGRESS_ENABLED.store(enabled, Relaxed);
+}
+
+/// Progress through the mounts of a container being backed up or restored
+///
+/// Archives are written and read by helper containers, so progress is counted in mounts rather
+/// than bytes. The bar is cleared when this is dropped, including when the operation fails.
+pub(crate) struct MountProgress {
+    bar: ProgressBar,
+}
+
+impl MountProgress {
+    /// Create progress for `mounts` mounts, hidden unless progress is enabled
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - What is being done, e.g. Backing up
+    /// * `container` - Name of container
+    /// * `mounts` - Number of mounts
+    ///
+    pub(crate) fn new(action: &str, container: &str, mounts: usize) -> Self {
+        if !PROGRESS_ENABLED.load(Relaxed) || mounts == 0 {
+            return MountProgress {
+                bar: ProgressBar::hidden(),
+            };
+        }
+        let bar = ProgressBar::new(mounts as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner} {prefix} [{bar:30}] {pos}/{len} mounts {wide_msg}")
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(&format!("{} {}", action, container));
+        bar.enable_steady_tick(100);
+        MountProgress { bar }
+    }
+
+    /// Show `mount` as the mount being worked on
+    pub(crate) fn start(&self, mount: &str) {
+        self.bar.set_message(mount);
+    }
+
+    /// Count the current mount as done
+    pub(crate) fn finish_mount(&self) {
+        self.bar.inc(1);
+    }
+}
+
+impl Drop for MountProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mount_progress_test() {
+        set_progress(false);
+        let progress = MountProgress::new("Backing up", "web", 2);
+        assert!(progress.bar.is_hidden());
+        progress.start("data");
+        progress.finish_mount();
+        assert_eq!(progress.bar.position(), 1);
+
+        set_progress(true);
+        assert!(MountProgress::new("Backing up", "web", 0).bar.is_hidden());
+        set_progress(false);
+    }
+}
@@ -0,0 +1,570 @@
+use crate::backup::{
+    archive_codec, parse_container_backup, referenced_by_state, Codec, POINTER_EXTENSION,
+};
+use crate::catalog::{forget_backups, query_catalog};
+use crate::container::{handle_container_output, last_stdout_line, run_dockyard_command};
+use crate::file::{list_directory, path_str};
+use crate::list::{sort_by_timestamp, TimeWindow};
+use crate::restore::read_incremental_info;
+use anyhow::{Context, Result};
+use bollard::container::LogOutput;
+use bollard::models::Mount;
+use bollard::Docker;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{read_to_string, remove_file};
+use std::path::{Path, PathBuf};
+
+/// Parse the number of backups to keep, which must be at least 1
+///
+/// # Arguments
+///
+/// * `keep` - Number of backups
+///
+pub fn parse_keep(keep: &str) -> Result<usize> {
+    match keep.parse::<usize>() {
+        Ok(keep) if keep > 0 => Ok(keep),
+        _ => Err(anyhow!(
+            "Invalid number of backups to keep {}, expected a number greater than 0",
+            keep
+        )),
+    }
+}
+
+/// Remove all but the most recent backups of a volume, bind or container
+///
+/// Runs `remove_old_backups` in a helper container with the backup location mounted.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `target` - Directory of backups relative to the backup location, e.g. `dockyard/volumes/<name>`
+/// * `keep` - Number of backups to keep
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn trim_backups(
+    docker: &Docker,
+    target: &Path,
+    keep: usize,
+    backup_mount: Mount,
+) -> Result<Vec<PathBuf>> {
+    let keep = keep.to_string();
+    let log_prefix = format!("trim {}", target.display());
+    let target = path_str(target)?;
+    let (exit_code, logs) = run_dockyard_command(
+        docker,
+        Some(vec![backup_mount]),
+        vec!["trim", "--keep", &keep, "/backup", target],
+    )
+    .await?;
+    handle_container_output(exit_code, &log_prefix, &logs)?;
+    let removed = parse_removed(&logs)?;
+    for path in &removed {
+        log::info!("Removed old backup {}", path.display());
+    }
+    Ok(removed)
+}
+
+/// Remove backups taken within a time window, along with archives only they reference
+///
+/// Runs `remove_backups_in_window` in a helper container with the backup location mounted.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `window` - Time window of backups to remove
+/// * `dry_run` - Return the paths that would be removed without removing them
+/// * `backup_mount` - Mount representing backup location
+///
+pub async fn prune_backups(
+    docker: &Docker,
+    window: &TimeWindow,
+    dry_run: bool,
+    backup_mount: Mount,
+) -> Result<Vec<PathBuf>> {
+    let since = window.since.map(|t| t.to_rfc3339());
+    let until = window.until.map(|t| t.to_rfc3339());
+    let mut args = vec!["expire", "/backup"];
+    if let Some(since) = &since {
+        args.extend(vec!["--since", since]);
+    }
+    if let Some(until) = &until {
+        args.extend(vec!["--until", until]);
+    }
+    if dry_run {
+        args.push("--dry-run");
+    }
+    let (exit_code, logs) = run_dockyard_command(docker, Some(vec![backup_mount]), args).await?;
+    handle_container_output(exit_code, "prune", &logs)?;
+    parse_removed(&logs)
+}
+
+/// Parse the JSON list of removed paths printed by a helper container
+fn parse_removed(logs: &[LogOutput]) -> Result<Vec<PathBuf>> {
+    let output = last_stdout_line(logs).unwrap_or_else(|| "[]".to_string());
+    serde_json::from_str(&output).context("Unable to parse removed backups")
+}
+
+/// Remove the oldest backups in `target` beyond the newest `keep`, returning the paths removed
+///
+/// Removing a container backup file also removes the archives it references, unless another
+/// container backup file still references them. Archives that a remaining pointer, incremental
+/// archive or backup state depends on are never removed.
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `target` - Directory of backups relative to `root`
+/// * `keep` - Number of backups to keep
+///
+pub fn remove_old_backups(root: &Path, target: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let backups = sort_by_timestamp(list_backups(root, target)?);
+    if backups.len() <= keep {
+        return Ok(vec![]);
+    }
+    remove_backups(root, &backups[..backups.len() - keep], false)
+}
+
+/// Remove backups taken within `window` anywhere in the backup location, returning the paths
+/// removed
+///
+/// Container backup files, volume archives and bind archives are all considered, with the same
+/// protection of referenced archives as `remove_old_backups`.
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `window` - Time window of backups to remove
+/// * `dry_run` - Return the paths that would be removed without removing them
+///
+pub fn remove_backups_in_window(
+    root: &Path,
+    window: &TimeWindow,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for kind in &["containers", "volumes", "binds"] {
+        for directory in list_backups(root, &Path::new("dockyard").join(kind))? {
+            if root.join(&directory).is_dir() {
+                backups.extend(
+                    list_backups(root, &directory)?
+                        .into_iter()
+                        .filter(|p| window.contains_backup(p)),
+                );
+            }
+        }
+    }
+    remove_backups(root, &backups, dry_run)
+}
+
+/// Remove `backups` along with the archives only removed container backup files reference
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `backups` - Container backup files and archives relative to `root`
+/// * `dry_run` - Return the paths that would be removed without removing them
+///
+fn remove_backups(root: &Path, backups: &[PathBuf], dry_run: bool) -> Result<Vec<PathBuf>> {
+    let old = backups.iter().cloned().collect::<HashSet<_>>();
+    let mut catalogued = catalog_references(root)?;
+    let mut references = |manifest: &Path| match catalogued.remove(manifest) {
+        Some(archives) => Ok(archives),
+        None => manifest_references(root, manifest),
+    };
+    let mut removed_manifests = vec![];
+    let mut referenced = HashSet::new();
+    for manifest in list_manifests(root)? {
+        if old.contains(&manifest) {
+            removed_manifests.push(manifest);
+        } else {
+            referenced.extend(references(&manifest)?);
+        }
+    }
+    let mut candidates = old
+        .into_iter()
+        .filter(|p| !removed_manifests.contains(p))
+        .collect::<HashSet<_>>();
+    for manifest in &removed_manifests {
+        candidates.extend(references(manifest)?);
+    }
+    candidates.retain(|p| !referenced.contains(p) && root.join(p).exists());
+    // Keeping a file can make an archive it depends on necessary too
+    loop {
+        let dependencies = find_dependencies(root, &candidates)?;
+        let count = candidates.len();
+        candidates.retain(|p| !dependencies.contains(p));
+        if candidates.len() == count {
+            break;
+        }
+    }
+
+    let mut removed = removed_manifests
+        .into_iter()
+        .chain(candidates.into_iter())
+        .collect::<Vec<_>>();
+    removed.sort();
+    if !dry_run {
+        for path in &removed {
+            remove_file(root.join(path))
+                .with_context(|| format!("Unable to remove {}", path.display()))?;
+        }
+        forget_backups(root, &removed);
+    }
+    Ok(removed)
+}
+
+/// Return paths of entries in `directory` relative to `root`
+fn list_backups(root: &Path, directory: &Path) -> Result<Vec<PathBuf>> {
+    Ok(list_directory(path_str(&root.join(directory))?)?
+        .into_iter()
+        .map(|name| directory.join(name))
+        .collect())
+}
+
+/// Return every container backup file in the backup location, relative to `root`
+fn list_manifests(root: &Path) -> Result<Vec<PathBuf>> {
+    let containers = Path::new("dockyard/containers");
+    let mut manifests = vec![];
+    for container in list_backups(root, containers)? {
+        if root.join(&container).is_dir() {
+            manifests.extend(sort_by_timestamp(
+                list_backups(root, &container)?
+                    .into_iter()
+                    .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+                    .collect(),
+            ));
+        }
+    }
+    Ok(manifests)
+}
+
+/// Return the archives referenced by each container backup file in the catalog of the backup
+/// location, empty if it has no catalog or the catalog is out of date
+fn catalog_references(root: &Path) -> Result<HashMap<PathBuf, Vec<PathBuf>>> {
+    Ok(query_catalog(root, None)?
+        .into_iter()
+        .flatten()
+        .map(|entry| {
+            let archives = entry.archives.into_iter().map(|a| a.path).collect();
+            (entry.backup_file, archives)
+        })
+        .collect())
+}
+
+/// Return archives referenced by a container backup file, relative to `root`
+fn manifest_references(root: &Path, manifest: &Path) -> Result<Vec<PathBuf>> {
+    let contents = read_to_string(root.join(manifest))
+        .with_context(|| format!("Unable to read {}", manifest.display()))?;
+    let mut backup = parse_container_backup(&contents)
+        .with_context(|| format!("Unable to parse {}", manifest.display()))?;
+    backup.resolve_archives(manifest);
+    Ok(backup
+        .mounts
+        .into_iter()
+        .map(|m| m.path)
+        .chain(backup.image_archive.into_iter())
+        .collect())
+}
+
+/// Return archives that files staying next to `candidates` depend on, relative to `root`
+///
+/// # Arguments
+///
+/// * `root` - Root of the backup location
+/// * `candidates` - Paths that are going to be removed
+///
+fn find_dependencies(root: &Path, candidates: &HashSet<PathBuf>) -> Result<HashSet<PathBuf>> {
+    let directories = candidates
+        .iter()
+        .filter_map(|p| p.parent())
+        .collect::<BTreeSet<_>>();
+    let mut dependencies = HashSet::new();
+    for directory in directories {
+        let output = root.join(directory);
+        for name in referenced_by_state(&output) {
+            dependencies.insert(directory.join(name));
+        }
+        for path in list_backups(root, directory)? {
+            if candidates.contains(&path) || !root.join(&path).is_file() {
+                continue;
+            }
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(POINTER_EXTENSION) => {
+                    let archive = read_to_string(root.join(&path))
+                        .with_context(|| format!("Unable to read {}", path.display()))?;
+                    dependencies.insert(directory.join(archive.trim()));
+                }
+                Some("json") => {}
+                // Archives may have custom extensions, so look for any gzipped file
+                _ if archive_codec(&root.join(&path))? == Some(Codec::Gzip) => {
+                    match read_incremental_info(&root.join(&path)) {
+                        Ok(Some(info)) => {
+                            dependencies.insert(directory.join(info.base));
+                        }
+                        Ok(None) => {}
+                        // An unreadable archive can't be restored, so it doesn't need its base
+                        Err(e) => log::warn!("Unable to read {}: {:#}", path.display(), e),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(dependencies)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backup::{
+        encode_container_backup, ContainerBackup, MountBackup, CONTAINER_BACKUP_SCHEMA_VERSION,
+    };
+    use crate::catalog::rebuild_catalog;
+    use crate::list::parse_window_date;
+    use bollard::models::MountPoint;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs::{create_dir_all, write, File};
+    use tempfile::TempDir;
+
+    fn write_manifest(root: &Path, path: &str, archives: &[&str]) {
+        let backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "web".to_string(),
+            container_config: Default::default(),
+            host_config: Default::default(),
+            mounts: archives
+                .iter()
+                .map(|a| MountBackup {
+                    path: PathBuf::from(a),
+                    mount: MountPoint::default(),
+                    checksum: None,
+                    volume_labels: Default::default(),
+                    resolved_source: None,
+                })
+                .collect(),
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        write_file(
+            root,
+            path,
+            &encode_container_backup(&backup, false).unwrap(),
+        );
+    }
+
+    fn write_file(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, contents).unwrap();
+    }
+
+    fn write_archive(root: &Path, path: &str) {
+        let path = root.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        tar::Builder::new(encoder)
+            .into_inner()
+            .unwrap()
+            .finish()
+            .unwrap();
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn parse_keep_test() {
+        assert_eq!(parse_keep("3").unwrap(), 3);
+        assert!(parse_keep("0").is_err());
+        assert!(parse_keep("-1").is_err());
+        assert!(parse_keep("many").is_err());
+    }
+
+    #[test]
+    fn remove_old_volume_backups_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        let volume = "dockyard/volumes/data";
+        for hour in 1..=5 {
+            write_archive(
+                root,
+                &format!("{}/2020-10-22T0{}:00:00+00:00.tgz", volume, hour),
+            );
+        }
+        // A pointer to the oldest archive and a container backup of the second keep them
+        write_file(
+            root,
+            &format!("{}/2020-10-22T06:00:00+00:00.ref", volume),
+            "2020-10-22T01:00:00+00:00.tgz",
+        );
+        write_manifest(
+            root,
+            "dockyard/containers/web/2020-10-22T02:00:00+00:00.json",
+            &["dockyard/volumes/data/2020-10-22T02:00:00+00:00.tgz"],
+        );
+
+        let removed = remove_old_backups(root, Path::new(volume), 3).unwrap();
+        assert_eq!(
+            removed,
+            paths(&["dockyard/volumes/data/2020-10-22T03:00:00+00:00.tgz"])
+        );
+        assert_eq!(
+            list_directory(root.join(volume).to_str().unwrap()).unwrap(),
+            vec![
+                "2020-10-22T01:00:00+00:00.tgz",
+                "2020-10-22T02:00:00+00:00.tgz",
+                "2020-10-22T04:00:00+00:00.tgz",
+                "2020-10-22T05:00:00+00:00.tgz",
+                "2020-10-22T06:00:00+00:00.ref",
+            ]
+        );
+        assert!(
+            remove_old_backups(root, Path::new("dockyard/volumes/missing"), 1)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn remove_backups_in_window_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        for day in 20..=22 {
+            let archive = format!("dockyard/volumes/data/2020-10-{}T12:00:00+00:00.tgz", day);
+            write_archive(root, &archive);
+            write_manifest(
+                root,
+                &format!(
+                    "dockyard/containers/web/2020-10-{}T12:00:00+00:00.json",
+                    day
+                ),
+                &[&archive],
+            );
+        }
+        write_archive(root, "dockyard/binds/:srv/2020-10-21T00:00:00+00:00.tgz");
+        let window = TimeWindow {
+            since: None,
+            until: Some(parse_window_date("2020-10-21", true).unwrap()),
+        };
+
+        let expected = paths(&[
+            "dockyard/binds/:srv/2020-10-21T00:00:00+00:00.tgz",
+            "dockyard/containers/web/2020-10-20T12:00:00+00:00.json",
+            "dockyard/containers/web/2020-10-21T12:00:00+00:00.json",
+            "dockyard/volumes/data/2020-10-20T12:00:00+00:00.tgz",
+            "dockyard/volumes/data/2020-10-21T12:00:00+00:00.tgz",
+        ]);
+        assert_eq!(
+            remove_backups_in_window(root, &window, true).unwrap(),
+            expected
+        );
+        assert!(expected.iter().all(|p| root.join(p).exists()));
+        assert_eq!(
+            remove_backups_in_window(root, &window, false).unwrap(),
+            expected
+        );
+        assert!(expected.iter().all(|p| !root.join(p).exists()));
+        assert!(root
+            .join("dockyard/volumes/data/2020-10-22T12:00:00+00:00.tgz")
+            .exists());
+    }
+
+    #[test]
+    fn remove_old_container_backups_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        let container = "dockyard/containers/web";
+        for hour in 1..=4 {
+            let archive = format!("dockyard/volumes/data/2020-10-22T0{}:00:00+00:00.tgz", hour);
+            write_archive(root, &archive);
+            write_manifest(
+                root,
+                &format!("{}/2020-10-22T0{}:00:00+00:00.json", container, hour),
+                &[
+                    &archive,
+                    "dockyard/binds/:srv/2020-10-22T00:00:00+00:00.tgz",
+                ],
+            );
+        }
+        write_archive(root, "dockyard/binds/:srv/2020-10-22T00:00:00+00:00.tgz");
+        // Another container still references the first volume archive
+        write_manifest(
+            root,
+            "dockyard/containers/worker/2020-10-22T01:00:00+00:00.json",
+            &["dockyard/volumes/data/2020-10-22T01:00:00+00:00.tgz"],
+        );
+
+        let removed = remove_old_backups(root, Path::new(container), 2).unwrap();
+        assert_eq!(
+            removed,
+            paths(&[
+                "dockyard/containers/web/2020-10-22T01:00:00+00:00.json",
+                "dockyard/containers/web/2020-10-22T02:00:00+00:00.json",
+                "dockyard/volumes/data/2020-10-22T02:00:00+00:00.tgz",
+            ])
+        );
+        assert!(root
+            .join("dockyard/volumes/data/2020-10-22T01:00:00+00:00.tgz")
+            .exists());
+        assert!(root
+            .join("dockyard/binds/:srv/2020-10-22T00:00:00+00:00.tgz")
+            .exists());
+    }
+    #[test]
+    fn remove_old_backups_catalog_test() {
+        let working_dir = TempDir::new().unwrap();
+        let root = working_dir.path();
+        let container = "dockyard/containers/web";
+        for hour in 1..=3 {
+            let archive = format!("dockyard/volumes/data/2020-10-22T0{}:00:00+00:00.tgz", hour);
+            write_archive(root, &archive);
+            write_manifest(
+                root,
+                &format!("{}/2020-10-22T0{}:00:00+00:00.json", container, hour),
+                &[&archive],
+            );
+        }
+        write_manifest(
+            root,
+            "dockyard/containers/worker/2020-10-22T01:00:00+00:00.json",
+            &["dockyard/volumes/data/2020-10-22T01:00:00+00:00.tgz"],
+        );
+        rebuild_catalog(root).unwrap();
+        // References come from the catalog, so container backup files aren't read
+        write_file(
+            root,
+            "dockyard/containers/worker/2020-10-22T01:00:00+00:00.json",
+            "unreadable",
+        );
+
+        let removed = remove_old_backups(root, Path::new(container), 1).unwrap();
+        assert_eq!(
+            removed,
+            paths(&[
+                "dockyard/containers/web/2020-10-22T01:00:00+00:00.json",
+                "dockyard/containers/web/2020-10-22T02:00:00+00:00.json",
+                "dockyard/volumes/data/2020-10-22T02:00:00+00:00.tgz",
+            ])
+        );
+        let remaining = query_catalog(root, None)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.backup_file)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remaining,
+            paths(&[
+                "dockyard/containers/web/2020-10-22T03:00:00+00:00.json",
+                "dockyard/containers/worker/2020-10-22T01:00:00+00:00.json",
+            ])
+        );
+    }
+}
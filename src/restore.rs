@@ -1,39 +1,893 @@
-use crate::backup::ContainerBackup;
-use crate::container::{check_image, handle_container_output, run_dockyard_command};
-use crate::file::decode_b64;
+use crate::backup::{
+    archive_codec, hash_path, parse_container_backup, parse_size, ContainerBackup, FileManifest,
+    IncrementalInfo, MountBackup, FILE_MANIFEST_MEMBER, INCREMENTAL_MEMBER, POINTER_EXTENSION,
+};
+use crate::container::{
+    check_mount, download_from_mount, get_or_build_image, get_volume_mount,
+    handle_container_output, load_image, pull_image, read_only_mount, run_dockyard_command,
+};
+use crate::file::{decode_b64, path_str};
+use crate::list::parse_backup_timestamp;
+use crate::mount::restore_mount;
+use crate::network::{connect_networks, find_network_endpoints, NetworkEndpoint};
+use crate::progress::MountProgress;
+use crate::script::{command_line, create_command, mount_arg, untranslated_settings, Script};
+use crate::swarm::check_swarm_references;
 use anyhow::{Context, Result};
-use bollard::container::{Config, CreateContainerOptions};
-use bollard::models::{Mount, MountTypeEnum};
-use bollard::volume::CreateVolumeOptions;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, RemoveContainerOptions,
+    RenameContainerOptions, StartContainerOptions, StopContainerOptions,
+};
+use bollard::models::{ContainerConfig, HostConfig, Mount, MountPoint, MountTypeEnum};
+use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions};
 use bollard::Docker;
+use chrono::{DateTime, FixedOffset, Utc};
 use flate2::read::GzDecoder;
-use futures::future::Either;
-use std::fs::{create_dir_all, File};
-use std::path::Path;
-use tar::Archive;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{
+    create_dir_all, hard_link, read_dir, read_to_string, remove_dir_all, remove_file, File,
+};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use tar::{Archive, Header};
 
-pub fn restore_directory(archive: &str, output: &str) -> Result<()> {
+/// Label Docker sets on volumes it creates for a container without a name
+pub const ANONYMOUS_VOLUME_LABEL: &str = "com.docker.volume.anonymous";
+
+/// Label set on rehearsal containers, its value is the prefix of their volumes
+pub const REHEARSAL_LABEL: &str = "com.github.aig787.dockyard.rehearsal";
+
+/// Options controlling how containers are restored
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Never pull images, loading them from the backup if they are not present locally
+    pub no_pull: bool,
+    /// Pull the latest image for the backed up tag even if it is present locally
+    pub refresh_image: bool,
+    /// Number of leading components to remove from archive member paths
+    pub strip_components: usize,
+    /// Extract absolute member paths relative to the output directory instead of rejecting them
+    pub allow_absolute: bool,
+    /// Remove existing contents of the output directory before extracting
+    pub clean: bool,
+    /// Fail instead of warning if the restored container's image differs from the backed up one
+    pub strict_image: bool,
+    /// User and group IDs to give restored files
+    pub owner: Option<(u32, u32)>,
+    /// Prepended to the name of every restored volume
+    pub volume_prefix: Option<String>,
+    /// Environment variables to set on restored containers, replacing backed up values
+    pub env: Vec<(String, String)>,
+    /// Template the restored container's name is generated from if none is given
+    pub name_template: Option<NameTemplate>,
+    /// Entrypoint to give restored containers instead of the backed up one
+    pub entrypoint: Option<Vec<String>>,
+    /// Command to give restored containers instead of the backed up one
+    pub cmd: Option<Vec<String>>,
+    /// Restore a copy named `<original>-rehearsal-<timestamp>` with its own volumes, leaving the
+    /// original container and its volumes untouched
+    pub rehearse: bool,
+    /// Memory limit in bytes to give restored containers instead of the backed up one
+    pub memory: Option<i64>,
+    /// Memory plus swap limit in bytes to give restored containers, -1 for unlimited swap
+    pub memory_swap: Option<i64>,
+    /// CPU limit in billionths of a CPU to give restored containers instead of the backed up one
+    pub nano_cpus: Option<i64>,
+    /// Restore swarm service tasks without checking their secrets and configs can be found
+    pub ignore_swarm_references: bool,
+    /// Prefixes of bind mount sources to replace, e.g. `/srv/data` with `/mnt/data`
+    pub bind_remap: Vec<(String, String)>,
+    /// How archive entries are merged with files that already exist in the output directory
+    pub merge: MergeMode,
+    /// Check restored files against the hashes recorded with `BackupOptions::file_manifest`
+    pub verify_files: bool,
+}
+
+/// How archive entries are merged with files that already exist in the output directory
+///
+/// Directories are always merged, modes only decide which files are replaced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeMode {
+    /// Replace existing files
+    Overwrite,
+    /// Keep existing files
+    SkipExisting,
+    /// Replace existing files only if the archive entry was modified after them
+    Newer,
+}
+
+impl Default for MergeMode {
+    fn default() -> Self {
+        MergeMode::Overwrite
+    }
+}
+
+impl MergeMode {
+    /// Return true if `entry` should be extracted over whatever is at `destination`
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Header of archive entry
+    /// * `destination` - Path the entry is extracted to
+    ///
+    fn extracts(self, header: &Header, destination: &Path) -> Result<bool> {
+        if self == MergeMode::Overwrite || header.entry_type().is_dir() {
+            return Ok(true);
+        }
+        let existing = match destination.symlink_metadata() {
+            Ok(existing) => existing,
+            Err(_) => return Ok(true),
+        };
+        match self {
+            MergeMode::Newer => Ok(header.mtime()? as i64 > existing.mtime()),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl RestoreOptions {
+    /// Return arguments passing these options to `dockyard restore directory`
+    fn directory_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.strip_components > 0 {
+            args.push("--strip-components".to_string());
+            args.push(self.strip_components.to_string());
+        }
+        if self.allow_absolute {
+            args.push("--allow-absolute".to_string());
+        }
+        if self.clean {
+            args.push("--clean".to_string());
+        }
+        if let Some((uid, gid)) = self.owner {
+            args.push("--chown".to_string());
+            args.push(format!("{}:{}", uid, gid));
+        }
+        match self.merge {
+            MergeMode::Overwrite => {}
+            MergeMode::SkipExisting => args.push("--skip-existing".to_string()),
+            MergeMode::Newer => args.push("--newer".to_string()),
+        }
+        if self.verify_files {
+            args.push("--verify-files".to_string());
+        }
+        args
+    }
+
+    /// Return true if the image should be pulled, given whether it is present locally
+    pub(crate) fn pulls_image(&self, present: bool) -> bool {
+        self.refresh_image || (!self.no_pull && !present)
+    }
+
+    /// Return name to restore the volume `name` to
+    pub(crate) fn volume_name(&self, name: &str) -> String {
+        match &self.volume_prefix {
+            Some(prefix) => format!("{}{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Return the host directory to restore the bind mount of `source` to
+    ///
+    /// The longest `bind_remap` prefix of `source` is replaced, prefixes only match whole path
+    /// components so `/srv/data` doesn't match `/srv/database`.
+    pub(crate) fn bind_source(&self, source: &str) -> String {
+        self.bind_remap
+            .iter()
+            .filter(|(from, _)| {
+                source == from
+                    || from == "/"
+                    || (source.starts_with(from.as_str()) && source[from.len()..].starts_with('/'))
+            })
+            .max_by_key(|(from, _)| from.len())
+            .map(|(from, to)| {
+                let rest = source[from.len()..].trim_start_matches('/');
+                match (to.as_str(), rest) {
+                    (to, "") => to.to_string(),
+                    ("/", rest) => format!("/{}", rest),
+                    (to, rest) => format!("{}/{}", to, rest),
+                }
+            })
+            .unwrap_or_else(|| source.to_string())
+    }
+}
+
+/// Parse a bind mount prefix mapping given as `FROM=TO`
+///
+/// # Arguments
+///
+/// * `remap` - Absolute source prefix and its replacement separated by `=`
+///
+pub fn parse_bind_remap(remap: &str) -> Result<(String, String)> {
+    let trim = |path: &str| match path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    };
+    let parts = remap.splitn(2, '=').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [from, to] if from.starts_with('/') && to.starts_with('/') => Ok((trim(from), trim(to))),
+        _ => Err(anyhow!(
+            "Invalid --bind-remap {}, expected absolute paths FROM=TO, e.g. /srv/data=/mnt/data",
+            remap
+        )),
+    }
+}
+
+/// Parse an owner given as `uid:gid`
+///
+/// # Arguments
+///
+/// * `owner` - Numeric user and group IDs separated by `:`
+///
+pub fn parse_owner(owner: &str) -> Result<(u32, u32)> {
+    let ids = owner
+        .splitn(2, ':')
+        .map(|id| id.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+    match ids.as_slice() {
+        [Some(uid), Some(gid)] => Ok((*uid, *gid)),
+        _ => Err(anyhow!("Invalid owner {}, expected numeric uid:gid", owner)),
+    }
+}
+
+/// Parse a CPU limit given as a number of CPUs, e.g. 1.5, into billionths of a CPU
+///
+/// # Arguments
+///
+/// * `cpus` - Number of CPUs
+///
+pub fn parse_cpus(cpus: &str) -> Result<i64> {
+    match cpus.parse::<f64>() {
+        Ok(count) if count.is_finite() && count > 0.0 => Ok((count * 1e9).round() as i64),
+        _ => Err(anyhow!(
+            "Invalid CPU limit {}, expected a positive number of CPUs, e.g. 1.5",
+            cpus
+        )),
+    }
+}
+
+/// Parse a memory plus swap limit, given as a size or -1 for unlimited swap
+///
+/// # Arguments
+///
+/// * `memory_swap` - Size, e.g. 2G, or -1
+///
+pub fn parse_memory_swap(memory_swap: &str) -> Result<i64> {
+    if memory_swap == "-1" {
+        return Ok(-1);
+    }
+    parse_size(memory_swap).map(|bytes| bytes as i64)
+}
+
+/// Part of a container name template
+#[derive(Clone, Debug, PartialEq)]
+enum NamePart {
+    Literal(String),
+    Original,
+    Timestamp,
+    BackupDate,
+}
+
+/// Template of a restored container's name, parsed with `parse_name_template`
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<NamePart>,
+}
+
+impl NameTemplate {
+    /// Generate a container name
+    ///
+    /// # Arguments
+    ///
+    /// * `original` - Name of the backed up container
+    /// * `backup_date` - Time the backup was taken, if known
+    /// * `now` - Time of the restore
+    ///
+    pub fn render(
+        &self,
+        original: &str,
+        backup_date: Option<DateTime<FixedOffset>>,
+        now: DateTime<Utc>,
+    ) -> Result<String> {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                NamePart::Literal(literal) => name.push_str(literal),
+                NamePart::Original => name.push_str(original),
+                NamePart::Timestamp => name.push_str(&now.format("%Y%m%dT%H%M%SZ").to_string()),
+                NamePart::BackupDate => match backup_date {
+                    Some(date) => name.push_str(&date.format("%Y-%m-%d").to_string()),
+                    None => {
+                        return Err(anyhow!(
+                            "Backup date of {} unknown, its file isn't named with a timestamp",
+                            original
+                        ))
+                    }
+                },
+            }
+        }
+        check_container_name(&name)?;
+        Ok(name)
+    }
+}
+
+/// Parse a container name template of literal text and `{original}`, `{timestamp}` and
+/// `{backup-date}` tokens
+///
+/// # Arguments
+///
+/// * `template` - Template to parse, e.g. `{original}-restored-{timestamp}`
+///
+pub fn parse_name_template(template: &str) -> Result<NameTemplate> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while !rest.is_empty() {
+        if let Some(token) = rest.strip_prefix('{') {
+            let end = token
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed {{ in name template {}", template))?;
+            parts.push(match &token[..end] {
+                "original" => NamePart::Original,
+                "timestamp" => NamePart::Timestamp,
+                "backup-date" => NamePart::BackupDate,
+                other => {
+                    return Err(anyhow!(
+                        "Unknown token {{{}}} in name template {}, expected {{original}}, {{timestamp}} or {{backup-date}}",
+                        other,
+                        template
+                    ))
+                }
+            });
+            rest = &token[end + 1..];
+        } else {
+            let end = rest.find('{').unwrap_or_else(|| rest.len());
+            let literal = &rest[..end];
+            if let Some(c) = literal
+                .chars()
+                .find(|c| !c.is_ascii_alphanumeric() && !"_.-".contains(*c))
+            {
+                return Err(anyhow!(
+                    "Invalid character {} in name template {}, container names may only contain [a-zA-Z0-9_.-]",
+                    c,
+                    template
+                ));
+            }
+            parts.push(NamePart::Literal(literal.to_string()));
+            rest = &rest[end..];
+        }
+    }
+    if parts.is_empty() {
+        return Err(anyhow!("Name template is empty"));
+    }
+    Ok(NameTemplate { parts })
+}
+
+/// Return the name of a rehearsal copy of the container `original`
+///
+/// # Arguments
+///
+/// * `original` - Name of the backed up container
+/// * `now` - Time of the restore
+///
+pub fn rehearsal_name(original: &str, now: DateTime<Utc>) -> String {
+    format!("{}-rehearsal-{}", original, now.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Fail if `name` isn't a valid Docker container name
+pub(crate) fn check_container_name(name: &str) -> Result<()> {
+    lazy_static! {
+        static ref CONTAINER_NAME: Regex = Regex::new("^[a-zA-Z0-9][a-zA-Z0-9_.-]+$").unwrap();
+    }
+    if CONTAINER_NAME.is_match(name) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Invalid container name {}, expected [a-zA-Z0-9][a-zA-Z0-9_.-]+",
+            name
+        ))
+    }
+}
+
+/// Read `KEY=VALUE` pairs from a dotenv file
+///
+/// Blank lines and `#` comments are skipped, a leading `export ` is ignored and values may be
+/// wrapped in single or double quotes.
+///
+/// # Arguments
+///
+/// * `path` - File to read
+///
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Unable to read env file {}", path.display()))?;
+    let mut env = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) if !key.is_empty() && !key.contains(char::is_whitespace) => value.trim(),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid line {} in {}, expected KEY=VALUE",
+                    number + 1,
+                    path.display()
+                ))
+            }
+        };
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        env.push((key.to_string(), value.to_string()));
+    }
+    Ok(env)
+}
+
+/// Set variables in `overrides` on `env`, replacing variables with the same name
+///
+/// # Arguments
+///
+/// * `env` - Container environment of `KEY=VALUE` strings
+/// * `overrides` - Names and values of variables to set
+///
+fn merge_env(env: Option<Vec<String>>, overrides: &[(String, String)]) -> Option<Vec<String>> {
+    if overrides.is_empty() {
+        return env;
+    }
+    let mut merged = env
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|variable| {
+            let name = variable.splitn(2, '=').next().unwrap();
+            !overrides.iter().any(|(key, _)| key == name)
+        })
+        .collect::<Vec<_>>();
+    merged.extend(
+        overrides
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value)),
+    );
+    Some(merged)
+}
+
+/// Extract archive into directory
+///
+/// Members with absolute paths are rejected unless `allow_absolute` is set, and members that
+/// would be written outside of `output` are always rejected. Incremental archives are applied
+/// on top of the full archive they were taken against.
+///
+/// # Arguments
+///
+/// * `archive` - Path to archive or pointer
+/// * `output` - Directory to extract to
+/// * `options` - Restore options
+///
+pub fn restore_directory(archive: &str, output: &str, options: &RestoreOptions) -> Result<()> {
     log::info!("Restoring {} to {}", archive, output);
     let output_path = Path::new(output);
-    let tar_file = File::open(Path::new(archive))?;
+    let archive_path = resolve_archive(Path::new(archive))?;
+    // Archives may have any extension, so check the contents are something we can decompress
+    if archive_codec(&archive_path)?.is_none() {
+        return Err(anyhow!("{} is not a gzip archive", archive_path.display()));
+    }
+    if options.verify_files && options.merge != MergeMode::Overwrite {
+        // Kept files are expected to differ from the backup
+        return Err(anyhow!(
+            "--verify-files checks every file the archive restores, it can't be used with --skip-existing or --newer"
+        ));
+    }
+    let incremental = read_incremental_info(&archive_path)?;
+    if incremental.is_some() && options.merge == MergeMode::SkipExisting {
+        // Files the base archive restores would be kept instead of their changed versions
+        return Err(anyhow!(
+            "{} is incremental and can't be restored with --skip-existing, use --newer instead",
+            archive_path.display()
+        ));
+    }
+    if options.clean && incremental.is_none() {
+        clean_directory(output_path)?;
+    }
+    if let Some(info) = &incremental {
+        let base = archive_path.with_file_name(&info.base);
+        log::info!(
+            "{} is incremental, restoring {} first",
+            archive_path.display(),
+            base.display()
+        );
+        // Ownership is set and files are verified once everything is extracted
+        let base_options = RestoreOptions {
+            owner: None,
+            verify_files: false,
+            ..options.clone()
+        };
+        restore_directory(path_str(&base)?, output, &base_options)?;
+    }
+    extract_archive(&archive_path, output_path, options)?;
+    if let Some(info) = incremental {
+        for deleted in info.deleted {
+            if let Some(path) = member_output_path(Path::new(&deleted), options)? {
+                let path = output_path.join(path);
+                if path.symlink_metadata().is_ok() {
+                    log::debug!("Removing deleted file {}", path.display());
+                    remove_file(&path)?;
+                }
+            }
+        }
+    }
+    if let Some((uid, gid)) = options.owner {
+        chown_directory(output_path, uid, gid)?;
+    }
+    if options.verify_files {
+        verify_restored_files(&archive_path, output_path, options)?;
+    }
+    Ok(())
+}
+
+/// Files of a restore that are missing or differ from the file manifest of their archive
+#[derive(Debug, Default, PartialEq)]
+pub struct FileVerification {
+    /// Number of files checked
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub corrupted: Vec<String>,
+}
+
+/// Check the files restored from an archive against the hashes recorded in it, failing if any
+/// are missing or corrupted
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to archive, not a pointer
+/// * `output_path` - Directory the archive was restored to
+/// * `options` - Restore options the archive was restored with
+///
+pub fn verify_restored_files(
+    archive_path: &Path,
+    output_path: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let manifest = read_file_manifest(archive_path)?.ok_or_else(|| {
+        anyhow!(
+            "{} has no file manifest to verify restored files against, back it up with --file-manifest",
+            archive_path.display()
+        )
+    })?;
+    let verification = compare_restored_files(&manifest, output_path, options)?;
+    for path in &verification.missing {
+        log::error!("Restored file {} is missing", path);
+    }
+    for path in &verification.corrupted {
+        log::error!("Restored file {} doesn't match its backup", path);
+    }
+    if verification.missing.is_empty() && verification.corrupted.is_empty() {
+        log::info!("Verified {} restored files", verification.checked);
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} files restored to {} are missing and {} don't match their backup",
+            verification.missing.len(),
+            verification.checked,
+            output_path.display(),
+            verification.corrupted.len()
+        ))
+    }
+}
+
+/// Return the file manifest of an archive, or `None` if it was backed up without one
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to archive
+///
+fn read_file_manifest(archive_path: &Path) -> Result<Option<FileManifest>> {
+    let tar_file = File::open(archive_path)
+        .with_context(|| format!("Unable to open {}", archive_path.display()))?;
+    let mut archive = Archive::new(GzDecoder::new(tar_file));
+    // The manifest is written after the archived files, older archives have it first
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(FILE_MANIFEST_MEMBER) {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            return Ok(Some(serde_json::from_str(&contents)?));
+        }
+    }
+    Ok(None)
+}
+
+/// Compare the files in `output_path` with the hashes in `manifest`
+///
+/// # Arguments
+///
+/// * `manifest` - File manifest of the restored archive
+/// * `output_path` - Directory the archive was restored to
+/// * `options` - Restore options, for the paths members were extracted to
+///
+fn compare_restored_files(
+    manifest: &FileManifest,
+    output_path: &Path,
+    options: &RestoreOptions,
+) -> Result<FileVerification> {
+    let mut verification = FileVerification::default();
+    for (name, hash) in &manifest.files {
+        let path = match member_output_path(Path::new(name), options)? {
+            Some(path) => output_path.join(path),
+            None => continue,
+        };
+        verification.checked += 1;
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                verification.missing.push(name.clone());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if hash_path(&path, &metadata, manifest.algorithm)?.as_ref() != Some(hash) {
+            verification.corrupted.push(name.clone());
+        }
+    }
+    Ok(verification)
+}
+
+/// Recursively change the owner of `directory` and its contents, without following symlinks
+///
+/// # Arguments
+///
+/// * `directory` - Directory to change owner of
+/// * `uid` - User ID to set
+/// * `gid` - Group ID to set
+///
+fn chown_directory(directory: &Path, uid: u32, gid: u32) -> Result<()> {
+    log::info!(
+        "Changing owner of {} to {}:{}",
+        directory.display(),
+        uid,
+        gid
+    );
+    let status = Command::new("chown")
+        .arg("-R")
+        .arg("-h")
+        .arg(format!("{}:{}", uid, gid))
+        .arg(directory)
+        .status()
+        .context("Unable to run chown")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "chown of {} to {}:{} failed with {}",
+            directory.display(),
+            uid,
+            gid,
+            status
+        ))
+    }
+}
+
+/// Remove everything inside `directory`, keeping the directory itself since it may be a mount
+///
+/// # Arguments
+///
+/// * `directory` - Directory to empty
+///
+fn clean_directory(directory: &Path) -> Result<()> {
+    if !directory.exists() {
+        return Ok(());
+    }
+    log::info!("Removing existing contents of {}", directory.display());
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if path.symlink_metadata()?.is_dir() {
+            remove_dir_all(&path)?;
+        } else {
+            remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Return description of an incremental archive, or `None` for full archives
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to archive
+///
+pub(crate) fn read_incremental_info(archive_path: &Path) -> Result<Option<IncrementalInfo>> {
+    let tar_file = File::open(archive_path)
+        .with_context(|| format!("Unable to open {}", archive_path.display()))?;
+    parse_incremental_info(tar_file)
+}
+
+/// Return description of an incremental archive read from `reader`, or `None` for full archives
+///
+/// # Arguments
+///
+/// * `reader` - Gzipped archive
+///
+pub(crate) fn parse_incremental_info(reader: impl Read) -> Result<Option<IncrementalInfo>> {
+    let mut archive = Archive::new(GzDecoder::new(reader));
+    let mut entry = match archive.entries()?.next() {
+        Some(entry) => entry?,
+        None => return Ok(None),
+    };
+    if entry.path()? != Path::new(INCREMENTAL_MEMBER) {
+        return Ok(None);
+    }
+    let mut contents = String::new();
+    entry.read_to_string(&mut contents)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+/// Extract archive members into `output_path`
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to archive
+/// * `output_path` - Directory to extract to
+/// * `options` - Restore options
+///
+fn extract_archive(
+    archive_path: &Path,
+    output_path: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let tar_file = File::open(&archive_path)?;
     let tar = GzDecoder::new(tar_file);
     let mut archive = Archive::new(tar);
     create_dir_all(&output_path)?;
-    archive.unpack(&output_path)?;
+    let canonical_output = output_path.canonicalize()?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let member = entry.path()?.into_owned();
+        if member == Path::new(INCREMENTAL_MEMBER) || member == Path::new(FILE_MANIFEST_MEMBER) {
+            continue;
+        }
+        let path = match member_output_path(&member, options)? {
+            Some(path) => path,
+            None => continue,
+        };
+        let destination = output_path.join(&path);
+        if let Some(parent) = destination.parent() {
+            create_dir_all(parent)?;
+            // Symlinks extracted earlier could point the parent outside of output
+            if !parent.canonicalize()?.starts_with(&canonical_output) {
+                return Err(anyhow!(
+                    "Refusing to extract {}, path escapes the output directory",
+                    member.display()
+                ));
+            }
+        }
+        if !options.merge.extracts(entry.header(), &destination)? {
+            log::debug!("Keeping existing {}", destination.display());
+            continue;
+        }
+        if entry.header().entry_type().is_hard_link() {
+            // Entry::unpack resolves link targets relative to the working directory
+            let link_name = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("Hard link {} has no target", member.display()))?
+                .into_owned();
+            let target = member_output_path(&link_name, options)?.ok_or_else(|| {
+                anyhow!(
+                    "Hard link {} target {} was stripped",
+                    member.display(),
+                    link_name.display()
+                )
+            })?;
+            // The target could also be reached through a symlink extracted earlier, its own
+            // name is kept so a link to a symlink still links to the symlink
+            let target = output_path.join(target);
+            let target = match (target.parent(), target.file_name()) {
+                (Some(parent), Some(name)) => parent
+                    .canonicalize()
+                    .with_context(|| format!("Failed to extract {}", member.display()))?
+                    .join(name),
+                _ => target,
+            };
+            if !target.starts_with(&canonical_output) {
+                return Err(anyhow!(
+                    "Refusing to extract {}, hard link target escapes the output directory",
+                    member.display()
+                ));
+            }
+            if destination.symlink_metadata().is_ok() {
+                remove_file(&destination)?;
+            }
+            hard_link(target, &destination)
+                .with_context(|| format!("Failed to extract {}", member.display()))?;
+            continue;
+        }
+        entry
+            .unpack(&destination)
+            .with_context(|| format!("Failed to extract {}", member.display()))?;
+    }
     Ok(())
 }
 
+/// Return path relative to the output directory to extract archive member to
+///
+/// Returns `None` if nothing is left of the path after stripping components.
+///
+/// # Arguments
+///
+/// * `member` - Path of archive member
+/// * `options` - Restore options
+///
+fn member_output_path(member: &Path, options: &RestoreOptions) -> Result<Option<PathBuf>> {
+    let mut components = vec![];
+    for component in member.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => {
+                if !options.allow_absolute {
+                    return Err(anyhow!(
+                        "Refusing to extract absolute path {}, use --allow-absolute to extract it into the output directory",
+                        member.display()
+                    ));
+                }
+            }
+            Component::ParentDir => {
+                return Err(anyhow!(
+                    "Refusing to extract {}, path escapes the output directory",
+                    member.display()
+                ))
+            }
+            Component::CurDir => {}
+            Component::Normal(c) => components.push(c),
+        }
+    }
+    let path = components
+        .into_iter()
+        .skip(options.strip_components)
+        .collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(path))
+    }
+}
+
+/// Return the archive a pointer refers to, or `archive` if it isn't a pointer
+///
+/// # Arguments
+///
+/// * `archive` - Path to archive or pointer written for an unchanged directory
+///
+fn resolve_archive(archive: &Path) -> Result<PathBuf> {
+    if archive.extension().and_then(|e| e.to_str()) != Some(POINTER_EXTENSION) {
+        return Ok(archive.to_path_buf());
+    }
+    let target = read_to_string(archive)
+        .with_context(|| format!("Unable to read pointer {}", archive.display()))?;
+    let resolved = archive.with_file_name(target.trim());
+    log::info!("{} points to {}", archive.display(), resolved.display());
+    Ok(resolved)
+}
+
 pub async fn restore_directory_from_mount(
     docker: &Docker,
     archive: String,
     backup_mount: Mount,
     directory: String,
+    options: &RestoreOptions,
 ) -> Result<()> {
     log::info!("Restoring directory {} from {}", directory, archive);
     let log_prefix = format!("restore directory {}", directory);
     let mounted_backup = format!("{}/{}", &backup_mount.target.as_ref().unwrap(), archive);
     let mounts = Some(vec![
-        backup_mount,
+        read_only_mount(backup_mount),
         Mount {
             target: Some("/output".to_string()),
             source: Some(directory.to_string()),
@@ -41,7 +895,9 @@ pub async fn restore_directory_from_mount(
             ..Default::default()
         },
     ]);
-    let cmd = vec!["restore", "directory", &mounted_backup, "/output"];
+    let directory_args = options.directory_args();
+    let mut cmd = vec!["restore", "directory", &mounted_backup, "/output"];
+    cmd.extend(directory_args.iter().map(String::as_str));
     let (exit_code, logs) = run_dockyard_command(docker, mounts, cmd).await?;
     handle_container_output(exit_code, &log_prefix, &logs)
 }
@@ -51,135 +907,1165 @@ pub async fn restore_volume(
     archive: String,
     backup_mount: Mount,
     volume_mount: Mount,
+    options: &RestoreOptions,
 ) -> Result<()> {
     log::info!(
         "Restoring volume {} from {}",
         volume_mount.source.as_ref().unwrap(),
         archive
     );
-    docker
-        .create_volume(CreateVolumeOptions {
-            name: volume_mount.source.as_ref().unwrap().to_string(),
-            driver: "local".to_string(),
-            driver_opts: Default::default(),
-            labels: Default::default(),
-        })
-        .await?;
+    let volume = volume_mount.source.as_ref().unwrap();
+    // A volume created beforehand may use another driver, which creating it again would reject
+    if docker.inspect_volume(volume).await.is_err() {
+        docker
+            .create_volume(CreateVolumeOptions {
+                name: volume.to_string(),
+                driver: "local".to_string(),
+                driver_opts: Default::default(),
+                labels: Default::default(),
+            })
+            .await?;
+    }
     let log_prefix = format!("restore volume {}", volume_mount.source.as_ref().unwrap());
     let mounted_backup = format!("{}/{}", &backup_mount.target.as_ref().unwrap(), archive);
     let volume_dir = volume_mount.target.as_ref().unwrap().to_string();
-    let cmd = vec!["restore", "directory", &mounted_backup, &volume_dir];
-    let mounts = Some(vec![backup_mount, volume_mount]);
+    let directory_args = options.directory_args();
+    let mut cmd = vec!["restore", "directory", &mounted_backup, &volume_dir];
+    cmd.extend(directory_args.iter().map(String::as_str));
+    let mounts = Some(vec![read_only_mount(backup_mount), volume_mount]);
     let (exit_code, logs) = run_dockyard_command(docker, mounts, cmd).await?;
     handle_container_output(exit_code, &log_prefix, &logs)
 }
 
-pub async fn restore_container(
+/// Restore archive from backup mount into a directory on the Docker host
+///
+/// Relative paths are resolved against the working directory and missing directories are
+/// created, since Docker refuses to bind mount paths that don't exist. Both assume the Docker
+/// host is the local machine.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `archive` - Path to archive relative to backup mount
+/// * `backup_mount` - Mount representing backup location
+/// * `directory` - Directory to restore to
+/// * `options` - Restore options
+///
+pub async fn restore_volume_to_directory(
+    docker: &Docker,
+    archive: String,
+    backup_mount: Mount,
+    directory: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let directory = if directory.is_absolute() {
+        directory.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(directory)
+    };
+    if !directory.exists() {
+        log::info!("Creating directory {}", directory.display());
+        create_dir_all(&directory)
+            .with_context(|| format!("Unable to create {}", directory.display()))?;
+    }
+    restore_directory_from_mount(
+        docker,
+        archive,
+        backup_mount,
+        path_str(&directory)?.to_string(),
+        options,
+    )
+    .await
+}
+
+/// Restore archive into the volume or directory mounted at `target` in an existing container
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Name of container
+/// * `target` - Path the volume or directory is mounted at in the container
+/// * `archive` - Path to archive relative to backup mount
+/// * `backup_mount` - Mount representing backup location
+/// * `stop` - Stop the container while restoring, starting it again afterwards
+/// * `options` - Restore options
+///
+pub async fn restore_volume_into(
     docker: &Docker,
-    backup_file: &str,
     container: &str,
+    target: &str,
+    archive: String,
     backup_mount: Mount,
+    stop: bool,
+    options: &RestoreOptions,
 ) -> Result<()> {
-    log::info!("Restoring container {} from {}", container, backup_file);
+    let info = docker
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+        .with_context(|| format!("Unable to inspect container {}", container))?;
+    let mount = info
+        .mounts
+        .unwrap_or_default()
+        .into_iter()
+        .find(|m| m.destination.as_deref() == Some(target))
+        .ok_or_else(|| anyhow!("Container {} has no mount at {}", container, target))?;
+    let running = info.state.and_then(|s| s.running).unwrap_or(false);
+    if stop && running {
+        log::info!("Stopping container {}", container);
+        docker
+            .stop_container(container, None::<StopContainerOptions>)
+            .await?;
+    }
+    let mount_backup = MountBackup {
+        path: PathBuf::from(archive),
+        mount,
+        checksum: None,
+        volume_labels: Default::default(),
+        resolved_source: None,
+    };
+    let result = restore_mount(docker, &mount_backup, backup_mount, options).await;
+    if stop && running {
+        log::info!("Starting container {}", container);
+        let started = docker
+            .start_container(container, None::<StartContainerOptions<String>>)
+            .await
+            .with_context(|| format!("Unable to start container {} after restoring", container));
+        match (&result, started) {
+            (Ok(_), Err(e)) => return Err(e),
+            (Err(_), Err(e)) => log::error!("{:#}", e),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Restore archive into a new volume and recreate an existing container with it in place of the
+/// volume mounted at `target`
+///
+/// The container keeps running while the volume is restored and is only stopped while it is
+/// recreated. Docker can't rename volumes, so the new volume is named `<volume>-swap-<timestamp>`,
+/// with the driver and labels of the old volume, and the old volume is left untouched to roll back
+/// to, or to remove once the restore is checked. If the original container can't be renamed, or
+/// the recreated one can't be created, connected to its networks or started, the original is put
+/// back and started again if it was running. Containers Docker removes when they stop can't be
+/// swapped. Returns the name of the new volume.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Name of container
+/// * `target` - Path the volume is mounted at in the container
+/// * `archive` - Path to archive relative to backup mount
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn swap_volume_into(
+    docker: &Docker,
+    container: &str,
+    target: &str,
+    archive: String,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<String> {
+    let info = docker
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+        .with_context(|| format!("Unable to inspect container {}", container))?;
+    let name = info
+        .name
+        .as_deref()
+        .unwrap_or(container)
+        .trim_start_matches('/')
+        .to_string();
+    if info.host_config.as_ref().and_then(|h| h.auto_remove) == Some(true) {
+        // Docker removes the container as soon as it's stopped, leaving nothing to recreate
+        return Err(anyhow!(
+            "Container {} is removed when it stops, its volumes can't be swapped",
+            name
+        ));
+    }
+    let networks = find_network_endpoints(&info);
+    let mounts = info.mounts.unwrap_or_default();
+    let old_volume = match mounts
+        .iter()
+        .find(|m| m.destination.as_deref() == Some(target))
+    {
+        Some(MountPoint {
+            typ: Some(typ),
+            name: Some(volume),
+            ..
+        }) if typ == "volume" => volume.clone(),
+        Some(_) => {
+            return Err(anyhow!(
+                "Mount of {} at {} isn't a volume, only volumes can be swapped",
+                container,
+                target
+            ))
+        }
+        None => {
+            return Err(anyhow!(
+                "Container {} has no mount at {}",
+                container,
+                target
+            ))
+        }
+    };
+    let now = Utc::now();
+    let new_volume = format!("{}-swap-{}", old_volume, now.format("%Y%m%dT%H%M%SZ"));
+    let old = docker
+        .inspect_volume(&old_volume)
+        .await
+        .with_context(|| format!("Unable to inspect volume {}", old_volume))?;
+    // Creating the volume first, restore_volume then reuses it
+    docker
+        .create_volume(CreateVolumeOptions {
+            name: new_volume.clone(),
+            driver: old.driver,
+            driver_opts: Default::default(),
+            labels: old.labels,
+        })
+        .await
+        .with_context(|| format!("Failed to create volume {}", new_volume))?;
+    if let Err(e) = restore_volume(
+        docker,
+        archive,
+        backup_mount,
+        get_volume_mount(new_volume.clone()),
+        options,
+    )
+    .await
+    {
+        log::warn!("Removing partially restored volume {}", new_volume);
+        let _ = docker
+            .remove_volume(&new_volume, None::<RemoveVolumeOptions>)
+            .await;
+        return Err(e);
+    }
+
+    let config = create_config(
+        info.config.unwrap_or_default(),
+        swapped_host_config(
+            info.host_config.unwrap_or_default(),
+            &mounts,
+            target,
+            &new_volume,
+        ),
+    );
+    let network_mode = config
+        .host_config
+        .as_ref()
+        .and_then(|h| h.network_mode.clone());
+    let running = info.state.and_then(|s| s.running).unwrap_or(false);
+    if running {
+        log::info!("Stopping container {}", name);
+        docker
+            .stop_container(&name, None::<StopContainerOptions>)
+            .await?;
+    }
+    let original = format!("{}-pre-swap-{}", name, now.format("%Y%m%dT%H%M%SZ"));
+    if let Err(e) = docker
+        .rename_container(
+            &name,
+            RenameContainerOptions {
+                name: original.as_str(),
+            },
+        )
+        .await
+        .with_context(|| format!("Unable to rename container {} to {}", name, original))
+    {
+        if running {
+            restart_swapped_container(docker, &name).await;
+        }
+        return Err(e);
+    }
+    let recreated = recreate_container(
+        docker,
+        &name,
+        config,
+        network_mode.as_deref(),
+        &networks,
+        running,
+    );
+    match recreated.await {
+        Ok(()) => {
+            docker
+                .remove_container(&original, None::<RemoveContainerOptions>)
+                .await?;
+            log::info!(
+                "Container {} now mounts volume {} at {}, volume {} was kept and can be removed once the restore is checked",
+                name,
+                new_volume,
+                target,
+                old_volume
+            );
+            Ok(new_volume)
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to recreate container {}, putting back the original",
+                name
+            );
+            let _ = docker
+                .remove_container(
+                    &name,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+            match docker
+                .rename_container(
+                    &original,
+                    RenameContainerOptions {
+                        name: name.as_str(),
+                    },
+                )
+                .await
+            {
+                Ok(()) if running => restart_swapped_container(docker, &name).await,
+                Ok(()) => {}
+                Err(rename_error) => log::error!(
+                    "Failed to rename container {} back to {}: {}",
+                    original,
+                    name,
+                    rename_error
+                ),
+            }
+            Err(e.context(format!(
+                "Unable to recreate container {} with volume {}",
+                name, new_volume
+            )))
+        }
+    }
+}
+
+/// Start a container stopped for a swap that failed, logging rather than returning any error so
+/// the error that caused the rollback is the one reported
+async fn restart_swapped_container(docker: &Docker, container: &str) {
+    log::info!("Starting container {}", container);
+    if let Err(e) = docker
+        .start_container(container, None::<StartContainerOptions<String>>)
+        .await
+    {
+        log::error!("Failed to start container {}: {}", container, e);
+    }
+}
+
+/// Create a container connected to `networks`, starting it if `start` is set
+async fn recreate_container(
+    docker: &Docker,
+    name: &str,
+    config: Config<String>,
+    network_mode: Option<&str>,
+    networks: &BTreeMap<String, NetworkEndpoint>,
+    start: bool,
+) -> Result<()> {
+    docker
+        .create_container(Some(CreateContainerOptions { name }), config)
+        .await?;
+    connect_networks(docker, name, network_mode, networks, true).await?;
+    if start {
+        log::info!("Starting container {}", name);
+        docker
+            .start_container(name, None::<StartContainerOptions<String>>)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Return `host_config` mounting `volume` at `target` in place of the volume mounted there
+///
+/// Volumes that aren't named in the host config, like those Docker creates for an image's
+/// `VOLUME`s, are mounted explicitly so the recreated container doesn't get new, empty ones.
+///
+/// # Arguments
+///
+/// * `host_config` - Host config of the container
+/// * `mounts` - Mounts of the container
+/// * `target` - Path the swapped volume is mounted at
+/// * `volume` - Name of volume to mount at `target`
+///
+fn swapped_host_config(
+    mut host_config: HostConfig,
+    mounts: &[MountPoint],
+    target: &str,
+    volume: &str,
+) -> HostConfig {
+    let mut mounted = HashSet::new();
+    for bind in host_config.binds.iter_mut().flatten() {
+        let mut parts = bind.splitn(3, ':');
+        let (source, destination) = (parts.next().unwrap_or_default(), parts.next());
+        let destination = match destination {
+            Some(destination) => destination.to_string(),
+            None => continue,
+        };
+        if destination == target && !source.starts_with('/') {
+            let rest = parts.next().map(|r| format!(":{}", r)).unwrap_or_default();
+            *bind = format!("{}:{}{}", volume, destination, rest);
+        }
+        mounted.insert(destination);
+    }
+    for mount in host_config.mounts.iter_mut().flatten() {
+        if mount.typ == Some(MountTypeEnum::VOLUME) && mount.target.as_deref() == Some(target) {
+            mount.source = Some(volume.to_string());
+        }
+        mounted.extend(mount.target.clone());
+    }
+    for point in mounts {
+        let destination = match (point.typ.as_deref(), &point.name, &point.destination) {
+            (Some("volume"), Some(_), Some(destination)) if !mounted.contains(destination) => {
+                destination
+            }
+            _ => continue,
+        };
+        let source = if destination == target {
+            volume.to_string()
+        } else {
+            point.name.clone().unwrap()
+        };
+        host_config.mounts.get_or_insert_with(Vec::new).push(Mount {
+            target: Some(destination.clone()),
+            source: Some(source),
+            typ: Some(MountTypeEnum::VOLUME),
+            read_only: point.rw.map(|rw| !rw),
+            ..Default::default()
+        });
+    }
+    host_config
+}
+
+/// Read container backup file from backup mount
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Path of backup file relative to backup mount
+/// * `backup_mount` - Mount representing backup location
+///
+pub(crate) async fn read_container_backup(
+    docker: &Docker,
+    backup_file: &str,
+    backup_mount: &Mount,
+) -> Result<ContainerBackup> {
+    check_backup_mount(docker, backup_mount).await?;
     let mounted_backup = format!("/backup/{}", backup_file);
     let (exit_code, logs) = run_dockyard_command(
         docker,
-        Some(vec![backup_mount.clone()]),
+        Some(vec![read_only_mount(backup_mount.clone())]),
         vec!["cat", "--encoded", "-f", &mounted_backup],
     )
-    .await?;
+    .await
+    .with_context(|| {
+        format!(
+            "Unable to read {} from {}, make sure it is available to the Docker host",
+            backup_file,
+            backup_mount.source.as_ref().unwrap()
+        )
+    })?;
     if logs.is_empty() {
         return Err(anyhow!("Found empty file"));
     }
-    let log_prefix = format!("restore container {}", container);
+    let log_prefix = format!("read container backup {}", backup_file);
     handle_container_output(exit_code, &log_prefix, &logs[0..logs.len() - 1])?;
     let container_backup = decode_b64(logs.last().unwrap().to_string().trim())?;
-    let container_backup: ContainerBackup = serde_json::from_str(&container_backup)?;
-    let mut mount_restore_processes = vec![];
-    for mb in container_backup.mounts {
-        let archive_path = mb.path.to_str().unwrap().to_string();
-        if mb.mount.typ.unwrap() == "bind" {
-            let directory = mb.mount.source.unwrap();
-            let f = restore_directory_from_mount(
-                docker,
-                archive_path,
-                backup_mount.clone(),
-                directory.clone(),
-            );
-            mount_restore_processes.push((directory, Either::Left(f)));
-        } else {
-            let volume = mb.mount.name.unwrap();
-            let volume_mount = Mount {
-                target: Some("/volume".to_string()),
-                source: Some(volume.clone()),
-                typ: Some(MountTypeEnum::VOLUME),
-                ..Default::default()
-            };
-            let f = restore_volume(docker, archive_path, backup_mount.clone(), volume_mount);
-            mount_restore_processes.push((volume, Either::Right(f)));
-        }
-    }
-    for (name, res) in mount_restore_processes {
-        res.await
-            .with_context(|| format!("Failed to restore mount {}", &name))?;
-        log::info!("Successfully restored mount {}", &name)
-    }
-
-    let image = container_backup.container_config.image.unwrap();
-    check_image(docker, &image).await?;
-
-    let container_config = Config {
-        hostname: container_backup.container_config.hostname,
-        domainname: container_backup.container_config.domainname,
-        user: container_backup.container_config.user,
-        attach_stdin: container_backup.container_config.attach_stdin,
-        attach_stdout: container_backup.container_config.attach_stdout,
-        attach_stderr: container_backup.container_config.attach_stderr,
-        exposed_ports: container_backup.container_config.exposed_ports,
-        tty: container_backup.container_config.tty,
-        open_stdin: container_backup.container_config.open_stdin,
-        stdin_once: container_backup.container_config.stdin_once,
-        env: container_backup.container_config.env,
-        cmd: container_backup.container_config.cmd,
-        healthcheck: container_backup.container_config.healthcheck,
-        args_escaped: container_backup.container_config.args_escaped,
-        image: Some(image),
-        volumes: container_backup.container_config.volumes,
-        working_dir: container_backup.container_config.working_dir,
-        entrypoint: container_backup.container_config.entrypoint,
-        network_disabled: container_backup.container_config.network_disabled,
-        mac_address: container_backup.container_config.mac_address,
-        on_build: container_backup.container_config.on_build,
-        labels: container_backup.container_config.labels,
-        stop_signal: container_backup.container_config.stop_signal,
-        stop_timeout: container_backup.container_config.stop_timeout,
-        shell: container_backup.container_config.shell,
-        host_config: Some(container_backup.host_config),
-        ..Default::default()
-    };
-
-    docker
-        .create_container(
-            Some(CreateContainerOptions { name: container }),
-            container_config,
-        )
-        .await?;
-    log::info!("Successfully restored container {}", container);
-    Ok(())
+    let mut container_backup = parse_container_backup(&container_backup)?;
+    container_backup.resolve_archives(Path::new(backup_file));
+    container_backup.relativize_archives(backup_mount)?;
+    Ok(container_backup)
 }
 
-#[cfg(test)]
+/// Restore container from a container backup file
+///
+/// Returns the name of the restored container. Read-only mounts stay read-only, their flags are
+/// restored with the rest of the host config while their contents are restored through writable
+/// helper mounts.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Container backup file relative to `backup_mount`
+/// * `container` - Name of restored container, generated from `options.name_template` if not set
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn restore_container(
+    docker: &Docker,
+    backup_file: &str,
+    container: Option<&str>,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<String> {
+    let mut container_backup = read_container_backup(docker, backup_file, &backup_mount).await?;
+    let container = restored_container_name(&container_backup, backup_file, container, options)?;
+    let container = container.as_str();
+    let rehearsal_options;
+    let options = if options.rehearse {
+        rehearsal_options = rehearsal_restore_options(container, options);
+        &rehearsal_options
+    } else {
+        options
+    };
+    log::info!("Restoring container {} from {}", container, backup_file);
+    match &container_backup.swarm {
+        Some(_) if options.ignore_swarm_references => {}
+        Some(references) => check_swarm_references(references)?,
+        None => {}
+    }
+    // The image is resolved and checked first, so a mismatch doesn't leave restored volumes behind
+    let image = container_backup.container_config.image.clone().unwrap();
+    let present = docker.inspect_image(&image).await.is_ok();
+    if options.pulls_image(present) {
+        pull_image(docker, &image).await?;
+    } else if !present {
+        match &container_backup.image_archive {
+            Some(archive) => {
+                let contents =
+                    download_from_mount(docker, read_only_mount(backup_mount.clone()), archive)
+                        .await?;
+                load_image(docker, &image, contents).await?;
+            }
+            None => {
+                return Err(anyhow!(
+                "Image {} not found and not included in backup, run without --no-pull to pull it",
+                image
+            ))
+            }
+        }
+    }
+    if let Some(expected) = &container_backup.image_id {
+        check_image(docker, container, &image, expected, options).await?;
+    }
+    attach_anonymous_volumes(&mut container_backup);
+    let progress = MountProgress::new("Restoring", container, container_backup.mounts.len());
+    for mount_backup in &container_backup.mounts {
+        if options.rehearse && mount_backup.mount.typ.as_deref() == Some("bind") {
+            log::warn!(
+                "Not restoring bind mount {} for rehearsal, it's shared with the original container",
+                mount_backup.mount.source.as_deref().unwrap_or_default()
+            );
+            progress.finish_mount();
+            continue;
+        }
+        progress.start(
+            mount_backup
+                .mount
+                .source
+                .as_deref()
+                .or_else(|| mount_backup.mount.destination.as_deref())
+                .unwrap_or_default(),
+        );
+        restore_mount(docker, mount_backup, backup_mount.clone(), options).await?;
+        progress.finish_mount();
+    }
+    drop(progress);
+    let container_config = restored_config(&mut container_backup, options);
+
+    docker
+        .create_container(
+            Some(CreateContainerOptions { name: container }),
+            container_config,
+        )
+        .await?;
+    connect_networks(
+        docker,
+        container,
+        container_backup.host_config.network_mode.as_deref(),
+        &container_backup.networks,
+        !options.rehearse,
+    )
+    .await?;
+    log::info!("Successfully restored container {}", container);
+    Ok(container.to_string())
+}
+
+/// Return a shell script of docker commands that restores a container backup the way
+/// `restore_container` does, without changing anything
+///
+/// Volumes are created and archives extracted by the same dockyard helper commands, then the
+/// container is created with `docker create`. Settings `docker create` has no flag for are listed
+/// in a comment instead.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_file` - Container backup file relative to `backup_mount`
+/// * `container` - Name of restored container, generated from `options.name_template` if not set
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Restore options
+///
+pub async fn restore_container_script(
+    docker: &Docker,
+    backup_file: &str,
+    container: Option<&str>,
+    backup_mount: Mount,
+    options: &RestoreOptions,
+) -> Result<String> {
+    let mut container_backup = read_container_backup(docker, backup_file, &backup_mount).await?;
+    let container = restored_container_name(&container_backup, backup_file, container, options)?;
+    let rehearsal_options;
+    let options = if options.rehearse {
+        rehearsal_options = rehearsal_restore_options(&container, options);
+        &rehearsal_options
+    } else {
+        options
+    };
+    attach_anonymous_volumes(&mut container_backup);
+    let helper = get_or_build_image(docker).await?;
+    let backup_mount = read_only_mount(backup_mount);
+    let backup_target = backup_mount.target.clone().unwrap();
+    let mut script = Script::new(&format!(
+        "Restore container {} from {}, generated by dockyard",
+        container, backup_file
+    ));
+    if let Some(swarm) = &container_backup.swarm {
+        script.comment(&format!(
+            "Was a task of swarm service {}, which has to use the secrets and configs it used",
+            swarm.service
+        ));
+    }
+    for mount_backup in &container_backup.mounts {
+        let mount = &mount_backup.mount;
+        let (name, output) = match mount.typ.as_deref() {
+            Some("bind") if options.rehearse => {
+                script.comment(&format!(
+                    "Bind mount {} is shared with the original container and not restored",
+                    mount.source.as_deref().unwrap_or_default()
+                ));
+                continue;
+            }
+            Some("bind") => {
+                let directory = options.bind_source(mount.source.as_ref().unwrap());
+                let output = Mount {
+                    target: Some("/output".to_string()),
+                    source: Some(directory.clone()),
+                    typ: Some(MountTypeEnum::BIND),
+                    ..Default::default()
+                };
+                (directory, output)
+            }
+            Some("volume") => {
+                let volume = options.volume_name(mount.name.as_ref().unwrap());
+                (volume.clone(), get_volume_mount(volume))
+            }
+            typ => {
+                return Err(anyhow!(
+                    "Unable to restore {} mount at {}",
+                    typ.unwrap_or("unknown"),
+                    mount.destination.as_deref().unwrap_or_default()
+                ))
+            }
+        };
+        script.comment(&format!(
+            "Restore {} from {}",
+            name,
+            mount_backup.path.display()
+        ));
+        if output.typ == Some(MountTypeEnum::VOLUME) {
+            let mut create = vec![
+                "docker".to_string(),
+                "volume".to_string(),
+                "create".to_string(),
+            ];
+            let mut labels = mount_backup.volume_labels.iter().collect::<Vec<_>>();
+            labels.sort();
+            for (key, value) in labels {
+                create.push("--label".to_string());
+                create.push(format!("{}={}", key, value));
+            }
+            create.push(name);
+            script.command(&create);
+        }
+        let archive = format!("{}/{}", backup_target, path_str(&mount_backup.path)?);
+        let mut restore = vec![
+            "docker".to_string(),
+            "run".to_string(),
+            "--rm".to_string(),
+            "--mount".to_string(),
+            mount_arg(&backup_mount),
+            "--mount".to_string(),
+            mount_arg(&output),
+            helper.clone(),
+            "dockyard".to_string(),
+            "restore".to_string(),
+            "directory".to_string(),
+            archive,
+            output.target.clone().unwrap(),
+        ];
+        restore.extend(options.directory_args());
+        script.command(&restore);
+    }
+    let container_config = restored_config(&mut container_backup, options);
+
+    let image = container_backup.container_config.image.clone().unwrap();
+    script.comment(&format!("Make image {} available", image));
+    let inspect = command_line(&["docker", "image", "inspect", &image]);
+    let pull = command_line(&["docker", "pull", &image]);
+    match &container_backup.image_archive {
+        _ if options.refresh_image => script.line(pull),
+        Some(archive) if options.no_pull => {
+            let archive = format!("{}/{}", backup_target, path_str(archive)?);
+            let cat = command_line(&[
+                "docker",
+                "run",
+                "--rm",
+                "--mount",
+                &mount_arg(&backup_mount),
+                &helper,
+                "cat",
+                &archive,
+            ]);
+            script.line(format!(
+                "{} >/dev/null 2>&1 || {} | docker load",
+                inspect, cat
+            ));
+        }
+        None if options.no_pull => script.line(inspect),
+        _ => script.line(format!("{} >/dev/null 2>&1 || {}", inspect, pull)),
+    }
+
+    script.comment(&format!("Create container {}", container));
+    let untranslated = untranslated_settings(&container_config);
+    if !untranslated.is_empty() {
+        script.line(format!(
+            "# Not reproduced by docker create: {}",
+            untranslated.join(", ")
+        ));
+    }
+    script.wrapped_command(&create_command(&container, &container_config));
+    let network_mode = container_backup.host_config.network_mode.as_deref();
+    for (network, endpoint) in &container_backup.networks {
+        let aliases = if options.rehearse {
+            &[][..]
+        } else {
+            endpoint.aliases.as_slice()
+        };
+        if network_mode == Some(network.as_str()) {
+            if aliases.is_empty() {
+                continue;
+            }
+            script.command(&[
+                "docker",
+                "network",
+                "disconnect",
+                network.as_str(),
+                container.as_str(),
+            ]);
+        }
+        let mut connect = vec!["docker", "network", "connect"];
+        for alias in aliases {
+            connect.push("--alias");
+            connect.push(alias);
+        }
+        connect.push(network);
+        connect.push(&container);
+        script.command(&connect);
+    }
+    Ok(script.render())
+}
+
+/// Return the config to create a restored container with, applying `options` to the backup
+///
+/// # Arguments
+///
+/// * `container_backup` - Container backup being restored
+/// * `options` - Restore options
+///
+fn restored_config(
+    container_backup: &mut ContainerBackup,
+    options: &RestoreOptions,
+) -> Config<String> {
+    if !options.bind_remap.is_empty() {
+        remap_bind_sources(&mut container_backup.host_config, options);
+    }
+    if let Some(prefix) = &options.volume_prefix {
+        prefix_volume_sources(&mut container_backup.host_config, prefix);
+        if options.rehearse {
+            container_backup
+                .container_config
+                .labels
+                .get_or_insert_with(Default::default)
+                .insert(REHEARSAL_LABEL.to_string(), prefix.clone());
+        }
+    }
+    if options.rehearse {
+        // The original container holds its host ports, publishing them again would fail
+        container_backup.host_config.port_bindings = None;
+        container_backup.host_config.publish_all_ports = None;
+    }
+    override_resource_limits(&mut container_backup.host_config, options);
+    for name in &container_backup.redacted_env {
+        if !options.env.iter().any(|(key, _)| key == name) {
+            log::warn!(
+                "{} was redacted from the backup, set it with --env-from-file",
+                name
+            );
+        }
+    }
+    let mut container_config = create_config(
+        container_backup.container_config.clone(),
+        container_backup.host_config.clone(),
+    );
+    container_config.env = merge_env(container_config.env, &options.env);
+    container_config.cmd = match (&options.entrypoint, &options.cmd) {
+        (_, Some(cmd)) => Some(cmd.clone()),
+        // Like docker run --entrypoint, a new entrypoint doesn't keep the backed up command
+        (Some(_), None) => None,
+        (None, None) => container_config.cmd,
+    };
+    container_config.entrypoint = options.entrypoint.clone().or(container_config.entrypoint);
+    container_config
+}
+
+/// Return the config to create a container with from the config of an existing or backed up one
+///
+/// # Arguments
+///
+/// * `container_config` - Config of the container
+/// * `host_config` - Host config of the container
+///
+fn create_config(container_config: ContainerConfig, host_config: HostConfig) -> Config<String> {
+    Config {
+        hostname: container_config.hostname,
+        domainname: container_config.domainname,
+        user: container_config.user,
+        attach_stdin: container_config.attach_stdin,
+        attach_stdout: container_config.attach_stdout,
+        attach_stderr: container_config.attach_stderr,
+        exposed_ports: container_config.exposed_ports,
+        tty: container_config.tty,
+        open_stdin: container_config.open_stdin,
+        stdin_once: container_config.stdin_once,
+        env: container_config.env,
+        cmd: container_config.cmd,
+        healthcheck: container_config.healthcheck,
+        args_escaped: container_config.args_escaped,
+        image: container_config.image,
+        volumes: container_config.volumes,
+        working_dir: container_config.working_dir,
+        entrypoint: container_config.entrypoint,
+        network_disabled: container_config.network_disabled,
+        mac_address: container_config.mac_address,
+        on_build: container_config.on_build,
+        labels: container_config.labels,
+        stop_signal: container_config.stop_signal,
+        stop_timeout: container_config.stop_timeout,
+        shell: container_config.shell,
+        host_config: Some(host_config),
+        ..Default::default()
+    }
+}
+
+/// Return the name to restore a container backup under
+///
+/// # Arguments
+///
+/// * `container_backup` - Container backup being restored
+/// * `backup_file` - Container backup file relative to the backup mount
+/// * `container` - Name given for the restored container
+/// * `options` - Restore options
+///
+pub(crate) fn restored_container_name(
+    container_backup: &ContainerBackup,
+    backup_file: &str,
+    container: Option<&str>,
+    options: &RestoreOptions,
+) -> Result<String> {
+    match (container, &options.name_template) {
+        _ if options.rehearse => Ok(rehearsal_name(&container_backup.name, Utc::now())),
+        (Some(container), _) => Ok(container.to_string()),
+        (None, Some(template)) => template.render(
+            &container_backup.name,
+            parse_backup_timestamp(Path::new(backup_file)),
+            Utc::now(),
+        ),
+        (None, None) => Err(anyhow!("No name given for the restored container")),
+    }
+}
+
+/// Return options restoring a rehearsal copy named `container`, with its volumes prefixed by
+/// its name so `dockyard cleanup-rehearsal` can find them
+///
+/// # Arguments
+///
+/// * `container` - Name of rehearsal container
+/// * `options` - Restore options
+///
+fn rehearsal_restore_options(container: &str, options: &RestoreOptions) -> RestoreOptions {
+    RestoreOptions {
+        volume_prefix: Some(format!("{}_", container)),
+        ..options.clone()
+    }
+}
+
+/// Replace the backed up resource limits with those given in `options`
+///
+/// Docker refuses a memory reservation or swap limit below the memory limit, and a CPU quota
+/// alongside a CPU limit, so backed up limits that conflict with an override are dropped.
+///
+/// # Arguments
+///
+/// * `host_config` - Host config of the container being restored
+/// * `options` - Restore options
+///
+fn override_resource_limits(host_config: &mut HostConfig, options: &RestoreOptions) {
+    if let Some(memory) = options.memory {
+        host_config.memory = Some(memory);
+        if host_config.memory_reservation.unwrap_or_default() > memory {
+            log::warn!("Dropping backed up memory reservation above the new memory limit");
+            host_config.memory_reservation = None;
+        }
+        let swap = host_config.memory_swap.unwrap_or_default();
+        if options.memory_swap.is_none() && swap > 0 && swap < memory {
+            log::warn!("Dropping backed up swap limit below the new memory limit");
+            host_config.memory_swap = None;
+        }
+    }
+    if let Some(memory_swap) = options.memory_swap {
+        host_config.memory_swap = Some(memory_swap);
+    }
+    if let Some(nano_cpus) = options.nano_cpus {
+        host_config.nano_cp_us = Some(nano_cpus);
+        host_config.cpu_period = None;
+        host_config.cpu_quota = None;
+    }
+}
+
+/// Mount restored anonymous volumes explicitly and label them as anonymous
+///
+/// Docker creates anonymous volumes for the image's `VOLUME`s and for volume mounts without a
+/// source. They aren't named in the host config, so without this the restored container would
+/// get new, empty anonymous volumes instead of the restored ones. The anonymous label is added to
+/// volumes backed up from Docker versions that didn't set it, so `docker volume prune` treats them
+/// like the originals. Volumes are classified by their label or name, since named volumes can also
+/// be missing from the host config when they're attached with `--volumes-from`.
+///
+/// # Arguments
+///
+/// * `container_backup` - Backup of container being restored
+///
+fn attach_anonymous_volumes(container_backup: &mut ContainerBackup) {
+    let host_config = &mut container_backup.host_config;
+    let mut named = host_config
+        .binds
+        .iter()
+        .flatten()
+        .filter(|bind| !bind.starts_with('/'))
+        .map(|bind| bind.splitn(2, ':').next().unwrap().to_string())
+        .collect::<HashSet<_>>();
+    named.extend(
+        host_config
+            .mounts
+            .iter()
+            .flatten()
+            .filter(|m| m.typ == Some(MountTypeEnum::VOLUME))
+            .filter_map(|m| m.source.clone())
+            .filter(|s| !s.is_empty()),
+    );
+    for mount_backup in &mut container_backup.mounts {
+        let point = &mount_backup.mount;
+        let name = match (point.typ.as_deref(), &point.name) {
+            (Some("volume"), Some(name))
+                if !named.contains(name)
+                    && is_anonymous_volume(name, &mount_backup.volume_labels) =>
+            {
+                name.clone()
+            }
+            _ => continue,
+        };
+        mount_backup
+            .volume_labels
+            .entry(ANONYMOUS_VOLUME_LABEL.to_string())
+            .or_insert_with(String::new);
+        let mounts = host_config.mounts.get_or_insert_with(Vec::new);
+        // Volume mounts without a source get the restored volume, others are added
+        match mounts.iter_mut().find(|m| {
+            m.typ == Some(MountTypeEnum::VOLUME)
+                && m.target == point.destination
+                && m.source.as_deref().unwrap_or_default().is_empty()
+        }) {
+            Some(mount) => mount.source = Some(name),
+            None => mounts.push(Mount {
+                target: point.destination.clone(),
+                source: Some(name),
+                typ: Some(MountTypeEnum::VOLUME),
+                read_only: point.rw.map(|rw| !rw),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Whether a volume was created by Docker rather than named by the user
+///
+/// Docker labels anonymous volumes on newer versions and names them with a random 64 character
+/// hex ID on all of them.
+///
+/// # Arguments
+///
+/// * `name` - Name of the volume
+/// * `labels` - Labels of the volume when it was backed up
+///
+fn is_anonymous_volume(name: &str, labels: &HashMap<String, String>) -> bool {
+    labels.contains_key(ANONYMOUS_VOLUME_LABEL)
+        || (name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Prepend `prefix` to the named volumes mounted by `host_config`
+///
+/// # Arguments
+///
+/// * `host_config` - Host config of the container being restored
+/// * `prefix` - Prefix of restored volume names
+///
+fn prefix_volume_sources(host_config: &mut HostConfig, prefix: &str) {
+    for mount in host_config.mounts.iter_mut().flatten() {
+        if mount.typ == Some(MountTypeEnum::VOLUME) {
+            if let Some(source) = mount.source.as_mut().filter(|s| !s.is_empty()) {
+                *source = format!("{}{}", prefix, source);
+            }
+        }
+    }
+    for bind in host_config.binds.iter_mut().flatten() {
+        // Host directories are absolute paths, anything else names a volume
+        if !bind.starts_with('/') {
+            *bind = format!("{}{}", prefix, bind);
+        }
+    }
+}
+
+/// Replace the host directories bind mounted by `host_config` as given by `options.bind_remap`
+///
+/// # Arguments
+///
+/// * `host_config` - Host config of the container being restored
+/// * `options` - Restore options
+///
+fn remap_bind_sources(host_config: &mut HostConfig, options: &RestoreOptions) {
+    for mount in host_config.mounts.iter_mut().flatten() {
+        if mount.typ == Some(MountTypeEnum::BIND) {
+            if let Some(source) = mount.source.as_mut() {
+                *source = options.bind_source(source);
+            }
+        }
+    }
+    for bind in host_config.binds.iter_mut().flatten() {
+        // Host directories are absolute paths, anything else names a volume
+        if bind.starts_with('/') {
+            let (source, rest) = match bind.find(':') {
+                Some(i) => bind.split_at(i),
+                None => (bind.as_str(), ""),
+            };
+            *bind = format!("{}{}", options.bind_source(source), rest);
+        }
+    }
+}
+
+/// Check the image a container is restored from is the one recorded at backup time
+///
+/// A tag that moved since the backup resolves to a different image. With `strict_image` an error
+/// is returned before anything is restored, otherwise a warning is logged.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `container` - Name of container being restored
+/// * `image` - Image the container is created from
+/// * `expected` - ID of image recorded in the backup
+/// * `options` - Restore options
+///
+async fn check_image(
+    docker: &Docker,
+    container: &str,
+    image: &str,
+    expected: &str,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let actual = docker.inspect_image(image).await?.id;
+    let message = match image_mismatch(container, Some(actual.as_str()), expected) {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+    if options.strict_image {
+        Err(anyhow!("{}, not restoring it", message))
+    } else {
+        log::warn!("{}", message);
+        Ok(())
+    }
+}
+
+/// Describe the difference between a restored container's image and the backed up one, if any
+fn image_mismatch(container: &str, actual: Option<&str>, expected: &str) -> Option<String> {
+    if actual == Some(expected) {
+        return None;
+    }
+    Some(format!(
+        "Restored container {} would use image {} but the backup was taken from image {}",
+        container,
+        actual.unwrap_or("unknown"),
+        expected
+    ))
+}
+
+/// Verify backup volume or directory exists on the Docker host being restored to
+///
+/// Directories are checked by bind mounting them in a stopped helper container, since the
+/// Docker host may not be the local machine.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup location
+///
+pub(crate) async fn check_backup_mount(docker: &Docker, backup_mount: &Mount) -> Result<()> {
+    let source = backup_mount.source.as_ref().unwrap();
+    match backup_mount.typ {
+        Some(MountTypeEnum::VOLUME) => {
+            if let Err(e) = docker.inspect_volume(source).await {
+                return Err(anyhow!(
+                    "Backup volume {} not found on Docker host: {}",
+                    source,
+                    e
+                ));
+            }
+        }
+        Some(MountTypeEnum::BIND) => {
+            if let Err(e) = check_mount(docker, read_only_mount(backup_mount.clone())).await {
+                return Err(anyhow!(
+                    "Backup directory {} not found on Docker host: {}",
+                    source,
+                    e
+                ));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
 mod test {
     use super::*;
-    use crate::backup::MountBackup;
-    use crate::container::{get_backup_directory_mount, run_docker_command};
+    use crate::backup::{
+        backup_container, backup_directory, BackupOptions, MountBackup,
+        CONTAINER_BACKUP_SCHEMA_VERSION,
+    };
+    use crate::container::{
+        check_image, get_backup_directory_mount, get_backup_volume_mount, run_docker_command,
+    };
     use bollard::container::{InspectContainerOptions, RemoveContainerOptions};
-    use bollard::models::{ContainerConfig, HostConfig, MountPoint};
+    use bollard::models::{
+        ContainerConfig, EndpointSettings, HostConfig, MountBindOptions,
+        MountBindOptionsPropagationEnum, MountPoint, PortBinding,
+    };
+    use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions};
+    use chrono::TimeZone;
     use flate2::write::GzEncoder;
     use flate2::Compression;
     use log::LevelFilter;
     use simple_logger::SimpleLogger;
-    use std::fs::{create_dir, read_dir, read_to_string};
+    use std::collections::HashMap;
+    use std::fs::{create_dir, read_dir, read_to_string, write};
     use std::io::Write;
     use std::path::PathBuf;
     use tempfile::TempDir;
@@ -193,7 +2079,328 @@ mod test {
         let archive_path = create_archive(&working_dir);
         let output = Path::join(&working_dir.path(), "output");
         create_dir(&output).unwrap();
-        restore_directory(&archive_path.to_str().unwrap(), &output.to_str().unwrap()).unwrap();
+        restore_directory(
+            &archive_path.to_str().unwrap(),
+            &output.to_str().unwrap(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn restore_directory_rejects_parent_paths_test() {
+        let working_dir = TempDir::new().unwrap();
+        let archive_path = create_raw_archive(&working_dir, b"../escaped");
+        let output = working_dir.path().join("output");
+        let error = restore_directory(
+            archive_path.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &RestoreOptions {
+                allow_absolute: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to extract ../escaped, path escapes the output directory"
+        );
+        assert!(!working_dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn restore_directory_rejects_hard_links_through_symlinks_test() {
+        let working_dir = TempDir::new().unwrap();
+        let outside = working_dir.path().join("outside");
+        create_dir(&outside).unwrap();
+        write(outside.join("secret"), "host file").unwrap();
+        let archive_path = working_dir.path().join("links.tgz");
+        let enc = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut tar = tar::Builder::new(enc);
+        for (entry_type, path, target) in &[
+            (tar::EntryType::Symlink, "d", outside.to_str().unwrap()),
+            (tar::EntryType::Link, "x", "d/secret"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_path(path).unwrap();
+            header.set_link_name(target).unwrap();
+            header.set_size(0);
+            header.set_mode(0o777);
+            header.set_cksum();
+            tar.append(&header, std::io::empty()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let output = working_dir.path().join("output");
+        let error = restore_directory(
+            archive_path.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to extract x, hard link target escapes the output directory"
+        );
+        assert!(output.join("x").symlink_metadata().is_err());
+        assert_eq!(outside.join("secret").metadata().unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn restore_directory_absolute_paths_test() {
+        let working_dir = TempDir::new().unwrap();
+        let archive_path = create_raw_archive(&working_dir, b"/data/file");
+        let archive = archive_path.to_str().unwrap();
+        let output = working_dir.path().join("output");
+
+        assert!(restore_directory(archive, output.to_str().unwrap(), &Default::default()).is_err());
+        assert!(!output.join("data/file").exists());
+
+        let options = RestoreOptions {
+            allow_absolute: true,
+            ..Default::default()
+        };
+        restore_directory(archive, output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(
+            read_to_string(output.join("data/file")).unwrap(),
+            "contents"
+        );
+
+        let stripped = working_dir.path().join("stripped");
+        let options = RestoreOptions {
+            allow_absolute: true,
+            strip_components: 1,
+            ..Default::default()
+        };
+        restore_directory(archive, stripped.to_str().unwrap(), &options).unwrap();
+        assert_eq!(read_to_string(stripped.join("file")).unwrap(), "contents");
+    }
+
+    #[test]
+    fn restore_directory_clean_test() {
+        let working_dir = TempDir::new().unwrap();
+        let archive_path = create_raw_archive(&working_dir, b"data/file");
+        let archive = archive_path.to_str().unwrap();
+        let output = working_dir.path().join("output");
+        create_dir(&output).unwrap();
+        create_dir(output.join("stale-dir")).unwrap();
+        File::create(output.join("stale-file")).unwrap();
+
+        restore_directory(archive, output.to_str().unwrap(), &Default::default()).unwrap();
+        assert!(output.join("stale-file").exists());
+
+        let options = RestoreOptions {
+            clean: true,
+            ..Default::default()
+        };
+        restore_directory(archive, output.to_str().unwrap(), &options).unwrap();
+        assert!(output.exists());
+        assert!(!output.join("stale-dir").exists());
+        assert!(!output.join("stale-file").exists());
+        assert_eq!(
+            read_to_string(output.join("data/file")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn restore_directory_merge_test() {
+        let working_dir = TempDir::new().unwrap();
+        let now = Utc::now().timestamp() as u64;
+        let archive_path = working_dir.path().join("merge.tgz");
+        let enc = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut tar = tar::Builder::new(enc);
+        for (name, mtime) in &[("older", 1000), ("newer", now + 3600), ("missing", 1000)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(8);
+            header.set_mode(0o644);
+            header.set_mtime(*mtime);
+            tar.append_data(&mut header, name, &b"restored"[..])
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        let archive = archive_path.to_str().unwrap();
+
+        let restore = |merge: MergeMode| {
+            let output = working_dir.path().join(format!("{:?}", merge));
+            create_dir(&output).unwrap();
+            write(output.join("older"), "existing").unwrap();
+            write(output.join("newer"), "existing").unwrap();
+            let options = RestoreOptions {
+                merge,
+                ..Default::default()
+            };
+            restore_directory(archive, output.to_str().unwrap(), &options).unwrap();
+            ["older", "newer", "missing"]
+                .iter()
+                .map(|name| read_to_string(output.join(name)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            restore(MergeMode::Overwrite),
+            vec!["restored", "restored", "restored"]
+        );
+        assert_eq!(
+            restore(MergeMode::SkipExisting),
+            vec!["existing", "existing", "restored"]
+        );
+        assert_eq!(
+            restore(MergeMode::Newer),
+            vec!["existing", "restored", "restored"]
+        );
+    }
+
+    #[test]
+    fn restore_directory_verify_files_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let input = working_dir.path().join("input");
+        create_dir_all(input.join("data")).unwrap();
+        write(input.join("config"), "listen 80").unwrap();
+        write(input.join("data").join("table"), "rows").unwrap();
+        std::os::unix::fs::symlink("table", input.join("data").join("current")).unwrap();
+        std::os::unix::fs::symlink("data", input.join("latest")).unwrap();
+        let output = working_dir.path().join("backups");
+        let backup_options = BackupOptions {
+            file_manifest: true,
+            ..Default::default()
+        };
+        let archive = output.join(
+            backup_directory(
+                input.to_str().unwrap(),
+                output.to_str().unwrap(),
+                &backup_options,
+            )
+            .unwrap(),
+        );
+
+        let restored = working_dir.path().join("restored");
+        let options = RestoreOptions {
+            verify_files: true,
+            ..Default::default()
+        };
+        restore_directory(
+            archive.to_str().unwrap(),
+            restored.to_str().unwrap(),
+            &options,
+        )
+        .unwrap();
+        assert!(!restored.join(FILE_MANIFEST_MEMBER).exists());
+
+        write(restored.join("data").join("table"), "truncated").unwrap();
+        remove_file(restored.join("config")).unwrap();
+        let manifest = read_file_manifest(&archive).unwrap().unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            vec!["config", "data/current", "data/table", "latest"]
+        );
+        assert_eq!(
+            compare_restored_files(&manifest, &restored, &options).unwrap(),
+            FileVerification {
+                checked: 4,
+                missing: vec!["config".to_string()],
+                corrupted: vec!["data/table".to_string()],
+            }
+        );
+        assert!(verify_restored_files(&archive, &restored, &options)
+            .unwrap_err()
+            .to_string()
+            .starts_with("1 of 4 files restored to"));
+
+        let without_manifest = output.join(
+            backup_directory(
+                input.to_str().unwrap(),
+                output.to_str().unwrap(),
+                &BackupOptions {
+                    archive_stem: Some("without-manifest".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+        );
+        assert!(read_file_manifest(&without_manifest).unwrap().is_none());
+        assert!(restore_directory(
+            without_manifest.to_str().unwrap(),
+            working_dir.path().join("unverified").to_str().unwrap(),
+            &options,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_owner_test() {
+        assert_eq!(parse_owner("999:999").unwrap(), (999, 999));
+        assert_eq!(parse_owner("0:100").unwrap(), (0, 100));
+        assert!(parse_owner("postgres:postgres").is_err());
+        assert!(parse_owner("999").is_err());
+        assert!(parse_owner("999:").is_err());
+    }
+
+    #[test]
+    fn name_template_test() {
+        let now = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        let backup_date = DateTime::parse_from_rfc3339("2021-02-01T00:00:00+00:00").ok();
+        let template = parse_name_template("{original}-restored-{timestamp}").unwrap();
+        assert_eq!(
+            template.render("nginx", backup_date, now).unwrap(),
+            "nginx-restored-20210304T050607Z"
+        );
+        let template = parse_name_template("{original}_{backup-date}").unwrap();
+        assert_eq!(
+            template.render("nginx", backup_date, now).unwrap(),
+            "nginx_2021-02-01"
+        );
+        assert!(template.render("nginx", None, now).is_err());
+        assert!(parse_name_template("test-{original}")
+            .unwrap()
+            .render("", None, now)
+            .is_ok());
+        assert!(parse_name_template("{original}")
+            .unwrap()
+            .render("-", None, now)
+            .is_err());
+
+        assert_eq!(
+            parse_name_template("{name}-copy").unwrap_err().to_string(),
+            "Unknown token {name} in name template {name}-copy, expected {original}, {timestamp} or {backup-date}"
+        );
+        assert!(parse_name_template("{original").is_err());
+        assert!(parse_name_template("{original}/copy").is_err());
+        assert!(parse_name_template("").is_err());
+    }
+
+    #[test]
+    fn restore_directory_chown_test() {
+        let working_dir = TempDir::new().unwrap();
+        let archive_path = create_raw_archive(&working_dir, b"data/file");
+        let output = working_dir.path().join("output");
+        create_dir(&output).unwrap();
+        // Only root can give files away, otherwise chown to ourselves
+        let metadata = std::fs::metadata(&output).unwrap();
+        let owner = if metadata.uid() == 0 {
+            (999, 999)
+        } else {
+            (metadata.uid(), metadata.gid())
+        };
+        let options = RestoreOptions {
+            owner: Some(owner),
+            ..Default::default()
+        };
+        restore_directory(
+            archive_path.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &options,
+        )
+        .unwrap();
+        for path in &[
+            output.clone(),
+            output.join("data"),
+            output.join("data/file"),
+        ] {
+            let metadata = std::fs::metadata(path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), owner);
+        }
     }
 
     #[test]
@@ -236,6 +2443,7 @@ mod test {
                     ..Default::default()
                 },
                 volume_mount.clone(),
+                &RestoreOptions::default(),
             )
             .await
             .unwrap();
@@ -257,6 +2465,36 @@ mod test {
         assert_eq!(count, 100);
     }
 
+    #[test]
+    fn restore_volume_to_directory_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let archive_path = create_archive(&working_dir);
+        let output = working_dir.path().join("missing/output");
+
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+        rt.block_on(restore_volume_to_directory(
+            &docker,
+            archive_path
+                .strip_prefix(working_dir.path())
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string()),
+            &output,
+            &RestoreOptions::default(),
+        ))
+        .unwrap();
+
+        assert_eq!(read_dir(&output).unwrap().count(), 100);
+        assert_eq!(
+            read_to_string(output.join("42")).unwrap(),
+            "Restore test data 42"
+        );
+    }
+
     #[test]
     fn restore_container_test() {
         let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
@@ -281,6 +2519,9 @@ mod test {
                 driver: driver.clone(),
                 ..Default::default()
             },
+            checksum: None,
+            volume_labels: Default::default(),
+            resolved_source: None,
         };
         let mount = Mount {
             target: destination.clone(),
@@ -289,6 +2530,7 @@ mod test {
             ..Default::default()
         };
         let container_backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
             name: container_name.clone(),
             container_config: ContainerConfig {
                 cmd: Some(vec![
@@ -305,6 +2547,13 @@ mod test {
                 ..Default::default()
             },
             mounts: vec![mount_backup],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
         };
         let backup_path = working_dir.path().join(backup_name);
         File::create(&backup_path)
@@ -318,8 +2567,9 @@ mod test {
             restore_container(
                 &docker,
                 backup_name,
-                container_name.as_str(),
+                Some(container_name.as_str()),
                 get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string()),
+                &RestoreOptions::default(),
             )
             .await
             .unwrap();
@@ -354,12 +2604,1383 @@ mod test {
         });
     }
 
-    fn create_archive(working_dir: &TempDir) -> PathBuf {
-        let input = Path::join(working_dir.path(), "input");
-        create_dir(input.as_path()).unwrap();
+    #[test]
+    fn pulls_image_test() {
+        let options = RestoreOptions::default();
+        assert!(options.pulls_image(false));
+        assert!(!options.pulls_image(true));
+        let no_pull = RestoreOptions {
+            no_pull: true,
+            ..Default::default()
+        };
+        assert!(!no_pull.pulls_image(false));
+        assert!(!no_pull.pulls_image(true));
+        let refresh = RestoreOptions {
+            refresh_image: true,
+            ..Default::default()
+        };
+        assert!(refresh.pulls_image(false));
+        assert!(refresh.pulls_image(true));
+    }
 
-        for i in 0..100 {
-            let mut f = File::create(Path::join(&input, i.to_string())).unwrap();
+    #[test]
+    fn rehearsal_test() {
+        let now = DateTime::parse_from_rfc3339("2020-10-22T23:09:02+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let name = rehearsal_name("web", now);
+        assert_eq!(name, "web-rehearsal-20201022T230902Z");
+        check_container_name(&name).unwrap();
+        let options = rehearsal_restore_options(
+            &name,
+            &RestoreOptions {
+                rehearse: true,
+                no_pull: true,
+                ..Default::default()
+            },
+        );
+        assert!(options.no_pull);
+        assert_eq!(
+            options.volume_name("data"),
+            "web-rehearsal-20201022T230902Z_data"
+        );
+    }
+
+    #[test]
+    fn bind_remap_test() {
+        let options = RestoreOptions {
+            bind_remap: vec![
+                parse_bind_remap("/srv=/opt").unwrap(),
+                parse_bind_remap("/srv/data/=/mnt/data").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(options.bind_source("/srv/data"), "/mnt/data");
+        assert_eq!(
+            options.bind_source("/srv/data/app/logs"),
+            "/mnt/data/app/logs"
+        );
+        assert_eq!(options.bind_source("/srv/database"), "/opt/database");
+        assert_eq!(options.bind_source("/srv"), "/opt");
+        assert_eq!(options.bind_source("/srvx/data"), "/srvx/data");
+        assert_eq!(
+            RestoreOptions::default().bind_source("/srv/data"),
+            "/srv/data"
+        );
+        let root = RestoreOptions {
+            bind_remap: vec![parse_bind_remap("/=/mnt/old/").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(root.bind_source("/srv/data"), "/mnt/old/srv/data");
+
+        assert!(parse_bind_remap("/srv/data").is_err());
+        assert!(parse_bind_remap("srv=/mnt").is_err());
+        assert!(parse_bind_remap("/srv=mnt").is_err());
+
+        let mut host_config = HostConfig {
+            binds: Some(vec![
+                "data:/var/lib/data:ro".to_string(),
+                "/srv/data/app:/app:ro".to_string(),
+                "/srv/config:/etc/app".to_string(),
+            ]),
+            mounts: Some(vec![
+                Mount {
+                    source: Some("/srv/data/app/cache".to_string()),
+                    typ: Some(MountTypeEnum::BIND),
+                    ..Default::default()
+                },
+                Mount {
+                    source: Some("srv".to_string()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+        remap_bind_sources(&mut host_config, &options);
+        assert_eq!(
+            host_config.binds.unwrap(),
+            vec![
+                "data:/var/lib/data:ro",
+                "/mnt/data/app:/app:ro",
+                "/opt/config:/etc/app"
+            ]
+        );
+        let sources = host_config
+            .mounts
+            .unwrap()
+            .into_iter()
+            .map(|m| m.source.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sources, vec!["/mnt/data/app/cache", "srv"]);
+    }
+
+    #[test]
+    fn prefix_volume_sources_test() {
+        let mut host_config = HostConfig {
+            binds: Some(vec![
+                "data:/var/lib/data:ro".to_string(),
+                "/srv/config:/etc/app".to_string(),
+            ]),
+            mounts: Some(vec![
+                Mount {
+                    source: Some("cache".to_string()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                },
+                Mount {
+                    source: Some("/srv/logs".to_string()),
+                    typ: Some(MountTypeEnum::BIND),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+        prefix_volume_sources(&mut host_config, "copy_");
+        assert_eq!(
+            host_config.binds.unwrap(),
+            vec!["copy_data:/var/lib/data:ro", "/srv/config:/etc/app"]
+        );
+        let sources = host_config
+            .mounts
+            .unwrap()
+            .into_iter()
+            .map(|m| m.source.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sources, vec!["copy_cache", "/srv/logs"]);
+    }
+
+    #[test]
+    fn restore_container_volume_prefix_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("restore_test_{}", test_id);
+        let restored_name = format!("restored_{}", container_name);
+        let volume_name = format!("restore_test_volume_{}", test_id);
+        let prefix = "copy_";
+        let prefixed_volume = format!("{}{}", prefix, volume_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let restored_mounts = rt
+            .block_on(async {
+                let restored_mounts = async {
+                    let config = Config {
+                        host_config: Some(HostConfig {
+                            mounts: Some(vec![Mount {
+                                source: Some(volume_name.clone()),
+                                target: Some("/data".to_string()),
+                                typ: Some(MountTypeEnum::VOLUME),
+                                ..Default::default()
+                            }]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    let options = RestoreOptions {
+                        volume_prefix: Some(prefix.to_string()),
+                        ..Default::default()
+                    };
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &options,
+                    )
+                    .await?;
+                    docker.inspect_volume(&prefixed_volume).await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>(restored.mounts.unwrap())
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[prefixed_volume.as_str(), volume_name.as_str()],
+                )
+                .await;
+                restored_mounts
+            })
+            .unwrap();
+        assert_eq!(restored_mounts.len(), 1);
+        assert_eq!(restored_mounts[0].name, Some(prefixed_volume));
+    }
+
+    #[test]
+    fn restore_container_read_only_mounts_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("restore_test_{}", test_id);
+        let restored_name = format!("restored_{}", container_name);
+        let mount_volume = format!("restore_test_mount_{}", test_id);
+        let bind_volume = format!("restore_test_bind_{}", test_id);
+        let volumes = [mount_volume.as_str(), bind_volume.as_str()];
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (restored_host_config, restored_mounts) = rt
+            .block_on(async {
+                let restored = async {
+                    let config = Config {
+                        host_config: Some(HostConfig {
+                            binds: Some(vec![format!("{}:/config:ro", bind_volume)]),
+                            mounts: Some(vec![Mount {
+                                source: Some(mount_volume.clone()),
+                                target: Some("/data".to_string()),
+                                typ: Some(MountTypeEnum::VOLUME),
+                                read_only: Some(true),
+                                ..Default::default()
+                            }]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    // Restore into fresh volumes rather than the ones backed up
+                    cleanup_containers_and_volumes(&docker, &[container_name.as_str()], &volumes)
+                        .await;
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &Default::default(),
+                    )
+                    .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>((
+                        restored.host_config.unwrap(),
+                        restored.mounts.unwrap(),
+                    ))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &volumes,
+                )
+                .await;
+                restored
+            })
+            .unwrap();
+        assert_eq!(
+            restored_host_config.binds,
+            Some(vec![format!("{}:/config:ro", bind_volume)])
+        );
+        assert_eq!(
+            restored_host_config.mounts.unwrap()[0].read_only,
+            Some(true)
+        );
+        assert_eq!(restored_mounts.len(), 2);
+        for mount in restored_mounts {
+            assert_eq!(mount.rw, Some(false), "{:?} is writable", mount.destination);
+        }
+    }
+
+    #[test]
+    fn restore_container_bind_propagation_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let shared_dir = TempDir::new().unwrap();
+        let slave_dir = TempDir::new().unwrap();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("restore_test_{}", test_id);
+        let restored_name = format!("restored_{}", container_name);
+        let shared_bind = format!("{}:/shared:shared", shared_dir.path().display());
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (restored_host_config, restored_mounts) = rt
+            .block_on(async {
+                let restored = async {
+                    let config = Config {
+                        host_config: Some(HostConfig {
+                            binds: Some(vec![shared_bind.clone()]),
+                            mounts: Some(vec![Mount {
+                                source: Some(slave_dir.path().to_str().unwrap().to_string()),
+                                target: Some("/slave".to_string()),
+                                typ: Some(MountTypeEnum::BIND),
+                                consistency: Some("cached".to_string()),
+                                bind_options: Some(MountBindOptions {
+                                    propagation: Some(MountBindOptionsPropagationEnum::RSLAVE),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            }]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    cleanup_containers_and_volumes(&docker, &[container_name.as_str()], &[]).await;
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &Default::default(),
+                    )
+                    .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>((
+                        restored.host_config.unwrap(),
+                        restored.mounts.unwrap(),
+                    ))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                restored
+            })
+            .unwrap();
+        assert_eq!(restored_host_config.binds, Some(vec![shared_bind]));
+        let mount = &restored_host_config.mounts.unwrap()[0];
+        assert_eq!(
+            mount.bind_options.as_ref().unwrap().propagation,
+            Some(MountBindOptionsPropagationEnum::RSLAVE)
+        );
+        assert_eq!(mount.consistency.as_deref(), Some("cached"));
+        let propagation = |destination: &str| {
+            restored_mounts
+                .iter()
+                .find(|m| m.destination.as_deref() == Some(destination))
+                .and_then(|m| m.propagation.clone())
+        };
+        assert_eq!(propagation("/shared").as_deref(), Some("shared"));
+        assert_eq!(propagation("/slave").as_deref(), Some("rslave"));
+    }
+
+    #[test]
+    fn restore_container_network_aliases_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("restore_test_{}", test_id);
+        let restored_name = format!("restored_{}", container_name);
+        let network_name = format!("restore_test_network_{}", test_id);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let restored_networks = rt
+            .block_on(async {
+                let restored_networks = async {
+                    docker
+                        .create_network(CreateNetworkOptions {
+                            name: network_name.as_str(),
+                            ..Default::default()
+                        })
+                        .await?;
+                    create_test_container(&docker, &container_name, Default::default()).await?;
+                    docker
+                        .connect_network(
+                            &network_name,
+                            ConnectNetworkOptions {
+                                container: container_name.as_str(),
+                                endpoint_config: EndpointSettings {
+                                    aliases: Some(vec!["db".to_string()]),
+                                    ..Default::default()
+                                },
+                            },
+                        )
+                        .await?;
+                    let backup = backup_container(
+                        &docker,
+                        &container_name,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?
+                    .path;
+                    cleanup_containers_and_volumes(&docker, &[container_name.as_str()], &[]).await;
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &Default::default(),
+                    )
+                    .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>(restored.network_settings.unwrap().networks.unwrap())
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                let _ = docker.remove_network(&network_name).await;
+                restored_networks
+            })
+            .unwrap();
+        let aliases = restored_networks[&network_name].aliases.clone().unwrap();
+        assert!(aliases.contains(&"db".to_string()));
+        assert!(restored_networks.contains_key("bridge"));
+    }
+
+    #[test]
+    fn swapped_host_config_test() {
+        let host_config = HostConfig {
+            binds: Some(vec![
+                "data:/data:ro".to_string(),
+                "/srv/config:/config".to_string(),
+            ]),
+            mounts: Some(vec![Mount {
+                source: Some("cache".to_string()),
+                target: Some("/cache".to_string()),
+                typ: Some(MountTypeEnum::VOLUME),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        let mounts = vec![MountPoint {
+            typ: Some("volume".to_string()),
+            name: Some("0123abcd".to_string()),
+            destination: Some("/var/lib/app".to_string()),
+            rw: Some(true),
+            ..Default::default()
+        }];
+
+        let swapped = swapped_host_config(host_config.clone(), &mounts, "/data", "data-swap");
+        assert_eq!(
+            swapped.binds.unwrap(),
+            vec!["data-swap:/data:ro", "/srv/config:/config"]
+        );
+        let swapped_mounts = swapped.mounts.unwrap();
+        assert_eq!(swapped_mounts[0].source.as_deref(), Some("cache"));
+        // The anonymous volume is kept rather than replaced with a new one
+        assert_eq!(swapped_mounts[1].source.as_deref(), Some("0123abcd"));
+        assert_eq!(swapped_mounts[1].read_only, Some(false));
+
+        let swapped = swapped_host_config(host_config, &mounts, "/cache", "cache-swap");
+        assert_eq!(
+            swapped.mounts.unwrap()[0].source.as_deref(),
+            Some("cache-swap")
+        );
+    }
+
+    #[test]
+    fn swap_volume_into_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let test_id = Uuid::new_v4().to_string();
+        let container_name = format!("swap_test_{}", test_id);
+        let volume = format!("swap_test_{}", test_id);
+        let network_name = format!("swap_test_network_{}", test_id);
+        let input = working_dir.path().join("input");
+        create_dir(&input).unwrap();
+        write(input.join("data"), "restored").unwrap();
+        let root = working_dir.path().join("backup");
+        let archive = backup_directory(
+            input.to_str().unwrap(),
+            root.join("dockyard/volumes/data").to_str().unwrap(),
+            &Default::default(),
+        )
+        .unwrap();
+        let archive = Path::new("dockyard/volumes/data")
+            .join(archive)
+            .to_str()
+            .unwrap()
+            .to_string();
+        let backup_mount = get_backup_directory_mount(root.to_str().unwrap().to_string());
+        let copied = working_dir.path().join("copied");
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (mounted, old_volume_kept, labels, aliases) = rt.block_on(async {
+            check_image(&docker, "alpine:latest").await.unwrap();
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: volume.clone(),
+                    driver: "local".to_string(),
+                    driver_opts: Default::default(),
+                    labels: vec![("app".to_string(), "web".to_string())]
+                        .into_iter()
+                        .collect(),
+                })
+                .await
+                .unwrap();
+            docker
+                .create_network(CreateNetworkOptions {
+                    name: network_name.as_str(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: container_name.as_str(),
+                    }),
+                    Config {
+                        image: Some("alpine:latest"),
+                        host_config: Some(HostConfig {
+                            binds: Some(vec![format!("{}:/data", volume)]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            docker
+                .connect_network(
+                    &network_name,
+                    ConnectNetworkOptions {
+                        container: container_name.as_str(),
+                        endpoint_config: EndpointSettings {
+                            aliases: Some(vec!["cache".to_string()]),
+                            ..Default::default()
+                        },
+                    },
+                )
+                .await
+                .unwrap();
+            let new_volume = swap_volume_into(
+                &docker,
+                &container_name,
+                "/data",
+                archive,
+                backup_mount,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            let swapped = docker
+                .inspect_container(&container_name, None::<InspectContainerOptions>)
+                .await
+                .unwrap();
+            let mounted = swapped
+                .mounts
+                .unwrap()
+                .into_iter()
+                .find(|m| m.destination.as_deref() == Some("/data"))
+                .and_then(|m| m.name);
+            let aliases = swapped.network_settings.unwrap().networks.unwrap()[&network_name]
+                .aliases
+                .clone()
+                .unwrap_or_default();
+            let labels = docker.inspect_volume(&new_volume).await.unwrap().labels;
+            copy_from_volume(&docker, &new_volume, copied.to_str().unwrap())
+                .await
+                .unwrap();
+            let old_volume_kept = docker.inspect_volume(&volume).await.is_ok();
+            docker
+                .remove_container(&container_name, None::<RemoveContainerOptions>)
+                .await
+                .unwrap();
+            for name in &[&volume, &new_volume] {
+                docker
+                    .remove_volume(name, None::<RemoveVolumeOptions>)
+                    .await
+                    .unwrap();
+            }
+            docker.remove_network(&network_name).await.unwrap();
+            assert_eq!(mounted.as_ref(), Some(&new_volume));
+            (mounted, old_volume_kept, labels, aliases)
+        });
+        assert!(mounted.unwrap().starts_with(&format!("{}-swap-", volume)));
+        assert!(old_volume_kept);
+        assert_eq!(labels.get("app").map(String::as_str), Some("web"));
+        assert!(aliases.contains(&"cache".to_string()));
+        assert_eq!(read_to_string(copied.join("data")).unwrap(), "restored");
+    }
+
+    #[test]
+    fn check_backup_mount_test() {
+        let working_dir = TempDir::new().unwrap();
+        let missing_volume = format!("check_backup_mount_test_{}", Uuid::new_v4());
+        let missing_directory = working_dir.path().join("missing");
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (existing, volume_error, directory_error) = rt.block_on(async {
+            let existing = check_backup_mount(
+                &docker,
+                &get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string()),
+            )
+            .await;
+            let volume_error =
+                check_backup_mount(&docker, &get_backup_volume_mount(missing_volume.clone()))
+                    .await
+                    .unwrap_err();
+            let directory_error = check_backup_mount(
+                &docker,
+                &get_backup_directory_mount(missing_directory.to_str().unwrap().to_string()),
+            )
+            .await
+            .unwrap_err();
+            (existing, volume_error, directory_error)
+        });
+        existing.unwrap();
+        assert!(volume_error.to_string().starts_with(&format!(
+            "Backup volume {} not found on Docker host: ",
+            missing_volume
+        )));
+        assert!(directory_error.to_string().starts_with(&format!(
+            "Backup directory {} not found on Docker host: ",
+            missing_directory.display()
+        )));
+    }
+
+    #[test]
+    fn restored_config_entrypoint_test() {
+        let container_backup = || ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "app".to_string(),
+            container_config: ContainerConfig {
+                entrypoint: Some(vec!["/bin/echo".to_string()]),
+                cmd: Some(vec!["hello".to_string()]),
+                ..Default::default()
+            },
+            host_config: Default::default(),
+            mounts: vec![],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        let config = restored_config(&mut container_backup(), &RestoreOptions::default());
+        assert_eq!(config.cmd, Some(vec!["hello".to_string()]));
+
+        let options = RestoreOptions {
+            entrypoint: Some(vec!["/bin/sh".to_string()]),
+            ..Default::default()
+        };
+        let config = restored_config(&mut container_backup(), &options);
+        assert_eq!(config.entrypoint, Some(vec!["/bin/sh".to_string()]));
+        assert_eq!(config.cmd, None);
+
+        let options = RestoreOptions {
+            cmd: Some(vec!["world".to_string()]),
+            ..Default::default()
+        };
+        let config = restored_config(&mut container_backup(), &options);
+        assert_eq!(config.entrypoint, Some(vec!["/bin/echo".to_string()]));
+        assert_eq!(config.cmd, Some(vec!["world".to_string()]));
+    }
+
+    #[test]
+    fn restored_config_rehearsal_test() {
+        let mut port_bindings = HashMap::new();
+        port_bindings.insert(
+            "80/tcp".to_string(),
+            Some(vec![PortBinding {
+                host_ip: None,
+                host_port: Some("8080".to_string()),
+            }]),
+        );
+        let container_backup = || ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "app".to_string(),
+            container_config: Default::default(),
+            host_config: HostConfig {
+                port_bindings: Some(port_bindings.clone()),
+                publish_all_ports: Some(true),
+                ..Default::default()
+            },
+            mounts: vec![],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        let config = restored_config(&mut container_backup(), &RestoreOptions::default());
+        let host_config = config.host_config.unwrap();
+        assert_eq!(host_config.port_bindings, Some(port_bindings.clone()));
+        assert_eq!(host_config.publish_all_ports, Some(true));
+
+        let options = rehearsal_restore_options(
+            "app-rehearsal",
+            &RestoreOptions {
+                rehearse: true,
+                ..Default::default()
+            },
+        );
+        let config = restored_config(&mut container_backup(), &options);
+        let host_config = config.host_config.unwrap();
+        assert_eq!(host_config.port_bindings, None);
+        assert_eq!(host_config.publish_all_ports, None);
+    }
+
+    #[test]
+    fn attach_anonymous_volumes_test() {
+        let volume_backup = |name: &str, destination: &str| MountBackup {
+            path: PathBuf::from(format!("dockyard/volumes/{}/backup.tgz", name)),
+            mount: MountPoint {
+                typ: Some("volume".to_string()),
+                name: Some(name.to_string()),
+                destination: Some(destination.to_string()),
+                rw: Some(false),
+                ..Default::default()
+            },
+            checksum: None,
+            volume_labels: Default::default(),
+            resolved_source: None,
+        };
+        let mut container_backup = ContainerBackup {
+            schema_version: CONTAINER_BACKUP_SCHEMA_VERSION,
+            name: "app".to_string(),
+            container_config: Default::default(),
+            host_config: HostConfig {
+                binds: Some(vec!["data:/data".to_string()]),
+                mounts: Some(vec![Mount {
+                    target: Some("/cache".to_string()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+            mounts: vec![
+                volume_backup("data", "/data"),
+                volume_backup(&"0123abcd".repeat(8), "/cache"),
+                volume_backup(&"4567ef01".repeat(8), "/var/lib/app"),
+                // Attached with --volumes-from, so it isn't in the host config
+                volume_backup("shared", "/shared"),
+            ],
+            image_archive: None,
+            image_id: None,
+            redacted_env: vec![],
+            layout: Default::default(),
+            hash_algorithm: Default::default(),
+            swarm: None,
+            networks: Default::default(),
+        };
+        attach_anonymous_volumes(&mut container_backup);
+
+        let mounts = container_backup.host_config.mounts.unwrap();
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].source, Some("0123abcd".repeat(8)));
+        assert_eq!(mounts[1].source, Some("4567ef01".repeat(8)));
+        assert_eq!(mounts[1].target, Some("/var/lib/app".to_string()));
+        assert_eq!(mounts[1].read_only, Some(true));
+        let labels = container_backup
+            .mounts
+            .iter()
+            .map(|m| m.volume_labels.contains_key(ANONYMOUS_VOLUME_LABEL))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn restore_container_anonymous_volume_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (volume, restored_mounts, labels) = rt
+            .block_on(async {
+                let restored = async {
+                    let volumes = vec![("/data", HashMap::new())].into_iter().collect();
+                    let config = Config {
+                        volumes: Some(volumes),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    let volume = docker
+                        .inspect_container(&container_name, None::<InspectContainerOptions>)
+                        .await?
+                        .mounts
+                        .unwrap()[0]
+                        .name
+                        .clone()
+                        .unwrap();
+                    // Removing the container removes its anonymous volume
+                    cleanup_containers_and_volumes(&docker, &[container_name.as_str()], &[]).await;
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(container_name.as_str()),
+                        backup_mount,
+                        &Default::default(),
+                    )
+                    .await?;
+                    let restored_mounts = docker
+                        .inspect_container(&container_name, None::<InspectContainerOptions>)
+                        .await?
+                        .mounts
+                        .unwrap();
+                    let labels = docker.inspect_volume(&volume).await?.labels;
+                    Ok::<_, anyhow::Error>((volume, restored_mounts, labels))
+                }
+                .await;
+                cleanup_containers_and_volumes(&docker, &[container_name.as_str()], &[]).await;
+                restored
+            })
+            .unwrap();
+        assert_eq!(restored_mounts.len(), 1);
+        assert_eq!(restored_mounts[0].name, Some(volume));
+        assert!(labels.contains_key(ANONYMOUS_VOLUME_LABEL));
+    }
+
+    #[test]
+    fn read_env_file_test() {
+        let working_dir = TempDir::new().unwrap();
+        let path = working_dir.path().join(".env");
+        write(
+            &path,
+            "# secrets\nDB_PASSWORD=hunter2\n\nexport API_KEY=\"a b=c\"\nEMPTY=\nQUOTED='x'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_env_file(&path).unwrap(),
+            vec![
+                ("DB_PASSWORD".to_string(), "hunter2".to_string()),
+                ("API_KEY".to_string(), "a b=c".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+                ("QUOTED".to_string(), "x".to_string()),
+            ]
+        );
+
+        write(&path, "DB_PASSWORD\n").unwrap();
+        assert_eq!(
+            read_env_file(&path).unwrap_err().to_string(),
+            format!("Invalid line 1 in {}, expected KEY=VALUE", path.display())
+        );
+    }
+
+    #[test]
+    fn restore_container_env_from_file_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let restored_name = format!("restored_{}", container_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let env_file = working_dir.path().join(".env");
+        write(&env_file, "DB_PASSWORD=hunter2\n").unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (backup, restored_env) = rt
+            .block_on(async {
+                let restored = async {
+                    let config = Config {
+                        env: Some(vec!["DB_PASSWORD=hunter2", "DB_USER=app"]),
+                        ..Default::default()
+                    };
+                    let backup_options = BackupOptions {
+                        redact_env: vec!["DB_PASSWORD".to_string()],
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &backup_options,
+                    )
+                    .await?;
+                    let options = RestoreOptions {
+                        env: read_env_file(&env_file)?,
+                        ..Default::default()
+                    };
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &options,
+                    )
+                    .await?;
+                    let restored_env = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?
+                        .config
+                        .unwrap()
+                        .env
+                        .unwrap();
+                    Ok::<_, anyhow::Error>((backup, restored_env))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                restored
+            })
+            .unwrap();
+        let manifest = read_to_string(working_dir.path().join(backup)).unwrap();
+        assert!(!manifest.contains("hunter2"));
+        assert!(restored_env.contains(&"DB_PASSWORD=hunter2".to_string()));
+        assert!(restored_env.contains(&"DB_USER=app".to_string()));
+    }
+
+    #[test]
+    fn restore_container_entrypoint_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let restored_name = format!("restored_{}", container_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (original, restored) = rt
+            .block_on(async {
+                let configs = async {
+                    let config = Config {
+                        entrypoint: Some(vec!["/bin/echo"]),
+                        cmd: Some(vec!["hello"]),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    let options = RestoreOptions {
+                        entrypoint: Some(vec!["/bin/sh".to_string()]),
+                        cmd: Some(vec!["-c".to_string(), "ls /data".to_string()]),
+                        ..Default::default()
+                    };
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &options,
+                    )
+                    .await?;
+                    let original = docker
+                        .inspect_container(&container_name, None::<InspectContainerOptions>)
+                        .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>((original.config.unwrap(), restored.config.unwrap()))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                configs
+            })
+            .unwrap();
+        assert_eq!(original.entrypoint, Some(vec!["/bin/echo".to_string()]));
+        assert_eq!(restored.entrypoint, Some(vec!["/bin/sh".to_string()]));
+        assert_eq!(
+            restored.cmd,
+            Some(vec!["-c".to_string(), "ls /data".to_string()])
+        );
+    }
+
+    #[test]
+    fn parse_resource_limits_test() {
+        assert_eq!(parse_cpus("1.5").unwrap(), 1_500_000_000);
+        assert_eq!(parse_cpus("0.25").unwrap(), 250_000_000);
+        assert!(parse_cpus("0").is_err());
+        assert!(parse_cpus("-1").is_err());
+        assert!(parse_cpus("many").is_err());
+        assert_eq!(parse_memory_swap("-1").unwrap(), -1);
+        assert_eq!(parse_memory_swap("1G").unwrap(), 1 << 30);
+        assert!(parse_memory_swap("-2").is_err());
+    }
+
+    #[test]
+    fn override_resource_limits_test() {
+        let backed_up = HostConfig {
+            memory: Some(1 << 30),
+            memory_reservation: Some(512 << 20),
+            memory_swap: Some(2 << 30),
+            cpu_period: Some(100_000),
+            cpu_quota: Some(200_000),
+            ..Default::default()
+        };
+
+        let mut host_config = backed_up.clone();
+        override_resource_limits(&mut host_config, &RestoreOptions::default());
+        assert_eq!(host_config, backed_up);
+
+        let mut host_config = backed_up.clone();
+        override_resource_limits(
+            &mut host_config,
+            &RestoreOptions {
+                memory: Some(256 << 20),
+                nano_cpus: Some(500_000_000),
+                ..Default::default()
+            },
+        );
+        assert_eq!(host_config.memory, Some(256 << 20));
+        assert_eq!(host_config.memory_reservation, None);
+        assert_eq!(host_config.memory_swap, Some(2 << 30));
+        assert_eq!(host_config.nano_cp_us, Some(500_000_000));
+        assert_eq!(host_config.cpu_period, None);
+        assert_eq!(host_config.cpu_quota, None);
+
+        let mut host_config = backed_up;
+        override_resource_limits(
+            &mut host_config,
+            &RestoreOptions {
+                memory: Some(4 << 30),
+                ..Default::default()
+            },
+        );
+        assert_eq!(host_config.memory_reservation, Some(512 << 20));
+        assert_eq!(host_config.memory_swap, None);
+    }
+
+    #[test]
+    fn restore_container_resource_limits_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let restored_name = format!("restored_{}", container_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (original, restored) = rt
+            .block_on(async {
+                let host_configs = async {
+                    let config = Config {
+                        host_config: Some(HostConfig {
+                            memory: Some(256 << 20),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    let options = RestoreOptions {
+                        memory: Some(128 << 20),
+                        memory_swap: Some(-1),
+                        nano_cpus: Some(500_000_000),
+                        ..Default::default()
+                    };
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &options,
+                    )
+                    .await?;
+                    let original = docker
+                        .inspect_container(&container_name, None::<InspectContainerOptions>)
+                        .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>((
+                        original.host_config.unwrap(),
+                        restored.host_config.unwrap(),
+                    ))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                host_configs
+            })
+            .unwrap();
+        assert_eq!(original.memory, Some(256 << 20));
+        assert_eq!(restored.memory, Some(128 << 20));
+        assert_eq!(restored.memory_swap, Some(-1));
+        assert_eq!(restored.nano_cp_us, Some(500_000_000));
+    }
+
+    #[test]
+    fn image_mismatch_test() {
+        assert_eq!(
+            image_mismatch("web", Some("sha256:abc"), "sha256:abc"),
+            None
+        );
+        assert_eq!(
+            image_mismatch("web", Some("sha256:def"), "sha256:abc").unwrap(),
+            "Restored container web would use image sha256:def but the backup was taken from image sha256:abc"
+        );
+    }
+
+    #[test]
+    fn restore_container_strict_image_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let restored_name = format!("restored_{}", container_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (backed_up_image_id, result, restored) = rt
+            .block_on(async {
+                let outcome = async {
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        Default::default(),
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    // Simulate the tag having pointed at another image when the backup was taken
+                    let backup_path = working_dir.path().join(&backup);
+                    let mut container_backup: ContainerBackup =
+                        serde_json::from_str(&read_to_string(&backup_path)?)?;
+                    let backed_up_image_id = container_backup.image_id.take();
+                    container_backup.image_id = Some(format!("sha256:{}", "0".repeat(64)));
+                    write(&backup_path, serde_json::to_string(&container_backup)?)?;
+
+                    let options = RestoreOptions {
+                        strict_image: true,
+                        ..Default::default()
+                    };
+                    let result = restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &options,
+                    )
+                    .await;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await;
+                    Ok::<_, anyhow::Error>((backed_up_image_id, result, restored))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                outcome
+            })
+            .unwrap();
+        assert!(backed_up_image_id.is_some());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("but the backup was taken from image sha256:000"));
+        assert!(restored.is_err());
+    }
+
+    #[test]
+    fn restore_interactive_container_test() {
+        let _ = SimpleLogger::new().with_level(LevelFilter::Info).init();
+        let working_dir = TempDir::new().unwrap();
+        let container_name = format!("restore_test_{}", Uuid::new_v4());
+        let restored_name = format!("restored_{}", container_name);
+        let backup_mount =
+            get_backup_directory_mount(working_dir.path().to_str().unwrap().to_string());
+        let mut rt = Runtime::new().unwrap();
+        let docker = Docker::connect_with_unix_defaults().unwrap();
+
+        let (original, restored) = rt
+            .block_on(async {
+                let configs = async {
+                    // Equivalent of docker create -it --entrypoint /bin/sh alpine -l
+                    let config = Config {
+                        entrypoint: Some(vec!["/bin/sh"]),
+                        cmd: Some(vec!["-l"]),
+                        attach_stdin: Some(true),
+                        attach_stdout: Some(true),
+                        attach_stderr: Some(true),
+                        tty: Some(true),
+                        open_stdin: Some(true),
+                        stdin_once: Some(true),
+                        ..Default::default()
+                    };
+                    let backup = create_and_back_up_container(
+                        &docker,
+                        &container_name,
+                        config,
+                        backup_mount.clone(),
+                        &Default::default(),
+                    )
+                    .await?;
+                    restore_container(
+                        &docker,
+                        backup.to_str().unwrap(),
+                        Some(restored_name.as_str()),
+                        backup_mount,
+                        &RestoreOptions::default(),
+                    )
+                    .await?;
+                    let original = docker
+                        .inspect_container(&container_name, None::<InspectContainerOptions>)
+                        .await?;
+                    let restored = docker
+                        .inspect_container(&restored_name, None::<InspectContainerOptions>)
+                        .await?;
+                    Ok::<_, anyhow::Error>((original.config.unwrap(), restored.config.unwrap()))
+                }
+                .await;
+                cleanup_containers_and_volumes(
+                    &docker,
+                    &[container_name.as_str(), restored_name.as_str()],
+                    &[],
+                )
+                .await;
+                configs
+            })
+            .unwrap();
+
+        assert_eq!(restored.attach_stdin, Some(true));
+        assert_eq!(restored.attach_stdin, original.attach_stdin);
+        assert_eq!(restored.attach_stdout, original.attach_stdout);
+        assert_eq!(restored.attach_stderr, original.attach_stderr);
+        assert_eq!(restored.tty, Some(true));
+        assert_eq!(restored.tty, original.tty);
+        assert_eq!(restored.open_stdin, Some(true));
+        assert_eq!(restored.open_stdin, original.open_stdin);
+        assert_eq!(restored.stdin_once, Some(true));
+        assert_eq!(restored.stdin_once, original.stdin_once);
+        assert_eq!(restored.entrypoint, original.entrypoint);
+        assert_eq!(restored.cmd, original.cmd);
+    }
+
+    /// Create a stopped alpine container named `name` from `config`
+    async fn create_test_container(
+        docker: &Docker,
+        name: &str,
+        config: Config<&str>,
+    ) -> Result<()> {
+        let image = "alpine:latest";
+        check_image(docker, image).await?;
+        docker
+            .create_container(
+                Some(CreateContainerOptions { name }),
+                Config {
+                    image: Some(image),
+                    ..config
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Create a stopped alpine container named `name` from `config` and back it up, returning
+    /// the path of its container backup file
+    async fn create_and_back_up_container(
+        docker: &Docker,
+        name: &str,
+        config: Config<&str>,
+        backup_mount: Mount,
+        options: &BackupOptions,
+    ) -> Result<PathBuf> {
+        create_test_container(docker, name, config).await?;
+        Ok(backup_container(docker, name, backup_mount, options)
+            .await?
+            .path)
+    }
+
+    /// Remove containers, with their anonymous volumes, then volumes, ignoring any that don't
+    /// exist so a test can clean up whether or not it got as far as creating them
+    async fn cleanup_containers_and_volumes(
+        docker: &Docker,
+        containers: &[&str],
+        volumes: &[&str],
+    ) {
+        for name in containers {
+            let _ = docker
+                .remove_container(
+                    name,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        v: true,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+        }
+        for name in volumes {
+            let _ = docker
+                .remove_volume(name, Some(RemoveVolumeOptions { force: true }))
+                .await;
+        }
+    }
+
+    fn create_archive(working_dir: &TempDir) -> PathBuf {
+        let input = Path::join(working_dir.path(), "input");
+        create_dir(input.as_path()).unwrap();
+
+        for i in 0..100 {
+            let mut f = File::create(Path::join(&input, i.to_string())).unwrap();
             f.write_all(format!("Restore test data {}", i).as_bytes())
                 .unwrap();
         }
@@ -372,6 +3993,22 @@ mod test {
         archive_path
     }
 
+    /// Create archive with a single member, bypassing the path checks in `tar::Builder`
+    fn create_raw_archive(working_dir: &TempDir, name: &[u8]) -> PathBuf {
+        let archive_path = working_dir.path().join("raw.tgz");
+        let contents = b"contents";
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let enc = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut tar = tar::Builder::new(enc);
+        tar.append(&header, &contents[..]).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        archive_path
+    }
+
     pub(crate) async fn copy_from_volume(
         docker: &Docker,
         volume: &str,
@@ -0,0 +1,283 @@
+use crate::backup::{host_destination, store_backup_file, BackupOptions, Layout, MountBackup};
+use crate::container::{
+    handle_container_output, last_stdout_line, read_only_mount, run_dockyard_command,
+};
+use crate::file::{decode_b64, path_str};
+use crate::hash::HashAlgorithm;
+use crate::list::{backup_file_exists, checksum_archives, ArchiveChecksums};
+use anyhow::{Context, Result};
+use bollard::models::{Mount, MountPoint};
+use bollard::Docker;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Name of the file recording the progress of the latest backup of a container
+pub const PARTIAL_BACKUP_FILE: &str = "backup.partial";
+
+/// Progress of a container backup, recorded after each mount so an interrupted backup can be
+/// resumed with `BackupOptions::resume`
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct PartialBackup {
+    /// Timestamp the backup is named with, which a resumed backup keeps
+    pub(crate) timestamp: String,
+    pub(crate) layout: Layout,
+    /// Algorithm of the checksums of `completed`
+    pub(crate) hash_algorithm: HashAlgorithm,
+    /// Destinations of the mounts the backup was going to archive
+    pub(crate) mounts: Vec<String>,
+    /// Mounts archived so far
+    pub(crate) completed: Vec<MountBackup>,
+    /// Whether the container backup file was written, leaving nothing to resume
+    #[serde(default)]
+    pub(crate) finished: bool,
+}
+
+impl PartialBackup {
+    /// Return the archived backup of `mount`, if it was completed
+    pub(crate) fn completed_mount(&self, mount: &MountPoint) -> Option<&MountBackup> {
+        self.completed
+            .iter()
+            .find(|backup| same_mount(&backup.mount, mount))
+    }
+}
+
+/// Return true if both mount points mount the same volume or directory at the same destination
+fn same_mount(a: &MountPoint, b: &MountPoint) -> bool {
+    a.typ == b.typ && a.name == b.name && a.source == b.source && a.destination == b.destination
+}
+
+/// Return path of the progress file of a container's backups, relative to the backup location
+///
+/// # Arguments
+///
+/// * `container_name` - Name of backed up container
+/// * `layout` - Layout of the backup
+///
+pub(crate) fn partial_backup_path(container_name: &str, layout: Layout) -> PathBuf {
+    match layout {
+        Layout::Nested => Path::new("dockyard/containers").join(container_name),
+        Layout::Flat => PathBuf::from(container_name),
+    }
+    .join(PARTIAL_BACKUP_FILE)
+}
+
+/// Write the progress of a container backup to the backup location, replacing earlier progress
+///
+/// Nothing is recorded for append-only backups, which can't replace files.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `partial` - Progress of the backup
+/// * `path` - Path of progress file relative to the backup location
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Backup options
+///
+pub(crate) async fn record_partial_backup(
+    docker: &Docker,
+    partial: &PartialBackup,
+    path: &Path,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<()> {
+    if options.append_only {
+        return Ok(());
+    }
+    let contents = serde_json::to_vec(partial)?;
+    let overwrite = BackupOptions {
+        destination_on_host: options.destination_on_host,
+        modes: options.modes,
+        ..Default::default()
+    };
+    store_backup_file(docker, backup_mount.clone(), path, &contents, &overwrite)
+        .await
+        .with_context(|| format!("Unable to record backup progress to {}", path.display()))
+}
+
+/// Read the progress of an interrupted container backup, `None` if there is nothing to resume
+///
+/// Progress recorded with another layout or hash algorithm than `options` is ignored, as its
+/// archives can't be reused.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `path` - Path of progress file relative to the backup location
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Backup options
+///
+pub(crate) async fn read_partial_backup(
+    docker: &Docker,
+    path: &Path,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<Option<PartialBackup>> {
+    let contents = if options.destination_on_host {
+        match read_to_string(host_destination(backup_mount)?.join(path)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        if !backup_file_exists(docker, path, backup_mount.clone()).await? {
+            return Ok(None);
+        }
+        let mounted_path = format!(
+            "{}/{}",
+            backup_mount.target.as_ref().unwrap(),
+            path_str(path)?
+        );
+        let (exit_code, logs) = run_dockyard_command(
+            docker,
+            Some(vec![read_only_mount(backup_mount.clone())]),
+            vec!["cat", "--encoded", "-f", &mounted_path],
+        )
+        .await?;
+        handle_container_output(exit_code, "read backup progress", &logs)?;
+        decode_b64(&last_stdout_line(&logs).unwrap_or_default())?
+    };
+    let partial = serde_json::from_str::<PartialBackup>(&contents)
+        .with_context(|| format!("Unable to parse backup progress {}", path.display()))?;
+    if partial.finished {
+        return Ok(None);
+    }
+    if partial.layout != options.layout || partial.hash_algorithm != options.hash_algorithm {
+        log::warn!(
+            "Backup {} was taken with another layout or hash algorithm, not resuming it",
+            partial.timestamp
+        );
+        return Ok(None);
+    }
+    Ok(Some(partial))
+}
+
+/// Drop the completed mounts of an interrupted backup whose archives are missing or don't
+/// match their checksum, so they are backed up again
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `partial` - Progress of the interrupted backup
+/// * `backup_mount` - Mount representing backup location
+/// * `options` - Backup options
+///
+pub(crate) async fn verify_partial_backup(
+    docker: &Docker,
+    partial: &mut PartialBackup,
+    backup_mount: &Mount,
+    options: &BackupOptions,
+) -> Result<()> {
+    if partial.completed.is_empty() {
+        return Ok(());
+    }
+    let root = if options.destination_on_host {
+        host_destination(backup_mount)?
+    } else {
+        PathBuf::from(backup_mount.target.as_ref().unwrap())
+    };
+    let paths = partial
+        .completed
+        .iter()
+        .map(|backup| root.join(&backup.path).to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let checksums = if options.destination_on_host {
+        let paths = paths.iter().map(String::as_str).collect::<Vec<_>>();
+        checksum_archives(&paths, partial.hash_algorithm)?
+    } else {
+        let mut cmd = vec!["checksum", "--hash-algo", partial.hash_algorithm.name()];
+        cmd.extend(paths.iter().map(String::as_str));
+        let (exit_code, logs) = run_dockyard_command(
+            docker,
+            Some(vec![read_only_mount(backup_mount.clone())]),
+            cmd,
+        )
+        .await?;
+        handle_container_output(exit_code, "checksum archives", &logs)?;
+        let output = last_stdout_line(&logs).unwrap_or_else(|| "{}".to_string());
+        serde_json::from_str::<ArchiveChecksums>(&output)
+            .context("Unable to parse archive checksums")?
+    };
+    let completed = std::mem::take(&mut partial.completed);
+    for (backup, path) in completed.into_iter().zip(paths) {
+        if is_resumable(backup.checksum.as_deref(), checksums.checksums.get(&path)) {
+            partial.completed.push(backup);
+        } else {
+            log::warn!(
+                "Archive {} of interrupted backup is missing or corrupt, backing it up again",
+                backup.path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Return true if an archive exists and matches its recorded checksum, if it has one
+///
+/// # Arguments
+///
+/// * `expected` - Checksum recorded when the archive was written
+/// * `actual` - Computed checksum, `Some(None)` if the archive doesn't exist
+///
+fn is_resumable(expected: Option<&str>, actual: Option<&Option<String>>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(Some(actual))) => expected == actual,
+        (None, Some(Some(_))) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_backup_path_test() {
+        assert_eq!(
+            partial_backup_path("web", Layout::Nested),
+            PathBuf::from("dockyard/containers/web/backup.partial")
+        );
+        assert_eq!(
+            partial_backup_path("web", Layout::Flat),
+            PathBuf::from("web/backup.partial")
+        );
+    }
+
+    #[test]
+    fn is_resumable_test() {
+        let checksum = Some("4f66ad9a".to_string());
+        assert!(is_resumable(Some("4f66ad9a"), Some(&checksum)));
+        assert!(!is_resumable(Some("0b2e5a1f"), Some(&checksum)));
+        assert!(!is_resumable(Some("4f66ad9a"), Some(&None)));
+        assert!(!is_resumable(Some("4f66ad9a"), None));
+        // Pointers to earlier archives have no checksum, they only have to exist
+        assert!(is_resumable(None, Some(&checksum)));
+        assert!(!is_resumable(None, Some(&None)));
+    }
+
+    #[test]
+    fn completed_mount_test() {
+        let mount = |destination: &str| MountPoint {
+            typ: Some("volume".to_string()),
+            name: Some("data".to_string()),
+            destination: Some(destination.to_string()),
+            ..Default::default()
+        };
+        let partial = PartialBackup {
+            timestamp: "2020-10-22T23:09:10+00:00".to_string(),
+            layout: Layout::Nested,
+            hash_algorithm: HashAlgorithm::default(),
+            mounts: vec!["/data".to_string(), "/backup".to_string()],
+            completed: vec![MountBackup {
+                path: PathBuf::from("dockyard/volumes/data/2020-10-22T23:09:11+00:00.tgz"),
+                mount: mount("/data"),
+                checksum: None,
+                volume_labels: Default::default(),
+                resolved_source: None,
+            }],
+            finished: false,
+        };
+        assert!(partial.completed_mount(&mount("/data")).is_some());
+        assert!(partial.completed_mount(&mount("/backup")).is_none());
+    }
+}
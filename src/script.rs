@@ -0,0 +1,641 @@
+use bollard::container::Config;
+use bollard::models::{HealthConfig, HostConfig, Mount};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Keys of `Config` rendered as `docker create` flags by `create_command`, or that only matter
+/// when attaching to the container
+const CONFIG_KEYS: &[&str] = &[
+    "AttachStdin",
+    "AttachStdout",
+    "AttachStderr",
+    "StdinOnce",
+    "ArgsEscaped",
+    "Hostname",
+    "Domainname",
+    "User",
+    "ExposedPorts",
+    "Tty",
+    "OpenStdin",
+    "Env",
+    "Cmd",
+    "Healthcheck",
+    "Image",
+    "Volumes",
+    "WorkingDir",
+    "Entrypoint",
+    "NetworkDisabled",
+    "MacAddress",
+    "Labels",
+    "StopSignal",
+    "StopTimeout",
+    "HostConfig",
+];
+
+/// Keys of `HostConfig` rendered as `docker create` flags by `create_command`
+const HOST_CONFIG_KEYS: &[&str] = &[
+    "Binds",
+    "Mounts",
+    "PortBindings",
+    "PublishAllPorts",
+    "RestartPolicy",
+    "NetworkMode",
+    "Privileged",
+    "CapAdd",
+    "CapDrop",
+    "Dns",
+    "DnsOptions",
+    "DnsSearch",
+    "ExtraHosts",
+    "Memory",
+    "MemorySwap",
+    "MemoryReservation",
+    "NanoCpus",
+    "CpuShares",
+    "CpusetCpus",
+    "PidsLimit",
+    "Devices",
+    "Tmpfs",
+    "VolumesFrom",
+    "Links",
+    "LogConfig",
+    "SecurityOpt",
+    "Sysctls",
+    "Ulimits",
+    "AutoRemove",
+    "ReadonlyRootfs",
+    "Init",
+    "ShmSize",
+    "PidMode",
+    "IpcMode",
+    "UsernsMode",
+    "UTSMode",
+    "GroupAdd",
+    "CgroupParent",
+    "OomKillDisable",
+    "Runtime",
+    "BlkioWeight",
+];
+
+/// Shell script of docker commands, rendered by `render`
+pub struct Script {
+    lines: Vec<String>,
+}
+
+impl Script {
+    /// Create script that stops at the first failing command
+    ///
+    /// # Arguments
+    ///
+    /// * `description` - Comment describing what the script does
+    ///
+    pub fn new(description: &str) -> Self {
+        Script {
+            lines: vec![
+                "#!/bin/sh".to_string(),
+                "set -eu".to_string(),
+                format!("# {}", description),
+            ],
+        }
+    }
+
+    /// Add a comment, preceded by a blank line
+    pub fn comment(&mut self, comment: &str) {
+        self.lines.push(String::new());
+        self.lines.push(format!("# {}", comment));
+    }
+
+    /// Add a command, quoting each argument
+    pub fn command<S: AsRef<str>>(&mut self, args: &[S]) {
+        self.lines.push(command_line(args));
+    }
+
+    /// Add a line as it is, for commands combined with shell operators
+    pub fn line(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    /// Add a command split into groups of arguments, each group on its own line
+    pub fn wrapped_command(&mut self, groups: &[Vec<String>]) {
+        let lines = groups
+            .iter()
+            .map(|group| command_line(group))
+            .collect::<Vec<_>>();
+        self.lines.push(lines.join(" \\\n    "));
+    }
+
+    /// Return the script
+    pub fn render(&self) -> String {
+        format!("{}\n", self.lines.join("\n"))
+    }
+}
+
+/// Quote `arg` for a POSIX shell, leaving it as it is if nothing in it needs quoting
+pub fn quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Return `args` quoted and joined into a command line
+pub fn command_line<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|arg| quote(arg.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Return the value of `docker --mount` for `mount`
+pub fn mount_arg(mount: &Mount) -> String {
+    let mut fields = vec![];
+    if let Some(typ) = &mount.typ {
+        fields.push(mount_field("type", &typ.to_string()));
+    }
+    if let Some(source) = &mount.source {
+        fields.push(mount_field("source", source));
+    }
+    if let Some(target) = &mount.target {
+        fields.push(mount_field("target", target));
+    }
+    if mount.read_only == Some(true) {
+        fields.push("readonly".to_string());
+    }
+    if let Some(consistency) = &mount.consistency {
+        fields.push(mount_field("consistency", consistency));
+    }
+    if let Some(propagation) = mount
+        .bind_options
+        .as_ref()
+        .and_then(|options| options.propagation.as_ref())
+    {
+        fields.push(mount_field("bind-propagation", &propagation.to_string()));
+    }
+    if let Some(options) = &mount.volume_options {
+        if options.no_copy == Some(true) {
+            fields.push("volume-nocopy".to_string());
+        }
+        for (key, value) in sorted(options.labels.as_ref()) {
+            fields.push(mount_field("volume-label", &format!("{}={}", key, value)));
+        }
+    }
+    if let Some(options) = &mount.tmpfs_options {
+        if let Some(size) = options.size_bytes {
+            fields.push(mount_field("tmpfs-size", &size.to_string()));
+        }
+        if let Some(mode) = options.mode {
+            fields.push(mount_field("tmpfs-mode", &format!("{:o}", mode)));
+        }
+    }
+    fields.join(",")
+}
+
+/// Return a `--mount` field, quoted as CSV if the value has a comma or quote in it
+fn mount_field(key: &str, value: &str) -> String {
+    if value.contains(',') || value.contains('"') {
+        format!("\"{}={}\"", key, value.replace('"', "\"\""))
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+/// Return the `docker create` command creating container `name` with `config`, split into
+/// groups of arguments
+///
+/// Settings `docker create` has no flag for are left out, see `untranslated_settings`.
+///
+/// # Arguments
+///
+/// * `name` - Name of container
+/// * `config` - Config to create the container with
+///
+pub fn create_command(name: &str, config: &Config<String>) -> Vec<Vec<String>> {
+    let mut groups = vec![
+        vec!["docker".to_string(), "create".to_string()],
+        flag("--name", name),
+    ];
+    let default_host_config = HostConfig::default();
+    let host_config = config.host_config.as_ref().unwrap_or(&default_host_config);
+    push_flag(&mut groups, "--hostname", config.hostname.as_ref());
+    push_flag(&mut groups, "--domainname", config.domainname.as_ref());
+    push_flag(&mut groups, "--user", config.user.as_ref());
+    push_flag(&mut groups, "--workdir", config.working_dir.as_ref());
+    push_flag(&mut groups, "--mac-address", config.mac_address.as_ref());
+    push_flag(&mut groups, "--stop-signal", config.stop_signal.as_ref());
+    push_flag(&mut groups, "--stop-timeout", config.stop_timeout.as_ref());
+    push_switch(&mut groups, "--tty", config.tty);
+    push_switch(&mut groups, "--interactive", config.open_stdin);
+    for env in config.env.iter().flatten() {
+        groups.push(flag("--env", env));
+    }
+    for (key, value) in sorted(config.labels.as_ref()) {
+        groups.push(flag("--label", &format!("{}={}", key, value)));
+    }
+    for (port, _) in sorted(config.exposed_ports.as_ref()) {
+        groups.push(flag("--expose", port));
+    }
+    if let Some(healthcheck) = &config.healthcheck {
+        push_healthcheck(&mut groups, healthcheck);
+    }
+    match &host_config.network_mode {
+        Some(mode) if mode != "default" => groups.push(flag("--network", mode)),
+        None if config.network_disabled == Some(true) => groups.push(flag("--network", "none")),
+        _ => {}
+    }
+
+    let mount_targets = host_config
+        .mounts
+        .iter()
+        .flatten()
+        .filter_map(|mount| mount.target.as_deref())
+        .chain(
+            host_config
+                .binds
+                .iter()
+                .flatten()
+                .filter_map(|bind| bind.split(':').nth(1)),
+        )
+        .collect::<HashSet<_>>();
+    for (volume, _) in sorted(config.volumes.as_ref()) {
+        if !mount_targets.contains(&volume.as_str()) {
+            groups.push(flag("--volume", volume));
+        }
+    }
+    for bind in host_config.binds.iter().flatten() {
+        groups.push(flag("--volume", bind));
+    }
+    for mount in host_config.mounts.iter().flatten() {
+        groups.push(flag("--mount", &mount_arg(mount)));
+    }
+    for (tmpfs, options) in sorted(host_config.tmpfs.as_ref()) {
+        let tmpfs = if options.is_empty() {
+            tmpfs.to_string()
+        } else {
+            format!("{}:{}", tmpfs, options)
+        };
+        groups.push(flag("--tmpfs", &tmpfs));
+    }
+    for container in host_config.volumes_from.iter().flatten() {
+        groups.push(flag("--volumes-from", container));
+    }
+    for (port, bindings) in sorted(host_config.port_bindings.as_ref()) {
+        for binding in bindings.iter().flatten() {
+            let host_port = binding.host_port.as_deref().unwrap_or_default();
+            let publish = match binding.host_ip.as_deref() {
+                Some(ip) if !ip.is_empty() => format!("{}:{}:{}", ip, host_port, port),
+                _ if host_port.is_empty() => port.to_string(),
+                _ => format!("{}:{}", host_port, port),
+            };
+            groups.push(flag("--publish", &publish));
+        }
+    }
+    push_switch(&mut groups, "--publish-all", host_config.publish_all_ports);
+    if let Some(policy) = &host_config.restart_policy {
+        let name = policy
+            .name
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        match policy.maximum_retry_count {
+            _ if name.is_empty() => {}
+            Some(retries) if retries > 0 => {
+                groups.push(flag("--restart", &format!("{}:{}", name, retries)))
+            }
+            _ => groups.push(flag("--restart", &name)),
+        }
+    }
+    for link in host_config.links.iter().flatten() {
+        groups.push(flag("--link", link));
+    }
+    for host in host_config.extra_hosts.iter().flatten() {
+        groups.push(flag("--add-host", host));
+    }
+    for dns in host_config.dns.iter().flatten() {
+        groups.push(flag("--dns", dns));
+    }
+    for option in host_config.dns_options.iter().flatten() {
+        groups.push(flag("--dns-option", option));
+    }
+    for search in host_config.dns_search.iter().flatten() {
+        groups.push(flag("--dns-search", search));
+    }
+    push_switch(&mut groups, "--privileged", host_config.privileged);
+    for cap in host_config.cap_add.iter().flatten() {
+        groups.push(flag("--cap-add", cap));
+    }
+    for cap in host_config.cap_drop.iter().flatten() {
+        groups.push(flag("--cap-drop", cap));
+    }
+    for option in host_config.security_opt.iter().flatten() {
+        groups.push(flag("--security-opt", option));
+    }
+    for group in host_config.group_add.iter().flatten() {
+        groups.push(flag("--group-add", group));
+    }
+    for device in host_config.devices.iter().flatten() {
+        let mut mapping = device.path_on_host.clone().unwrap_or_default();
+        if let Some(path) = &device.path_in_container {
+            mapping.push_str(&format!(":{}", path));
+        }
+        if let Some(permissions) = &device.cgroup_permissions {
+            mapping.push_str(&format!(":{}", permissions));
+        }
+        groups.push(flag("--device", &mapping));
+    }
+    for (key, value) in sorted(host_config.sysctls.as_ref()) {
+        groups.push(flag("--sysctl", &format!("{}={}", key, value)));
+    }
+    for ulimit in host_config.ulimits.iter().flatten() {
+        groups.push(flag(
+            "--ulimit",
+            &format!(
+                "{}={}:{}",
+                ulimit.name.as_deref().unwrap_or_default(),
+                ulimit.soft.unwrap_or_default(),
+                ulimit.hard.unwrap_or_default()
+            ),
+        ));
+    }
+    if let Some(log_config) = &host_config.log_config {
+        push_flag(&mut groups, "--log-driver", log_config.typ.as_ref());
+        for (key, value) in sorted(log_config.config.as_ref()) {
+            groups.push(flag("--log-opt", &format!("{}={}", key, value)));
+        }
+    }
+    push_flag(&mut groups, "--memory", host_config.memory.as_ref());
+    push_flag(
+        &mut groups,
+        "--memory-swap",
+        host_config.memory_swap.as_ref(),
+    );
+    push_flag(
+        &mut groups,
+        "--memory-reservation",
+        host_config.memory_reservation.as_ref(),
+    );
+    if let Some(nano_cpus) = host_config.nano_cp_us {
+        groups.push(flag("--cpus", &(nano_cpus as f64 / 1e9).to_string()));
+    }
+    push_flag(&mut groups, "--cpu-shares", host_config.cpu_shares.as_ref());
+    push_flag(
+        &mut groups,
+        "--cpuset-cpus",
+        host_config.cpuset_cpus.as_ref(),
+    );
+    push_flag(&mut groups, "--pids-limit", host_config.pids_limit.as_ref());
+    push_flag(
+        &mut groups,
+        "--blkio-weight",
+        host_config.blkio_weight.as_ref(),
+    );
+    push_flag(&mut groups, "--shm-size", host_config.shm_size.as_ref());
+    push_flag(&mut groups, "--pid", host_config.pid_mode.as_ref());
+    push_flag(&mut groups, "--ipc", host_config.ipc_mode.as_ref());
+    push_flag(&mut groups, "--userns", host_config.userns_mode.as_ref());
+    push_flag(&mut groups, "--uts", host_config.uts_mode.as_ref());
+    push_flag(
+        &mut groups,
+        "--cgroup-parent",
+        host_config.cgroup_parent.as_ref(),
+    );
+    push_flag(&mut groups, "--runtime", host_config.runtime.as_ref());
+    push_switch(
+        &mut groups,
+        "--oom-kill-disable",
+        host_config.oom_kill_disable,
+    );
+    push_switch(&mut groups, "--read-only", host_config.readonly_rootfs);
+    push_switch(&mut groups, "--init", host_config.init);
+    push_switch(&mut groups, "--rm", host_config.auto_remove);
+
+    // docker create takes a single entrypoint, the rest of it goes before the command
+    let mut command = vec![];
+    if let Some(entrypoint) = &config.entrypoint {
+        let mut entrypoint = entrypoint.iter();
+        groups.push(flag(
+            "--entrypoint",
+            entrypoint.next().map(String::as_str).unwrap_or_default(),
+        ));
+        command.extend(entrypoint.cloned());
+    }
+    command.insert(0, config.image.clone().unwrap_or_default());
+    command.extend(config.cmd.iter().flatten().cloned());
+    groups.push(command);
+    groups
+}
+
+/// Return the settings in `config` that `create_command` doesn't render, e.g. `HostConfig.Isolation`
+pub fn untranslated_settings(config: &Config<String>) -> Vec<String> {
+    let mut settings = vec![];
+    let config = serde_json::to_value(config).unwrap_or(Value::Null);
+    for (key, value) in config.as_object().into_iter().flatten() {
+        if key == "HostConfig" {
+            for (key, value) in value.as_object().into_iter().flatten() {
+                if !HOST_CONFIG_KEYS.contains(&key.as_str()) && !is_unset(value) {
+                    settings.push(format!("HostConfig.{}", key));
+                }
+            }
+        } else if !CONFIG_KEYS.contains(&key.as_str()) && !is_unset(value) {
+            settings.push(key.to_string());
+        }
+    }
+    settings.sort();
+    settings
+}
+
+/// Return true if `value` is a JSON null, false, zero or empty value
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Bool(b) => !b,
+        Value::Number(n) => n.as_f64() == Some(0.0),
+        Value::String(s) => s.is_empty(),
+        Value::Array(a) => a.is_empty(),
+        Value::Object(o) => o.values().all(is_unset),
+    }
+}
+
+fn flag(name: &str, value: &str) -> Vec<String> {
+    vec![name.to_string(), value.to_string()]
+}
+
+fn push_flag<T: ToString>(groups: &mut Vec<Vec<String>>, name: &str, value: Option<&T>) {
+    if let Some(value) = value {
+        let value = value.to_string();
+        if !value.is_empty() {
+            groups.push(flag(name, &value));
+        }
+    }
+}
+
+fn push_switch(groups: &mut Vec<Vec<String>>, name: &str, value: Option<bool>) {
+    if value == Some(true) {
+        groups.push(vec![name.to_string()]);
+    }
+}
+
+/// Add the `--health-*` flags of `healthcheck`
+fn push_healthcheck(groups: &mut Vec<Vec<String>>, healthcheck: &HealthConfig) {
+    let test = healthcheck.test.as_deref().unwrap_or_default();
+    match test.first().map(String::as_str) {
+        Some("NONE") => groups.push(vec!["--no-healthcheck".to_string()]),
+        Some("CMD-SHELL") => groups.push(flag("--health-cmd", &test[1..].join(" "))),
+        Some("CMD") => groups.push(flag("--health-cmd", &command_line(&test[1..]))),
+        _ => {}
+    }
+    let durations = [
+        ("--health-interval", healthcheck.interval),
+        ("--health-timeout", healthcheck.timeout),
+        ("--health-start-period", healthcheck.start_period),
+    ];
+    for (name, nanos) in durations.iter() {
+        if let Some(nanos) = nanos.filter(|n| *n > 0) {
+            groups.push(flag(name, &go_duration(nanos)));
+        }
+    }
+    if let Some(retries) = healthcheck.retries.filter(|r| *r > 0) {
+        groups.push(flag("--health-retries", &retries.to_string()));
+    }
+}
+
+/// Render nanoseconds as a duration the docker CLI parses, e.g. `30s`
+fn go_duration(nanos: i64) -> String {
+    if nanos % 1_000_000_000 == 0 {
+        format!("{}s", nanos / 1_000_000_000)
+    } else if nanos % 1_000_000 == 0 {
+        format!("{}ms", nanos / 1_000_000)
+    } else {
+        format!("{}ns", nanos)
+    }
+}
+
+/// Return the entries of an optional map sorted by key, so scripts are stable
+fn sorted<V>(map: Option<&HashMap<String, V>>) -> BTreeMap<&String, &V> {
+    map.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bollard::models::{
+        MountBindOptions, MountBindOptionsPropagationEnum, MountTypeEnum, PortBinding,
+        RestartPolicy, RestartPolicyNameEnum,
+    };
+
+    #[test]
+    fn quote_test() {
+        assert_eq!(quote("nginx:1.19"), "nginx:1.19");
+        assert_eq!(quote("KEY=value"), "KEY=value");
+        assert_eq!(quote("two words"), "'two words'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("$HOME"), "'$HOME'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn mount_arg_test() {
+        let mount = Mount {
+            typ: Some(MountTypeEnum::BIND),
+            source: Some("/srv/data,old".to_string()),
+            target: Some("/data".to_string()),
+            read_only: Some(true),
+            bind_options: Some(MountBindOptions {
+                propagation: Some(MountBindOptionsPropagationEnum::RSLAVE),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            mount_arg(&mount),
+            "type=bind,\"source=/srv/data,old\",target=/data,readonly,bind-propagation=rslave"
+        );
+    }
+
+    #[test]
+    fn create_command_test() {
+        let mut port_bindings = HashMap::new();
+        port_bindings.insert(
+            "80/tcp".to_string(),
+            Some(vec![PortBinding {
+                host_ip: Some("".to_string()),
+                host_port: Some("8080".to_string()),
+            }]),
+        );
+        let mut volumes = HashMap::new();
+        volumes.insert("/data".to_string(), HashMap::new());
+        volumes.insert("/cache".to_string(), HashMap::new());
+        let config = Config {
+            image: Some("nginx:1.19".to_string()),
+            env: Some(vec!["GREETING=hello world".to_string()]),
+            entrypoint: Some(vec!["/docker-entrypoint.sh".to_string(), "-v".to_string()]),
+            cmd: Some(vec!["nginx".to_string()]),
+            volumes: Some(volumes),
+            healthcheck: Some(HealthConfig {
+                test: Some(vec![
+                    "CMD-SHELL".to_string(),
+                    "curl -f localhost".to_string(),
+                ]),
+                interval: Some(30_000_000_000),
+                ..Default::default()
+            }),
+            host_config: Some(HostConfig {
+                binds: Some(vec!["data:/data:rw".to_string()]),
+                port_bindings: Some(port_bindings),
+                restart_policy: Some(RestartPolicy {
+                    name: Some(RestartPolicyNameEnum::ON_FAILURE),
+                    maximum_retry_count: Some(3),
+                }),
+                network_mode: Some("default".to_string()),
+                nano_cp_us: Some(1_500_000_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut script = Script::new("Restore nginx");
+        script.wrapped_command(&create_command("nginx", &config));
+        assert_eq!(
+            script.render(),
+            "#!/bin/sh
+set -eu
+# Restore nginx
+docker create \\
+    --name nginx \\
+    --env 'GREETING=hello world' \\
+    --health-cmd 'curl -f localhost' \\
+    --health-interval 30s \\
+    --volume /cache \\
+    --volume data:/data:rw \\
+    --publish 8080:80/tcp \\
+    --restart on-failure:3 \\
+    --cpus 1.5 \\
+    --entrypoint /docker-entrypoint.sh \\
+    nginx:1.19 -v nginx
+"
+        );
+    }
+
+    #[test]
+    fn untranslated_settings_test() {
+        let config = Config {
+            image: Some("nginx".to_string()),
+            attach_stdout: Some(true),
+            on_build: Some(vec!["RUN true".to_string()]),
+            host_config: Some(HostConfig {
+                memory: Some(0),
+                oom_score_adj: Some(500),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            untranslated_settings(&config),
+            vec!["HostConfig.OomScoreAdj", "OnBuild"]
+        );
+        assert!(untranslated_settings(&Config::default()).is_empty());
+    }
+}
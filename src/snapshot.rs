@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use uuid::Uuid;
+
+/// Filesystem type of btrfs in `statfs`
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+
+/// Inode number of the root directory of every btrfs subvolume
+const BTRFS_SUBVOLUME_INODE: u64 = 256;
+
+/// Filesystem that can take a read-only, point-in-time copy of a directory
+///
+/// Backends are tried in the order of `BACKENDS`, add new ones there.
+pub trait SnapshotBackend: Sync {
+    /// Name of the backend shown in logs
+    fn name(&self) -> &'static str;
+
+    /// Return true if `path` can be snapshotted by this backend
+    fn supports(&self, path: &Path) -> bool;
+
+    /// Create a read-only snapshot of `path`, returning the directory it is readable at
+    fn create(&self, path: &Path) -> Result<PathBuf>;
+
+    /// Remove a snapshot created by `create`
+    fn release(&self, snapshot: &Path) -> Result<()>;
+}
+
+/// Snapshots of btrfs subvolumes, taken with the `btrfs` command
+///
+/// Only the root of a subvolume can be snapshotted, so volumes have to be created as
+/// subvolumes for this to apply.
+pub struct Btrfs;
+
+impl SnapshotBackend for Btrfs {
+    fn name(&self) -> &'static str {
+        "btrfs"
+    }
+
+    fn supports(&self, path: &Path) -> bool {
+        let is_subvolume = path
+            .metadata()
+            .map_or(false, |m| m.is_dir() && m.ino() == BTRFS_SUBVOLUME_INODE);
+        is_subvolume && filesystem_type(path).ok() == Some(BTRFS_SUPER_MAGIC)
+    }
+
+    fn create(&self, path: &Path) -> Result<PathBuf> {
+        let snapshot = snapshot_path(path)?;
+        run_btrfs(
+            &["subvolume", "snapshot", "-r"],
+            &[path, snapshot.as_path()],
+        )?;
+        Ok(snapshot)
+    }
+
+    fn release(&self, snapshot: &Path) -> Result<()> {
+        run_btrfs(&["subvolume", "delete"], &[snapshot])
+    }
+}
+
+/// Snapshot backends, in the order they are tried
+static BACKENDS: &[&dyn SnapshotBackend] = &[&Btrfs];
+
+/// Read-only snapshot of a directory, released when dropped
+pub struct Snapshot {
+    path: PathBuf,
+    backend: &'static dyn SnapshotBackend,
+}
+
+impl Snapshot {
+    /// Return the directory the snapshot is readable at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        log::debug!("Releasing snapshot {}", self.path.display());
+        if let Err(e) = self.backend.release(&self.path) {
+            log::warn!(
+                "Failed to release {} snapshot {}: {:#}",
+                self.backend.name(),
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Take a snapshot of `path` with the first backend that supports it
+///
+/// Returns `None` after warning if no backend supports `path` or the snapshot fails, so the
+/// caller can back up the live directory instead.
+///
+/// # Arguments
+///
+/// * `path` - Directory to snapshot
+///
+pub fn take_snapshot(path: &Path) -> Option<Snapshot> {
+    let backend = match BACKENDS.iter().find(|backend| backend.supports(path)) {
+        Some(backend) => *backend,
+        None => {
+            log::warn!(
+                "{} doesn't support snapshots, backing up the live directory",
+                path.display()
+            );
+            return None;
+        }
+    };
+    match backend.create(path) {
+        Ok(snapshot) => {
+            log::info!(
+                "Created {} snapshot {} of {}",
+                backend.name(),
+                snapshot.display(),
+                path.display()
+            );
+            Some(Snapshot {
+                path: snapshot,
+                backend,
+            })
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to snapshot {}, backing up the live directory: {:#}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Return the path of a new snapshot of `path`, next to it so it is on the same filesystem
+fn snapshot_path(path: &Path) -> Result<PathBuf> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Unable to snapshot {}", path.display()))?;
+    Ok(parent.join(format!(".dockyard-snapshot-{}", Uuid::new_v4())))
+}
+
+/// Return the `statfs` filesystem type of the filesystem holding `path`
+// The type of f_type differs between C libraries
+#[allow(clippy::unnecessary_cast)]
+fn filesystem_type(path: &Path) -> Result<i64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // statfs only writes to stat, which is read once it has succeeded
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to get filesystem of {}", path.display()));
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_type as i64)
+}
+
+/// Run a `btrfs` command on the host
+///
+/// # Arguments
+///
+/// * `args` - Subcommand and flags
+/// * `paths` - Paths passed after `args`
+///
+fn run_btrfs(args: &[&str], paths: &[&Path]) -> Result<()> {
+    let output = Command::new("btrfs")
+        .args(args)
+        .args(paths)
+        .output()
+        .context("Unable to run btrfs")?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "btrfs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn take_snapshot_unsupported_test() {
+        let working_dir = TempDir::new().unwrap();
+        if filesystem_type(working_dir.path()).unwrap() == BTRFS_SUPER_MAGIC {
+            return;
+        }
+        assert!(!Btrfs.supports(working_dir.path()));
+        assert!(take_snapshot(working_dir.path()).is_none());
+    }
+
+    #[test]
+    fn snapshot_path_test() {
+        let snapshot = snapshot_path(Path::new("/var/lib/docker/volumes/data/_data")).unwrap();
+        assert_eq!(
+            snapshot.parent().unwrap(),
+            Path::new("/var/lib/docker/volumes/data")
+        );
+        assert!(snapshot
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".dockyard-snapshot-"));
+        assert!(snapshot_path(Path::new("/")).is_err());
+    }
+}
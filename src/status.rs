@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub const DEFAULT_STATUS_FILE: &str = "/tmp/dockyard-watch.json";
+
+/// Progress of a running watch, read by `dockyard status`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct WatchStatus {
+    pub pid: u32,
+    pub next_backup: Option<String>,
+    pub current_container: Option<String>,
+    pub containers_completed: usize,
+    pub containers_total: usize,
+    pub last_backup_started: Option<String>,
+    pub last_backup_finished: Option<String>,
+    pub last_error: Option<String>,
+    pub updated: String,
+}
+
+/// Status file kept up to date by watch
+///
+/// Failing to write the status is logged but never fails a backup.
+pub struct StatusFile {
+    path: Option<PathBuf>,
+    status: WatchStatus,
+}
+
+impl StatusFile {
+    /// Create a status file, or a no-op tracker if `path` is `None`
+    pub fn new(path: Option<&Path>) -> StatusFile {
+        StatusFile {
+            path: path.map(Path::to_path_buf),
+            status: WatchStatus {
+                pid: process::id(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Apply `f` to the current status and write it to disk
+    pub fn update<F: FnOnce(&mut WatchStatus)>(&mut self, f: F) {
+        f(&mut self.status);
+        self.status.updated = Utc::now().to_rfc3339();
+        if let Some(path) = &self.path {
+            if let Err(e) = write_status(path, &self.status) {
+                log::warn!("Failed to write status file {}: {:#}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Write status to `path`, replacing any previous status atomically
+///
+/// # Arguments
+///
+/// * `path` - Status file location
+/// * `status` - Status to write
+///
+pub fn write_status(path: &Path, status: &WatchStatus) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(status)?)
+        .with_context(|| format!("Unable to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| {
+        format!(
+            "Unable to move {} to {}",
+            tmp_path.display(),
+            path.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Read status written by a running watch
+///
+/// # Arguments
+///
+/// * `path` - Status file location
+///
+pub fn read_status(path: &Path) -> Result<WatchStatus> {
+    let contents = fs::read_to_string(path).with_context(|| {
+        format!(
+            "Unable to read status file {}, is dockyard watch running?",
+            path.display()
+        )
+    })?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Render status for display
+pub fn format_status(status: &WatchStatus) -> String {
+    let mut lines = vec![format!("Watch PID: {}", status.pid)];
+    match &status.current_container {
+        Some(container) => lines.push(format!(
+            "Backing up: {} ({}/{} containers complete)",
+            container, status.containers_completed, status.containers_total
+        )),
+        None => lines.push("Backing up: idle".to_string()),
+    }
+    let or_never = |s: &Option<String>| s.clone().unwrap_or_else(|| "never".to_string());
+    lines.push(format!(
+        "Next backup: {}",
+        status
+            .next_backup
+            .clone()
+            .unwrap_or_else(|| "-".to_string())
+    ));
+    lines.push(format!(
+        "Last backup started: {}",
+        or_never(&status.last_backup_started)
+    ));
+    lines.push(format!(
+        "Last backup finished: {}",
+        or_never(&status.last_backup_finished)
+    ));
+    if let Some(e) = &status.last_error {
+        lines.push(format!("Last error: {}", e));
+    }
+    lines.push(format!("Updated: {}", status.updated));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn write_and_read_status_test() {
+        let working_dir = TempDir::new().unwrap();
+        let path = working_dir.path().join("status.json");
+        let mut status_file = StatusFile::new(Some(&path));
+        status_file.update(|s| {
+            s.current_container = Some("nginx".to_string());
+            s.containers_total = 2;
+        });
+        let status = read_status(&path).unwrap();
+        assert_eq!(status.pid, process::id());
+        assert_eq!(status.current_container, Some("nginx".to_string()));
+        assert_eq!(status.containers_total, 2);
+        assert!(format_status(&status).contains("Backing up: nginx (0/2 containers complete)"));
+    }
+
+    #[test]
+    fn read_missing_status_test() {
+        let working_dir = TempDir::new().unwrap();
+        let error = read_status(&working_dir.path().join("missing.json")).unwrap_err();
+        assert!(error.to_string().contains("is dockyard watch running?"));
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use bollard::models::Service;
+use bollard::service::InspectServiceOptions;
+use bollard::Docker;
+use std::collections::HashMap;
+use std::process::Command;
+
+/// Label Docker sets on the containers it runs for a swarm service, its value is the service ID
+pub const SERVICE_ID_LABEL: &str = "com.docker.swarm.service.id";
+
+/// Label Docker sets on the containers it runs for a swarm service, its value is the service name
+pub const SERVICE_NAME_LABEL: &str = "com.docker.swarm.service.name";
+
+/// Swarm secrets and configs a service task container uses, which its backup doesn't contain
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SwarmReferences {
+    /// Service the container was a task of
+    pub service: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub configs: Vec<String>,
+}
+
+impl SwarmReferences {
+    /// Return the secrets and configs referenced by the container spec of `service`
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of service
+    /// * `service` - Service inspect response
+    ///
+    fn of_service(name: &str, service: &Service) -> Self {
+        let container_spec = service
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.task_template.as_ref())
+            .and_then(|template| template.container_spec.as_ref());
+        let mut secrets = container_spec
+            .and_then(|spec| spec.secrets.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|secret| secret.secret_name.clone())
+            .collect::<Vec<_>>();
+        let mut configs = container_spec
+            .and_then(|spec| spec.configs.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|config| config.config_name.clone())
+            .collect::<Vec<_>>();
+        secrets.sort();
+        secrets.dedup();
+        configs.sort();
+        configs.dedup();
+        SwarmReferences {
+            service: name.to_string(),
+            secrets,
+            configs,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.secrets.is_empty() && self.configs.is_empty()
+    }
+
+    /// Return the secrets and configs that `available` doesn't reference, e.g. `secret db_password`
+    fn missing_from(&self, available: &SwarmReferences) -> Vec<String> {
+        let secrets = self
+            .secrets
+            .iter()
+            .filter(|secret| !available.secrets.contains(secret))
+            .map(|secret| format!("secret {}", secret));
+        let configs = self
+            .configs
+            .iter()
+            .filter(|config| !available.configs.contains(config))
+            .map(|config| format!("config {}", config));
+        secrets.chain(configs).collect()
+    }
+}
+
+/// Return the secrets and configs used by the swarm service a container is a task of
+///
+/// Returns `None` for containers that aren't swarm service tasks. Services can only be inspected
+/// on swarm managers, so failing to inspect one is logged rather than failing the backup.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `labels` - Labels of the container
+///
+pub(crate) async fn find_swarm_references(
+    docker: &Docker,
+    labels: Option<&HashMap<String, String>>,
+) -> Option<SwarmReferences> {
+    let service_id = labels?.get(SERVICE_ID_LABEL)?;
+    let name = labels?
+        .get(SERVICE_NAME_LABEL)
+        .unwrap_or(service_id)
+        .as_str();
+    match docker
+        .inspect_service(service_id, None::<InspectServiceOptions>)
+        .await
+    {
+        Ok(service) => {
+            let references = SwarmReferences::of_service(name, &service);
+            log::info!(
+                "Recording {} secrets and {} configs of swarm service {}",
+                references.secrets.len(),
+                references.configs.len(),
+                name
+            );
+            Some(references)
+        }
+        Err(e) => {
+            log::warn!(
+                "Unable to record secrets and configs of swarm service {}, back up on a swarm manager to record them: {}",
+                name,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Fail if secrets or configs a backed up swarm service task used don't exist
+///
+/// Swarm refuses to create or update services that reference secrets or configs that don't
+/// exist, so each one is inspected. The Docker API client doesn't support secrets and configs,
+/// so they are inspected with the docker CLI, which has to run on a swarm manager.
+///
+/// # Arguments
+///
+/// * `references` - Secrets and configs recorded in the container backup
+///
+pub(crate) fn check_swarm_references(references: &SwarmReferences) -> Result<()> {
+    if references.is_empty() {
+        return Ok(());
+    }
+    let available = SwarmReferences {
+        service: references.service.clone(),
+        secrets: existing_objects("secret", &references.secrets)?,
+        configs: existing_objects("config", &references.configs)?,
+    };
+    let missing = references.missing_from(&available);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Container was a task of swarm service {}, which used {} that no longer exist. Create them \
+         before restoring, or restore with --ignore-swarm-references",
+        references.service,
+        missing.join(", ")
+    ))
+}
+
+/// Return the swarm secrets or configs in `names` that exist
+///
+/// # Arguments
+///
+/// * `kind` - `secret` or `config`
+/// * `names` - Names of the objects to inspect
+///
+fn existing_objects(kind: &str, names: &[String]) -> Result<Vec<String>> {
+    let mut existing = vec![];
+    for name in names {
+        let output = Command::new("docker")
+            .args(&[kind, "inspect", "--format", "{{.ID}}", name])
+            .output()
+            .with_context(|| format!("Unable to run docker to inspect {} {}", kind, name))?;
+        if output.status.success() {
+            existing.push(name.clone());
+            continue;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !is_not_found(&stderr) {
+            return Err(anyhow!(
+                "Unable to inspect {} {}: {}",
+                kind,
+                name,
+                stderr.trim()
+            ));
+        }
+    }
+    Ok(existing)
+}
+
+/// Return true if the docker CLI failed because the object it inspected doesn't exist
+fn is_not_found(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    stderr.contains("no such") || stderr.contains("not found")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bollard::models::{
+        ServiceSpec, TaskSpec, TaskSpecContainerSpec, TaskSpecContainerSpecConfigs,
+        TaskSpecContainerSpecSecrets,
+    };
+
+    fn service(secrets: &[&str], configs: &[&str]) -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                task_template: Some(TaskSpec {
+                    container_spec: Some(TaskSpecContainerSpec {
+                        secrets: Some(
+                            secrets
+                                .iter()
+                                .map(|name| TaskSpecContainerSpecSecrets {
+                                    secret_name: Some(name.to_string()),
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        ),
+                        configs: Some(
+                            configs
+                                .iter()
+                                .map(|name| TaskSpecContainerSpecConfigs {
+                                    config_name: Some(name.to_string()),
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        ),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn of_service_test() {
+        let references = SwarmReferences::of_service(
+            "web",
+            &service(&["tls", "db_password", "tls"], &["nginx"]),
+        );
+        assert_eq!(
+            references,
+            SwarmReferences {
+                service: "web".to_string(),
+                secrets: vec!["db_password".to_string(), "tls".to_string()],
+                configs: vec!["nginx".to_string()],
+            }
+        );
+        let references = SwarmReferences::of_service("web", &Service::default());
+        assert!(references.is_empty());
+    }
+
+    #[test]
+    fn missing_from_test() {
+        let recorded =
+            SwarmReferences::of_service("web", &service(&["db_password", "tls"], &["nginx"]));
+        assert!(recorded.missing_from(&recorded).is_empty());
+        let available = SwarmReferences::of_service("web", &service(&["tls"], &[]));
+        assert_eq!(
+            recorded.missing_from(&available),
+            vec!["secret db_password", "config nginx"]
+        );
+        assert_eq!(recorded.missing_from(&SwarmReferences::default()).len(), 3);
+    }
+
+    #[test]
+    fn is_not_found_test() {
+        assert!(is_not_found("Error: No such secret: db_password\n"));
+        assert!(is_not_found(
+            "Error response from daemon: config nginx not found\n"
+        ));
+        assert!(!is_not_found(
+            "Error response from daemon: This node is not a swarm manager.\n"
+        ));
+    }
+}
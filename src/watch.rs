@@ -1,28 +1,326 @@
-use crate::backup::backup_container;
+use crate::backup::{backup_container, BackupOptions, BackupTimeout};
 use crate::cleanup::get_all_containers;
+use crate::status::StatusFile;
 use anyhow::Result;
 use bollard::models::{ContainerSummaryInner, Mount};
 use bollard::Docker;
 use chrono::Utc;
 use cron::Schedule;
-use std::collections::HashSet;
+use futures::future::try_join_all;
+use futures::stream::{self, Stream, StreamExt};
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::time;
 
 pub const DISABLED_LABEL: &str = "com.github.aig787.dockyard.disabled";
 
+/// Compile a `--match` pattern, which must match the whole container name
+///
+/// # Arguments
+///
+/// * `pattern` - Regular expression for container names
+///
+pub fn parse_name_filter(pattern: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| anyhow!("Invalid --match {}: {}", pattern, e))
+}
+
+/// Cron expression used when watch is given no `--cron`
+pub const DEFAULT_CRON: &str = "0 0 0 * * * *";
+
+/// Number of upcoming fire times checked against `WatchOptions::min_interval`
+const INTERVAL_SAMPLE_SIZE: usize = 10;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pause or resume scheduled backups, backups that are already running finish
+pub fn set_paused(paused: bool) {
+    set_pause_flag(&PAUSED, paused)
+}
+
+/// Set `flag` to `paused`, logging whether watch was paused or resumed
+fn set_pause_flag(flag: &AtomicBool, paused: bool) {
+    match (flag.swap(paused, Relaxed), paused) {
+        (false, true) => {
+            log::info!("Pausing watch, scheduled backups are skipped until it is resumed")
+        }
+        (true, false) => log::info!("Resuming watch"),
+        (_, true) => log::info!("Watch is already paused"),
+        (_, false) => log::info!("Watch is already running"),
+    }
+}
+
+/// Return whether scheduled backups are paused
+pub fn is_paused() -> bool {
+    PAUSED.load(Relaxed)
+}
+
+/// Signal that pauses or resumes scheduled backups
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PauseSignal {
+    /// Pause scheduled backups, or resume them if they're paused, sent with SIGUSR1
+    Toggle,
+    /// Resume scheduled backups, sent with SIGUSR2
+    Resume,
+}
+
+/// Toggle pausing scheduled backups on SIGUSR1 and resume them on SIGUSR2, until the process exits
+pub async fn handle_pause_signals() -> Result<()> {
+    let toggle = signal(SignalKind::user_defined1())?;
+    let resume = signal(SignalKind::user_defined2())?;
+    let signals = stream::unfold((toggle, resume), |(mut toggle, mut resume)| async move {
+        let signal = tokio::select! {
+            Some(_) = toggle.recv() => PauseSignal::Toggle,
+            Some(_) = resume.recv() => PauseSignal::Resume,
+            else => return None,
+        };
+        Some((signal, (toggle, resume)))
+    });
+    apply_pause_signals(&PAUSED, signals).await;
+    Ok(())
+}
+
+/// Pause or resume by setting `paused` for each of `signals`, until the stream ends
+///
+/// # Arguments
+///
+/// * `paused` - Flag set while scheduled backups are paused
+/// * `signals` - Stream of pause signals
+///
+async fn apply_pause_signals(paused: &AtomicBool, signals: impl Stream<Item = PauseSignal>) {
+    futures::pin_mut!(signals);
+    while let Some(signal) = signals.next().await {
+        match signal {
+            PauseSignal::Toggle => set_pause_flag(paused, !paused.load(Relaxed)),
+            PauseSignal::Resume => set_pause_flag(paused, false),
+        }
+    }
+}
+
+/// Label a container must have to be backed up by a schedule, with an optional value
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelSelector {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.find('=') {
+            Some(0) => Err(anyhow!(
+                "Invalid label selector {}, expected key[=value]",
+                s
+            )),
+            Some(i) => Ok(LabelSelector {
+                key: s[..i].to_string(),
+                value: Some(s[i + 1..].to_string()),
+            }),
+            None if s.is_empty() => Err(anyhow!("Empty label selector")),
+            None => Ok(LabelSelector {
+                key: s.to_string(),
+                value: None,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.key, value),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
+
+impl LabelSelector {
+    /// Return true if `labels` has the selected key, with the selected value if set
+    pub fn matches(&self, labels: Option<&HashMap<String, String>>) -> bool {
+        match labels.and_then(|l| l.get(&self.key)) {
+            Some(value) => self.value.as_ref().map_or(true, |v| v == value),
+            None => false,
+        }
+    }
+}
+
+/// Cron schedule backing up the containers matching `selector`
+///
+/// A schedule without a selector backs up every container not selected by another schedule.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupSchedule {
+    pub cron: String,
+    pub selector: Option<LabelSelector>,
+}
+
+/// Pair each `--label` with the `--cron` before it on the command line
+///
+/// # Arguments
+///
+/// * `crons` - Cron expressions with their argument positions
+/// * `labels` - Label selectors with their argument positions
+///
+pub fn parse_schedules(
+    crons: Vec<(usize, String)>,
+    labels: Vec<(usize, String)>,
+) -> Result<Vec<BackupSchedule>> {
+    if crons.is_empty() {
+        if let Some((_, label)) = labels.first() {
+            return Err(anyhow!("--label {} must follow a --cron", label));
+        }
+        return Ok(vec![BackupSchedule {
+            cron: DEFAULT_CRON.to_string(),
+            selector: None,
+        }]);
+    }
+    let mut schedules = crons
+        .into_iter()
+        .map(|(index, cron)| {
+            (
+                index,
+                BackupSchedule {
+                    cron,
+                    selector: None,
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    schedules.sort_by_key(|(index, _)| *index);
+    for (index, label) in labels {
+        let (_, schedule) = schedules
+            .iter_mut()
+            .filter(|(i, _)| *i < index)
+            .last()
+            .ok_or_else(|| anyhow!("--label {} must follow a --cron", label))?;
+        if schedule.selector.is_some() {
+            return Err(anyhow!(
+                "--cron {} has more than one --label",
+                schedule.cron
+            ));
+        }
+        schedule.selector = Some(label.parse()?);
+    }
+    Ok(schedules.into_iter().map(|(_, s)| s).collect())
+}
+
+/// Options controlling which containers watch backs up and when
+#[derive(Clone, Debug, Default)]
+pub struct WatchOptions {
+    /// Schedules to run, each with its own container selection
+    pub schedules: Vec<BackupSchedule>,
+    /// Names of containers to exclude from every schedule
+    pub exclude_containers: HashSet<String>,
+    /// Only back up containers whose name matches
+    pub name_filter: Option<Regex>,
+    /// Only back up containers with this label, instead of every container that doesn't opt out
+    /// with `DISABLED_LABEL`
+    pub enable_label: Option<LabelSelector>,
+    /// Also back up containers that are not running
+    pub include_stopped: bool,
+    /// Warn at startup about schedules that fire more often than this
+    pub min_interval: Option<Duration>,
+}
+
+/// Back up containers on each schedule until a backup fails
+///
+/// Schedules run concurrently in the same task, sharing the helper container limit set by
+/// `set_jobs` and the status file. Schedules firing more often than
+/// `watch_options.min_interval` are warned about before the first backup. Backups scheduled
+/// while paused with `set_paused` are skipped.
+///
+/// # Arguments
+///
+/// * `docker` - Docker client
+/// * `backup_mount` - Mount representing backup destination
+/// * `watch_options` - Schedules and container selection
+/// * `options` - Backup options
+/// * `status_file` - File to write progress to
+///
 pub async fn backup_on_interval(
     docker: &Docker,
-    cron: &str,
     backup_mount: Mount,
-    exclude_containers: &HashSet<String>,
-    exclude_volumes: &HashSet<String>,
+    watch_options: &WatchOptions,
+    options: &BackupOptions,
+    status_file: Option<&Path>,
 ) -> Result<()> {
-    let schedule = match Schedule::from_str(cron) {
-        Ok(s) => s,
-        Err(e) => return Err(anyhow!("Failed to parse cron expression {}: {}", cron, e)),
-    };
-    for datetime in schedule.upcoming(Utc) {
+    let mut schedules = vec![];
+    for schedule in &watch_options.schedules {
+        match Schedule::from_str(&schedule.cron) {
+            Ok(s) => schedules.push((schedule, s)),
+            Err(e) => {
+                return Err(anyhow!(
+                    "Failed to parse cron expression {}: {}",
+                    schedule.cron,
+                    e
+                ))
+            }
+        }
+    }
+    if let Some(min_interval) = watch_options.min_interval {
+        for (schedule, cron) in &schedules {
+            match shortest_interval(cron) {
+                Some(interval) if interval < min_interval => log::warn!(
+                    "--cron {} fires as often as every {}s, more often than --min-interval {}s, backups taking longer than that will run back to back",
+                    schedule.cron,
+                    interval.as_secs(),
+                    min_interval.as_secs()
+                ),
+                _ => {}
+            }
+        }
+    }
+    let status = RefCell::new(StatusFile::new(status_file));
+    try_join_all(schedules.into_iter().map(|(schedule, cron)| {
+        run_schedule(
+            docker,
+            &backup_mount,
+            watch_options,
+            options,
+            schedule,
+            cron,
+            &status,
+        )
+    }))
+    .await
+    .map(|_| ())
+}
+
+/// Return the shortest time between the next few fire times of a schedule
+///
+/// # Arguments
+///
+/// * `cron` - Schedule to check
+///
+fn shortest_interval(cron: &Schedule) -> Option<Duration> {
+    let upcoming = cron
+        .upcoming(Utc)
+        .take(INTERVAL_SAMPLE_SIZE)
+        .collect::<Vec<_>>();
+    upcoming
+        .windows(2)
+        .filter_map(|pair| (pair[1] - pair[0]).to_std().ok())
+        .min()
+}
+
+async fn run_schedule(
+    docker: &Docker,
+    backup_mount: &Mount,
+    watch_options: &WatchOptions,
+    options: &BackupOptions,
+    schedule: &BackupSchedule,
+    cron: Schedule,
+    status: &RefCell<StatusFile>,
+) -> Result<()> {
+    for datetime in cron.upcoming(Utc) {
         let now = Utc::now();
         let now_epoch = now.timestamp();
         let datetime_epoch = datetime.timestamp();
@@ -36,32 +334,84 @@ pub async fn backup_on_interval(
         } else {
             time::Duration::from_secs((datetime_epoch - now_epoch) as u64)
         };
-        log::info!("Scheduling backup for {}", datetime.to_rfc2822());
+        log::info!(
+            "Scheduling backup of {} for {}",
+            describe_selection(schedule),
+            datetime.to_rfc2822()
+        );
+        status
+            .borrow_mut()
+            .update(|s| s.next_backup = Some(datetime.to_rfc3339()));
         log::debug!("Sleeping for {} millis", &duration.as_millis());
         tokio::time::delay_for(duration).await;
+        if is_paused() {
+            log::info!(
+                "Watch is paused, skipping backup of {} scheduled for {}",
+                describe_selection(schedule),
+                datetime.to_rfc2822()
+            );
+            continue;
+        }
 
-        let res =
-            backup_all_containers(docker, &backup_mount, exclude_containers, exclude_volumes).await;
+        status.borrow_mut().update(|s| {
+            s.last_backup_started = Some(Utc::now().to_rfc3339());
+            s.last_error = None;
+        });
+        let res = backup_all_containers(
+            docker,
+            backup_mount,
+            watch_options,
+            options,
+            schedule,
+            status,
+        )
+        .await;
         if let Err(e) = res {
+            status.borrow_mut().update(|s| {
+                s.current_container = None;
+                s.last_error = Some(format!("{:#}", e));
+            });
             return Err(e);
         }
+        status
+            .borrow_mut()
+            .update(|s| s.last_backup_finished = Some(Utc::now().to_rfc3339()));
     }
     Ok(())
 }
 
+fn describe_selection(schedule: &BackupSchedule) -> String {
+    match &schedule.selector {
+        Some(selector) => format!("containers labeled {}", selector),
+        None => "containers".to_string(),
+    }
+}
+
 async fn backup_all_containers(
     docker: &Docker,
     backup_mount: &Mount,
-    exclude_containers: &HashSet<String>,
-    exclude_volumes: &HashSet<String>,
+    watch_options: &WatchOptions,
+    options: &BackupOptions,
+    schedule: &BackupSchedule,
+    status: &RefCell<StatusFile>,
 ) -> Result<()> {
+    let exclude_containers = &watch_options.exclude_containers;
     log::debug!("Excluding containers: {:?}", exclude_containers);
-    log::debug!("Excluding volumes: {:?}", exclude_volumes);
-    let containers = get_all_containers(docker)
+    if let Some(filter) = &watch_options.name_filter {
+        log::debug!("Only backing up containers matching {}", filter);
+    }
+    if let Some(selector) = &watch_options.enable_label {
+        log::debug!("Only backing up containers labeled {}", selector);
+    }
+    log::debug!("Excluding volumes: {:?}", options.exclude_volumes);
+    let containers = get_all_containers(docker, watch_options.include_stopped)
         .await?
         .into_iter()
         .filter(|container| {
             should_back_up(container)
+                && matches_schedule(container, schedule, &watch_options.schedules)
+                && matches_name_filter(container, watch_options.name_filter.as_ref())
+                && matches_enable_label(container, watch_options.enable_label.as_ref())
                 && container
                     .names
                     .as_ref()
@@ -70,27 +420,81 @@ async fn backup_all_containers(
                     .all(|n| !exclude_containers.contains(&n.replace("/", "")))
         })
         .collect::<Vec<_>>();
-    log::info!("Found {} running containers", containers.len());
+    if watch_options.include_stopped {
+        log::info!("Found {} containers", containers.len());
+    } else {
+        log::info!("Found {} running containers", containers.len());
+    }
+    let total = containers.len();
+    status.borrow_mut().update(|s| {
+        s.containers_completed = 0;
+        s.containers_total = total;
+    });
     for container in containers {
         let container_name = container.names.unwrap();
         let container_name = container_name.first().unwrap().replace("/", "");
-        let backup_location = backup_container(
-            &docker,
-            &container_name,
-            backup_mount.clone(),
-            exclude_volumes,
-        )
-        .await?;
-        log::info!(
-            "Successfully backed up {} to {}",
-            container_name,
-            backup_location.display()
-        );
+        status
+            .borrow_mut()
+            .update(|s| s.current_container = Some(container_name.clone()));
+        match backup_container(&docker, &container_name, backup_mount.clone(), options).await {
+            Ok(result) => log::info!(
+                "Successfully backed up {} to {}",
+                container_name,
+                result.path.display()
+            ),
+            // A hung mount shouldn't stop the other containers from being backed up
+            Err(e) if e.downcast_ref::<BackupTimeout>().is_some() => {
+                log::error!("Failed to back up {}: {:#}", container_name, e);
+                status
+                    .borrow_mut()
+                    .update(|s| s.last_error = Some(format!("{:#}", e)));
+            }
+            Err(e) => return Err(e),
+        }
+        status.borrow_mut().update(|s| s.containers_completed += 1);
     }
+    status.borrow_mut().update(|s| s.current_container = None);
     Ok(())
 }
 
-fn should_back_up(container_summary: &ContainerSummaryInner) -> bool {
+fn matches_schedule(
+    container_summary: &ContainerSummaryInner,
+    schedule: &BackupSchedule,
+    schedules: &[BackupSchedule],
+) -> bool {
+    let labels = container_summary.labels.as_ref();
+    match &schedule.selector {
+        Some(selector) => selector.matches(labels),
+        None => !schedules
+            .iter()
+            .filter_map(|s| s.selector.as_ref())
+            .any(|selector| selector.matches(labels)),
+    }
+}
+
+fn matches_name_filter(container_summary: &ContainerSummaryInner, filter: Option<&Regex>) -> bool {
+    match filter {
+        None => true,
+        Some(filter) => container_summary
+            .names
+            .as_ref()
+            .unwrap()
+            .iter()
+            .any(|n| filter.is_match(n.trim_start_matches('/'))),
+    }
+}
+
+fn matches_enable_label(
+    container_summary: &ContainerSummaryInner,
+    selector: Option<&LabelSelector>,
+) -> bool {
+    selector.map_or(true, |selector| {
+        selector.matches(container_summary.labels.as_ref())
+    })
+}
+
+/// Return true unless the container opted out of backups with `DISABLED_LABEL`
+pub(crate) fn should_back_up(container_summary: &ContainerSummaryInner) -> bool {
     match &container_summary.labels {
         None => true,
         Some(labels) => {
@@ -103,3 +507,157 @@ fn should_back_up(container_summary: &ContainerSummaryInner) -> bool {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn container(name: &str) -> ContainerSummaryInner {
+        ContainerSummaryInner {
+            names: Some(vec![format!("/{}", name)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_name_filter_test() {
+        let filter = parse_name_filter("myapp-.*").unwrap();
+        let matched = ["myapp-web", "myapp-db", "other", "not-myapp-web", "myapp"]
+            .iter()
+            .map(|name| container(name))
+            .filter(|c| matches_name_filter(c, Some(&filter)))
+            .map(|c| c.names.unwrap().remove(0))
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec!["/myapp-web", "/myapp-db"]);
+        assert!(matches_name_filter(&container("other"), None));
+    }
+
+    #[test]
+    fn apply_pause_signals_test() {
+        let mut rt = Runtime::new().unwrap();
+        let paused = AtomicBool::new(false);
+        let transitions = [
+            (PauseSignal::Toggle, true),
+            (PauseSignal::Toggle, false),
+            (PauseSignal::Toggle, true),
+            (PauseSignal::Resume, false),
+            (PauseSignal::Resume, false),
+        ];
+        for (signal, expected) in &transitions {
+            rt.block_on(apply_pause_signals(&paused, stream::iter(vec![*signal])));
+            assert_eq!(paused.load(Relaxed), *expected);
+        }
+    }
+
+    #[test]
+    fn shortest_interval_test() {
+        let every_minute = Schedule::from_str("0 * * * * * *").unwrap();
+        assert_eq!(
+            shortest_interval(&every_minute),
+            Some(Duration::from_secs(60))
+        );
+        // Irregular schedules are judged by their closest fire times
+        let twice_hourly = Schedule::from_str("0 0,5 * * * * *").unwrap();
+        assert_eq!(
+            shortest_interval(&twice_hourly),
+            Some(Duration::from_secs(5 * 60))
+        );
+        let daily = Schedule::from_str(DEFAULT_CRON).unwrap();
+        assert_eq!(
+            shortest_interval(&daily),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parse_schedules_test() {
+        let schedules = parse_schedules(
+            vec![
+                (1, "0 0 * * * * *".to_string()),
+                (5, DEFAULT_CRON.to_string()),
+            ],
+            vec![(3, "com.example.tier=db".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            schedules,
+            vec![
+                BackupSchedule {
+                    cron: "0 0 * * * * *".to_string(),
+                    selector: Some(LabelSelector {
+                        key: "com.example.tier".to_string(),
+                        value: Some("db".to_string()),
+                    }),
+                },
+                BackupSchedule {
+                    cron: DEFAULT_CRON.to_string(),
+                    selector: None,
+                },
+            ]
+        );
+        assert_eq!(
+            parse_schedules(vec![], vec![]).unwrap()[0].cron,
+            DEFAULT_CRON
+        );
+        assert!(parse_schedules(
+            vec![(3, DEFAULT_CRON.to_string())],
+            vec![(1, "db".to_string())]
+        )
+        .is_err());
+        assert!(parse_schedules(
+            vec![(1, DEFAULT_CRON.to_string())],
+            vec![(3, "db".to_string()), (5, "web".to_string())]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn matches_schedule_test() {
+        let schedules = parse_schedules(
+            vec![
+                (1, "0 0 * * * * *".to_string()),
+                (3, DEFAULT_CRON.to_string()),
+            ],
+            vec![(2, "database".to_string())],
+        )
+        .unwrap();
+        let mut database = container("postgres");
+        database.labels = Some(
+            vec![("database".to_string(), "".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let web = container("nginx");
+        assert!(matches_schedule(&database, &schedules[0], &schedules));
+        assert!(!matches_schedule(&database, &schedules[1], &schedules));
+        assert!(!matches_schedule(&web, &schedules[0], &schedules));
+        assert!(matches_schedule(&web, &schedules[1], &schedules));
+    }
+
+    #[test]
+    fn matches_enable_label_test() {
+        let mut labeled = container("postgres");
+        labeled.labels = Some(
+            vec![("mycompany.backup".to_string(), "true".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let unlabeled = container("nginx");
+        let present = "mycompany.backup".parse::<LabelSelector>().unwrap();
+        let value = "mycompany.backup=true".parse::<LabelSelector>().unwrap();
+        let other_value = "mycompany.backup=false".parse::<LabelSelector>().unwrap();
+        assert!(matches_enable_label(&labeled, Some(&present)));
+        assert!(matches_enable_label(&labeled, Some(&value)));
+        assert!(!matches_enable_label(&labeled, Some(&other_value)));
+        assert!(!matches_enable_label(&unlabeled, Some(&present)));
+        assert!(matches_enable_label(&unlabeled, None));
+        assert_eq!(value.to_string(), "mycompany.backup=true");
+    }
+
+    #[test]
+    fn parse_name_filter_test() {
+        assert!(parse_name_filter("web|db").unwrap().is_match("db"));
+        assert!(parse_name_filter("myapp-(").is_err());
+    }
+}
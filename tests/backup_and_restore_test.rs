@@ -10,7 +10,9 @@ use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
 use bollard::models::{HostConfig, Mount, MountTypeEnum};
 use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions};
 use bollard::Docker;
+use dockyard::backup::BackupOptions;
 use dockyard::container::{get_backup_volume_mount, run_dockyard_command, set_command_verbosity};
+use dockyard::restore::RestoreOptions;
 use futures::TryStreamExt;
 use log::LevelFilter;
 use simple_logger::SimpleLogger;
@@ -146,7 +148,7 @@ fn backup_all_container_volumes_test() {
             &client,
             &container_name,
             backup_mount.clone(),
-            &HashSet::new(),
+            &BackupOptions::default(),
         )
         .await
         .unwrap();
@@ -171,9 +173,10 @@ fn backup_all_container_volumes_test() {
         let restored_name = format!("restored_{}", container_name);
         dockyard::restore::restore_container(
             &client,
-            backup.to_str().unwrap(),
-            &restored_name,
+            backup.path.to_str().unwrap(),
+            Some(restored_name.as_str()),
             backup_mount.clone(),
+            &RestoreOptions::default(),
         )
         .await
         .unwrap();